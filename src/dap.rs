@@ -0,0 +1,891 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::iter;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::Value;
+
+use zerodmg_codes::instruction::Instruction;
+use zerodmg_codes::symbols::Symbols;
+use zerodmg_emulator::{
+    io_register_name, parse_address, Breakpoint, CallKind, Debugger, Expression, GameBoy,
+    RunMode, StopReason,
+};
+
+// how many cycles run between checks for requests, such as to pause
+const RUN_SLICE_CYCLES: u64 = 0x1_0000;
+// there's only one CPU to debug
+const THREAD_ID: u64 = 1;
+// the variables shown in every stack frame
+const REGISTERS_REFERENCE: u64 = 1;
+const IO_REGISTERS_REFERENCE: u64 = 2;
+// the exception breakpoint filter for stopping on stack imbalances
+const IMBALANCE_FILTER: &str = "imbalance";
+
+const NOT_LAUNCHED: &str = "no ROM has been launched";
+
+/// How the debug adapter protocol is spoken to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Over standard input and output, for clients that start the adapter
+    /// themselves.
+    Stdio,
+    /// Over one connection accepted on a port at 127.0.0.1, or any free port
+    /// if it's 0.
+    Tcp(u16),
+}
+
+/// Serves a debug adapter protocol client, such as VS Code, which launches a
+/// ROM and debugs it until disconnecting.
+pub fn run(transport: Transport) {
+    let (input, output): (Box<BufRead + Send>, Box<Write + Send>) = match transport {
+        Transport::Stdio => (Box::new(BufReader::new(io::stdin())), Box::new(io::stdout())),
+        Transport::Tcp(port) => {
+            let listener =
+                TcpListener::bind(("127.0.0.1", port)).expect("failed to listen for a client");
+            println!(
+                "; Waiting for a debug adapter client at {}",
+                listener.local_addr().unwrap()
+            );
+            let (stream, _) = listener.accept().expect("failed to accept a client");
+            let input = stream.try_clone().expect("failed to read from client");
+            (Box::new(BufReader::new(input)), Box::new(stream))
+        }
+    };
+    Session::new(output).serve(&read_messages(input));
+}
+
+/// Reads messages on another thread, until the input ends or is invalid.
+fn read_messages(mut input: Box<BufRead + Send>) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Reads a message: headers including its Content-Length, a blank line,
+/// then that many bytes of JSON. Returns None at the end of the input.
+fn read_message(input: &mut BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = parts.next().and_then(|value| value.trim().parse().ok());
+        }
+    }
+    let mut body = vec![0; length.unwrap()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message(output: &mut Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// A disassembly of a ROM bank, which clients show as a source file, with
+/// the address of the instruction on each line.
+struct Listing {
+    text: String,
+    addresses: Vec<Option<u16>>,
+}
+
+impl Listing {
+    fn new(rom: &[u8], bank: usize, symbols: &Symbols) -> Self {
+        let base: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+        let start = (bank * 0x4000).min(rom.len());
+        let bytes = &rom[start..(start + 0x4000).min(rom.len())];
+        let mut labels: Vec<(u16, &str)> = symbols
+            .iter()
+            .filter(|symbol| symbol.bank == bank && base <= symbol.address)
+            .map(|symbol| (symbol.address - base, symbol.name.as_ref()))
+            .collect();
+        labels.reverse();
+
+        let mut listing = Listing {
+            text: String::new(),
+            addresses: vec![],
+        };
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let address = base + offset as u16;
+            while labels.last().map_or(false, |&(label, _)| usize::from(label) <= offset) {
+                let (_, name) = labels.pop().unwrap();
+                listing.push(None, format!("{}:", name));
+            }
+            // labels are more likely than the previous bytes to be the start
+            // of an instruction, so decoding doesn't run past them
+            let limit = labels
+                .last()
+                .map_or(bytes.len(), |&(label, _)| usize::from(label));
+            // padded so that an instruction cut off by the end of the bank
+            // decodes, and is then rejected by the limit
+            let instruction = Instruction::from_byte_iter(
+                &mut bytes[offset..].iter().cloned().chain(iter::repeat(0x00).take(2)),
+            );
+            match instruction {
+                Some(instruction) if offset + usize::from(instruction.byte_len()) <= limit => {
+                    let code = instruction.to_string();
+                    listing.push(Some(address), format!("    {:<24}; {:04X}", code, address));
+                    offset += usize::from(instruction.byte_len());
+                }
+                _ => {
+                    let code = format!("DB ${:02X}", bytes[offset]);
+                    listing.push(Some(address), format!("    {:<24}; {:04X}", code, address));
+                    offset += 1;
+                }
+            }
+        }
+        listing
+    }
+
+    fn push(&mut self, address: Option<u16>, line: String) {
+        self.text.push_str(&line);
+        self.text.push('\n');
+        self.addresses.push(address);
+    }
+
+    /// The line of the instruction at an address, counting from 1.
+    fn line_of(&self, address: u16) -> Option<usize> {
+        self.addresses
+            .iter()
+            .position(|&line| line == Some(address))
+            .map(|i| i + 1)
+    }
+
+    /// The address of the instruction on a line, or the next one after it
+    /// if the line is a label.
+    fn address_at(&self, line: usize) -> Option<u16> {
+        self.addresses
+            .iter()
+            .skip(line.checked_sub(1)?)
+            .filter_map(|&address| address)
+            .next()
+    }
+}
+
+/// The state of a debugging session.
+struct Session {
+    output: Box<Write + Send>,
+    // the number of the latest message sent
+    seq: u64,
+    gameboy: Option<GameBoy>,
+    debugger: Debugger,
+    // disassembled ROM banks, created as they're needed
+    listings: HashMap<usize, Listing>,
+    // the debugger's numbers for the breakpoints from each request, which
+    // replace the earlier ones of the same kind
+    source_breakpoints: HashMap<u64, Vec<usize>>,
+    function_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    // how execution is continuing, while it is
+    running: Option<RunMode>,
+}
+
+impl Session {
+    fn new(output: Box<Write + Send>) -> Self {
+        Self {
+            output,
+            seq: 0,
+            gameboy: None,
+            debugger: Debugger::new(),
+            listings: HashMap::new(),
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+            stop_on_entry: false,
+            running: None,
+        }
+    }
+
+    /// Handles requests until the client disconnects, running the emulator
+    /// in between while it's continuing.
+    fn serve(&mut self, requests: &Receiver<Value>) {
+        loop {
+            let request = match self.running {
+                Some(mode) => match requests.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        self.run_slice(mode);
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => return,
+                },
+                None => match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                },
+            };
+            if !self.handle(&request) {
+                return;
+            }
+        }
+    }
+
+    /// Responds to a request, then does anything that must come after the
+    /// response, returning whether to keep serving.
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        let arguments = &request["arguments"];
+        let response = match command.as_ref() {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => self.set_exception_breakpoints(arguments),
+            "configurationDone" | "disconnect" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CPU" }] })),
+            "stackTrace" => self.stack_trace(arguments),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "source" => self.source(arguments),
+            "readMemory" => self.read_memory(arguments),
+            "disassemble" => self.disassemble(arguments),
+            "continue" => self
+                .gameboy()
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "pause" => self.gameboy().map(|_| Value::Null),
+            _ => Err(format!("unsupported request: {}", command)),
+        };
+        let succeeded = response.is_ok();
+        self.respond(request, response);
+        if !succeeded {
+            return true;
+        }
+
+        match command.as_ref() {
+            "launch" => self.send_event("initialized", Value::Null),
+            "configurationDone" if self.gameboy.is_some() => {
+                if self.stop_on_entry {
+                    self.send_stopped("entry", None, None);
+                } else {
+                    self.running = Some(RunMode::Continue);
+                }
+            }
+            "continue" => self.running = Some(RunMode::Continue),
+            "next" => {
+                let mode = RunMode::step_over(self.gameboy.as_ref().unwrap());
+                match mode {
+                    Some(mode) => self.running = Some(mode),
+                    None => self.step(),
+                }
+            }
+            "stepIn" => self.step(),
+            "stepOut" => {
+                self.running = Some(RunMode::step_out(self.gameboy.as_ref().unwrap()));
+            }
+            "pause" if self.running.is_some() => {
+                self.running = None;
+                self.send_stopped("pause", None, None);
+            }
+            "disconnect" => return false,
+            _ => {}
+        }
+        true
+    }
+
+    fn gameboy(&self) -> Result<&GameBoy, String> {
+        self.gameboy.as_ref().ok_or_else(|| NOT_LAUNCHED.to_string())
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.output, &message).expect("failed to write to client");
+    }
+
+    fn respond(&mut self, request: &Value, response: Result<Value, String>) {
+        let mut message = json!({
+            "type": "response",
+            "request_seq": request["seq"].clone(),
+            "command": request["command"].clone(),
+            "success": response.is_ok(),
+        });
+        match response {
+            Ok(Value::Null) => {}
+            Ok(body) => message["body"] = body,
+            Err(error) => message["message"] = json!(error),
+        }
+        self.send(message);
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message);
+    }
+
+    fn send_stopped(&mut self, reason: &str, breakpoint: Option<usize>, text: Option<String>) {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(id) = breakpoint {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        if let Some(text) = text {
+            body["text"] = json!(text.clone());
+            body["description"] = json!(text);
+        }
+        self.send_event("stopped", body);
+    }
+
+    /// Runs the emulator, catching panics such as on unimplemented or invalid
+    /// instructions, which are reported instead of ending the session.
+    fn execute<F>(&mut self, run: F) -> Result<StopReason, String>
+    where
+        F: FnOnce(&Debugger, &mut GameBoy) -> StopReason,
+    {
+        let debugger = &self.debugger;
+        let gameboy = self.gameboy.as_mut().ok_or_else(|| NOT_LAUNCHED.to_string())?;
+        panic::catch_unwind(AssertUnwindSafe(|| run(debugger, gameboy))).map_err(panic_message)
+    }
+
+    fn step(&mut self) {
+        let result = self.execute(|debugger, gameboy| debugger.step(gameboy));
+        self.report(result);
+    }
+
+    fn run_slice(&mut self, mode: RunMode) {
+        let result = self.execute(|debugger, gameboy| {
+            debugger.resume(gameboy, mode, RUN_SLICE_CYCLES)
+        });
+        if result != Ok(StopReason::CyclesElapsed) {
+            self.running = None;
+            self.report(result);
+        } else {
+            self.send_log();
+        }
+    }
+
+    /// Sends the messages logged by the emulator as console output.
+    fn send_log(&mut self) {
+        let log = match self.gameboy.as_mut() {
+            Some(gameboy) => gameboy.take_log(),
+            None => vec![],
+        };
+        for message in log {
+            self.send_event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", message) }),
+            );
+        }
+    }
+
+    /// Reports why execution stopped, after any stack imbalances detected
+    /// while it was running.
+    fn report(&mut self, result: Result<StopReason, String>) {
+        let imbalances: Vec<String> = match self.gameboy.as_mut() {
+            Some(gameboy) => {
+                let imbalances = gameboy.take_stack_imbalances();
+                imbalances
+                    .iter()
+                    .map(|imbalance| imbalance.describe(gameboy.symbols()))
+                    .collect()
+            }
+            None => vec![],
+        };
+        self.send_log();
+        for imbalance in imbalances {
+            self.send_event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", imbalance) }),
+            );
+        }
+
+        match result {
+            Ok(StopReason::Breakpoint(id)) => self.send_stopped("breakpoint", Some(id), None),
+            Ok(StopReason::Watchpoint(hit)) => {
+                self.send_stopped("data breakpoint", None, Some(hit.to_string()))
+            }
+            Ok(StopReason::StackImbalance(imbalance)) => {
+                let text = imbalance.describe(self.gameboy.as_ref().unwrap().symbols());
+                self.send_stopped("exception", None, Some(text))
+            }
+            Ok(_) => self.send_stopped("step", None, None),
+            Err(message) => self.send_stopped("exception", None, Some(message)),
+        }
+    }
+
+    /// Starts the ROM given as `program`, with the symbols from `symbols` or
+    /// a `.sym` file beside it, if any.
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("launch requires a program")?;
+        let mut builder = GameBoy::builder()
+            .rom_file(Path::new(program))
+            .map_err(|error| format!("can't read {}: {}", program, error))?;
+        if let Some(path) = arguments["bootRom"].as_str() {
+            builder = builder
+                .boot_rom_file(Path::new(path))
+                .map_err(|error| format!("can't read {}: {}", path, error))?;
+        }
+        if arguments["skipBootRom"].as_bool() == Some(true) {
+            builder = builder.skip_boot_rom();
+        }
+        if let Some(model) = arguments["model"].as_str() {
+            builder = builder.model(model.parse()?);
+        }
+        let symbols_path = match arguments["symbols"].as_str() {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(program).with_extension("sym")).filter(|path| path.exists()),
+        };
+        if let Some(path) = symbols_path {
+            builder = builder
+                .symbols_file(&path)
+                .map_err(|error| format!("can't read {}: {}", path.display(), error))?;
+        }
+        let gameboy = builder
+            .build()
+            .map_err(|error| format!("can't run {}: {}", program, error))?;
+
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool() == Some(true);
+        self.listings.clear();
+        self.gameboy = Some(gameboy);
+        Ok(Value::Null)
+    }
+
+    /// The listing of a ROM bank, disassembling it if it hasn't been yet.
+    fn listing(&mut self, bank: usize) -> Option<&Listing> {
+        let gameboy = self.gameboy.as_ref()?;
+        let rom = gameboy.cartridge().rom();
+        if bank * 0x4000 >= rom.len() {
+            return None;
+        }
+        let symbols = gameboy.symbols();
+        Some(
+            self.listings
+                .entry(bank)
+                .or_insert_with(|| Listing::new(rom, bank, symbols)),
+        )
+    }
+
+    fn source_for(&self, bank: usize) -> Value {
+        json!({
+            "name": format!("ROM bank {:02X}", bank),
+            "sourceReference": bank + 1,
+        })
+    }
+
+    /// The source and line of the instruction at an address, if it's in ROM.
+    fn location(&mut self, address: u16) -> Option<(Value, usize)> {
+        if address > 0x7FFF {
+            return None;
+        }
+        let bank = self.gameboy.as_ref()?.bank_at(address);
+        let line = self.listing(bank)?.line_of(address)?;
+        Some((self.source_for(bank), line))
+    }
+
+    fn source(&mut self, arguments: &Value) -> Result<Value, String> {
+        let reference = arguments["sourceReference"]
+            .as_u64()
+            .or_else(|| arguments["source"]["sourceReference"].as_u64())
+            .unwrap_or(0);
+        let bank = reference.checked_sub(1).ok_or("unknown source")? as usize;
+        let listing = self.listing(bank).ok_or("unknown source")?;
+        Ok(json!({ "content": listing.text.clone(), "mimeType": "text/x-asm" }))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let reference = arguments["source"]["sourceReference"].as_u64().unwrap_or(0);
+        for id in self.source_breakpoints.remove(&reference).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut ids = vec![];
+        let mut results = vec![];
+        for requested in arguments["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            let bank = reference.wrapping_sub(1) as usize;
+            let address = match self.listing(bank).and_then(|listing| listing.address_at(line)) {
+                Some(address) => address,
+                None => {
+                    results.push(unverified("only ROM listings can have breakpoints"));
+                    continue;
+                }
+            };
+            let condition = match parse_condition(&requested["condition"]) {
+                Ok(condition) => condition,
+                Err(error) => {
+                    results.push(unverified(&error));
+                    continue;
+                }
+            };
+            let id = self.debugger.add_breakpoint(Breakpoint {
+                bank: Some(bank),
+                address: Some(address),
+                condition,
+            });
+            ids.push(id);
+            let line = self.listing(bank).and_then(|listing| listing.line_of(address));
+            results.push(json!({
+                "id": id,
+                "verified": true,
+                "source": self.source_for(bank),
+                "line": line,
+            }));
+        }
+        self.source_breakpoints.insert(reference, ids);
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /// Sets breakpoints on labels from the symbols, or on addresses.
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        for id in self.function_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut results = vec![];
+        for requested in arguments["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let name = requested["name"].as_str().unwrap_or("");
+            let mut breakpoint = match Breakpoint::parse(name, self.gameboy()?.symbols()) {
+                Ok(ref breakpoint) if breakpoint.address.is_none() => {
+                    results.push(unverified("a function breakpoint needs an address"));
+                    continue;
+                }
+                Ok(breakpoint) => breakpoint,
+                Err(_) => {
+                    results.push(unverified(&format!("unknown label: {}", name)));
+                    continue;
+                }
+            };
+            match parse_condition(&requested["condition"]) {
+                Ok(Some(condition)) => breakpoint.condition = Some(condition),
+                Ok(None) => {}
+                Err(error) => {
+                    results.push(unverified(&error));
+                    continue;
+                }
+            }
+            let id = self.debugger.add_breakpoint(breakpoint);
+            self.function_breakpoints.push(id);
+            results.push(json!({ "id": id, "verified": true }));
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /// Sets whether to stop on stack imbalances, the only kind of exception
+    /// that doesn't stop execution anyway.
+    fn set_exception_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let filters = arguments["filters"].as_array().cloned().unwrap_or_default();
+        let stop = filters.iter().any(|filter| filter == IMBALANCE_FILTER);
+        self.debugger.set_stop_on_imbalance(stop);
+        Ok(Value::Null)
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        for id in self.instruction_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut results = vec![];
+        for requested in arguments["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let address = memory_reference(&requested["instructionReference"], &requested["offset"])
+                .and_then(|address| parse_condition(&requested["condition"]).map(|c| (address, c)));
+            let (address, condition) = match address {
+                Ok(found) => found,
+                Err(error) => {
+                    results.push(unverified(&error));
+                    continue;
+                }
+            };
+            let id = self.debugger.add_breakpoint(Breakpoint {
+                bank: None,
+                address: Some(address),
+                condition,
+            });
+            self.instruction_breakpoints.push(id);
+            results.push(json!({
+                "id": id,
+                "verified": true,
+                "instructionReference": format!("0x{:04X}", address),
+            }));
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /// The current instruction, then the calls that led to it, from the
+    /// emulator's shadow call stack.
+    fn stack_trace(&mut self, arguments: &Value) -> Result<Value, String> {
+        let gameboy = self.gameboy()?;
+        let pc = gameboy.pc();
+        let mut locations = vec![(gameboy.bank_at(pc), pc, CallKind::Call)];
+        for frame in gameboy.call_stack().iter().rev() {
+            locations.push((frame.caller_bank, frame.caller, frame.kind));
+        }
+        let start = arguments["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match arguments["levels"].as_u64() {
+            Some(0) | None => locations.len(),
+            Some(levels) => levels as usize,
+        };
+
+        let mut frames = vec![];
+        for (id, &(bank, address, kind)) in locations.iter().enumerate().skip(start).take(levels) {
+            let mut name = self.gameboy()?.symbols().describe(bank, address);
+            if kind == CallKind::Interrupt {
+                name.push_str(" (interrupted)");
+            }
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:04X}", address),
+            });
+            if let Some((source, line)) = self.location(address) {
+                frame["source"] = source;
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            frames.push(frame);
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": locations.len() }))
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Result<Value, String> {
+        let gameboy = self.gameboy()?;
+        let registers = gameboy.registers();
+        let byte = |name: &str, value: u16| variable(name, format!("0x{:02X}", value & 0xFF));
+        let word = |name: &str, value: u16| {
+            let mut variable = variable(name, format!("0x{:04X}", value));
+            variable["memoryReference"] = json!(format!("0x{:04X}", value));
+            variable
+        };
+        let variables = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => vec![
+                byte("A", registers.af >> 8),
+                byte("F", registers.af),
+                byte("B", registers.bc >> 8),
+                byte("C", registers.bc),
+                byte("D", registers.de >> 8),
+                byte("E", registers.de),
+                byte("H", registers.hl >> 8),
+                byte("L", registers.hl),
+                word("BC", registers.bc),
+                word("DE", registers.de),
+                word("HL", registers.hl),
+                word("SP", registers.sp),
+                word("PC", registers.pc),
+                variable("ZF", registers.z_flag().to_string()),
+                variable("NF", registers.n_flag().to_string()),
+                variable("HF", registers.h_flag().to_string()),
+                variable("CF", registers.c_flag().to_string()),
+                variable("IME", registers.ime.to_string()),
+                variable("bank", gameboy.bank_at(registers.pc).to_string()),
+                variable("cycles", gameboy.cycles().to_string()),
+            ],
+            Some(IO_REGISTERS_REFERENCE) => (0xFF00..=0xFFFF)
+                .filter_map(|addr| {
+                    let name = io_register_name(addr)?;
+                    let mut variable = byte(name, u16::from(gameboy.peek(addr)));
+                    variable["memoryReference"] = json!(format!("0x{:04X}", addr));
+                    Some(variable)
+                }).collect(),
+            _ => vec![],
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Evaluates an expression in the debugger's syntax, like `[rLY] > 0x90`.
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+        let expression: Expression = arguments["expression"].as_str().unwrap_or("").parse()?;
+        let value = expression.evaluate(self.gameboy()?);
+        let result = if value < 0 {
+            value.to_string()
+        } else {
+            format!("0x{:X} ({})", value, value)
+        };
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    fn read_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let gameboy = self.gameboy()?;
+        let reference = arguments["memoryReference"].as_str().ok_or("invalid reference")?;
+        let start = i64::from(parse_address(reference)?) + arguments["offset"].as_i64().unwrap_or(0);
+        let count = arguments["count"].as_i64().unwrap_or(0);
+        let end = (start + count).min(0x1_0000);
+        if start < 0 || end <= start {
+            return Ok(json!({ "address": format!("0x{:04X}", start.max(0)), "unreadableBytes": count }));
+        }
+        let bytes: Vec<u8> = (start..end).map(|addr| gameboy.peek(addr as u16)).collect();
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn disassemble(&mut self, arguments: &Value) -> Result<Value, String> {
+        let address = memory_reference(&arguments["memoryReference"], &arguments["offset"])?;
+        let offset = arguments["instructionOffset"].as_i64().unwrap_or(0);
+        let count = arguments["instructionCount"].as_u64().unwrap_or(0) as usize;
+        let gameboy = self.gameboy()?;
+        let start = if offset < 0 {
+            instruction_before(gameboy, address, -offset as usize)
+        } else {
+            gameboy.disassemble(address, offset as usize + 1)[offset as usize].0
+        };
+        let decoded = gameboy.disassemble(start, count);
+
+        let mut instructions = vec![];
+        for (address, instruction) in decoded {
+            let gameboy = self.gameboy()?;
+            let bytes: Vec<String> = (0..instruction.byte_len())
+                .map(|i| format!("{:02X}", gameboy.peek(address.wrapping_add(i))))
+                .collect();
+            let symbols = gameboy.symbols();
+            let label = symbols.label_at(gameboy.bank_at(address), address);
+            let text =
+                symbols.format_instruction(instruction, address, &|target| gameboy.bank_at(target));
+            let mut json = json!({
+                "address": format!("0x{:04X}", address),
+                "instruction": text,
+                "instructionBytes": bytes.join(" "),
+            });
+            if let Some(label) = label {
+                json["symbol"] = json!(label);
+            }
+            if let Some((source, line)) = self.location(address) {
+                json["location"] = source;
+                json["line"] = json!(line);
+            }
+            instructions.push(json);
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsConditionalBreakpoints": true,
+        "supportsFunctionBreakpoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsEvaluateForHovers": true,
+        "supportsReadMemoryRequest": true,
+        "supportsDisassembleRequest": true,
+        "supportsSteppingGranularity": true,
+        "exceptionBreakpointFilters": [{
+            "filter": IMBALANCE_FILTER,
+            "label": "Stack imbalances",
+            "description": "Stop when the stack gets out of step with the calls made, such as \
+                            when a POP discards a return address",
+            "default": false,
+        }],
+    })
+}
+
+fn scopes() -> Value {
+    json!({
+        "scopes": [
+            { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+            { "name": "IO registers", "variablesReference": IO_REGISTERS_REFERENCE, "expensive": false },
+        ]
+    })
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn unverified(message: &str) -> Value {
+    json!({ "verified": false, "message": message })
+}
+
+fn parse_condition(condition: &Value) -> Result<Option<Expression>, String> {
+    match condition.as_str() {
+        Some(condition) if !condition.trim().is_empty() => condition.parse().map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Parses a memory reference, which is an address in hex, plus an offset.
+fn memory_reference(reference: &Value, offset: &Value) -> Result<u16, String> {
+    let reference = reference.as_str().ok_or("invalid reference")?;
+    let address = i64::from(parse_address(reference)?) + offset.as_i64().unwrap_or(0);
+    if 0 <= address && address <= 0xFFFF {
+        Ok(address as u16)
+    } else {
+        Err(format!("address out of range: {}", address))
+    }
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "emulation stopped by a panic".to_string(),
+        },
+    }
+}
+
+/// The address of the instruction `count` before one, found by decoding from
+/// earlier addresses until one lines up with it, or guessed if none do.
+fn instruction_before(gameboy: &GameBoy, address: u16, count: usize) -> u16 {
+    let earliest = usize::from(address).saturating_sub(count * 3) as u16;
+    for start in earliest..address {
+        let mut starts = vec![];
+        let mut next = start;
+        while next < address {
+            starts.push(next);
+            next = next.saturating_add(gameboy.disassemble(next, 1)[0].1.byte_len());
+        }
+        if next == address && starts.len() >= count {
+            return starts[starts.len() - count];
+        }
+    }
+    usize::from(address).saturating_sub(count) as u16
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (i, &byte)| group | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
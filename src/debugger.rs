@@ -0,0 +1,391 @@
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use zerodmg_codes::instruction::Instruction;
+use zerodmg_emulator::{
+    io_register_address, io_register_name, Breakpoint, Debugger, Expression, GameBoy, RunMode,
+    StopReason, Watchpoint,
+};
+
+// how many instructions are shown after the current one
+const DISASSEMBLY_AFTER: usize = 5;
+// how many already-executed instructions are shown before the current one
+const DISASSEMBLY_BEFORE: usize = 3;
+// how long to run between checks for input interrupting it, in M-cycles
+const RUN_SLICE_CYCLES: u64 = 0x1_0000;
+
+const HELP: &str = "\
+commands:
+  step [count]             (s) execute instructions
+  next                     (n) execute an instruction, running calls to completion
+  continue                 (c) run until a breakpoint
+  finish                   (fin) run until the current function returns
+  break <[bank:]address|label> [if <condition>]
+                           (b) add a breakpoint, or stop whenever a condition
+                           is true with `break if <condition>`
+  delete <number>          remove a breakpoint
+  breakpoints              list breakpoints
+  watch [r|w|x] <start>[-end] [if <condition>]
+                           stop when memory is read, written (the default) or
+                           executed, like `watch rw c000-c0ff`
+  unwatch <number>         remove a watchpoint
+  watchpoints              list watchpoints
+  log [register...|off]    print accesses to IO registers, like `log lcdc stat`
+  registers                (r) show registers and flags
+  memory <address> [len]   (x) show memory in hex, from an address or label
+  disassemble [address]    (d) show instructions, around PC by default
+  history [count]          (h) show the latest instructions executed
+  backtrace                (bt) show the calls that led to the current
+                           instruction
+  imbalance [stop|report]  whether to stop when the stack gets out of step
+                           with the calls made, or only report it
+  print <expression>       (p) evaluate an expression, like `[rLY] > 0x90`
+  trace [condition|off]    print instructions executed while a condition is
+                           true, like `trace bank == 2`
+  quit                     (q)
+an empty line repeats the previous command, and any line interrupts running";
+
+/// Runs an interactive debugger on standard input, until it's quit or the
+/// input ends.
+pub fn run(gameboy: &mut GameBoy) {
+    let mut debugger = Debugger::new();
+    let lines = read_lines();
+    let mut previous = String::new();
+
+    println!("; Debugging {}, type help for commands", describe(gameboy));
+    show_location(gameboy);
+    loop {
+        print!("(zerodmg) ");
+        io::stdout().flush().unwrap();
+        let line = match lines.recv() {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = if line.trim().is_empty() {
+            previous.clone()
+        } else {
+            line.trim().to_string()
+        };
+        previous = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        match run_command(gameboy, &mut debugger, &lines, &line, &words) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(message) => println!("{}", message),
+        }
+    }
+}
+
+/// Reads lines from standard input on another thread, so that they can
+/// interrupt the emulator while it's running.
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let sent = line.ok().map(|line| sender.send(line).is_ok());
+            if sent != Some(true) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn describe(gameboy: &GameBoy) -> String {
+    let title = gameboy.cartridge().title();
+    if title.is_empty() {
+        "untitled ROM".to_string()
+    } else {
+        title
+    }
+}
+
+/// Runs a command, returning whether to keep going.
+fn run_command(
+    gameboy: &mut GameBoy,
+    debugger: &mut Debugger,
+    lines: &Receiver<String>,
+    line: &str,
+    words: &[&str],
+) -> Result<bool, String> {
+    let arg = |i: usize| words.get(i).cloned();
+    // everything after the command, for expressions containing spaces
+    let rest = line[words[0].len()..].trim();
+    let count = |i: usize, default: usize| match arg(i) {
+        Some(n) => n.parse().map_err(|_| format!("invalid count: {}", n)),
+        None => Ok(default),
+    };
+
+    match words[0] {
+        "step" | "s" => {
+            let steps = count(1, 1)?;
+            execute(gameboy, lines, |gameboy| {
+                let mut reason = StopReason::Stepped;
+                for _ in 0..steps {
+                    reason = debugger.step(gameboy);
+                    // such as a watchpoint being hit
+                    if reason != StopReason::Stepped {
+                        break;
+                    }
+                }
+                reason
+            });
+        }
+        "next" | "n" => match RunMode::step_over(gameboy) {
+            Some(mode) => run_in_slices(gameboy, debugger, lines, mode),
+            None => execute(gameboy, lines, |gameboy| debugger.step(gameboy)),
+        },
+        "continue" | "c" => run_in_slices(gameboy, debugger, lines, RunMode::Continue),
+        "finish" | "fin" => {
+            let mode = RunMode::step_out(gameboy);
+            run_in_slices(gameboy, debugger, lines, mode);
+        }
+        "break" | "b" => {
+            if rest.is_empty() {
+                return Err("break requires an address or condition".to_string());
+            }
+            let breakpoint = Breakpoint::parse(rest, gameboy.symbols())?;
+            let description = breakpoint.to_string();
+            let id = debugger.add_breakpoint(breakpoint);
+            println!("breakpoint {}: {}", id, description);
+        }
+        "delete" => {
+            let id = count(1, 0)?;
+            if !debugger.remove_breakpoint(id) {
+                return Err(format!("no breakpoint {}", id));
+            }
+        }
+        "breakpoints" => {
+            for (id, breakpoint) in debugger.breakpoints() {
+                println!("{:3}  {}", id, breakpoint);
+            }
+        }
+        "watch" => {
+            if rest.is_empty() {
+                return Err("watch requires an address".to_string());
+            }
+            let watchpoint: Watchpoint = rest.parse()?;
+            let description = watchpoint.to_string();
+            let id = gameboy.add_watchpoint(watchpoint);
+            println!("watchpoint {}: {}", id, description);
+        }
+        "unwatch" => {
+            let id = count(1, 0)?;
+            if !gameboy.remove_watchpoint(id) {
+                return Err(format!("no watchpoint {}", id));
+            }
+        }
+        "watchpoints" => {
+            for (id, watchpoint) in gameboy.watchpoints() {
+                println!("{:3}  {}", id, watchpoint);
+            }
+        }
+        "log" => match rest {
+            "" => {
+                let names: Vec<String> = gameboy
+                    .logged_io_registers()
+                    .into_iter()
+                    .map(|addr| io_register_name(addr).unwrap_or("?").to_string())
+                    .collect();
+                println!("logging: {}", names.join(" "));
+            }
+            "off" => {
+                for addr in gameboy.logged_io_registers() {
+                    gameboy.set_io_logging(addr, false)?;
+                }
+            }
+            _ => {
+                let mut addrs = vec![];
+                for name in rest.split_whitespace() {
+                    match io_register_address(name) {
+                        Some(addr) => addrs.push(addr),
+                        None => return Err(format!("unknown IO register: {}", name)),
+                    }
+                }
+                for addr in addrs {
+                    gameboy.set_io_logging(addr, true)?;
+                }
+            }
+        },
+        "registers" | "r" => println!("{}", gameboy.registers()),
+        "memory" | "x" => {
+            let address = gameboy.resolve_address(arg(1).ok_or("memory requires an address")?)?;
+            let length = count(2, 0x40)?;
+            print_memory(gameboy, address, length);
+        }
+        "disassemble" | "d" => match arg(1) {
+            Some(address) => {
+                let address = gameboy.resolve_address(address)?;
+                for (address, instruction) in gameboy.disassemble(address, count(2, 10)?) {
+                    print_label(gameboy, address);
+                    println!("    0x{:04X}  {}", address, format(gameboy, address, instruction));
+                }
+            }
+            None => print_disassembly(gameboy),
+        },
+        "history" | "h" => {
+            for line in gameboy.latest_executions(count(1, 16)?) {
+                println!("{}", line);
+            }
+        }
+        "backtrace" | "bt" => {
+            for line in gameboy.backtrace() {
+                println!("{}", line);
+            }
+        }
+        "imbalance" => match rest {
+            "" if debugger.stop_on_imbalance() => println!("stopping on stack imbalances"),
+            "" => println!("reporting stack imbalances"),
+            "stop" => debugger.set_stop_on_imbalance(true),
+            "report" => debugger.set_stop_on_imbalance(false),
+            _ => return Err(format!("expected stop or report: {}", rest)),
+        },
+        "print" | "p" => {
+            let expression: Expression = rest.parse()?;
+            let value = expression.evaluate(gameboy);
+            if value < 0 {
+                println!("{}", value);
+            } else {
+                println!("0x{:X} ({})", value, value);
+            }
+        }
+        "trace" => match rest {
+            "" => match debugger.trace_filter() {
+                Some(filter) => println!("tracing while {}", filter),
+                None => println!("not tracing"),
+            },
+            "off" => debugger.set_trace_filter(None),
+            _ => debugger.set_trace_filter(Some(rest.parse()?)),
+        },
+        "help" | "?" => println!("{}", HELP),
+        "quit" | "q" => return Ok(false),
+        command => return Err(format!("unknown command: {}", command)),
+    }
+    Ok(true)
+}
+
+/// Runs the emulator in a mode a slice at a time, until it stops or a line
+/// of input interrupts it.
+fn run_in_slices(
+    gameboy: &mut GameBoy,
+    debugger: &Debugger,
+    lines: &Receiver<String>,
+    mode: RunMode,
+) {
+    execute(gameboy, lines, |gameboy| {
+        debugger.resume(gameboy, mode, RUN_SLICE_CYCLES)
+    });
+}
+
+/// Runs the emulator until it stops for a reason other than CyclesElapsed,
+/// or a line of input interrupts it, reporting why it stopped and any stack
+/// imbalances along the way. Emulation panics, such as on unimplemented or
+/// invalid instructions, are reported with a backtrace instead of ending the
+/// debugger, which is when inspecting the state is most useful.
+fn execute<F>(gameboy: &mut GameBoy, lines: &Receiver<String>, mut run: F)
+where
+    F: FnMut(&mut GameBoy) -> StopReason,
+{
+    let result = loop {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run(gameboy)));
+        for message in gameboy.take_log() {
+            println!("{}", message);
+        }
+        if result.as_ref().ok() != Some(&StopReason::CyclesElapsed) {
+            break result;
+        }
+        if lines.try_recv().is_ok() {
+            println!("interrupted");
+            break result;
+        }
+    };
+    for imbalance in gameboy.take_stack_imbalances() {
+        println!("{}", imbalance.describe(gameboy.symbols()));
+    }
+    match result {
+        Ok(StopReason::Breakpoint(id)) => println!("breakpoint {}", id),
+        Ok(StopReason::Returned) => println!("returned"),
+        Ok(StopReason::Watchpoint(hit)) => println!("{}", hit),
+        Ok(StopReason::StackImbalance(imbalance)) => {
+            println!("{}", imbalance.describe(gameboy.symbols()))
+        }
+        Ok(_) => {}
+        Err(_) => {
+            println!("emulation stopped by a panic");
+            for line in gameboy.backtrace() {
+                println!("{}", line);
+            }
+        }
+    }
+    show_location(gameboy);
+}
+
+fn show_location(gameboy: &GameBoy) {
+    let pc = gameboy.pc();
+    let bank = gameboy.bank_at(pc);
+    let (_, instruction) = gameboy.disassemble(pc, 1)[0];
+    let label = match gameboy.symbols().nearest(bank, pc) {
+        Some(_) => format!(" <{}>", gameboy.symbols().describe(bank, pc)),
+        None => String::new(),
+    };
+    println!(
+        "=> {:02X}:{:04X}{}  {}",
+        bank,
+        pc,
+        label,
+        format(gameboy, pc, instruction)
+    );
+}
+
+// an instruction, with the label of the address it jumps to if there is one
+fn format(gameboy: &GameBoy, address: u16, instruction: Instruction) -> String {
+    gameboy
+        .symbols()
+        .format_instruction(instruction, address, &|target| gameboy.bank_at(target))
+}
+
+// prints the label at an address on its own line, if it has one
+fn print_label(gameboy: &GameBoy, address: u16) {
+    if let Some(label) = gameboy.symbols().label_at(gameboy.bank_at(address), address) {
+        println!("{}:", label);
+    }
+}
+
+// shows the latest instructions executed, then the next ones to execute
+fn print_disassembly(gameboy: &GameBoy) {
+    for line in gameboy.latest_executions(DISASSEMBLY_BEFORE) {
+        println!("       {}", line);
+    }
+    let pc = gameboy.pc();
+    for (address, instruction) in gameboy.disassemble(pc, DISASSEMBLY_AFTER + 1) {
+        let marker = if address == pc { "=>" } else { "  " };
+        print_label(gameboy, address);
+        println!(
+            "    {} 0x{:04X}  {}",
+            marker,
+            address,
+            format(gameboy, address, instruction)
+        );
+    }
+}
+
+fn print_memory(gameboy: &GameBoy, address: u16, length: usize) {
+    let start = address & !0xF;
+    let end = usize::from(address) + length;
+    for row in (usize::from(start)..end.min(0x1_0000)).step_by(0x10) {
+        let bytes: Vec<u8> = (row..row + 0x10).map(|a| gameboy.peek(a as u16)).collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = bytes
+            .iter()
+            .map(|&b| if 0x20 <= b && b < 0x7F { b as char } else { '.' })
+            .collect();
+        println!("{:04X}:  {}  {}", row, hex.join(" "), text);
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot::Sender;
+
+use zerodmg_emulator::{
+    Button, Expression, GameBoy, LoadedState, Movie, MovieRecorder, Pacer, RewindBuffer,
+    StateError, FRAME_RATE,
+};
+
+// how often the latest instructions are logged, in M-cycles
+const LOG_INTERVAL: u64 = (1024 * 1024) / 2;
+// how many of the latest instructions are logged
+const LOG_SIZE: usize = 32;
+
+/// A request from the UI server to the emulator thread, which owns the
+/// [GameBoy], handled between frames.
+pub enum Command {
+    /// Replies with a save state of the current frame, which other
+    /// emulators supporting BESS can also load.
+    SaveState(Sender<Vec<u8>>),
+    /// Loads a save state, replying with whether it could be loaded.
+    LoadState(Vec<u8>, Sender<Result<LoadedState, StateError>>),
+    /// Returns to the previous frame, replying with whether it was still in
+    /// the rewind history.
+    StepBack(Sender<bool>),
+    /// Presses or releases a button, from the next frame on.
+    SetButton(Button, bool),
+    /// Replies with the value of an expression for the current state.
+    Evaluate(Expression, Sender<i64>),
+}
+
+/// Whether a movie is being recorded or played back.
+pub enum MovieMode {
+    Off,
+    Recording(MovieRecorder<File>),
+    /// Playing back a movie, with the index of the next frame.
+    Playing(Movie, usize),
+}
+
+/// What the emulator thread keeps alongside the [GameBoy].
+pub struct Session {
+    rewind: RewindBuffer,
+    movie: MovieMode,
+    // the buttons held in the web UI, which are only applied between frames
+    // so that movies can reproduce them exactly
+    buttons: u8,
+}
+
+impl Session {
+    pub fn new(rewind: RewindBuffer, movie: MovieMode) -> Self {
+        Self {
+            rewind,
+            movie,
+            buttons: 0x00,
+        }
+    }
+
+    fn stop_movie(&mut self, reason: &str) {
+        match self.movie {
+            MovieMode::Off => return,
+            MovieMode::Recording(_) => println!("; Stopped recording movie: {}", reason),
+            MovieMode::Playing(_, _) => println!("; Stopped playing movie: {}", reason),
+        }
+        self.movie = MovieMode::Off;
+    }
+
+    /// Handles a command, returning whether it moved the emulator to a
+    /// different point in time.
+    fn handle_command(&mut self, gameboy: &mut GameBoy, command: Command) -> bool {
+        // the requester may have given up waiting, which is fine
+        match command {
+            Command::SaveState(reply) => {
+                let _ = reply.send(gameboy.save_bess_state());
+                false
+            }
+            Command::LoadState(state, reply) => {
+                let result = gameboy.load_state(&state);
+                let loaded = result.is_ok();
+                if loaded {
+                    self.stop_movie("a state was loaded");
+                    self.rewind.clear();
+                    self.rewind.record(gameboy);
+                }
+                let _ = reply.send(result);
+                loaded
+            }
+            Command::StepBack(reply) => {
+                // stepping back isn't allowed while a movie is recording or
+                // playing, since it would make the movie skip or repeat frames
+                let in_movie = match self.movie {
+                    MovieMode::Off => false,
+                    _ => true,
+                };
+                let stepped = !in_movie && self.rewind.step_back(gameboy);
+                let _ = reply.send(stepped);
+                stepped
+            }
+            Command::SetButton(button, pressed) => {
+                if pressed {
+                    self.buttons |= button.bit();
+                } else {
+                    self.buttons &= !button.bit();
+                }
+                false
+            }
+            Command::Evaluate(expression, reply) => {
+                let _ = reply.send(expression.evaluate(gameboy));
+                false
+            }
+        }
+    }
+
+    /// Runs a frame with the buttons held in the UI, or the next frame of the
+    /// movie being played, and records it.
+    fn run_frame(&mut self, gameboy: &mut GameBoy, pacer: &Mutex<Pacer>) {
+        let played = match self.movie {
+            MovieMode::Playing(ref movie, ref mut frame) if *frame < movie.len() => {
+                let result = movie.play_frame(gameboy, *frame);
+                *frame += 1;
+                Some(result)
+            }
+            _ => None,
+        };
+        match played {
+            Some(Ok(())) => {}
+            Some(Err(error)) => {
+                pacer.lock().unwrap().pause();
+                self.stop_movie(&error.to_string());
+            }
+            None => {
+                if let MovieMode::Playing(_, _) = self.movie {
+                    self.stop_movie("it finished");
+                }
+                gameboy.set_buttons(self.buttons);
+                gameboy.run_frame();
+            }
+        }
+
+        let recorded = match self.movie {
+            MovieMode::Recording(ref mut recorder) => recorder.record_frame(gameboy),
+            _ => Ok(()),
+        };
+        if let Err(error) = recorded {
+            self.stop_movie(&error.to_string());
+        }
+        self.rewind.record(gameboy);
+    }
+}
+
+/// Runs the emulator forever, a frame at a time, as scheduled by the pacer,
+/// handling commands between frames, recording each frame in the rewind
+/// history and periodically logging the latest instructions and any stack
+/// imbalances.
+pub fn run(
+    gameboy: &mut GameBoy,
+    pacer: &Mutex<Pacer>,
+    session: &mut Session,
+    commands: &Receiver<Command>,
+) -> ! {
+    let frame_duration = Duration::from_nanos((1e9 / FRAME_RATE) as u64);
+    let mut log_at_cycle = gameboy.cycles() + LOG_INTERVAL;
+    session.rewind.record(gameboy);
+
+    loop {
+        while let Ok(command) = commands.try_recv() {
+            if session.handle_command(gameboy, command) {
+                // the cycle count may have gone backwards, so the schedule
+                // and logging start over from it
+                pacer.lock().unwrap().resync(gameboy.cycles(), Instant::now());
+                log_at_cycle = gameboy.cycles() + LOG_INTERVAL;
+            }
+        }
+
+        let should_run = pacer.lock().unwrap().next_frame();
+        if !should_run {
+            thread::sleep(frame_duration);
+            continue;
+        }
+
+        // a panic, such as on an unimplemented instruction, is reported
+        // with what led to it before it ends the thread
+        let ran = panic::catch_unwind(AssertUnwindSafe(|| session.run_frame(gameboy, pacer)));
+        if let Err(payload) = ran {
+            println!("; emulation stopped by a panic");
+            print_recent_executions(gameboy);
+            println!("; backtrace:");
+            for line in gameboy.backtrace() {
+                println!(";   {}", line);
+            }
+            panic::resume_unwind(payload);
+        }
+
+        for message in gameboy.take_log() {
+            println!("; {}", message);
+        }
+        for hit in gameboy.take_watch_hits() {
+            println!("; {}", hit);
+        }
+
+        let wait = pacer
+            .lock()
+            .unwrap()
+            .frame_done(gameboy.cycles(), Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+
+        if gameboy.cycles() >= log_at_cycle {
+            log_at_cycle += LOG_INTERVAL;
+            print_recent_executions(gameboy);
+            for imbalance in gameboy.take_stack_imbalances() {
+                println!("; {}", imbalance.describe(gameboy.symbols()));
+            }
+        }
+    }
+}
+
+// prints the instructions executed since they were last printed, up to
+// LOG_SIZE of them
+fn print_recent_executions(gameboy: &mut GameBoy) {
+    println!("; assembly:                        addr:         t|μs:   codes:");
+    println!("; ---------                        ------        -----   --------");
+    for line in gameboy.take_recent_executions(LOG_SIZE) {
+        println!("{}", line);
+    }
+    println!();
+}
@@ -3,24 +3,434 @@
 
 use std::any::Any;
 use std::clone::Clone;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use hyper::server::Http;
 
+#[macro_use]
+extern crate serde_json;
+
+use zerodmg_codes::assembled::AssembledRom;
 use zerodmg_emulator as emulator;
 
+mod dap;
+mod debugger;
+mod frontend;
 mod server;
 
+// how many frames apart the hashes in recorded movies are
+const MOVIE_HASH_INTERVAL: u32 = 60;
+
+/// Options from the command line.
+#[derive(Debug, Default)]
+struct Options {
+    /// Whether to run the command-line debugger, instead of the UI.
+    debug: bool,
+    /// Whether to serve a debug adapter protocol client, instead of the UI.
+    dap: bool,
+    /// Port to wait for the debug adapter protocol client on, instead of
+    /// using standard input and output.
+    dap_port: Option<u16>,
+    /// Game ROM to run, instead of the built-in demo.
+    rom: Option<PathBuf>,
+    /// Boot ROM to run, instead of the embedded DMG boot ROM.
+    boot_rom: Option<PathBuf>,
+    /// Whether to start the game without running a boot ROM.
+    skip_boot_rom: bool,
+    /// Symbol file naming addresses in the game ROM, instead of the `.sym`
+    /// file beside it.
+    symbols: Option<PathBuf>,
+    /// Symbol file to write the labels found by tracing the game ROM to,
+    /// instead of running it.
+    export_symbols: Option<PathBuf>,
+    /// Model to emulate, instead of the one the game asks for.
+    model: Option<emulator::Model>,
+    /// What RAM holds at power-on.
+    ram_init: emulator::RamInit,
+    /// Whether to print bytes sent over the serial port.
+    serial: bool,
+    /// IO registers to print each access to.
+    log_io: Vec<u16>,
+    /// Whether to print writes to VRAM and OAM while the PPU is using them.
+    log_video_access: bool,
+    /// Save state to start from.
+    load_state: Option<PathBuf>,
+    /// WAV file to record audio to.
+    record_audio: Option<PathBuf>,
+    /// Whether to also record each channel to its own WAV file.
+    record_channels: bool,
+    /// VGM file to log sound register writes to.
+    record_vgm: Option<PathBuf>,
+    /// GBS file to render to WAV, instead of running the emulator.
+    gbs: Option<PathBuf>,
+    /// Song in the GBS file to render, counting from 1.
+    track: Option<u8>,
+    /// How long to render the GBS song for.
+    seconds: Option<u32>,
+    /// How fast to run, relative to real time.
+    speed: emulator::Speed,
+    /// Whether to start paused.
+    paused: bool,
+    /// How many seconds of frames can be stepped back through.
+    rewind_seconds: Option<u32>,
+    /// How many frames apart rewind snapshots are taken.
+    rewind_interval: Option<u32>,
+    /// Movie file to record the buttons held during each frame to.
+    record_movie: Option<PathBuf>,
+    /// Movie file to play back, instead of taking input from the UI.
+    play_movie: Option<PathBuf>,
+}
+
+impl Options {
+    fn from_args() -> Self {
+        let mut options = Self::default();
+        let mut args = env::args().skip(1).peekable();
+        match args.peek().map(String::as_ref) {
+            Some("debug") => options.debug = true,
+            Some("dap") => options.dap = true,
+            _ => {}
+        }
+        if options.debug || options.dap {
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--record-audio" => {
+                    let path = args.next().expect("--record-audio requires a path");
+                    options.record_audio = Some(PathBuf::from(path));
+                }
+                "--record-channels" => options.record_channels = true,
+                "--record-vgm" => {
+                    let path = args.next().expect("--record-vgm requires a path");
+                    options.record_vgm = Some(PathBuf::from(path));
+                }
+                "--gbs" => {
+                    let path = args.next().expect("--gbs requires a path");
+                    options.gbs = Some(PathBuf::from(path));
+                }
+                "--track" => {
+                    let track = args.next().expect("--track requires a number");
+                    options.track = Some(track.parse().expect("invalid --track number"));
+                }
+                "--seconds" => {
+                    let seconds = args.next().expect("--seconds requires a number");
+                    options.seconds = Some(seconds.parse().expect("invalid --seconds number"));
+                }
+                "--speed" => {
+                    let speed = args.next().expect("--speed requires a number or uncapped");
+                    options.speed = speed.parse().expect("invalid --speed");
+                }
+                "--paused" => options.paused = true,
+                "--rewind-seconds" => {
+                    let seconds = args.next().expect("--rewind-seconds requires a number");
+                    options.rewind_seconds =
+                        Some(seconds.parse().expect("invalid --rewind-seconds number"));
+                }
+                "--rewind-interval" => {
+                    let frames = args.next().expect("--rewind-interval requires a number");
+                    options.rewind_interval =
+                        Some(frames.parse().expect("invalid --rewind-interval number"));
+                }
+                "--boot-rom" => {
+                    let path = args.next().expect("--boot-rom requires a path");
+                    options.boot_rom = Some(PathBuf::from(path));
+                }
+                "--skip-boot-rom" => options.skip_boot_rom = true,
+                "--sym" => {
+                    let path = args.next().expect("--sym requires a path");
+                    options.symbols = Some(PathBuf::from(path));
+                }
+                "--export-sym" => {
+                    let path = args.next().expect("--export-sym requires a path");
+                    options.export_symbols = Some(PathBuf::from(path));
+                }
+                "--model" => {
+                    let model = args.next().expect("--model requires dmg or cgb");
+                    options.model = Some(model.parse().expect("invalid --model"));
+                }
+                "--ram" => {
+                    let ram_init = args.next().expect("--ram requires an initialization");
+                    options.ram_init = ram_init.parse().expect("invalid --ram");
+                }
+                "--serial" => options.serial = true,
+                "--port" => {
+                    let port = args.next().expect("--port requires a number");
+                    options.dap_port = Some(port.parse().expect("invalid --port number"));
+                }
+                "--log-io" => {
+                    let names = args.next().expect("--log-io requires register names");
+                    for name in names.split(',') {
+                        let addr = emulator::io_register_address(name)
+                            .unwrap_or_else(|| panic!("unknown IO register: {}", name));
+                        options.log_io.push(addr);
+                    }
+                }
+                "--log-video-access" => options.log_video_access = true,
+                "--load-state" => {
+                    let path = args.next().expect("--load-state requires a path");
+                    options.load_state = Some(PathBuf::from(path));
+                }
+                "--record-movie" => {
+                    let path = args.next().expect("--record-movie requires a path");
+                    options.record_movie = Some(PathBuf::from(path));
+                }
+                "--play-movie" => {
+                    let path = args.next().expect("--play-movie requires a path");
+                    options.play_movie = Some(PathBuf::from(path));
+                }
+                _ if !arg.starts_with("--") && options.rom.is_none() => {
+                    options.rom = Some(PathBuf::from(arg));
+                }
+                _ => panic!("unexpected argument: {}", arg),
+            }
+        }
+        options
+    }
+}
+
+/// Creates the Game Boy configured by the options, starting at the beginning
+/// of the movie being played if there is one.
+fn build_gameboy(
+    options: &Options,
+    movie: Option<&emulator::Movie>,
+    output_buffer: Arc<Mutex<emulator::Output>>,
+) -> emulator::GameBoy {
+    let mut builder = emulator::GameBoy::builder().output(output_buffer);
+    if let Some(ref path) = options.rom {
+        println!("; Loading {}", path.display());
+        builder = builder.rom_file(path).expect("failed to read ROM");
+    }
+    if let Some(ref path) = options.boot_rom {
+        builder = builder.boot_rom_file(path).expect("failed to read boot ROM");
+    }
+    if options.skip_boot_rom {
+        builder = builder.skip_boot_rom();
+    }
+    let default_symbols = options
+        .rom
+        .as_ref()
+        .map(|path| path.with_extension("sym"))
+        .filter(|path| path.exists());
+    if let Some(ref path) = options.symbols.clone().or(default_symbols) {
+        println!("; Loading symbols from {}", path.display());
+        builder = builder.symbols_file(path).expect("failed to read symbols");
+    }
+    if let Some(model) = options.model {
+        builder = builder.model(model);
+    }
+    if options.serial {
+        builder = builder.serial(emulator::SerialStdout);
+    }
+    if let Some(movie) = movie {
+        let mut gameboy = match movie.start(builder) {
+            Ok(gameboy) => gameboy,
+            Err(error) => panic!("can't play movie: {}", error),
+        };
+        start_io_logging(options, &mut gameboy);
+        return gameboy;
+    }
+    let mut gameboy = match builder.ram_init(options.ram_init).build() {
+        Ok(gameboy) => gameboy,
+        Err(error) => panic!("can't run ROM: {}", error),
+    };
+    start_io_logging(options, &mut gameboy);
+    if let Some(ref path) = options.load_state {
+        println!("; Loading state from {}", path.display());
+        let state = fs::read(path).expect("failed to read save state");
+        match gameboy.load_state(&state) {
+            Ok(ref loaded) if loaded.different_rom => {
+                println!("; Warning: save state is for a different ROM")
+            }
+            Ok(_) => {}
+            Err(error) => panic!("can't load save state: {}", error),
+        }
+    }
+    gameboy
+}
+
+fn start_io_logging(options: &Options, gameboy: &mut emulator::GameBoy) {
+    for &addr in options.log_io.iter() {
+        gameboy
+            .set_io_logging(addr, true)
+            .expect("--log-io only accepts IO register names");
+    }
+    gameboy.set_video_access_logging(options.log_video_access);
+}
+
+/// Creates the rewind history configured by the options, by default keeping
+/// ten seconds of snapshots taken every four frames.
+fn build_rewind_buffer(options: &Options) -> emulator::RewindBuffer {
+    let seconds = options.rewind_seconds.unwrap_or(10);
+    let interval = options.rewind_interval.unwrap_or(4).max(1);
+    let frames = f64::from(seconds) * emulator::FRAME_RATE;
+    emulator::RewindBuffer::new(interval, (frames / f64::from(interval)).ceil() as usize)
+}
+
+/// Reads the movie to play back, if there is one.
+fn read_movie(options: &Options) -> Option<emulator::Movie> {
+    let path = options.play_movie.as_ref()?;
+    println!("; Playing movie from {}", path.display());
+    let bytes = fs::read(path).expect("failed to read movie");
+    match emulator::Movie::from_bytes(&bytes) {
+        Ok(movie) => Some(movie),
+        Err(error) => panic!("invalid movie: {}", error),
+    }
+}
+
+/// Starts recording or playing back a movie, as configured by the options.
+/// A recording's RAM must be initialized from a seed, so one is chosen if it
+/// would otherwise be random.
+fn start_movie(
+    options: &Options,
+    movie: Option<emulator::Movie>,
+    gameboy: &emulator::GameBoy,
+) -> frontend::MovieMode {
+    if let Some(movie) = movie {
+        return frontend::MovieMode::Playing(movie, 0);
+    }
+    let path = match options.record_movie {
+        Some(ref path) => path,
+        None => return frontend::MovieMode::Off,
+    };
+    println!("; Recording movie to {}", path.display());
+    let seed = match options.ram_init {
+        emulator::RamInit::Seeded(seed) => seed,
+        _ => 0,
+    };
+    let file = fs::File::create(path).expect("failed to create movie file");
+    let recorder = emulator::MovieRecorder::new(file, gameboy, seed, MOVIE_HASH_INTERVAL)
+        .expect("failed to start recording movie");
+    frontend::MovieMode::Recording(recorder)
+}
+
+/// Writes the labels found by tracing the code in the game ROM to a symbol
+/// file, without running it.
+fn export_symbols(options: &Options, sym_path: &PathBuf) {
+    let rom_path = options.rom.as_ref().expect("--export-sym requires a ROM");
+    let bytes = fs::read(rom_path).expect("failed to read ROM");
+    let symbols = AssembledRom::new(bytes).symbols();
+    println!(
+        "; Writing {} labels to {}",
+        symbols.len(),
+        sym_path.display()
+    );
+    fs::write(sym_path, symbols.to_string()).expect("failed to write symbols");
+}
+
+/// Renders a song from a GBS file to a WAV file, without the UI.
+fn render_gbs(options: Options, gbs_path: PathBuf) {
+    let bytes = fs::read(&gbs_path).expect("failed to read GBS file");
+    let gbs = match emulator::GbsFile::from_bytes(&bytes) {
+        Ok(gbs) => gbs,
+        Err(error) => panic!("invalid GBS file: {}", error),
+    };
+    let track = options.track.unwrap_or(gbs.first_song);
+    let seconds = options.seconds.unwrap_or(120);
+    let wav_path = options
+        .record_audio
+        .unwrap_or_else(|| gbs_path.with_extension("wav"));
+    println!(
+        "; {} - {} ({}), track {} of {}",
+        gbs.title, gbs.author, gbs.copyright, track, gbs.song_count
+    );
+
+    let output_buffer = Arc::new(Mutex::new(emulator::Output::new()));
+    let mut player = emulator::GbsPlayer::new(gbs, output_buffer);
+    println!("; Rendering {} seconds to {}", seconds, wav_path.display());
+    player
+        .gameboy()
+        .start_audio_recording(&wav_path, 48_000, options.record_channels)
+        .expect("failed to start recording audio");
+    if let Some(ref path) = options.record_vgm {
+        println!("; Logging sound to {}", path.display());
+        player
+            .gameboy()
+            .start_vgm_logging(path)
+            .expect("failed to start logging sound");
+    }
+
+    player.start_song(track);
+    player.run_cycles(u64::from(seconds) * u64::from(emulator::AUDIO_SAMPLE_RATE));
+
+    player
+        .gameboy()
+        .stop_audio_recording()
+        .expect("failed to finish recording audio");
+    player
+        .gameboy()
+        .stop_vgm_logging()
+        .expect("failed to finish logging sound");
+}
+
 pub fn main() -> Result<(), Box<Any + Send>> {
+    let mut options = Options::from_args();
+    if options.record_movie.is_some() && options.ram_init == emulator::RamInit::Random {
+        options.ram_init = emulator::RamInit::Seeded(rand::random());
+    }
+
+    if options.dap {
+        dap::run(match options.dap_port {
+            Some(port) => dap::Transport::Tcp(port),
+            None => dap::Transport::Stdio,
+        });
+        return Ok(());
+    }
+
+    if let Some(ref path) = options.export_symbols {
+        export_symbols(&options, path);
+        return Ok(());
+    }
+
+    if let Some(gbs_path) = options.gbs.take() {
+        render_gbs(options, gbs_path);
+        return Ok(());
+    }
+
     let output_buffer = Arc::new(Mutex::new(emulator::Output::new()));
+    if options.debug {
+        let mut gameboy = build_gameboy(&options, None, output_buffer);
+        debugger::run(&mut gameboy);
+        return Ok(());
+    }
+
     let also_output_buffer = output_buffer.clone();
 
+    let mut pacer = emulator::Pacer::new();
+    pacer.set_speed(options.speed);
+    if options.paused {
+        pacer.pause();
+    }
+    let pacer = Arc::new(Mutex::new(pacer));
+    let also_pacer = pacer.clone();
+
+    let (commands, command_receiver) = mpsc::channel();
+    let commands = Arc::new(Mutex::new(commands));
+
     let emulator_thread = thread::spawn(move || {
         thread::sleep(Duration::from_millis(250));
-        let mut gameboy = emulator::GameBoy::new(also_output_buffer.clone());
-        gameboy.run();
+        let movie = read_movie(&options);
+        let mut gameboy = build_gameboy(&options, movie.as_ref(), also_output_buffer.clone());
+        let movie = start_movie(&options, movie, &gameboy);
+        let mut session = frontend::Session::new(build_rewind_buffer(&options), movie);
+        if let Some(path) = options.record_audio {
+            println!("; Recording audio to {}", path.display());
+            gameboy
+                .start_audio_recording(&path, 48_000, options.record_channels)
+                .expect("failed to start recording audio");
+        }
+        if let Some(path) = options.record_vgm {
+            println!("; Logging sound to {}", path.display());
+            gameboy
+                .start_vgm_logging(&path)
+                .expect("failed to start logging sound");
+        }
+        frontend::run(&mut gameboy, &also_pacer, &mut session, &command_receiver);
     });
 
     let http_server_thread = thread::spawn(move || {
@@ -31,6 +441,8 @@ pub fn main() -> Result<(), Box<Any + Send>> {
             .bind(&addr, move || {
                 Ok(server::GameBoyIOServer {
                     output_buffer: output_buffer.clone(),
+                    pacer: pacer.clone(),
+                    commands: commands.clone(),
                 })
             }).unwrap()
             .run()
@@ -1,16 +1,24 @@
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 
 use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType};
 use hyper::server::{Request, Response, Service};
-use hyper::{Get, StatusCode};
+use hyper::{Get, Post, StatusCode};
 
-use futures::future::Future;
+use futures::future::{Either, Future};
+use futures::sync::oneshot;
+use futures::Stream;
 
 use zerodmg_emulator;
 
+use crate::frontend::Command;
+
 /// Simple HTTP server displaying emulator output
 pub struct GameBoyIOServer {
     pub output_buffer: Arc<Mutex<zerodmg_emulator::Output>>,
+    pub pacer: Arc<Mutex<zerodmg_emulator::Pacer>>,
+    // requests for the emulator thread
+    pub commands: Arc<Mutex<Sender<Command>>>,
 }
 
 impl Service for GameBoyIOServer {
@@ -21,8 +29,6 @@ impl Service for GameBoyIOServer {
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        // println!("; {} {}", req.method(), req.path());
-
         match (req.method(), req.path()) {
             (&Get, "/") => {
                 let html = include_bytes!("io.html").to_vec();
@@ -34,21 +40,265 @@ impl Service for GameBoyIOServer {
                 ))
             }
             (&Get, "/output.png") => {
-                let display = self.output_buffer.lock().unwrap().combined_image();
-                let mut encoded_image = Vec::new();
-                display
-                    .write_to(&mut encoded_image, image::ImageOutputFormat::PNG)
-                    .expect("failed to write image to memory buffer -- really?!");
+                let image = self.output_buffer.lock().unwrap().combined_image();
+                png_response(&image)
+            }
+            (&Get, "/screen.png") => {
+                let image = self.output_buffer.lock().unwrap().screen.clone();
+                png_response(&image)
+            }
+            (&Get, "/oam.txt") => {
+                let mut listing = String::new();
+                for sprite in self.output_buffer.lock().unwrap().oam.iter() {
+                    listing.push_str(&format!("{}\n", sprite));
+                }
+                Box::new(futures::future::ok(
+                    Response::new()
+                        .with_header(ContentLength(listing.len() as u64))
+                        .with_header(ContentType::plaintext())
+                        .with_header(CacheControl(vec![CacheDirective::NoStore]))
+                        .with_body(listing),
+                ))
+            }
+            (&Get, "/audio.txt") => {
+                let mut listing = String::new();
+                for status in self.output_buffer.lock().unwrap().channel_status.iter() {
+                    listing.push_str(&format!("{}\n", status));
+                }
                 Box::new(futures::future::ok(
                     Response::new()
-                        .with_header(ContentLength(encoded_image.len() as u64))
+                        .with_header(ContentLength(listing.len() as u64))
+                        .with_header(ContentType::plaintext())
                         .with_header(CacheControl(vec![CacheDirective::NoStore]))
-                        .with_body(encoded_image),
+                        .with_body(listing),
                 ))
             }
+            (&Get, "/pacing.txt") => {
+                let stats = format!("{}\n", self.pacer.lock().unwrap().stats());
+                Box::new(futures::future::ok(
+                    Response::new()
+                        .with_header(ContentLength(stats.len() as u64))
+                        .with_header(ContentType::plaintext())
+                        .with_header(CacheControl(vec![CacheDirective::NoStore]))
+                        .with_body(stats),
+                ))
+            }
+            (&Get, path) if path.starts_with("/pacing/") => {
+                let command = &path["/pacing/".len()..];
+                let status = if update_pacer(&mut self.pacer.lock().unwrap(), command) {
+                    StatusCode::NoContent
+                } else {
+                    StatusCode::NotFound
+                };
+                Box::new(futures::future::ok(Response::new().with_status(status)))
+            }
+            (&Get, "/rewind/step") => {
+                // stay on the earlier frame, instead of running forward again
+                self.pacer.lock().unwrap().pause();
+                let (reply, response) = oneshot::channel();
+                self.commands
+                    .lock()
+                    .unwrap()
+                    .send(Command::StepBack(reply))
+                    .unwrap();
+                Box::new(
+                    response
+                        .map(|stepped| {
+                            let status = if stepped {
+                                StatusCode::NoContent
+                            } else {
+                                StatusCode::Conflict
+                            };
+                            Response::new().with_status(status)
+                        }).or_else(emulator_stopped),
+                )
+            }
+            (&Get, path) if path.starts_with("/key/") => {
+                let status = match parse_key_command(&path["/key/".len()..]) {
+                    Some((button, pressed)) => {
+                        self.commands
+                            .lock()
+                            .unwrap()
+                            .send(Command::SetButton(button, pressed))
+                            .unwrap();
+                        StatusCode::NoContent
+                    }
+                    None => StatusCode::NotFound,
+                };
+                Box::new(futures::future::ok(Response::new().with_status(status)))
+            }
+            (&Get, path) if path.starts_with("/audio/") => {
+                let command = &path["/audio/".len()..];
+                let mut output_buffer = self.output_buffer.lock().unwrap();
+                let status = if update_channel_mix(&mut output_buffer.channel_mix, command) {
+                    StatusCode::NoContent
+                } else {
+                    StatusCode::NotFound
+                };
+                Box::new(futures::future::ok(Response::new().with_status(status)))
+            }
+            (&Get, "/state") => {
+                let (reply, response) = oneshot::channel();
+                self.commands
+                    .lock()
+                    .unwrap()
+                    .send(Command::SaveState(reply))
+                    .unwrap();
+                Box::new(
+                    response
+                        .map(|state| {
+                            Response::new()
+                                .with_header(ContentLength(state.len() as u64))
+                                .with_header(ContentType::octet_stream())
+                                .with_header(CacheControl(vec![CacheDirective::NoStore]))
+                                .with_body(state)
+                        }).or_else(emulator_stopped),
+                )
+            }
+            (&Post, "/state") => {
+                let commands = self.commands.clone();
+                Box::new(req.body().concat2().and_then(move |body| {
+                    let (reply, response) = oneshot::channel();
+                    commands
+                        .lock()
+                        .unwrap()
+                        .send(Command::LoadState(body.to_vec(), reply))
+                        .unwrap();
+                    response
+                        .map(|result| match result {
+                            Ok(ref loaded) if loaded.different_rom => {
+                                let message = "warning: save state is for a different ROM\n";
+                                Response::new()
+                                    .with_header(ContentLength(message.len() as u64))
+                                    .with_header(ContentType::plaintext())
+                                    .with_body(message)
+                            }
+                            Ok(_) => Response::new().with_status(StatusCode::NoContent),
+                            Err(error) => {
+                                let message = format!("{}\n", error);
+                                Response::new()
+                                    .with_status(StatusCode::BadRequest)
+                                    .with_header(ContentLength(message.len() as u64))
+                                    .with_header(ContentType::plaintext())
+                                    .with_body(message)
+                            }
+                        }).or_else(emulator_stopped)
+                }))
+            }
+            (&Post, "/eval") => {
+                let commands = self.commands.clone();
+                Box::new(req.body().concat2().and_then(move |body| {
+                    let parsed = String::from_utf8(body.to_vec())
+                        .map_err(|_| "expression isn't UTF-8".to_string())
+                        .and_then(|source| source.parse::<zerodmg_emulator::Expression>());
+                    let result = match parsed {
+                        Ok(expression) => {
+                            let (reply, response) = oneshot::channel();
+                            commands
+                                .lock()
+                                .unwrap()
+                                .send(Command::Evaluate(expression, reply))
+                                .unwrap();
+                            Either::A(response.map(Ok))
+                        }
+                        Err(error) => Either::B(futures::future::ok(Err(error))),
+                    };
+                    result
+                        .map(|result| {
+                            let (status, message) = match result {
+                                Ok(value) => (StatusCode::Ok, format!("{}\n", value)),
+                                Err(error) => (StatusCode::BadRequest, format!("{}\n", error)),
+                            };
+                            Response::new()
+                                .with_status(status)
+                                .with_header(ContentLength(message.len() as u64))
+                                .with_header(ContentType::plaintext())
+                                .with_header(CacheControl(vec![CacheDirective::NoStore]))
+                                .with_body(message)
+                        }).or_else(emulator_stopped)
+                }))
+            }
+            (&Get, path) if path.starts_with("/output/") && path.ends_with(".png") => {
+                let name = &path["/output/".len()..path.len() - ".png".len()];
+                let image = self.output_buffer.lock().unwrap().image(name).cloned();
+                match image {
+                    Some(image) => png_response(&image),
+                    None => Box::new(futures::future::ok(
+                        Response::new().with_status(StatusCode::NotFound),
+                    )),
+                }
+            }
             _ => Box::new(futures::future::ok(
                 Response::new().with_status(StatusCode::NotFound),
             )),
         }
     }
 }
+
+/// Responds to a request the emulator thread dropped without replying to,
+/// such as after it stopped on a panic.
+fn emulator_stopped(_: oneshot::Canceled) -> Result<Response, hyper::Error> {
+    Ok(Response::new().with_status(StatusCode::InternalServerError))
+}
+
+/// Applies a command like `mute/1`, `unmute/1`, `solo/1` or `unsolo` to the
+/// channel mix, returning false if it's not a valid command.
+fn update_channel_mix(channel_mix: &mut zerodmg_emulator::ChannelMix, command: &str) -> bool {
+    let parts: Vec<&str> = command.split('/').collect();
+    let channel = parts.get(1).and_then(|n| n.parse::<u8>().ok());
+    let result = match (parts[0], channel) {
+        ("mute", Some(n)) => channel_mix.set_muted(n, true),
+        ("unmute", Some(n)) => channel_mix.set_muted(n, false),
+        ("solo", Some(n)) => channel_mix.set_solo(Some(n)),
+        ("unsolo", None) => channel_mix.set_solo(None),
+        _ => return false,
+    };
+    result.is_ok()
+}
+
+/// Parses a command like `down/arrowleft` or `up/enter`, naming a key the web
+/// UI maps to a button.
+fn parse_key_command(command: &str) -> Option<(zerodmg_emulator::Button, bool)> {
+    let parts: Vec<&str> = command.splitn(2, '/').collect();
+    let pressed = match parts[0] {
+        "down" => true,
+        "up" => false,
+        _ => return None,
+    };
+    let key = match parts.get(1) {
+        Some(&"%20") => "space",
+        Some(key) => key,
+        None => return None,
+    };
+    key.parse().ok().map(|button| (button, pressed))
+}
+
+/// Applies a command like `pause`, `resume`, `advance`, `speed/2` or
+/// `speed/uncapped` to the pacer, returning false if it's not a valid command.
+fn update_pacer(pacer: &mut zerodmg_emulator::Pacer, command: &str) -> bool {
+    match command {
+        "pause" => pacer.pause(),
+        "resume" => pacer.resume(),
+        "advance" => pacer.advance_frame(),
+        _ if command.starts_with("speed/") => match command["speed/".len()..].parse() {
+            Ok(speed) => pacer.set_speed(speed),
+            Err(_) => return false,
+        },
+        _ => return false,
+    }
+    true
+}
+
+/// Encodes an image as an uncached PNG response.
+fn png_response(image: &image::DynamicImage) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let mut encoded_image = Vec::new();
+    image
+        .write_to(&mut encoded_image, image::ImageOutputFormat::PNG)
+        .expect("failed to write image to memory buffer -- really?!");
+    Box::new(futures::future::ok(
+        Response::new()
+            .with_header(ContentLength(encoded_image.len() as u64))
+            .with_header(CacheControl(vec![CacheDirective::NoStore]))
+            .with_body(encoded_image),
+    ))
+}
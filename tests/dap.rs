@@ -0,0 +1,259 @@
+#![feature(rust_2018_preview)]
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+#[macro_use]
+extern crate serde_json;
+
+use serde_json::Value;
+
+use zerodmg_codes::instruction::prelude::*;
+
+/// Writes a ROM that calls a function setting B then loops forever, with a
+/// symbol file beside it, returning the ROM's path. The first bank ends with
+/// the opcode of an instruction that doesn't fit in it.
+fn write_rom(name: &str) -> PathBuf {
+    let mut rom = vec![0x00; 0x8000];
+    let program = [
+        (0x0100, vec![CALL(0x0150), LD(A, 0x42), JR(-2)]),
+        (0x0150, vec![LD(B, 0x01), RET]),
+    ];
+    for &(address, ref instructions) in program.iter() {
+        let bytes: Vec<u8> = instructions.iter().flat_map(|i| i.to_bytes()).collect();
+        rom[address..address + bytes.len()].copy_from_slice(&bytes);
+    }
+    rom[0x3FFF] = 0x3E;
+    let path = env::temp_dir().join(format!("zerodmg-{}-{}.gb", name, std::process::id()));
+    fs::write(&path, rom).unwrap();
+    fs::write(path.with_extension("sym"), "00:0100 Start\n00:0150 SetB\n").unwrap();
+    path
+}
+
+fn zerodmg() -> Command {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push(format!("zerodmg{}", env::consts::EXE_SUFFIX));
+    Command::new(path)
+}
+
+/// A scripted debug adapter protocol client.
+struct Client {
+    input: Box<Write>,
+    output: Box<BufRead>,
+    seq: u64,
+    // events received while waiting for responses
+    events: Vec<Value>,
+}
+
+impl Client {
+    fn new(input: Box<Write>, output: Box<BufRead>) -> Self {
+        Client {
+            input,
+            output,
+            seq: 0,
+            events: vec![],
+        }
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            assert!(self.output.read_line(&mut line).unwrap() > 0, "adapter exited");
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            assert!(line.starts_with("Content-Length: "), "bad header: {}", line);
+            length = line["Content-Length: ".len()..].parse().unwrap();
+        }
+        let mut body = vec![0; length];
+        self.output.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Sends a request, returning the response's body after checking that
+    /// it succeeded.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }).to_string();
+        write!(self.input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.input.flush().unwrap();
+        loop {
+            let message = self.receive();
+            if message["type"] == "event" {
+                self.events.push(message);
+            } else if message["request_seq"] == self.seq {
+                assert_eq!(message["success"], true, "{} failed: {}", command, message);
+                return message["body"].clone();
+            }
+        }
+    }
+
+    /// Waits for an event, returning its body.
+    fn event(&mut self, name: &str) -> Value {
+        loop {
+            if let Some(i) = self.events.iter().position(|event| event["event"] == name) {
+                return self.events.remove(i)["body"].clone();
+            }
+            let message = self.receive();
+            self.events.push(message);
+        }
+    }
+
+    fn evaluate(&mut self, expression: &str) -> Value {
+        self.request("evaluate", json!({ "expression": expression }))["result"].clone()
+    }
+
+    fn frames(&mut self) -> Vec<Value> {
+        let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+        trace["stackFrames"].as_array().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_debugging_over_stdio() {
+    let rom = write_rom("dap-stdio");
+    let mut child: Child = zerodmg()
+        .arg("dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut client = Client::new(
+        Box::new(child.stdin.take().unwrap()),
+        Box::new(BufReader::new(child.stdout.take().unwrap())),
+    );
+
+    let capabilities = client.request("initialize", json!({ "adapterID": "zerodmg" }));
+    assert_eq!(capabilities["supportsDisassembleRequest"], true);
+    assert_eq!(capabilities["exceptionBreakpointFilters"][0]["filter"], "imbalance");
+    client.request(
+        "launch",
+        json!({ "program": rom, "skipBootRom": true, "stopOnEntry": true }),
+    );
+    client.event("initialized");
+    let breakpoints = client.request(
+        "setFunctionBreakpoints",
+        json!({ "breakpoints": [{ "name": "setb" }] }),
+    );
+    assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("stopped")["reason"], "entry");
+    let frames = client.frames();
+    assert_eq!(frames[0]["name"], "Start");
+    assert_eq!(frames[0]["source"]["sourceReference"], 1);
+
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    let frames = client.frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["name"], "SetB");
+    assert_eq!(frames[1]["instructionPointerReference"], "0x0100");
+    let registers = client.request("variables", json!({ "variablesReference": 1 }));
+    let pc = registers["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|variable| variable["name"] == "PC")
+        .unwrap()
+        .clone();
+    assert_eq!(pc["value"], "0x0150");
+
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(client.evaluate("pc"), "0x152 (338)");
+    client.request("stepOut", json!({ "threadId": 1 }));
+    client.event("stopped");
+    assert_eq!(client.evaluate("pc == 0x0103 && b == 1"), "0x1 (1)");
+
+    let memory = client.request("readMemory", json!({ "memoryReference": "0x0100", "count": 3 }));
+    assert_eq!(memory["data"], "zVAB");
+    let disassembly = client.request(
+        "disassemble",
+        json!({ "memoryReference": "0x0103", "instructionOffset": -1, "instructionCount": 3 }),
+    );
+    let instructions = disassembly["instructions"].as_array().unwrap();
+    assert_eq!(instructions[0]["address"], "0x0100");
+    assert_eq!(instructions[0]["symbol"], "Start");
+    assert_eq!(instructions[2]["address"], "0x0105");
+
+    // a breakpoint on the line of the loop in the bank's listing
+    let listing = client.request("source", json!({ "sourceReference": 1 }));
+    let content = listing["content"].as_str().unwrap();
+    assert!(content.lines().last().unwrap().contains("DB $3E"));
+    let line = content
+        .lines()
+        .position(|line| line.ends_with("; 0105"))
+        .unwrap()
+        + 1;
+    let source = json!({ "name": "ROM bank 00", "sourceReference": 1 });
+    client.request(
+        "setBreakpoints",
+        json!({ "source": source, "breakpoints": [{ "line": line, "condition": "a == 0x42" }] }),
+    );
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(client.evaluate("pc"), "0x105 (261)");
+
+    // running forever until paused
+    client.request("setBreakpoints", json!({ "source": source, "breakpoints": [] }));
+    client.request("continue", json!({ "threadId": 1 }));
+    client.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "pause");
+    // pausing again does nothing, since it's not running
+    client.request("pause", json!({ "threadId": 1 }));
+    client.evaluate("pc");
+    assert!(!client.events.iter().any(|event| event["event"] == "stopped"));
+
+    client.request("disconnect", json!({}));
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_debugging_over_tcp() {
+    let rom = write_rom("dap-tcp");
+    let mut child = zerodmg()
+        .args(&["dap", "--port", "0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let address = line.trim().rsplit(' ').next().unwrap().to_string();
+    let stream = TcpStream::connect(address).unwrap();
+    let mut client = Client::new(
+        Box::new(stream.try_clone().unwrap()),
+        Box::new(BufReader::new(stream)),
+    );
+
+    client.request("initialize", json!({ "adapterID": "zerodmg" }));
+    client.request("launch", json!({ "program": rom, "skipBootRom": true }));
+    client.event("initialized");
+    let breakpoints = client.request(
+        "setInstructionBreakpoints",
+        json!({ "breakpoints": [{ "instructionReference": "0x0150", "offset": 2 }] }),
+    );
+    assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(client.evaluate("pc"), "0x152 (338)");
+    client.request("disconnect", json!({}));
+    assert!(child.wait().unwrap().success());
+}
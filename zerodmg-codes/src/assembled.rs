@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
 
 use crate::disassembled::prelude::*;
 use crate::instruction::prelude::*;
+use crate::symbols::{rom_location, Symbol, Symbols};
 
 /// Re-exports important traits and types for glob importing.
 pub mod prelude {
@@ -27,6 +29,27 @@ fn test_assembled_from_bytes_then_get_instructions_trivial() {
     assert_eq!(NOP, assembled.get_known_instruction(0x0000));
 }
 
+#[test]
+fn test_symbols_from_tracing() {
+    let mut bytes = vec![0x00; 0x0200];
+    let program = [
+        (0x0100, vec![CALL(0x0150), JR(-2)]),
+        (0x0150, vec![JP(0x0160)]),
+        (0x0160, vec![RET]),
+    ];
+    for &(address, ref instructions) in program.iter() {
+        let code: Vec<u8> = instructions.iter().flat_map(|i| i.to_bytes()).collect();
+        bytes[address..address + code.len()].copy_from_slice(&code);
+    }
+    let symbols = AssembledRom::new(bytes).symbols();
+    assert_eq!(symbols.label_at(0, 0x0100), Some("Boot"));
+    assert_eq!(symbols.label_at(0, 0x0103), Some("Jump_000_0103"));
+    assert_eq!(symbols.label_at(0, 0x0150), Some("Call_000_0150"));
+    assert_eq!(symbols.label_at(0, 0x0160), Some("Jump_000_0160"));
+    assert_eq!(symbols.label_at(0, 0x0040), Some("VBlankInterrupt"));
+    assert_eq!(symbols.label_at(0, 0x0008), Some("RST_08"));
+}
+
 #[test]
 fn test_bytes_from_assembled() {
     let assembled = AssembledRom::example();
@@ -244,6 +267,70 @@ impl AssembledRom {
         }
     }
 
+    /// Labels for the instructions that tracing found can be jumped to,
+    /// which can be written as a `.sym` file. Calls' targets are named like
+    /// `Call_000_0150` and other jumps' like `Jump_000_0150`, after their
+    /// bank and address, while the restart, interrupt and entry points are
+    /// named for what they are.
+    pub fn symbols(&self) -> Symbols {
+        let decoded = |offset: usize| match self.bytes.get(offset).map(|byte| byte.role) {
+            Some(RomByteRole::InstructionStart { .. }) => true,
+            _ => false,
+        };
+
+        let mut calls = HashSet::new();
+        let mut jumps = HashSet::new();
+        for (offset, byte) in self.bytes.iter().enumerate() {
+            let instruction = match byte.role {
+                RomByteRole::InstructionStart { instruction, .. } => instruction,
+                _ => continue,
+            };
+            let (bank, address) = rom_location(offset);
+            let target = match instruction.jump_target(address) {
+                Some(target) if target < 0x4000 => (0, target),
+                // a switchable bank can only be known from inside itself, or
+                // if there are no others
+                Some(target) if target < 0x8000 && bank > 0 => (bank, target),
+                Some(target) if target < 0x8000 && self.bytes.len() <= 0x8000 => (1, target),
+                _ => continue,
+            };
+            match instruction {
+                CALL(_) | CALL_IF(_, _) => calls.insert(target),
+                _ => jumps.insert(target),
+            };
+        }
+
+        let mut symbols = Symbols::new();
+        for offset in 0..self.bytes.len() {
+            let (bank, address) = rom_location(offset);
+            let name = match (bank, address) {
+                (0, 0x0000) | (0, 0x0008) | (0, 0x0010) | (0, 0x0018) | (0, 0x0020)
+                | (0, 0x0028) | (0, 0x0030) | (0, 0x0038) => format!("RST_{:02X}", address),
+                (0, 0x0040) => "VBlankInterrupt".to_string(),
+                (0, 0x0048) => "LCDCInterrupt".to_string(),
+                (0, 0x0050) => "TimerOverflowInterrupt".to_string(),
+                (0, 0x0058) => "SerialTransferCompleteInterrupt".to_string(),
+                (0, 0x0060) => "JoypadTransitionInterrupt".to_string(),
+                (0, 0x0100) => "Boot".to_string(),
+                _ if calls.contains(&(bank, address)) => {
+                    format!("Call_{:03X}_{:04X}", bank, address)
+                }
+                _ if jumps.contains(&(bank, address)) => {
+                    format!("Jump_{:03X}_{:04X}", bank, address)
+                }
+                _ => continue,
+            };
+            if decoded(offset) {
+                symbols.insert(Symbol {
+                    bank,
+                    address,
+                    name,
+                });
+            }
+        }
+        symbols
+    }
+
     /// Returns some arbitrary value of this type.
     pub fn example() -> AssembledRom {
         AssembledRom {
@@ -412,7 +499,9 @@ impl AssembledRom {
             }
         }
 
-        DisassembledRom::from(filtered_blocks)
+        let mut disassembled = DisassembledRom::from(filtered_blocks);
+        disassembled.set_symbols(self.symbols());
+        disassembled
     }
 }
 
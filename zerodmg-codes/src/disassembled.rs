@@ -6,6 +6,7 @@ use std::fmt::Display;
 use self::prelude::*;
 use crate::assembled::prelude::*;
 use crate::instruction::prelude::*;
+use crate::symbols::{rom_location, Symbols};
 
 /// Re-exports important traits and types for glob importing.
 pub mod prelude {
@@ -20,12 +21,38 @@ fn test_assemble() {
     let _assembled = disassembled.assemble();
 }
 
+#[test]
+fn test_display_with_symbols() {
+    let mut disassembled = DisassembledRom::from(vec![
+        RomBlock {
+            address: Some(0x0150),
+            content: Code(vec![INC(A), JR(-2), RET]),
+        },
+        RomBlock {
+            address: Some(0x0160),
+            content: Data(vec![0x12, 0x34]),
+        },
+    ]);
+    let mut symbols = Symbols::new();
+    symbols.insert(crate::symbols::Symbol::new(0, 0x0150, "Main"));
+    symbols.insert(crate::symbols::Symbol::new(0, 0x0151, "Main.loop"));
+    symbols.insert(crate::symbols::Symbol::new(0, 0x0160, "Table"));
+    disassembled.set_symbols(symbols);
+    assert_eq!(
+        disassembled.to_string(),
+        "Main:\n    INC A\nMain.loop:\n    JR -2 <Main.loop>\n    RET\n\n\
+         Table:\n    DATA 0x1234\n\n"
+    );
+}
+
 /// A ROM in a disassembled assembly-like structure.
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 pub struct DisassembledRom {
     /// An ordered list of blocks of code or data in the ROM.
     blocks: Vec<RomBlock>,
     // TODO: use parallel arrays instead?
+    /// Labels shown in place of the addresses they're for.
+    symbols: Symbols,
 }
 
 /// A contiguous block of ROM code or data, with optional metadata.
@@ -55,9 +82,20 @@ impl DisassembledRom {
                 address: None,
                 content: Code(vec![INC(A), INC(A), INC(B), INC(C)]),
             }],
+            symbols: Symbols::new(),
         }
     }
 
+    /// The labels shown in place of the addresses they're for.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Replaces the labels shown in place of the addresses they're for.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
     /// Creates an [AssembledRom] by compiling [Code] blocks in a
     /// [DisassembledRom], concatenating them with the [Data] blocks, and
     /// inserting zero-padding to align with specified addresses.
@@ -125,7 +163,10 @@ impl DisassembledRom {
 
 impl From<Vec<RomBlock>> for DisassembledRom {
     fn from(blocks: Vec<RomBlock>) -> Self {
-        DisassembledRom { blocks }
+        DisassembledRom {
+            blocks,
+            symbols: Symbols::new(),
+        }
     }
 }
 
@@ -138,6 +179,7 @@ impl From<Vec<RomBlockContent>> for DisassembledRom {
                     content,
                     address: None,
                 }).collect(),
+            symbols: Symbols::new(),
         }
     }
 }
@@ -152,7 +194,7 @@ impl Display for DisassembledRom {
     /// Encodes this ROM as a pseudo-assembly string.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for block in self.blocks.iter() {
-            Display::fmt(&block, f)?;
+            block.fmt_with_symbols(f, &self.symbols)?;
             write!(f, "\n")?;
         }
         Ok(())
@@ -173,15 +215,18 @@ impl RomBlock {
             }
         }
     }
-}
 
-impl Display for RomBlock {
-    /// Encodes this block as a pseudo-assembly string.
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(address) = self.address {
-            write!(f, "0x{:04X}:\n", address)?;
-        } else {
-            write!(f, "0x____:\n")?;
+    /// Encodes this block as a pseudo-assembly string, with labels in place
+    /// of the addresses they're for.
+    fn fmt_with_symbols(&self, f: &mut fmt::Formatter, symbols: &Symbols) -> fmt::Result {
+        let location = self.address.map(|address| rom_location(usize::from(address)));
+        let label = |offset: u16| {
+            location.and_then(|(bank, address)| symbols.label_at(bank, address + offset))
+        };
+        match (label(0), self.address) {
+            (Some(label), _) => write!(f, "{}:\n", label)?,
+            (None, Some(address)) => write!(f, "0x{:04X}:\n", address)?,
+            (None, None) => write!(f, "0x____:\n")?,
         }
 
         match self.content {
@@ -205,8 +250,25 @@ impl Display for RomBlock {
             }
             Code(ref instructions) => {
                 // TODO: exclude trailing padding NOPs
+                let mut offset = 0;
                 for instruction in instructions.iter() {
-                    write!(f, "    {}\n", instruction)?;
+                    match location {
+                        Some((bank, address)) => {
+                            if offset > 0 {
+                                if let Some(label) = label(offset) {
+                                    write!(f, "{}:\n", label)?;
+                                }
+                            }
+                            // jumps out of a switchable bank are assumed to
+                            // stay in it
+                            let bank_at = |target: u16| if target < 0x4000 { 0 } else { bank.max(1) };
+                            let code =
+                                symbols.format_instruction(*instruction, address + offset, &bank_at);
+                            write!(f, "    {}\n", code)?;
+                        }
+                        None => write!(f, "    {}\n", instruction)?,
+                    }
+                    offset += instruction.byte_len();
                 }
             }
         }
@@ -214,6 +276,13 @@ impl Display for RomBlock {
     }
 }
 
+impl Display for RomBlock {
+    /// Encodes this block as a pseudo-assembly string.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with_symbols(f, &Symbols::new())
+    }
+}
+
 #[macro_export]
 macro_rules! code_blocks {
     (
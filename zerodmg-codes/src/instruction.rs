@@ -384,16 +384,16 @@ impl Instruction {
             LD_8_TO_FF_C => vec![0xE2],
             LD_8_FROM_FF_C => vec![0xF2],
             LD_8_TO_MEMORY_IMMEDIATE(address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xEA, low, high]
             }
             LD_8_FROM_MEMORY_IMMEDIATE(address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xFA, low, high]
             }
             // 16-Bit Loads
             LD_16_IMMEDIATE(register, value) => {
-                let (low, high) = u16_to_u8s(value);
+                let [low, high] = u16_to_u8s(value);
                 let opcode = 0x01 | (register.index() << 4);
                 vec![opcode, low, high]
             }
@@ -403,27 +403,27 @@ impl Instruction {
             LD_HL_FROM_SP => vec![0xF9],
             LD_HL_FROM_SP_PLUS(offset) => vec![0xF8, offset as u8],
             LD_SP_TO_IMMEDIATE_ADDRESS(address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0x08, low, high]
             }
             // Jumps and Calls
             JP(address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xC3, low, high]
             }
             JP_HL => vec![0xE9],
             JP_IF(condition, address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xC2 | (condition.index() << 3), low, high]
             }
             JR(offset) => vec![0x18, offset as u8],
             JR_IF(condition, offset) => vec![0x20 | (condition.index() << 3), offset as u8],
             CALL(address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xCD, low, high]
             }
             CALL_IF(condition, address) => {
-                let (low, high) = u16_to_u8s(address);
+                let [low, high] = u16_to_u8s(address);
                 vec![0xC4 | (condition.index() << 3), low, high]
             }
             RST(target) => vec![0xC7 + target.address()],
@@ -605,6 +605,21 @@ impl Instruction {
         }
     }
 
+    /// The address this instruction jumps or calls to when it's at the given
+    /// address, if that's known without running it.
+    pub fn jump_target(&self, address: u16) -> Option<u16> {
+        match *self {
+            JP(target) | JP_IF(_, target) | CALL(target) | CALL_IF(_, target) => Some(target),
+            JR(offset) | JR_IF(_, offset) => Some(
+                address
+                    .wrapping_add(self.byte_len())
+                    .wrapping_add(offset as u16),
+            ),
+            RST(target) => Some(u16::from(target.address())),
+            _ => None,
+        }
+    }
+
     /// The number of bytes this instruction will occupy in the ROM.
     pub fn byte_len(&self) -> u16 {
         match self {
@@ -20,6 +20,9 @@ pub mod disassembled;
 /// ROM data constants/factories.
 pub mod roms;
 
+/// Reading symbol files, which name addresses in ROMs.
+pub mod symbols;
+
 /// Re-exports important traits and types for glob importing.
 pub mod prelude {
     pub use crate::assembled::prelude::*;
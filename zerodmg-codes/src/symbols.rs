@@ -0,0 +1,269 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::instruction::prelude::*;
+
+#[test]
+fn test_parse_symbols() {
+    let symbols: Symbols = "\
+        ; File generated by rgblink\n\
+        00:0150 Main\n\
+        00:0160 Main.loop\n\
+        02:4000 Bank2Start ; a comment\n\
+        \n\
+        00:C000 wScore\n"
+        .parse()
+        .unwrap();
+    assert_eq!(symbols.len(), 4);
+    assert_eq!(symbols.label_at(0, 0x0150), Some("Main"));
+    assert_eq!(symbols.label_at(1, 0x0150), None);
+    assert_eq!(symbols.get("bank2start"), Some(&Symbol::new(2, 0x4000, "Bank2Start")));
+    assert_eq!(
+        symbols.nearest(0, 0x0158),
+        Some((&Symbol::new(0, 0x0150, "Main"), 8))
+    );
+    // labels in ROM don't name RAM addresses
+    assert_eq!(symbols.nearest(0, 0x8000), None);
+    assert_eq!(symbols.describe(0, 0xC001), "wScore+1");
+    assert_eq!(symbols.describe(3, 0x4001), "0x4001");
+    assert_eq!(symbols.nearest(2, 0x3FFF), None);
+    assert_eq!(symbols.get("MAIN.LOOP"), Some(&Symbol::new(0, 0x0160, "Main.loop")));
+    assert_eq!(symbols.get("Missing"), None);
+    assert!("0150 Main".parse::<Symbols>().is_err());
+    assert!("00:0150".parse::<Symbols>().is_err());
+}
+
+#[test]
+fn test_format_symbols() {
+    let mut symbols = Symbols::new();
+    symbols.insert(Symbol::new(1, 0x4000, "Far"));
+    symbols.insert(Symbol::new(0, 0x0150, "Main"));
+    assert_eq!(symbols.to_string(), "00:0150 Main\n01:4000 Far\n");
+    assert_eq!(symbols.to_string().parse::<Symbols>(), Ok(symbols.clone()));
+
+    let bank_at = |address: u16| if address < 0x4000 { 0 } else { 1 };
+    assert_eq!(
+        symbols.format_instruction(CALL(0x4000), 0x0100, &bank_at),
+        "CALL 0x4000 <Far>"
+    );
+    assert_eq!(
+        symbols.format_instruction(JR(-4), 0x0152, &bank_at),
+        "JR -4 <Main>"
+    );
+    assert_eq!(rom_location(0x0150), (0, 0x0150));
+    assert_eq!(rom_location(0x8010), (2, 0x4010));
+}
+
+/// The bank and address at which the byte at an offset in a ROM image is
+/// read while its bank is mapped.
+pub fn rom_location(offset: usize) -> (usize, u16) {
+    let bank = offset / 0x4000;
+    let address = if bank == 0 {
+        offset as u16
+    } else {
+        0x4000 | (offset % 0x4000) as u16
+    };
+    (bank, address)
+}
+
+/// A label naming an address in a bank.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// The bank the label is in: the ROM bank for addresses below 0x8000,
+    /// the WRAM bank for 0xD000-0xDFFF, and 0 elsewhere.
+    pub bank: usize,
+    /// The address of the label while its bank is mapped.
+    pub address: u16,
+    /// The label.
+    pub name: String,
+}
+
+impl Symbol {
+    /// Creates a symbol.
+    pub fn new(bank: usize, address: u16, name: &str) -> Self {
+        Symbol {
+            bank,
+            address,
+            name: name.to_string(),
+        }
+    }
+}
+
+/// A set of labels for a ROM, as written to `.sym` files by assemblers like
+/// RGBDS and read by debuggers like BGB: a `bank:address label` line for
+/// each, in hex, with `;` starting comments.
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub struct Symbols {
+    // ordered by bank then address, so they can be binary searched
+    symbols: Vec<Symbol>,
+    // the location of the first label with each name, in lowercase
+    names: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    /// Creates an empty set of labels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a label, after any others at the same address.
+    pub fn insert(&mut self, symbol: Symbol) {
+        let key = (symbol.bank, symbol.address);
+        let location = self
+            .names
+            .entry(symbol.name.to_lowercase())
+            .or_insert(key);
+        if key < *location {
+            *location = key;
+        }
+        let i = self.first_after(key);
+        self.symbols.insert(i, symbol);
+    }
+
+    // the index of the first label at or after a location
+    fn first_at(&self, key: (usize, u16)) -> usize {
+        self.symbols
+            .binary_search_by(|symbol| match (symbol.bank, symbol.address).cmp(&key) {
+                Ordering::Less => Ordering::Less,
+                _ => Ordering::Greater,
+            }).unwrap_err()
+    }
+
+    // the index of the first label after a location
+    fn first_after(&self, key: (usize, u16)) -> usize {
+        self.symbols
+            .binary_search_by(|symbol| match (symbol.bank, symbol.address).cmp(&key) {
+                Ordering::Greater => Ordering::Greater,
+                _ => Ordering::Less,
+            }).unwrap_err()
+    }
+
+    // the labels at a location
+    fn at(&self, bank: usize, address: u16) -> &[Symbol] {
+        let key = (bank, address);
+        &self.symbols[self.first_at(key)..self.first_after(key)]
+    }
+
+    /// The number of labels.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Whether there are no labels.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The labels, ordered by bank then address.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Finds a label by name, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        let name = name.to_lowercase();
+        let &(bank, address) = self.names.get(&name)?;
+        self.at(bank, address)
+            .iter()
+            .find(|symbol| symbol.name.to_lowercase() == name)
+    }
+
+    /// The first label at exactly an address, if any.
+    pub fn label_at(&self, bank: usize, address: u16) -> Option<&str> {
+        self.at(bank, address)
+            .first()
+            .map(|symbol| symbol.name.as_ref())
+    }
+
+    /// The closest label at or before an address, in the same bank and on
+    /// the same side of the ROM/RAM divide, with the address's offset from
+    /// it.
+    pub fn nearest(&self, bank: usize, address: u16) -> Option<(&Symbol, u16)> {
+        let i = self.first_after((bank, address));
+        let symbol = self.symbols[..i].last()?;
+        if symbol.bank != bank || (symbol.address < 0x8000) != (address < 0x8000) {
+            return None;
+        }
+        Some((symbol, address - symbol.address))
+    }
+
+    /// Describes an address by the closest label before it, like `Main+3`,
+    /// or in hex if there isn't one.
+    pub fn describe(&self, bank: usize, address: u16) -> String {
+        match self.nearest(bank, address) {
+            Some((symbol, 0)) => symbol.name.clone(),
+            Some((symbol, offset)) => format!("{}+{}", symbol.name, offset),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    /// Formats an instruction at an address, followed by the label it jumps
+    /// or calls to, if any, like `CALL 0x0150 <Main>`. Targets are looked up
+    /// in the banks given by `bank_at`.
+    pub fn format_instruction(
+        &self,
+        instruction: Instruction,
+        address: u16,
+        bank_at: &Fn(u16) -> usize,
+    ) -> String {
+        let label = instruction
+            .jump_target(address)
+            .and_then(|target| self.label_at(bank_at(target), target));
+        match label {
+            Some(label) => format!("{} <{}>", instruction, label),
+            None => instruction.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Symbols {
+    /// Writes the labels in the `.sym` format they can be parsed from.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for symbol in self.symbols.iter() {
+            writeln!(f, "{:02X}:{:04X} {}", symbol.bank, symbol.address, symbol.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Symbols {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parsed = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("invalid symbol on line {}: {}", i + 1, line);
+            let mut words = line.split_whitespace();
+            let location = words.next().ok_or_else(invalid)?;
+            let name = words.next().ok_or_else(invalid)?;
+            let mut parts = location.split(':');
+            let (bank, address) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(bank), Some(address), None) => (bank, address),
+                _ => return Err(invalid()),
+            };
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| invalid())?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+            parsed.push(Symbol::new(bank, address, name));
+        }
+
+        // sorted all at once, rather than inserting each in order, keeping
+        // the file's order for labels at the same address
+        parsed.sort_by_key(|symbol| (symbol.bank, symbol.address));
+        let mut names = HashMap::new();
+        for symbol in &parsed {
+            names
+                .entry(symbol.name.to_lowercase())
+                .or_insert((symbol.bank, symbol.address));
+        }
+        Ok(Symbols {
+            symbols: parsed,
+            names,
+        })
+    }
+}
@@ -1,38 +1,1434 @@
+use super::audio_debug::{draw_wave_ram, ChannelMix, ChannelScope, ChannelStatus};
+use super::builder::RamInitializer;
+use super::model::Model;
+use super::save_state::{StateReader, StateWriter};
+use super::vgm::VgmWriter;
+use super::wav::AudioRecorder;
 use super::GameBoy;
 
+use std::io;
+use std::path::Path;
+
+#[test]
+fn test_cgb_sound_rom_passes() {
+    let rom = include_bytes!("../../zerodmg-codes/src/roms/blargg_tests/cgb_sound/cgb_sound.gb");
+    let mut gameboy = GameBoy::builder()
+        .rom_bytes(rom.to_vec())
+        .skip_boot_rom()
+        .build()
+        .unwrap();
+
+    // the test writes 0x80 to $A000 while running, then its result code
+    // within about a minute of M-cycles
+    let mut started = false;
+    gameboy.run_until(60 * 1_048_576, |gameboy| {
+        let running = gameboy.peek(0xA000) == 0x80;
+        started |= running;
+        started && !running
+    });
+    let output: String = (0xA004..0xC000)
+        .map(|addr| gameboy.peek(addr))
+        .take_while(|&byte| byte != 0x00)
+        .map(char::from)
+        .collect();
+    assert_eq!(gameboy.peek(0xA000), 0x00, "{}", output);
+    assert!(output.contains("Passed"), "{}", output);
+}
+
+#[test]
+fn test_square_duty_cycles() {
+    let mut channel = SquareChannel::new();
+    channel.dac_enabled = true;
+    channel.enabled = true;
+    channel.envelope.volume = 0xF;
+    channel.duty = 0b10;
+    // with a frequency of 2047, each duty step takes one M-cycle
+    channel.frequency = 2047;
+    let mut high_steps = 0;
+    for _ in 0..8 {
+        channel.cycle();
+        if channel.output() > 0 {
+            high_steps += 1;
+        }
+    }
+    assert_eq!(high_steps, 4);
+}
+
+#[test]
+fn test_noise_lfsr_sequence() {
+    let mut channel = NoiseChannel::new();
+    channel.trigger(0);
+    assert_eq!(channel.lfsr, 0x7FFF);
+    channel.step_lfsr();
+    assert_eq!(channel.lfsr, 0x3FFF);
+    // in 7-bit mode the feedback is also copied into bit 6
+    channel.width_mode = true;
+    channel.lfsr = 0b0000_0000_0000_0001;
+    channel.step_lfsr();
+    assert_eq!(channel.lfsr, 0b0100_0000_0100_0000);
+}
+
+#[test]
+fn test_length_counter_disables_channel() {
+    let mut length = LengthCounter::new(64);
+    length.load(62);
+    length.enabled = true;
+    assert!(!length.clock());
+    assert!(length.clock());
+    assert!(!length.clock());
+}
+
+#[test]
+fn test_sweep_overflow_disables_channel() {
+    let mut channel = SquareChannel::new();
+    channel.sweep.set_register(0b0001_0001);
+    channel.envelope.set_register(0xF0);
+    channel.dac_enabled = true;
+    channel.frequency = 0x7FF;
+    channel.trigger(0);
+    // 0x7FF + (0x7FF >> 1) overflows on the check made at trigger time
+    assert!(!channel.enabled);
+}
+
+#[test]
+fn test_leaving_negate_after_a_calculation_disables_channel() {
+    let mut channel = SquareChannel::new();
+    channel.envelope.set_register(0xF0);
+    channel.dac_enabled = true;
+    channel.set_sweep_register(0b0001_1001);
+    channel.trigger(0);
+    channel.set_sweep_register(0b0001_1010);
+    assert!(channel.enabled);
+    channel.set_sweep_register(0b0001_0010);
+    assert!(!channel.enabled);
+
+    // but not if no calculation has been made in negate mode since the
+    // trigger, which only makes one with a non-zero shift
+    channel.set_sweep_register(0b0000_1001);
+    channel.trigger(0);
+    channel.set_sweep_register(0b0001_1000);
+    channel.trigger(0);
+    channel.set_sweep_register(0b0001_0000);
+    assert!(channel.enabled);
+}
+
+#[test]
+fn test_length_enable_clocks_extra_on_odd_steps() {
+    let mut length = LengthCounter::new(64);
+    length.load(63);
+    // enabling the length when the next step won't clock it clocks it once
+    assert!(length.write_control(true, false, 1));
+    assert_eq!(length.counter, 0);
+    // triggering then reloads it, minus that same extra clock
+    assert!(!length.write_control(true, true, 1));
+    assert_eq!(length.counter, 63);
+}
+
+#[test]
+fn test_envelope_trigger_timing() {
+    let mut envelope = SquareChannel::new().envelope;
+    envelope.set_register(0xA2);
+    envelope.trigger(0);
+    assert_eq!(envelope.volume, 0xA);
+    envelope.clock();
+    assert_eq!(envelope.volume, 0xA);
+    envelope.clock();
+    assert_eq!(envelope.volume, 0x9);
+
+    // triggering just before the envelope step delays the first change
+    envelope.trigger(7);
+    envelope.clock();
+    envelope.clock();
+    assert_eq!(envelope.volume, 0xA);
+    envelope.clock();
+    assert_eq!(envelope.volume, 0x9);
+
+    // a period of 0 never changes the volume
+    envelope.set_register(0x58);
+    envelope.trigger(0);
+    for _ in 0..16 {
+        envelope.clock();
+    }
+    assert_eq!(envelope.volume, 0x5);
+}
+
+#[test]
+fn test_dmg_wave_retrigger_corrupts_wave_ram() {
+    let retrigger = |model: Model, position: u8, timer: i32| {
+        let mut gameboy = GameBoy::builder().model(model).build().unwrap();
+        gameboy.set_audio_register(0x16, 0x80);
+        for i in 0..16 {
+            gameboy.aud.wave_ram[i] = i as u8;
+        }
+        gameboy.set_audio_register(0x0A, 0x80);
+        gameboy.aud.wave.enabled = true;
+        gameboy.aud.wave.position = position;
+        gameboy.aud.wave.timer = timer;
+        gameboy.set_audio_register(0x0E, 0x80);
+        gameboy.aud.wave_ram
+    };
+    let unchanged: Vec<u8> = (0..16).collect();
+
+    // just as the next sample is read, the byte it's in is copied to the
+    // start, or the four-byte block it's in if it's past the first block
+    assert_eq!(retrigger(Model::Dmg, 2, 2)[..4], [1, 1, 2, 3]);
+    assert_eq!(retrigger(Model::Dmg, 9, 2)[..8], [4, 5, 6, 7, 4, 5, 6, 7]);
+    // but not otherwise, or on the CGB
+    assert_eq!(retrigger(Model::Dmg, 9, 20)[..], unchanged[..]);
+    assert_eq!(retrigger(Model::Cgb, 9, 2)[..], unchanged[..]);
+}
+
+#[test]
+fn test_register_read_masks_and_power_off() {
+    use super::Output;
+    use std::sync::{Arc, Mutex};
+
+    let mut gameboy = GameBoy::new(Arc::new(Mutex::new(Output::new())));
+    gameboy.set_audio_register(0x16, 0x80);
+    gameboy.set_audio_register(0x01, 0b1011_0101);
+    assert_eq!(gameboy.audio_register(0x01), 0b1011_1111);
+    assert_eq!(gameboy.audio_register(0x16), 0xF0);
+    assert_eq!(gameboy.audio_register(0x1A), 0xFF);
+
+    gameboy.set_audio_register(0x16, 0x00);
+    assert_eq!(gameboy.audio_register(0x01), 0x3F);
+    assert_eq!(gameboy.audio_register(0x16), 0x70);
+    gameboy.set_audio_register(0x14, 0x77);
+    assert_eq!(gameboy.audio_register(0x14), 0x00);
+}
+
+#[test]
+fn test_cgb_power_off_and_wave_ram_access() {
+    for &model in [Model::Dmg, Model::Cgb].iter() {
+        let mut gameboy = GameBoy::builder().model(model).build().unwrap();
+        let dmg = model == Model::Dmg;
+
+        // only the DMG keeps its length counters through a power cycle, and
+        // lets them be loaded while powered off
+        gameboy.set_audio_register(0x16, 0x80);
+        gameboy.set_audio_register(0x01, 0x30);
+        gameboy.set_audio_register(0x16, 0x00);
+        assert_eq!(gameboy.aud.square_1.length.counter, if dmg { 16 } else { 0 });
+        gameboy.set_audio_register(0x01, 0x20);
+        assert_eq!(gameboy.aud.square_1.length.counter, if dmg { 32 } else { 0 });
+
+        // while the wave channel plays, only the CGB can always access the
+        // byte being played
+        gameboy.set_audio_register(0x16, 0x80);
+        for i in 0..16 {
+            gameboy.set_wave_ram(i, 0x5A);
+        }
+        gameboy.set_audio_register(0x0A, 0x80);
+        gameboy.set_audio_register(0x0E, 0x80);
+        assert_eq!(gameboy.wave_ram(3), if dmg { 0xFF } else { 0x5A });
+        gameboy.set_wave_ram(3, 0x12);
+        assert_eq!(gameboy.aud.wave_ram[0], if dmg { 0x5A } else { 0x12 });
+    }
+}
+
+#[test]
+fn test_vgm_logging_starts_without_triggering() {
+    use super::Output;
+    use std::sync::{Arc, Mutex};
+
+    let path = std::env::temp_dir().join(format!("zerodmg-trigger-{}.vgm", std::process::id()));
+    let mut gameboy = GameBoy::new(Arc::new(Mutex::new(Output::new())));
+    gameboy.set_audio_register(0x16, 0x80);
+    gameboy.set_audio_register(0x04, 0xC7);
+    gameboy.start_vgm_logging(&path).unwrap();
+    gameboy.stop_vgm_logging().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let commands: Vec<&[u8]> = bytes[0x100..].chunks(3).collect();
+    assert!(commands.contains(&&[0xB3, 0x04, 0x47][..]));
+}
+
+/// The rate at which the APU produces samples: once per M-cycle.
+pub const SAMPLE_RATE: u32 = 1_048_576;
+
+// the number of M-cycles between steps of the 512 Hz frame sequencer
+const FRAME_SEQUENCER_PERIOD: u32 = SAMPLE_RATE / 512;
+
+// how many samples are collected before they're handed to the output buffer
+const SAMPLE_BATCH_SIZE: usize = 1024;
+
+// bits of each register from 0xFF10 to 0xFF2F that always read as 1,
+// because they're write-only or unused
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+// the high, low, and transition states of the four square wave duty cycles
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+// the base periods of the noise channel, in T-cycles
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// One sample of the APU's stereo output, with each side in -1.0 to 1.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StereoSample {
+    pub left: f32,
+    pub right: f32,
+}
+
 /// Game Boy audio controller state
 pub struct AudioData {
     t: u64,
     registers: [u8; 0x2F],
+    // waveform played by channel 3, as 32 four-bit samples
+    wave_ram: [u8; 0x10],
+    square_1: SquareChannel,
+    square_2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    // M-cycles until the next frame sequencer step
+    frame_sequencer_timer: u32,
+    // which of the 8 frame sequencer steps is next
+    frame_sequencer_step: u8,
+    // samples produced since they were last handed to the output buffer
+    samples: Vec<StereoSample>,
+    // the WAV file(s) being recorded to, if any
+    recorder: Option<AudioRecorder>,
+    // the VGM file register writes are being logged to, if any
+    vgm: Option<VgmWriter>,
+    // whether the WAV and VGM files are skipping what's being played, such
+    // as while rewinding replays frames
+    recording_paused: bool,
+    // which channels are included in the mixed output
+    channel_mix: ChannelMix,
+    // the recent output of each channel, for debugging
+    scopes: [ChannelScope; 4],
 }
 
 impl AudioData {
-    pub fn new() -> Self {
+    pub fn new(ram: &mut RamInitializer) -> Self {
         Self {
             t: 0,
             registers: [0; 0x2F],
+            wave_ram: {
+                let mut a = [0u8; 0x10];
+                ram.fill(&mut a);
+                a
+            },
+            square_1: SquareChannel::new(),
+            square_2: SquareChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+            samples: Vec::with_capacity(SAMPLE_BATCH_SIZE),
+            recorder: None,
+            vgm: None,
+            recording_paused: false,
+            channel_mix: ChannelMix::default(),
+            scopes: [
+                ChannelScope::new(),
+                ChannelScope::new(),
+                ChannelScope::new(),
+                ChannelScope::new(),
+            ],
+        }
+    }
+
+    /// Saves the state of the APU, other than recording and debugging.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.t);
+        state.bytes(&self.registers);
+        state.bytes(&self.wave_ram);
+        self.square_1.save_state(state);
+        self.square_2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.u32(self.frame_sequencer_timer);
+        state.u8(self.frame_sequencer_step);
+        state.bool(self.square_1.sweep.negated);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u64(&mut self.t);
+        state.bytes(&mut self.registers);
+        state.bytes(&mut self.wave_ram);
+        self.square_1.load_state(state);
+        self.square_2.load_state(state);
+        self.wave.load_state(state);
+        self.noise.load_state(state);
+        state.u32(&mut self.frame_sequencer_timer);
+        state.u8(&mut self.frame_sequencer_step);
+        state.bool(&mut self.square_1.sweep.negated);
+    }
+}
+
+/// Counts down to disabling a channel, if enabled.
+#[derive(Clone, Copy, Debug)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    // 64 for most channels, 256 for the wave channel
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    /// Handles a write to the channel's NRx4 register, including the extra
+    /// clocking that happens when the length is enabled on a frame sequencer
+    /// step that won't clock it. Returns whether the channel should be
+    /// disabled.
+    fn write_control(&mut self, enable: bool, trigger: bool, next_step: u8) -> bool {
+        let next_step_clocks_length = next_step % 2 == 0;
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+
+        let mut expired = false;
+        if !next_step_clocks_length && !was_enabled && enable && self.counter > 0 {
+            self.counter -= 1;
+            expired = self.counter == 0;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && !next_step_clocks_length {
+                self.counter -= 1;
+            }
+        }
+
+        expired && !trigger
+    }
+
+    /// Clocks the counter, returning whether the channel should be disabled.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bool(&mut self.enabled);
+        state.u16(&mut self.counter);
+    }
+}
+
+/// Periodically adjusts a channel's volume.
+#[derive(Clone, Copy, Debug, Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn set_register(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0b0000_1000 != 0;
+        self.period = value & 0b0000_0111;
+    }
+
+    /// Whether the register value leaves the channel's DAC powered.
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self, next_step: u8) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+        // the timer is clocked sooner than expected if it's about to be
+        if next_step == 7 {
+            self.timer += 1;
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 0xF {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.u8(&mut self.initial_volume);
+        state.bool(&mut self.increase);
+        state.u8(&mut self.period);
+        state.u8(&mut self.volume);
+        state.u8(&mut self.timer);
+    }
+}
+
+/// Periodically adjusts square channel 1's frequency.
+#[derive(Clone, Copy, Debug, Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    // whether a calculation has been made in negate mode since the trigger
+    negated: bool,
+}
+
+impl Sweep {
+    fn set_register(&mut self, value: u8) {
+        self.period = (value & 0b0111_0000) >> 4;
+        self.negate = value & 0b0000_1000 != 0;
+        self.shift = value & 0b0000_0111;
+    }
+
+    fn reload_timer(&mut self) {
+        // a period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Calculates the next frequency, which disables the channel if it's
+    /// above 2047.
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.u8(&mut self.period);
+        state.bool(&mut self.negate);
+        state.u8(&mut self.shift);
+        state.u8(&mut self.timer);
+        state.bool(&mut self.enabled);
+        state.u16(&mut self.shadow_frequency);
+    }
+}
+
+/// A square wave channel (1 with a frequency sweep, or 2).
+#[derive(Clone, Copy, Debug)]
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    // T-cycles until the next duty step
+    timer: i32,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Sweep,
+}
+
+impl SquareChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+        }
+    }
+
+    fn cycle(&mut self) {
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.timer += (2048 - i32::from(self.frequency)) * 4;
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    fn trigger(&mut self, next_step: u8) {
+        self.enabled = self.dac_enabled;
+        self.timer = (2048 - i32::from(self.frequency)) * 4;
+        self.envelope.trigger(next_step);
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.negated = false;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.next_frequency() > 2047 {
+            self.enabled = false;
+        }
+    }
+
+    fn set_sweep_register(&mut self, value: u8) {
+        self.sweep.set_register(value);
+        // leaving negate mode after it was used in a calculation disables
+        // the channel
+        if self.sweep.negated && !self.sweep.negate {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+        if !self.sweep.enabled || self.sweep.period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if self.sweep.shift != 0 {
+            self.sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked for overflow again immediately
+            if self.sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// The current digital output, from 0 to 15.
+    fn output(&self) -> u8 {
+        if self.enabled {
+            DUTY_PATTERNS[usize::from(self.duty)][usize::from(self.duty_step)]
+                * self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.duty);
+        state.u8(self.duty_step);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        self.sweep.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.dac_enabled);
+        state.u8(&mut self.duty);
+        state.u8(&mut self.duty_step);
+        state.u16(&mut self.frequency);
+        state.i32(&mut self.timer);
+        self.length.load_state(state);
+        self.envelope.load_state(state);
+        self.sweep.load_state(state);
+    }
+}
+
+/// The channel playing back samples from wave RAM.
+#[derive(Clone, Copy, Debug)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    // the NR32 output level: mute, 100%, 50% or 25%
+    volume_code: u8,
+    frequency: u16,
+    // T-cycles until the next sample
+    timer: i32,
+    // which of the 32 samples in wave RAM is being played
+    position: u8,
+    // the last sample read from wave RAM
+    sample: u8,
+    // whether a sample was read from wave RAM in the last M-cycle
+    just_read: bool,
+    length: LengthCounter,
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            just_read: false,
+            length: LengthCounter::new(256),
+        }
+    }
+
+    fn cycle(&mut self, wave_ram: &[u8; 0x10]) {
+        self.just_read = false;
+        if !self.enabled {
+            return;
+        }
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.just_read = true;
+            self.timer += (2048 - i32::from(self.frequency)) * 2;
+            self.position = (self.position + 1) % 32;
+            let byte = wave_ram[usize::from(self.position / 2)];
+            // the high nibble is played first
+            self.sample = if self.position % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        // there's a short delay before the first sample is read
+        self.timer = (2048 - i32::from(self.frequency)) * 2 + 6;
+        self.position = 0;
+    }
+
+    /// The index of the wave RAM byte the channel is playing from.
+    fn byte_index(&self) -> usize {
+        usize::from(self.position / 2)
+    }
+
+    /// The current digital output, from 0 to 15.
+    fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            0
+        } else {
+            self.sample >> (self.volume_code - 1)
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        state.u8(self.position);
+        state.u8(self.sample);
+        state.bool(self.just_read);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.dac_enabled);
+        state.u8(&mut self.volume_code);
+        state.u16(&mut self.frequency);
+        state.i32(&mut self.timer);
+        state.u8(&mut self.position);
+        state.u8(&mut self.sample);
+        state.bool(&mut self.just_read);
+        self.length.load_state(state);
+    }
+}
+
+/// The channel producing pseudo-random noise from a linear-feedback shift
+/// register.
+#[derive(Clone, Copy, Debug)]
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    // whether the LFSR is shortened to 7 bits, for a more periodic sound
+    width_mode: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    // T-cycles until the next LFSR step
+    timer: i32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[usize::from(self.divisor_code)] << self.clock_shift
+    }
+
+    fn cycle(&mut self) {
+        self.timer -= 4;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            // shifts of 14 and 15 stop the LFSR from being clocked
+            if self.clock_shift < 14 {
+                self.step_lfsr();
+            }
+        }
+    }
+
+    fn step_lfsr(&mut self) {
+        let feedback = (self.lfsr & 0b01) ^ ((self.lfsr & 0b10) >> 1);
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.width_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn trigger(&mut self, next_step: u8) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.envelope.trigger(next_step);
+        self.lfsr = 0x7FFF;
+    }
+
+    /// The current digital output, from 0 to 15.
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0b1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.clock_shift);
+        state.bool(self.width_mode);
+        state.u8(self.divisor_code);
+        state.u16(self.lfsr);
+        state.i32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.dac_enabled);
+        state.u8(&mut self.clock_shift);
+        state.bool(&mut self.width_mode);
+        state.u8(&mut self.divisor_code);
+        state.u16(&mut self.lfsr);
+        state.i32(&mut self.timer);
+        self.length.load_state(state);
+        self.envelope.load_state(state);
+    }
+}
+
+/// Converts a channel's digital output into the analog output of its DAC, or
+/// None if the DAC is off.
+fn dac(dac_enabled: bool, digital: u8) -> Option<f32> {
+    if dac_enabled {
+        Some(f32::from(digital) / 7.5 - 1.0)
+    } else {
+        None
+    }
 }
 
 pub trait AudioController {
     fn audio_cycle(&mut self);
     fn audio_register(&self, index: usize) -> u8;
     fn set_audio_register(&mut self, index: usize, value: u8);
+    fn wave_ram(&self, index: usize) -> u8;
+    fn set_wave_ram(&mut self, index: usize, value: u8);
+    fn set_nr52(&mut self, value: u8);
+    fn audio_powered(&self) -> bool;
+    fn step_frame_sequencer(&mut self);
+    fn channel_outputs(&self) -> [Option<f32>; 4];
+    fn mix_audio(&self, channels: &[Option<f32>; 4]) -> StereoSample;
+    fn flush_audio_samples(&mut self);
+    fn channel_status(&self, channel: u8) -> ChannelStatus;
+    fn draw_audio_output(&mut self);
 }
 
 impl AudioController for GameBoy {
     fn audio_cycle(&mut self) {
         self.aud.t += 1;
+
+        self.aud.frame_sequencer_timer -= 1;
+        if self.aud.frame_sequencer_timer == 0 {
+            self.aud.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+            if self.audio_powered() {
+                self.step_frame_sequencer();
+            }
+        }
+
+        self.aud.square_1.cycle();
+        self.aud.square_2.cycle();
+        self.aud.wave.cycle(&self.aud.wave_ram);
+        self.aud.noise.cycle();
+
+        let channels = self.channel_outputs();
+        let mut sweep_completed = false;
+        for (scope, channel) in self.aud.scopes.iter_mut().zip(channels.iter()) {
+            sweep_completed = scope.push(*channel);
+        }
+        if sweep_completed {
+            self.draw_audio_output();
+        }
+
+        let mut audible = channels;
+        for (number, channel) in (1..=4).zip(audible.iter_mut()) {
+            if !self.aud.channel_mix.is_audible(number) {
+                *channel = None;
+            }
+        }
+        let sample = self.mix_audio(&audible);
+        let paused = self.aud.recording_paused;
+        if let Some(recorder) = self.aud.recorder.as_mut().filter(|_| !paused) {
+            let mut channel_samples = [0.0; 4];
+            for (value, channel) in channel_samples.iter_mut().zip(channels.iter()) {
+                *value = channel.unwrap_or(0.0);
+            }
+            recorder.push(sample, channel_samples);
+        }
+        self.aud.samples.push(sample);
+        if self.aud.samples.len() >= SAMPLE_BATCH_SIZE {
+            self.flush_audio_samples();
+        }
     }
 
     fn audio_register(&self, index: usize) -> u8 {
-        // println!("    ; audio_registers[0x{:02X}] ", index);
-        self.aud.registers[index]
+        let value = match index {
+            // NR52 reports which channels are playing
+            0x16 => {
+                let aud = &self.aud;
+                (aud.registers[index] & 0b1000_0000)
+                    | if aud.square_1.enabled { 0b0001 } else { 0 }
+                    | if aud.square_2.enabled { 0b0010 } else { 0 }
+                    | if aud.wave.enabled { 0b0100 } else { 0 }
+                    | if aud.noise.enabled { 0b1000 } else { 0 }
+            }
+            0x00..=0x15 => self.aud.registers[index],
+            _ => 0x00,
+        };
+        value | READ_MASKS[index]
     }
 
     fn set_audio_register(&mut self, index: usize, value: u8) {
-        // println!("    ; audio_registers[0x{:02X}] = {:02X}", index, value);
-        self.aud.registers[index] = value;
+        let paused = self.aud.recording_paused;
+        if let Some(vgm) = self.aud.vgm.as_mut().filter(|_| !paused) {
+            vgm.write_register(self.aud.t, index as u8, value);
+        }
+
+        if index == 0x16 {
+            self.set_nr52(value);
+            return;
+        }
+        if index > 0x16 {
+            // unused
+            return;
+        }
+
+        let next_step = self.aud.frame_sequencer_step;
+        let model = self.model;
+        let powered = self.audio_powered();
+        let aud = &mut self.aud;
+
+        if !powered {
+            // while powered off, registers can't be written, except that the
+            // DMG's length counters can still be loaded
+            if model == Model::Dmg {
+                match index {
+                    0x01 => aud.square_1.length.load(u16::from(value & 0b0011_1111)),
+                    0x06 => aud.square_2.length.load(u16::from(value & 0b0011_1111)),
+                    0x0B => aud.wave.length.load(u16::from(value)),
+                    0x10 => aud.noise.length.load(u16::from(value & 0b0011_1111)),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        aud.registers[index] = value;
+
+        let enable_length = value & 0b0100_0000 != 0;
+        let trigger = value & 0b1000_0000 != 0;
+        match index {
+            // NR10: square 1 sweep
+            0x00 => aud.square_1.set_sweep_register(value),
+            // NR11, NR21: duty and length
+            0x01 | 0x06 => {
+                let channel = if index == 0x01 {
+                    &mut aud.square_1
+                } else {
+                    &mut aud.square_2
+                };
+                channel.duty = value >> 6;
+                channel.length.load(u16::from(value & 0b0011_1111));
+            }
+            // NR12, NR22: envelope
+            0x02 | 0x07 => {
+                let channel = if index == 0x02 {
+                    &mut aud.square_1
+                } else {
+                    &mut aud.square_2
+                };
+                channel.envelope.set_register(value);
+                channel.dac_enabled = channel.envelope.dac_enabled();
+                if !channel.dac_enabled {
+                    channel.enabled = false;
+                }
+            }
+            // NR13, NR23: frequency low bits
+            0x03 | 0x08 => {
+                let channel = if index == 0x03 {
+                    &mut aud.square_1
+                } else {
+                    &mut aud.square_2
+                };
+                channel.frequency = (channel.frequency & 0x0700) | u16::from(value);
+            }
+            // NR14, NR24: frequency high bits, length enable and trigger
+            0x04 | 0x09 => {
+                let channel = if index == 0x04 {
+                    &mut aud.square_1
+                } else {
+                    &mut aud.square_2
+                };
+                channel.frequency =
+                    (channel.frequency & 0x00FF) | (u16::from(value & 0b0000_0111) << 8);
+                if channel.length.write_control(enable_length, trigger, next_step) {
+                    channel.enabled = false;
+                }
+                if trigger {
+                    channel.trigger(next_step);
+                }
+            }
+            // NR30: wave DAC power
+            0x0A => {
+                aud.wave.dac_enabled = value & 0b1000_0000 != 0;
+                if !aud.wave.dac_enabled {
+                    aud.wave.enabled = false;
+                }
+            }
+            // NR31: wave length
+            0x0B => aud.wave.length.load(u16::from(value)),
+            // NR32: wave output level
+            0x0C => aud.wave.volume_code = (value & 0b0110_0000) >> 5,
+            // NR33: wave frequency low bits
+            0x0D => aud.wave.frequency = (aud.wave.frequency & 0x0700) | u16::from(value),
+            // NR34: wave frequency high bits, length enable and trigger
+            0x0E => {
+                aud.wave.frequency =
+                    (aud.wave.frequency & 0x00FF) | (u16::from(value & 0b0000_0111) << 8);
+                if aud.wave.length.write_control(enable_length, trigger, next_step) {
+                    aud.wave.enabled = false;
+                }
+                if trigger {
+                    // retriggering the DMG's wave channel just as it reads a
+                    // sample corrupts the start of wave RAM
+                    if model == Model::Dmg && aud.wave.enabled && aud.wave.timer <= 4 {
+                        let next_byte = usize::from((aud.wave.position + 1) % 32 / 2);
+                        if next_byte < 4 {
+                            aud.wave_ram[0] = aud.wave_ram[next_byte];
+                        } else {
+                            let start = next_byte & !0b11;
+                            for i in 0..4 {
+                                aud.wave_ram[i] = aud.wave_ram[start + i];
+                            }
+                        }
+                    }
+                    aud.wave.trigger();
+                }
+            }
+            // NR41: noise length
+            0x10 => aud.noise.length.load(u16::from(value & 0b0011_1111)),
+            // NR42: noise envelope
+            0x11 => {
+                aud.noise.envelope.set_register(value);
+                aud.noise.dac_enabled = aud.noise.envelope.dac_enabled();
+                if !aud.noise.dac_enabled {
+                    aud.noise.enabled = false;
+                }
+            }
+            // NR43: noise frequency and LFSR width
+            0x12 => {
+                aud.noise.clock_shift = value >> 4;
+                aud.noise.width_mode = value & 0b0000_1000 != 0;
+                aud.noise.divisor_code = value & 0b0000_0111;
+            }
+            // NR44: noise length enable and trigger
+            0x13 => {
+                if aud.noise.length.write_control(enable_length, trigger, next_step) {
+                    aud.noise.enabled = false;
+                }
+                if trigger {
+                    aud.noise.trigger(next_step);
+                }
+            }
+            // NR50 and NR51 are only used when mixing
+            _ => {}
+        }
+    }
+
+    fn wave_ram(&self, index: usize) -> u8 {
+        let wave = &self.aud.wave;
+        if wave.enabled {
+            // while playing, accesses go to the byte being played instead,
+            // but the DMG only allows it just as the byte is read
+            if self.model == Model::Dmg && !wave.just_read {
+                0xFF
+            } else {
+                self.aud.wave_ram[wave.byte_index()]
+            }
+        } else {
+            self.aud.wave_ram[index]
+        }
+    }
+
+    fn set_wave_ram(&mut self, index: usize, value: u8) {
+        let paused = self.aud.recording_paused;
+        if let Some(vgm) = self.aud.vgm.as_mut().filter(|_| !paused) {
+            vgm.write_register(self.aud.t, 0x20 + index as u8, value);
+        }
+
+        let wave = self.aud.wave;
+        if wave.enabled {
+            if self.model != Model::Dmg || wave.just_read {
+                self.aud.wave_ram[wave.byte_index()] = value;
+            }
+        } else {
+            self.aud.wave_ram[index] = value;
+        }
+    }
+
+    fn set_nr52(&mut self, value: u8) {
+        let was_powered = self.audio_powered();
+        let powered = value & 0b1000_0000 != 0;
+
+        if was_powered && !powered {
+            // powering off clears every register, but the DMG's length
+            // counters are left alone
+            let keep_lengths = self.model == Model::Dmg;
+            let aud = &mut self.aud;
+            let lengths = [
+                aud.square_1.length.counter,
+                aud.square_2.length.counter,
+                aud.wave.length.counter,
+                aud.noise.length.counter,
+            ];
+            aud.square_1 = SquareChannel::new();
+            aud.square_2 = SquareChannel::new();
+            aud.wave = WaveChannel::new();
+            aud.noise = NoiseChannel::new();
+            if keep_lengths {
+                aud.square_1.length.counter = lengths[0];
+                aud.square_2.length.counter = lengths[1];
+                aud.wave.length.counter = lengths[2];
+                aud.noise.length.counter = lengths[3];
+            }
+            for register in aud.registers.iter_mut() {
+                *register = 0x00;
+            }
+        } else if !was_powered && powered {
+            // the frame sequencer starts again from its first step
+            self.aud.frame_sequencer_step = 0;
+            self.aud.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+        }
+
+        self.aud.registers[0x16] = value & 0b1000_0000;
+    }
+
+    fn audio_powered(&self) -> bool {
+        self.aud.registers[0x16] & 0b1000_0000 != 0
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let aud = &mut self.aud;
+        let step = aud.frame_sequencer_step;
+        aud.frame_sequencer_step = (step + 1) % 8;
+
+        // length counters are clocked on even steps
+        if step % 2 == 0 {
+            if aud.square_1.length.clock() {
+                aud.square_1.enabled = false;
+            }
+            if aud.square_2.length.clock() {
+                aud.square_2.enabled = false;
+            }
+            if aud.wave.length.clock() {
+                aud.wave.enabled = false;
+            }
+            if aud.noise.length.clock() {
+                aud.noise.enabled = false;
+            }
+        }
+
+        // the sweep is clocked on steps 2 and 6
+        if step == 2 || step == 6 {
+            aud.square_1.clock_sweep();
+        }
+
+        // envelopes are clocked on step 7
+        if step == 7 {
+            aud.square_1.envelope.clock();
+            aud.square_2.envelope.clock();
+            aud.noise.envelope.clock();
+        }
+    }
+
+    fn channel_outputs(&self) -> [Option<f32>; 4] {
+        if !self.audio_powered() {
+            return [None; 4];
+        }
+
+        let aud = &self.aud;
+        [
+            dac(aud.square_1.dac_enabled, aud.square_1.output()),
+            dac(aud.square_2.dac_enabled, aud.square_2.output()),
+            dac(aud.wave.dac_enabled, aud.wave.output()),
+            dac(aud.noise.dac_enabled, aud.noise.output()),
+        ]
+    }
+
+    fn mix_audio(&self, channels: &[Option<f32>; 4]) -> StereoSample {
+        let aud = &self.aud;
+
+        // NR51 routes each channel to each side
+        let nr51 = aud.registers[0x15];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, channel) in channels.iter().enumerate() {
+            if let Some(output) = channel {
+                if nr51 & (0b0001_0000 << i) != 0 {
+                    left += output;
+                }
+                if nr51 & (0b0000_0001 << i) != 0 {
+                    right += output;
+                }
+            }
+        }
+
+        // NR50 sets the master volume of each side, from 1 to 8
+        let nr50 = aud.registers[0x14];
+        let left_volume = f32::from(((nr50 & 0b0111_0000) >> 4) + 1) / 8.0;
+        let right_volume = f32::from((nr50 & 0b0000_0111) + 1) / 8.0;
+
+        StereoSample {
+            left: left / 4.0 * left_volume,
+            right: right / 4.0 * right_volume,
+        }
+    }
+
+    fn flush_audio_samples(&mut self) {
+        self.output_buffer
+            .lock()
+            .unwrap()
+            .audio
+            .push(&self.aud.samples);
+        self.aud.samples.clear();
+    }
+
+    fn channel_status(&self, channel: u8) -> ChannelStatus {
+        let aud = &self.aud;
+        let powered = self.audio_powered();
+        let (enabled, dac_enabled, frequency, volume, duty) = match channel {
+            1 | 2 => {
+                let square = if channel == 1 {
+                    &aud.square_1
+                } else {
+                    &aud.square_2
+                };
+                (
+                    square.enabled,
+                    square.dac_enabled,
+                    131_072.0 / (2048.0 - f32::from(square.frequency)),
+                    square.envelope.volume,
+                    Some(square.duty),
+                )
+            }
+            3 => {
+                let wave = &aud.wave;
+                let volume = if wave.volume_code == 0 {
+                    0
+                } else {
+                    0xF >> (wave.volume_code - 1)
+                };
+                (
+                    wave.enabled,
+                    wave.dac_enabled,
+                    65_536.0 / (2048.0 - f32::from(wave.frequency)),
+                    volume,
+                    None,
+                )
+            }
+            _ => {
+                let noise = &aud.noise;
+                (
+                    noise.enabled,
+                    noise.dac_enabled,
+                    4_194_304.0 / noise.period() as f32,
+                    noise.envelope.volume,
+                    None,
+                )
+            }
+        };
+        ChannelStatus {
+            channel,
+            enabled: powered && enabled,
+            dac_enabled: powered && dac_enabled,
+            frequency,
+            volume,
+            duty,
+            audible: aud.channel_mix.is_audible(channel),
+        }
+    }
+
+    fn draw_audio_output(&mut self) {
+        let statuses: Vec<ChannelStatus> = (1..=4).map(|n| self.channel_status(n)).collect();
+        let scopes: Vec<_> = statuses
+            .iter()
+            .zip(self.aud.scopes.iter())
+            .map(|(status, scope)| scope.draw(status))
+            .collect();
+        let playing = if statuses[2].enabled {
+            Some(self.aud.wave.position)
+        } else {
+            None
+        };
+        let wave_ram = draw_wave_ram(&self.aud.wave_ram, playing);
+
+        let mut output_buffer = self.output_buffer.lock().unwrap();
+        output_buffer.channel_scopes = scopes;
+        output_buffer.channel_status = statuses;
+        output_buffer.wave_ram = wave_ram;
+        // the mix may have been changed by a frontend holding the output
+        self.aud.channel_mix = output_buffer.channel_mix;
+    }
+}
+
+impl GameBoy {
+    /// Which channels are included in the mixed output.
+    pub fn channel_mix(&self) -> ChannelMix {
+        self.aud.channel_mix
+    }
+
+    /// Mutes or solos channels in the mixed output, though they're still
+    /// recorded to their own files.
+    pub fn set_channel_mix(&mut self, channel_mix: ChannelMix) {
+        self.aud.channel_mix = channel_mix;
+        self.output_buffer.lock().unwrap().channel_mix = channel_mix;
+    }
+
+    /// Starts recording audio to a 16-bit PCM WAV file at the given sample
+    /// rate, replacing any recording in progress. If `per_channel` is set,
+    /// each channel is also recorded to its own file, like `music.ch1.wav`.
+    pub fn start_audio_recording(
+        &mut self,
+        path: &Path,
+        sample_rate: u32,
+        per_channel: bool,
+    ) -> io::Result<()> {
+        self.stop_audio_recording()?;
+        self.aud.recorder = Some(AudioRecorder::create(path, sample_rate, per_channel)?);
+        Ok(())
+    }
+
+    /// Starts logging APU register writes to a VGM file, replacing any log in
+    /// progress. The file starts with the current register values, so it can
+    /// be started at any point.
+    pub fn start_vgm_logging(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_vgm_logging()?;
+        let t = self.aud.t;
+        let mut vgm = VgmWriter::create(path, t)?;
+        // power first, so that the other writes aren't ignored
+        vgm.write_register(t, 0x16, self.aud.registers[0x16]);
+        for index in 0x00..0x16 {
+            let value = match index {
+                // NR14, NR24, NR34 and NR44 keep the trigger bit that was
+                // written, which would restart the channel if replayed
+                0x04 | 0x09 | 0x0E | 0x13 => self.aud.registers[index] & 0x7F,
+                _ => self.aud.registers[index],
+            };
+            vgm.write_register(t, index as u8, value);
+        }
+        for index in 0x00..0x10 {
+            vgm.write_register(t, 0x20 + index as u8, self.aud.wave_ram[index]);
+        }
+        self.aud.vgm = Some(vgm);
+        Ok(())
+    }
+
+    /// Stops logging APU register writes, finishing the VGM file.
+    pub fn stop_vgm_logging(&mut self) -> io::Result<()> {
+        match self.aud.vgm.take() {
+            Some(vgm) => vgm.finish(self.aud.t),
+            None => Ok(()),
+        }
+    }
+
+    /// Pauses or resumes the WAV recording and VGM log, which leave out what
+    /// is played while paused.
+    pub fn set_audio_recording_paused(&mut self, paused: bool) {
+        self.aud.recording_paused = paused;
+    }
+
+    /// Stops recording audio, finishing the file(s).
+    pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+        match self.aud.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
     }
 }
@@ -0,0 +1,329 @@
+use std::fmt;
+
+use image::{DynamicImage, GenericImage, ImageBuffer, Rgba};
+
+#[test]
+fn test_solo_overrides_mutes() {
+    let mut mix = ChannelMix::default();
+    mix.set_muted(2, true).unwrap();
+    assert!(mix.is_audible(1));
+    assert!(!mix.is_audible(2));
+
+    mix.set_solo(Some(2)).unwrap();
+    assert!(!mix.is_audible(1));
+    assert!(mix.is_audible(2));
+    assert!(!mix.is_audible(4));
+
+    mix.set_solo(None).unwrap();
+    assert!(mix.is_audible(4));
+
+    // channels are numbered from 1 to 4
+    assert!(mix.set_muted(0, true).is_err());
+    assert!(mix.set_solo(Some(5)).is_err());
+    assert_eq!(mix.muted(5), None);
+    assert!(!mix.is_audible(0));
+}
+
+#[test]
+fn test_scope_records_range_of_each_column() {
+    let mut scope = ChannelScope::new();
+    for i in 0..SCOPE_COLUMN_CYCLES {
+        let output = if i % 2 == 0 { Some(-0.5) } else { Some(0.25) };
+        assert!(!scope.push(output));
+    }
+    scope.push(None);
+    assert_eq!(scope.columns[0], Some((-0.5, 0.25)));
+    assert_eq!(scope.columns[1], None);
+
+    for _ in 0..(SCOPE_WIDTH - 1) * SCOPE_COLUMN_CYCLES - 2 {
+        assert!(!scope.push(Some(0.0)));
+    }
+    // the last cycle of the last column completes the sweep
+    assert!(scope.push(Some(0.0)));
+}
+
+/// The width of each channel's waveform image, in columns.
+pub const SCOPE_WIDTH: usize = 256;
+
+// the number of M-cycles shown in each column of a waveform, so that the
+// whole image covers about one frame
+const SCOPE_COLUMN_CYCLES: usize = 64;
+
+// the heights of the parts of each channel's image
+const WAVEFORM_HEIGHT: u32 = 64;
+const BAR_HEIGHT: u32 = 4;
+const CHANNEL_IMAGE_HEIGHT: u32 = WAVEFORM_HEIGHT + 3 * (BAR_HEIGHT + 2);
+
+// the range of frequencies on the frequency bar, as powers of two
+const MIN_FREQUENCY_LOG2: f32 = 5.0;
+const MAX_FREQUENCY_LOG2: f32 = 18.0;
+
+const BACKGROUND_COLOR: Rgba<u8> = Rgba {
+    data: [0x20, 0x20, 0x28, 0xFF],
+};
+const AXIS_COLOR: Rgba<u8> = Rgba {
+    data: [0x40, 0x40, 0x50, 0xFF],
+};
+const MUTED_COLOR: Rgba<u8> = Rgba {
+    data: [0x70, 0x70, 0x70, 0xFF],
+};
+const CHANNEL_COLORS: [Rgba<u8>; 4] = [
+    Rgba {
+        data: [0xFF, 0x60, 0x60, 0xFF],
+    },
+    Rgba {
+        data: [0xFF, 0xD0, 0x40, 0xFF],
+    },
+    Rgba {
+        data: [0x50, 0xA0, 0xFF, 0xFF],
+    },
+    Rgba {
+        data: [0x60, 0xE0, 0x80, 0xFF],
+    },
+];
+
+/// Which APU channels are heard in the mixed output, for debugging sound.
+/// Channels are numbered from 1 to 4.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChannelMix {
+    muted: [bool; 4],
+    solo: Option<u8>,
+}
+
+impl ChannelMix {
+    /// Whether a channel is muted, or None if there's no such channel.
+    pub fn muted(&self, channel: u8) -> Option<bool> {
+        channel_index(channel).map(|i| self.muted[i])
+    }
+
+    pub fn set_muted(&mut self, channel: u8, muted: bool) -> Result<(), String> {
+        let i = channel_index(channel).ok_or_else(|| invalid_channel(channel))?;
+        self.muted[i] = muted;
+        Ok(())
+    }
+
+    /// The only channel heard, if any, regardless of which are muted.
+    pub fn solo(&self) -> Option<u8> {
+        self.solo
+    }
+
+    pub fn set_solo(&mut self, channel: Option<u8>) -> Result<(), String> {
+        if let Some(channel) = channel {
+            channel_index(channel).ok_or_else(|| invalid_channel(channel))?;
+        }
+        self.solo = channel;
+        Ok(())
+    }
+
+    /// Whether the channel is included in the mixed output.
+    pub fn is_audible(&self, channel: u8) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => self.muted(channel) == Some(false),
+        }
+    }
+}
+
+// the index of a channel numbered from 1 to 4, if it's one of them
+fn channel_index(channel: u8) -> Option<usize> {
+    match channel {
+        1..=4 => Some(usize::from(channel - 1)),
+        _ => None,
+    }
+}
+
+fn invalid_channel(channel: u8) -> String {
+    format!("invalid channel: {}, expected 1 to 4", channel)
+}
+
+/// A snapshot of the state of one APU channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelStatus {
+    /// The channel number, from 1 to 4.
+    pub channel: u8,
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    /// The frequency of the tone (or of noise generator steps), in Hz.
+    pub frequency: f32,
+    /// The volume, from 0 to 15.
+    pub volume: u8,
+    /// The duty cycle selected in NRx1, for the square channels.
+    pub duty: Option<u8>,
+    /// Whether the channel is included in the mixed output.
+    pub audible: bool,
+}
+
+impl ChannelStatus {
+    /// The fraction of each period a square channel's output is high.
+    pub fn duty_fraction(&self) -> Option<f32> {
+        self.duty.map(|duty| [0.125, 0.25, 0.5, 0.75][usize::from(duty & 0b11)])
+    }
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CH{} {:3} DAC {:3} {:9.2}Hz volume {:2}",
+            self.channel,
+            if self.enabled { "on" } else { "off" },
+            if self.dac_enabled { "on" } else { "off" },
+            self.frequency,
+            self.volume
+        )?;
+        if let Some(duty) = self.duty_fraction() {
+            write!(f, " duty {:4.1}%", duty * 100.0)?;
+        }
+        if !self.audible {
+            write!(f, " (muted)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The recent output of a channel, as the range of its values in each
+/// column of a waveform image that's swept from left to right.
+#[derive(Clone, Debug)]
+pub struct ChannelScope {
+    columns: Vec<Option<(f32, f32)>>,
+    column: usize,
+    column_cycle: usize,
+}
+
+impl ChannelScope {
+    pub fn new() -> Self {
+        Self {
+            columns: vec![None; SCOPE_WIDTH],
+            column: 0,
+            column_cycle: 0,
+        }
+    }
+
+    /// Adds the channel's output for one M-cycle, or None if its DAC is off.
+    /// Returns whether this completed a sweep across the image.
+    pub fn push(&mut self, output: Option<f32>) -> bool {
+        let column = &mut self.columns[self.column];
+        if self.column_cycle == 0 {
+            *column = None;
+        }
+        if let Some(value) = output {
+            *column = Some(match *column {
+                Some((low, high)) => (low.min(value), high.max(value)),
+                None => (value, value),
+            });
+        }
+
+        self.column_cycle += 1;
+        if self.column_cycle < SCOPE_COLUMN_CYCLES {
+            return false;
+        }
+        self.column_cycle = 0;
+        self.column = (self.column + 1) % SCOPE_WIDTH;
+        self.column == 0
+    }
+
+    /// Draws the waveform, above bars showing the channel's volume, duty
+    /// cycle (for square channels) and frequency (on a log scale).
+    pub fn draw(&self, status: &ChannelStatus) -> DynamicImage {
+        let mut image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+            SCOPE_WIDTH as u32,
+            CHANNEL_IMAGE_HEIGHT,
+            BACKGROUND_COLOR,
+        ));
+        let color = if status.audible {
+            CHANNEL_COLORS[usize::from(status.channel - 1) % 4]
+        } else {
+            MUTED_COLOR
+        };
+
+        let value_y = |value: f32| {
+            let y = (1.0 - value) / 2.0 * (WAVEFORM_HEIGHT - 1) as f32;
+            (y.round() as u32).min(WAVEFORM_HEIGHT - 1)
+        };
+        for x in 0..SCOPE_WIDTH as u32 {
+            image.put_pixel(x, value_y(0.0), AXIS_COLOR);
+        }
+        for (x, column) in self.columns.iter().enumerate() {
+            if let Some((low, high)) = *column {
+                for y in value_y(high)..=value_y(low) {
+                    image.put_pixel(x as u32, y, color);
+                }
+            }
+        }
+        // the column being drawn next
+        for y in 0..WAVEFORM_HEIGHT {
+            image.put_pixel(self.column as u32, y, AXIS_COLOR);
+        }
+
+        let mut bar_y = WAVEFORM_HEIGHT + 2;
+        let mut draw_bar = |image: &mut DynamicImage, filled: &Fn(u32) -> bool| {
+            for x in 0..SCOPE_WIDTH as u32 {
+                let pixel = if filled(x) { color } else { AXIS_COLOR };
+                for y in bar_y..bar_y + BAR_HEIGHT {
+                    image.put_pixel(x, y, pixel);
+                }
+            }
+            bar_y += BAR_HEIGHT + 2;
+        };
+
+        let volume_width = u32::from(status.volume) * SCOPE_WIDTH as u32 / 15;
+        draw_bar(&mut image, &|x| x < volume_width);
+
+        match status.duty_fraction() {
+            // one period of the square wave
+            Some(duty) => {
+                let high_width = (duty * SCOPE_WIDTH as f32) as u32;
+                draw_bar(&mut image, &|x| x < high_width)
+            }
+            None => draw_bar(&mut image, &|_| false),
+        }
+
+        let frequency_width = if status.frequency > 0.0 {
+            let position = (status.frequency.log2() - MIN_FREQUENCY_LOG2)
+                / (MAX_FREQUENCY_LOG2 - MIN_FREQUENCY_LOG2);
+            (position.max(0.0).min(1.0) * SCOPE_WIDTH as f32) as u32
+        } else {
+            0
+        };
+        draw_bar(&mut image, &|x| x < frequency_width);
+
+        image
+    }
+}
+
+impl Default for ChannelScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Draws the 32 four-bit samples in wave RAM as bars, highlighting the one
+/// being played if the wave channel is enabled.
+pub fn draw_wave_ram(wave_ram: &[u8; 0x10], playing: Option<u8>) -> DynamicImage {
+    let bar_width = SCOPE_WIDTH as u32 / 32;
+    let level_height = WAVEFORM_HEIGHT / 16;
+    let mut image = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+        SCOPE_WIDTH as u32,
+        WAVEFORM_HEIGHT,
+        BACKGROUND_COLOR,
+    ));
+    for position in 0..32u8 {
+        let byte = wave_ram[usize::from(position / 2)];
+        // the high nibble is played first
+        let sample = if position % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        let color = if playing == Some(position) {
+            Rgba {
+                data: [0xFF, 0xFF, 0xFF, 0xFF],
+            }
+        } else {
+            CHANNEL_COLORS[2]
+        };
+        let top = WAVEFORM_HEIGHT - (u32::from(sample) + 1) * level_height;
+        for x in 0..bar_width - 1 {
+            for y in top..WAVEFORM_HEIGHT {
+                image.put_pixel(u32::from(position) * bar_width + x, y, color);
+            }
+        }
+    }
+    image
+}
@@ -0,0 +1,360 @@
+use super::audio::{StereoSample, SAMPLE_RATE as APU_SAMPLE_RATE};
+
+#[test]
+fn test_resampler_settles_at_step_level() {
+    let mut resampler = Resampler::new(APU_SAMPLE_RATE, 48_000);
+    let mut buffer = AudioRingBuffer::new(64, 64);
+    let high = StereoSample {
+        left: 0.5,
+        right: -0.25,
+    };
+    for _ in 0..APU_SAMPLE_RATE / 100 {
+        resampler.push(high, &mut buffer);
+    }
+    // (just under) 10ms of input gives 10ms of output
+    assert_eq!(buffer.len(), 479);
+    let frame = buffer.pop_frame().unwrap();
+    let last = frame[frame.len() - 1];
+    assert!((last.left - 0.5).abs() < 0.001);
+    assert!((last.right + 0.25).abs() < 0.001);
+}
+
+#[test]
+fn test_high_pass_removes_dc_offset() {
+    let mut filter = HighPassFilter::new(APU_SAMPLE_RATE);
+    let offset = StereoSample {
+        left: -1.0,
+        right: -1.0,
+    };
+    let mut output = filter.apply(offset);
+    assert!(output.left < -0.99);
+    for _ in 0..APU_SAMPLE_RATE {
+        output = filter.apply(offset);
+    }
+    assert!(output.left.abs() < 0.001);
+}
+
+#[test]
+fn test_ring_buffer_drops_oldest_frames() {
+    let mut buffer = AudioRingBuffer::new(2, 4);
+    for i in 0..12 {
+        buffer.push(StereoSample {
+            left: i as f32,
+            right: 0.0,
+        });
+    }
+    assert_eq!(buffer.level(), 1.0);
+    assert_eq!(buffer.pop_frame().unwrap()[0].left, 4.0);
+    assert_eq!(buffer.pop_frame().unwrap()[0].left, 8.0);
+    assert_eq!(buffer.pop_frame(), None);
+}
+
+/// The number of stereo samples in each frame pulled from an [AudioOutput].
+pub const AUDIO_FRAME_SIZE: usize = 512;
+
+// how many frames an AudioOutput buffers before dropping the oldest
+const AUDIO_FRAMES_BUFFERED: usize = 8;
+
+// the most that dynamic rate control will speed up or slow down the output
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+// the length of the band-limited step kernel, in output samples
+const KERNEL_TAPS: usize = 16;
+// the number of sub-sample positions the kernel is precomputed for
+const KERNEL_PHASES: usize = 64;
+// the kernel's cutoff frequency, relative to the output Nyquist frequency
+const KERNEL_CUTOFF: f64 = 0.9;
+
+/// Turns the APU's raw samples into audio at a standard sample rate, and
+/// buffers it in fixed-size frames for an audio device to pull.
+#[derive(Clone, Debug)]
+pub struct AudioOutput {
+    high_pass: HighPassFilter,
+    resampler: Resampler,
+    buffer: AudioRingBuffer,
+    dynamic_rate_control: bool,
+}
+
+impl AudioOutput {
+    /// Creates an output stage producing the given sample rate, such as
+    /// 44100 or 48000 Hz.
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            high_pass: HighPassFilter::new(APU_SAMPLE_RATE),
+            resampler: Resampler::new(APU_SAMPLE_RATE, sample_rate),
+            buffer: AudioRingBuffer::new(AUDIO_FRAMES_BUFFERED, AUDIO_FRAME_SIZE),
+            dynamic_rate_control: true,
+        }
+    }
+
+    /// The sample rate of the frames produced.
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.output_rate
+    }
+
+    /// Sets whether the output rate is nudged up or down to keep the buffer
+    /// half full, so that audio stays in sync with emulation even though
+    /// it's paced by a different clock from the audio device.
+    pub fn set_dynamic_rate_control(&mut self, enabled: bool) {
+        self.dynamic_rate_control = enabled;
+        if !enabled {
+            self.resampler.set_rate_adjustment(0.0);
+        }
+    }
+
+    /// Adds samples produced by the APU, at its native rate.
+    pub fn push(&mut self, samples: &[StereoSample]) {
+        for sample in samples {
+            let filtered = self.high_pass.apply(*sample);
+            self.resampler.push(filtered, &mut self.buffer);
+        }
+
+        if self.dynamic_rate_control {
+            let level = f64::from(self.buffer.level());
+            self.resampler
+                .set_rate_adjustment((0.5 - level) * 2.0 * MAX_RATE_ADJUSTMENT);
+        }
+    }
+
+    /// Removes the oldest complete frame of samples, if there is one.
+    pub fn pop_frame(&mut self) -> Option<Vec<StereoSample>> {
+        self.buffer.pop_frame()
+    }
+
+    /// How full the buffer is, from 0.0 to 1.0.
+    pub fn buffer_level(&self) -> f32 {
+        self.buffer.level()
+    }
+}
+
+impl Default for AudioOutput {
+    fn default() -> Self {
+        Self::new(48_000)
+    }
+}
+
+/// The capacitor between the DMG's mixer and its output, which blocks DC
+/// offsets such as those from enabled DACs playing silence.
+#[derive(Clone, Copy, Debug)]
+pub struct HighPassFilter {
+    // the fraction of the charge the capacitor keeps after each sample
+    charge_factor: f32,
+    capacitor: StereoSample,
+}
+
+impl HighPassFilter {
+    /// Creates a filter for samples at the given rate.
+    pub fn new(sample_rate: u32) -> Self {
+        // the capacitor keeps 0.999958 of its charge each T-cycle
+        let t_cycles_per_sample = 4_194_304.0 / f64::from(sample_rate);
+        Self {
+            charge_factor: 0.999_958_f64.powf(t_cycles_per_sample) as f32,
+            capacitor: StereoSample::default(),
+        }
+    }
+
+    /// Filters the next sample.
+    pub fn apply(&mut self, input: StereoSample) -> StereoSample {
+        let output = StereoSample {
+            left: input.left - self.capacitor.left,
+            right: input.right - self.capacitor.right,
+        };
+        self.capacitor = StereoSample {
+            left: input.left - output.left * self.charge_factor,
+            right: input.right - output.right * self.charge_factor,
+        };
+        output
+    }
+}
+
+/// A band-limited step synthesis resampler. Each change in the input is
+/// added to the output as a windowed-sinc impulse at its exact sub-sample
+/// position, and the output is the running sum of those impulses, so square
+/// waves come out without aliasing.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    // output samples per input sample, including any rate adjustment
+    ratio: f64,
+    // the position of the next input sample, in output samples after the
+    // first pending delta
+    position: f64,
+    // the impulse response at each sub-sample phase
+    kernel: Vec<[f32; KERNEL_TAPS]>,
+    // changes in level not yet summed into the output
+    deltas: Vec<StereoSample>,
+    last_input: StereoSample,
+    level: StereoSample,
+}
+
+impl Resampler {
+    /// Creates a resampler between the given sample rates.
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        let mut kernel = vec![[0.0; KERNEL_TAPS]; KERNEL_PHASES];
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut sum = 0.0;
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // distance from the impulse, which is delayed by half the
+                // kernel so that it's causal
+                let x = k as f64 - (KERNEL_TAPS / 2) as f64 - offset;
+                let value = KERNEL_CUTOFF * sinc(KERNEL_CUTOFF * x) * blackman(x);
+                *tap = value as f32;
+                sum += value;
+            }
+            // each impulse must sum to exactly one so steps settle at the
+            // right level
+            for tap in taps.iter_mut() {
+                *tap /= sum as f32;
+            }
+        }
+
+        Self {
+            input_rate,
+            output_rate,
+            ratio: f64::from(output_rate) / f64::from(input_rate),
+            position: 0.0,
+            kernel,
+            deltas: vec![StereoSample::default(); KERNEL_TAPS],
+            last_input: StereoSample::default(),
+            level: StereoSample::default(),
+        }
+    }
+
+    /// Speeds up (if positive) or slows down (if negative) the output by the
+    /// given fraction.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.ratio = f64::from(self.output_rate) / f64::from(self.input_rate) * (1.0 + adjustment);
+    }
+
+    /// Adds an input sample, pushing any output samples completed by it.
+    pub fn push<S: SampleSink>(&mut self, input: StereoSample, output: &mut S) {
+        let delta = StereoSample {
+            left: input.left - self.last_input.left,
+            right: input.right - self.last_input.right,
+        };
+        if delta != StereoSample::default() {
+            let start = self.position.floor();
+            let phase = ((self.position - start) * KERNEL_PHASES as f64) as usize;
+            let start = start as usize;
+            if self.deltas.len() < start + KERNEL_TAPS {
+                self.deltas
+                    .resize(start + KERNEL_TAPS, StereoSample::default());
+            }
+            for (k, tap) in self.kernel[phase.min(KERNEL_PHASES - 1)].iter().enumerate() {
+                self.deltas[start + k].left += delta.left * tap;
+                self.deltas[start + k].right += delta.right * tap;
+            }
+            self.last_input = input;
+        }
+
+        self.position += self.ratio;
+
+        // deltas before the next input's position can't change any more
+        let complete = (self.position.floor() as usize).min(self.deltas.len());
+        if complete > 0 {
+            for delta in self.deltas.drain(..complete) {
+                self.level.left += delta.left;
+                self.level.right += delta.right;
+                output.push_sample(self.level);
+            }
+            self.position -= complete as f64;
+            if self.deltas.len() < KERNEL_TAPS {
+                self.deltas.resize(KERNEL_TAPS, StereoSample::default());
+            }
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+    }
+}
+
+/// The Blackman window over the kernel, centred on zero.
+fn blackman(x: f64) -> f64 {
+    let n = (x + (KERNEL_TAPS / 2) as f64) / KERNEL_TAPS as f64;
+    if n < 0.0 || n > 1.0 {
+        return 0.0;
+    }
+    let tau = 2.0 * std::f64::consts::PI;
+    0.42 - 0.5 * (tau * n).cos() + 0.08 * (2.0 * tau * n).cos()
+}
+
+/// Somewhere resampled audio can be sent.
+pub trait SampleSink {
+    fn push_sample(&mut self, sample: StereoSample);
+}
+
+impl SampleSink for AudioRingBuffer {
+    fn push_sample(&mut self, sample: StereoSample) {
+        self.push(sample);
+    }
+}
+
+/// A fixed-capacity queue of samples that's read in fixed-size frames,
+/// overwriting the oldest samples if it fills up.
+#[derive(Clone, Debug)]
+pub struct AudioRingBuffer {
+    samples: Vec<StereoSample>,
+    // index of the oldest sample
+    start: usize,
+    len: usize,
+    frame_size: usize,
+}
+
+impl AudioRingBuffer {
+    /// Creates a buffer holding the given number of frames of the given
+    /// number of samples.
+    pub fn new(frames: usize, frame_size: usize) -> Self {
+        Self {
+            samples: vec![StereoSample::default(); frames * frame_size],
+            start: 0,
+            len: 0,
+            frame_size,
+        }
+    }
+
+    pub fn push(&mut self, sample: StereoSample) {
+        let capacity = self.samples.len();
+        let end = (self.start + self.len) % capacity;
+        self.samples[end] = sample;
+        if self.len < capacity {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % capacity;
+        }
+    }
+
+    /// Removes the oldest frame of samples, if a complete one is available.
+    pub fn pop_frame(&mut self) -> Option<Vec<StereoSample>> {
+        if self.len < self.frame_size {
+            return None;
+        }
+        let capacity = self.samples.len();
+        let frame = (0..self.frame_size)
+            .map(|i| self.samples[(self.start + i) % capacity])
+            .collect();
+        self.start = (self.start + self.frame_size) % capacity;
+        self.len -= self.frame_size;
+        Some(frame)
+    }
+
+    /// The number of samples buffered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How full the buffer is, from 0.0 to 1.0.
+    pub fn level(&self) -> f32 {
+        self.len as f32 / self.samples.len() as f32
+    }
+}
@@ -0,0 +1,400 @@
+use zerodmg_codes::instruction::prelude::*;
+use zerodmg_utils::little_endian::{u16_to_u8s, u32_to_u8s, u8s_to_u16, u8s_to_u32};
+
+use super::audio::AudioController;
+use super::cpu::{CPUController, GetSetRegisters};
+use super::joypad::JoypadController;
+use super::memory::MemoryController;
+use super::model::Model;
+use super::save_state::{LoadedState, StateError};
+use super::serial::SerialController;
+use super::timer::TimerController;
+use super::video::VideoController;
+use super::GameBoy;
+
+#[test]
+fn test_bess_round_trip() {
+    let mut gameboy = GameBoy::builder().build().unwrap();
+    for _ in 0..20 {
+        gameboy.run_frame();
+    }
+    let mut state = gameboy.save_bess_state();
+    // hide the native state, so only the BESS blocks can be loaded
+    state[0] = b'X';
+
+    let mut loaded = GameBoy::builder().build().unwrap();
+    loaded.load_state(&state).unwrap();
+    assert_eq!(loaded.pc(), gameboy.pc());
+    assert_eq!(loaded.af(), gameboy.af());
+    assert_eq!(loaded.get_register(HL), gameboy.get_register(HL));
+    assert_eq!(loaded.get_register(SP), gameboy.get_register(SP));
+    // only the banks a DMG has are included
+    assert_eq!(loaded.mem.wram_bytes()[..0x2000], gameboy.mem.wram_bytes()[..0x2000]);
+    assert_eq!(loaded.mem.hram_bytes(), gameboy.mem.hram_bytes());
+    assert_eq!(loaded.vid.vram_bytes()[..0x2000], gameboy.vid.vram_bytes()[..0x2000]);
+    assert_eq!(loaded.ly(), gameboy.ly());
+    assert_eq!(loaded.lcdc(), gameboy.lcdc());
+    assert_eq!(loaded.bgp(), gameboy.bgp());
+    assert_eq!(loaded.mem(0xFF50), gameboy.mem(0xFF50));
+}
+
+#[test]
+fn test_bess_with_only_core_block() {
+    let mut core = vec![0u8; CORE_SIZE];
+    core[0x00] = 1;
+    core[0x02] = 1;
+    core[0x04..0x08].copy_from_slice(b"GD  ");
+    core[0x08] = 0x50;
+    core[0x09] = 0x01;
+    core[0x18 + 0x50] = 0x01;
+
+    // another emulator's state, followed by the BESS blocks and footer
+    let mut state = vec![0xEE; 64];
+    let first_block = state.len() as u32;
+    push_block(&mut state, b"CORE", &core);
+    push_block(&mut state, b"XYZW", &[1, 2, 3]);
+    push_block(&mut state, b"END ", &[]);
+    state.extend(&u32_to_u8s(first_block));
+    state.extend(b"BESS");
+
+    let mut gameboy = GameBoy::builder().build().unwrap();
+    let wram = gameboy.mem.wram_bytes().to_vec();
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.pc(), 0x0150);
+    assert!(!gameboy.mem.boot_rom_mapped());
+    // memory not included in the state is left unchanged
+    assert_eq!(gameboy.mem.wram_bytes(), &wram[..]);
+
+    let mut without_core = vec![];
+    push_block(&mut without_core, b"END ", &[]);
+    without_core.extend(&u32_to_u8s(0));
+    without_core.extend(b"BESS");
+    assert_eq!(
+        gameboy.load_state(&without_core),
+        Err(StateError::InvalidBess("missing CORE block"))
+    );
+}
+
+#[test]
+fn test_bess_info_from_different_rom() {
+    let gameboy = GameBoy::builder().build().unwrap();
+    let rom = gameboy.mem.cartridge().rom().to_vec();
+    let info = rom_info(&rom);
+    assert_eq!(info[..0x10], rom[0x0134..0x0144]);
+    assert_eq!(info[0x10..], rom[0x014E..0x0150]);
+
+    let mut state = gameboy.save_bess_state();
+    state[0] = b'X';
+    let mut loaded = GameBoy::builder().build().unwrap();
+    assert_eq!(loaded.load_state(&state).unwrap().different_rom, false);
+
+    // a patched ROM with a different checksum is only flagged, not refused
+    let start = state
+        .windows(4)
+        .rposition(|tag| tag == b"INFO")
+        .unwrap();
+    state[start + 8 + 0x10] ^= 0xFF;
+    assert_eq!(loaded.load_state(&state).unwrap().different_rom, true);
+    assert_eq!(loaded.pc(), gameboy.pc());
+}
+
+// the size of the CORE block, including the references to memory buffers
+const CORE_SIZE: usize = 0xD0;
+// where the memory-mapped registers are in the CORE block
+const CORE_IO_OFFSET: usize = 0x18;
+// the CORE block version we write, and the major version we can read
+const CORE_MAJOR_VERSION: u16 = 1;
+const CORE_MINOR_VERSION: u16 = 1;
+
+// the memory buffers referenced by the CORE block, in order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Buffer {
+    Wram,
+    Vram,
+    CartridgeRam,
+    Oam,
+    Hram,
+    BgPalettes,
+    ObjPalettes,
+}
+
+const BUFFERS: [Buffer; 7] = [
+    Buffer::Wram,
+    Buffer::Vram,
+    Buffer::CartridgeRam,
+    Buffer::Oam,
+    Buffer::Hram,
+    Buffer::BgPalettes,
+    Buffer::ObjPalettes,
+];
+
+fn push_block(state: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    state.extend(tag);
+    state.extend(&u32_to_u8s(payload.len() as u32));
+    state.extend(payload);
+}
+
+// the INFO block identifying a ROM: its title, followed by its global
+// checksum
+fn rom_info(rom: &[u8]) -> Vec<u8> {
+    let mut info = vec![0u8; 0x12];
+    for (i, byte) in rom.iter().skip(0x0134).take(0x10).enumerate() {
+        info[i] = *byte;
+    }
+    for (i, byte) in rom.iter().skip(0x014E).take(0x02).enumerate() {
+        info[0x10 + i] = *byte;
+    }
+    info
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&u16_to_u8s(value));
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u8s_to_u32([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+impl GameBoy {
+    fn buffer(&self, buffer: Buffer) -> &[u8] {
+        let cgb = self.model == Model::Cgb;
+        match buffer {
+            Buffer::Wram => &self.mem.wram_bytes()[..if cgb { 0x8000 } else { 0x2000 }],
+            Buffer::Vram => &self.vid.vram_bytes()[..if cgb { 0x4000 } else { 0x2000 }],
+            Buffer::CartridgeRam => self.mem.cartridge().ram(),
+            Buffer::Oam => self.vid.oam_bytes(),
+            Buffer::Hram => self.mem.hram_bytes(),
+            Buffer::BgPalettes if cgb => self.vid.bg_palette_ram(),
+            Buffer::ObjPalettes if cgb => self.vid.obj_palette_ram(),
+            _ => &[],
+        }
+    }
+
+    fn buffer_mut(&mut self, buffer: Buffer) -> &mut [u8] {
+        match buffer {
+            Buffer::Wram => self.mem.wram_bytes_mut(),
+            Buffer::Vram => self.vid.vram_bytes_mut(),
+            Buffer::CartridgeRam => self.mem.cartridge_mut().ram_mut(),
+            Buffer::Oam => self.vid.oam_bytes_mut(),
+            Buffer::Hram => self.mem.hram_bytes_mut(),
+            Buffer::BgPalettes => self.vid.bg_palette_ram_mut(),
+            Buffer::ObjPalettes => self.vid.obj_palette_ram_mut(),
+        }
+    }
+
+    /// Saves a state that other emulators can also load, with the blocks of
+    /// the Best Effort Save State format following our own.
+    pub fn save_bess_state(&self) -> Vec<u8> {
+        let mut state = self.save_state();
+
+        let mut core = vec![0u8; CORE_SIZE];
+        put_u16(&mut core, 0x00, CORE_MAJOR_VERSION);
+        put_u16(&mut core, 0x02, CORE_MINOR_VERSION);
+        core[0x04..0x08].copy_from_slice(match self.model {
+            Model::Dmg => b"GD  ",
+            Model::Cgb => b"CC  ",
+        });
+        put_u16(&mut core, 0x08, self.pc());
+        put_u16(&mut core, 0x0A, self.af());
+        put_u16(&mut core, 0x0C, self.get_register(BC));
+        put_u16(&mut core, 0x0E, self.get_register(DE));
+        put_u16(&mut core, 0x10, self.get_register(HL));
+        put_u16(&mut core, 0x12, self.get_register(SP));
+        core[0x14] = self.cpu.ime() as u8;
+        core[0x15] = self.ie();
+        // halted or running, since a stopped CPU is just running STOP again
+        core[0x16] = self.cpu.halted() as u8;
+
+        for i in 0..0x80 {
+            let addr = 0xFF00 + i as u16;
+            core[CORE_IO_OFFSET + i] = self.peek(addr);
+        }
+        core[CORE_IO_OFFSET + 0x50] = if self.mem.boot_rom_mapped() { 0x00 } else { 0x01 };
+        if self.model == Model::Cgb {
+            // the KEY0 register, which selects DMG compatibility mode
+            core[CORE_IO_OFFSET + 0x4C] = if self.cgb_mode { 0x80 } else { 0x04 };
+        }
+
+        let mut reference_offset = CORE_IO_OFFSET + 0x80;
+        for &buffer in BUFFERS.iter() {
+            let bytes = self.buffer(buffer);
+            core[reference_offset..reference_offset + 4]
+                .copy_from_slice(&u32_to_u8s(bytes.len() as u32));
+            core[reference_offset + 4..reference_offset + 8]
+                .copy_from_slice(&u32_to_u8s(state.len() as u32));
+            state.extend(bytes);
+            reference_offset += 8;
+        }
+
+        let first_block = state.len() as u32;
+        let name = format!("zerodmg {}", env!("CARGO_PKG_VERSION"));
+        push_block(&mut state, b"NAME", name.as_bytes());
+        push_block(&mut state, b"INFO", &rom_info(self.mem.cartridge().rom()));
+        push_block(&mut state, b"CORE", &core);
+        let mbc_writes = self.mem.cartridge().mbc_writes();
+        if !mbc_writes.is_empty() {
+            let mut mbc = vec![];
+            for (addr, value) in mbc_writes {
+                let [low, high] = u16_to_u8s(addr);
+                mbc.extend(&[low, high, value]);
+            }
+            push_block(&mut state, b"MBC ", &mbc);
+        }
+        push_block(&mut state, b"END ", &[]);
+
+        state.extend(&u32_to_u8s(first_block));
+        state.extend(b"BESS");
+        state
+    }
+
+    /// Loads the BESS blocks at the end of a state saved by another emulator,
+    /// restoring as much as we can from what's there. Nothing is changed if
+    /// the state can't be loaded.
+    pub fn load_bess_state(&mut self, state: &[u8]) -> Result<LoadedState, StateError> {
+        if state.len() < 8 || &state[state.len() - 4..] != b"BESS" {
+            return Err(StateError::InvalidSignature);
+        }
+        let footer = state.len() - 8;
+        let mut offset = read_u32(state, footer) as usize;
+
+        let mut blocks = vec![];
+        loop {
+            if offset + 8 > footer {
+                return Err(StateError::Truncated);
+            }
+            let tag = &state[offset..offset + 4];
+            let length = read_u32(state, offset + 4) as usize;
+            let start = offset + 8;
+            if start + length > footer {
+                return Err(StateError::Truncated);
+            }
+            if tag == b"END " {
+                break;
+            }
+            blocks.push((tag, &state[start..start + length]));
+            offset = start + length;
+        }
+
+        let core = match blocks.iter().find(|&&(tag, _)| tag == b"CORE") {
+            Some(&(_, core)) => core,
+            None => return Err(StateError::InvalidBess("missing CORE block")),
+        };
+        if core.len() < CORE_SIZE {
+            return Err(StateError::InvalidBess("CORE block is too short"));
+        }
+        let major_version = u8s_to_u16(core[0], core[1]);
+        if major_version != CORE_MAJOR_VERSION {
+            return Err(StateError::UnsupportedVersion(major_version));
+        }
+        let mut loaded = LoadedState::default();
+        if let Some(&(_, info)) = blocks.iter().find(|&&(tag, _)| tag == b"INFO") {
+            loaded.different_rom = info != &rom_info(self.mem.cartridge().rom())[..];
+        }
+
+        self.load_bess_core(core, state);
+        if let Some(&(_, mbc)) = blocks.iter().find(|&&(tag, _)| tag == b"MBC ") {
+            for write in mbc.chunks(3).filter(|write| write.len() == 3) {
+                let addr = u8s_to_u16(write[0], write[1]);
+                if addr <= 0x7FFF {
+                    self.mem.cartridge_mut().write_rom(addr, write[2]);
+                }
+            }
+        }
+
+        self.debug_latest_executions.clear();
+        self.debug_latest_executions_next_i = 0;
+        self.calls.clear();
+        Ok(loaded)
+    }
+
+    fn load_bess_core(&mut self, core: &[u8], state: &[u8]) {
+        let u16_at = |offset: usize| u8s_to_u16(core[offset], core[offset + 1]);
+        let io = &core[CORE_IO_OFFSET..CORE_IO_OFFSET + 0x80];
+
+        self.model = match core[0x04] {
+            b'C' => Model::Cgb,
+            // including the Super Game Boy, whose extra features we don't
+            // have
+            _ => Model::Dmg,
+        };
+        let cartridge_supports_cgb = Model::from_header(self.mem.cartridge().rom()) == Model::Cgb;
+        self.cgb_mode = self.model == Model::Cgb && cartridge_supports_cgb && io[0x4C] & 0x04 == 0;
+
+        self.set_pc(u16_at(0x08));
+        self.set_af(u16_at(0x0A));
+        self.set_register(BC, u16_at(0x0C));
+        self.set_register(DE, u16_at(0x0E));
+        self.set_register(HL, u16_at(0x10));
+        self.set_register(SP, u16_at(0x12));
+        self.cpu.set_ime(core[0x14] != 0);
+        self.set_ie(core[0x15]);
+        self.cpu.set_halted(core[0x16] == 1);
+
+        // buffers that are missing or the wrong size are left alone, or
+        // partially copied
+        let mut reference_offset = CORE_IO_OFFSET + 0x80;
+        for &buffer in BUFFERS.iter() {
+            let size = read_u32(core, reference_offset) as usize;
+            let offset = read_u32(core, reference_offset + 4) as usize;
+            reference_offset += 8;
+            if size == 0 || offset + size > state.len() {
+                continue;
+            }
+            let source = &state[offset..offset + size];
+            let destination = self.buffer_mut(buffer);
+            let length = source.len().min(destination.len());
+            destination[..length].copy_from_slice(&source[..length]);
+        }
+
+        // registers are restored without the side effects of writing them,
+        // like starting transfers or triggering sound channels
+        self.mem.set_boot_rom_mapped(io[0x50] == 0);
+        self.cpu.set_double_speed(io[0x4D] & 0x80 != 0);
+        self.set_key1(io[0x4D] & 0x01);
+        self.set_p1(io[0x00]);
+        self.set_sb(io[0x01]);
+        self.set_sc(io[0x02] & 0b0111_1111);
+        self.set_ift(io[0x0F]);
+        // DIV is restarted, with the timer disabled so that doesn't count
+        // as a TIMA increment
+        self.set_tac(0x00);
+        self.set_div(0x00);
+        self.set_tima(io[0x05]);
+        self.set_tma(io[0x06]);
+        self.set_tac(io[0x07]);
+
+        self.set_audio_register(0x16, io[0x26] & 0x80);
+        for i in 0x00..0x16 {
+            let value = match i {
+                // NRx4, without the trigger bit
+                0x04 | 0x09 | 0x0E | 0x13 => io[0x10 + i] & 0b0111_1111,
+                _ => io[0x10 + i],
+            };
+            self.set_audio_register(i, value);
+        }
+        for i in 0..0x10 {
+            self.set_wave_ram(i, io[0x30 + i]);
+        }
+
+        self.set_lcdc(io[0x40]);
+        self.set_stat(io[0x41]);
+        self.set_scy(io[0x42]);
+        self.set_scx(io[0x43]);
+        self.vid.restart_line(io[0x44]);
+        self.set_lyc(io[0x45]);
+        self.set_bgp(io[0x47]);
+        self.set_obp0(io[0x48]);
+        self.set_obp1(io[0x49]);
+        self.set_wy(io[0x4A]);
+        self.set_wx(io[0x4B]);
+        self.set_vbk(io[0x4F]);
+        self.set_bcps(io[0x68]);
+        self.set_ocps(io[0x6A]);
+        self.set_svbk(io[0x70]);
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+
+use zerodmg_codes::assembled::AssembledRom;
+use zerodmg_codes::instruction::prelude::*;
+use zerodmg_codes::symbols::Symbols;
+
+use super::audio::AudioData;
+use super::callstack::CallStackData;
+use super::cartridge::{Cartridge, CartridgeError};
+use super::cpu::{CPUController, CPUData, GetSetRegisters};
+use super::joypad::JoypadData;
+use super::memory::{MemoryController, MemoryData};
+use super::model::Model;
+use super::serial::{SerialData, SerialSink};
+use super::timer::TimerData;
+use super::video::VideoData;
+use super::watch::WatchData;
+use super::{GameBoy, Output};
+
+#[test]
+fn test_builder_runs_rom_without_boot_rom() {
+    use super::serial::SerialLog;
+
+    // prints "ok" over the serial port, then loops forever
+    let mut program = vec![];
+    for &byte in b"ok" {
+        program.push(LD(A, byte));
+        program.push(LD_8_TO_FF_IMMEDIATE(0x01));
+        program.push(LD(A, 0x81));
+        program.push(LD_8_TO_FF_IMMEDIATE(0x02));
+    }
+    program.push(JR(-2));
+    let mut rom = vec![0x00; 0x100];
+    for instruction in program {
+        rom.extend(instruction.to_bytes());
+    }
+
+    let serial = SerialLog::new();
+    let mut gameboy = GameBoy::builder()
+        .rom_bytes(rom)
+        .skip_boot_rom()
+        .ram_init(RamInit::Filled(0x00))
+        .serial(serial.clone())
+        .build()
+        .unwrap();
+    assert_eq!(gameboy.pc(), 0x0100);
+    gameboy.run_cycles(100);
+    assert_eq!(serial.text(), "ok");
+}
+
+#[test]
+fn test_seeded_ram_is_repeatable() {
+    let bytes = |init: RamInit| {
+        let mut ram = RamInitializer::new(init);
+        let mut bytes = [0u8; 16];
+        ram.fill(&mut bytes);
+        bytes
+    };
+    assert_eq!(bytes(RamInit::Seeded(7)), bytes(RamInit::Seeded(7)));
+    assert_ne!(bytes(RamInit::Seeded(7)), bytes(RamInit::Seeded(8)));
+    assert_eq!(bytes(RamInit::Filled(0xAB)), [0xAB; 16]);
+    assert_eq!("seed:7".parse(), Ok(RamInit::Seeded(7)));
+    assert_eq!("fill:0xFF".parse(), Ok(RamInit::Filled(0xFF)));
+}
+
+/// What RAM and registers hold when the Game Boy is powered on, before the
+/// boot ROM or game initializes them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamInit {
+    /// Different random values each time, as on real hardware.
+    Random,
+    /// Random values generated from a seed, the same each time.
+    Seeded(u64),
+    /// Every byte set to the same value.
+    Filled(u8),
+}
+
+impl Default for RamInit {
+    fn default() -> Self {
+        RamInit::Random
+    }
+}
+
+impl FromStr for RamInit {
+    type Err = String;
+
+    /// Parses `random`, `zero`, `seed:<n>` or `fill:<byte>`, where the byte
+    /// can be written in hex with a `0x` prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid RAM initialization: {}", s);
+        if s == "random" {
+            Ok(RamInit::Random)
+        } else if s == "zero" {
+            Ok(RamInit::Filled(0x00))
+        } else if s.starts_with("seed:") {
+            s["seed:".len()..]
+                .parse()
+                .map(RamInit::Seeded)
+                .map_err(|_| invalid())
+        } else if s.starts_with("fill:") {
+            let value = &s["fill:".len()..];
+            let parsed = if value.starts_with("0x") {
+                u8::from_str_radix(&value[2..], 16)
+            } else {
+                value.parse()
+            };
+            parsed.map(RamInit::Filled).map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+/// Produces power-on values as directed by a [RamInit].
+pub struct RamInitializer {
+    fill: Option<u8>,
+    rng: SmallRng,
+}
+
+impl RamInitializer {
+    pub fn new(init: RamInit) -> Self {
+        match init {
+            RamInit::Random => Self {
+                fill: None,
+                rng: SmallRng::from_entropy(),
+            },
+            RamInit::Seeded(seed) => Self {
+                fill: None,
+                rng: SmallRng::seed_from_u64(seed),
+            },
+            RamInit::Filled(value) => Self {
+                fill: Some(value),
+                rng: SmallRng::seed_from_u64(0),
+            },
+        }
+    }
+
+    pub fn byte(&mut self) -> u8 {
+        match self.fill {
+            Some(value) => value,
+            None => self.rng.gen(),
+        }
+    }
+
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for byte in bytes.iter_mut() {
+            *byte = self.byte();
+        }
+    }
+}
+
+// where the boot ROM comes from
+enum BootRom {
+    Embedded,
+    External(Vec<u8>),
+    Skipped,
+}
+
+/// Configures and creates a [GameBoy].
+///
+/// By default it runs the built-in demo ROM, after the embedded DMG boot ROM,
+/// on the model the cartridge asks for, with random RAM.
+pub struct GameBoyBuilder {
+    rom: Option<Vec<u8>>,
+    cartridge: Option<Cartridge>,
+    boot_rom: BootRom,
+    model: Option<Model>,
+    ram_init: RamInit,
+    output_buffer: Option<Arc<Mutex<Output>>>,
+    serial: Option<Box<SerialSink>>,
+    symbols: Symbols,
+}
+
+impl GameBoyBuilder {
+    pub fn new() -> Self {
+        Self {
+            rom: None,
+            cartridge: None,
+            boot_rom: BootRom::Embedded,
+            model: None,
+            ram_init: RamInit::default(),
+            output_buffer: None,
+            serial: None,
+            symbols: Symbols::new(),
+        }
+    }
+
+    /// Uses a cartridge with the given ROM image, and the MBC and RAM
+    /// described in its header.
+    pub fn rom_bytes(mut self, rom: Vec<u8>) -> Self {
+        self.rom = Some(rom);
+        self
+    }
+
+    pub fn rom(self, rom: &AssembledRom) -> Self {
+        self.rom_bytes(rom.to_bytes())
+    }
+
+    pub fn rom_file(self, path: &Path) -> io::Result<Self> {
+        Ok(self.rom_bytes(fs::read(path)?))
+    }
+
+    /// Uses the given cartridge, such as one for a GBS file, instead of one
+    /// made from a ROM image.
+    pub fn cartridge(mut self, cartridge: Cartridge) -> Self {
+        self.cartridge = Some(cartridge);
+        self
+    }
+
+    /// Runs the given boot ROM instead of the embedded DMG one. A CGB boot
+    /// ROM, with its second part at 0x0200-0x08FF, may also be used.
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.boot_rom = BootRom::External(boot_rom);
+        self
+    }
+
+    pub fn boot_rom_file(self, path: &Path) -> io::Result<Self> {
+        Ok(self.boot_rom(fs::read(path)?))
+    }
+
+    /// Starts running the game at 0x0100, with the registers set as the
+    /// boot ROM would leave them.
+    pub fn skip_boot_rom(mut self) -> Self {
+        self.boot_rom = BootRom::Skipped;
+        self
+    }
+
+    /// Emulates the given model, instead of the one the cartridge asks for.
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn ram_init(mut self, ram_init: RamInit) -> Self {
+        self.ram_init = ram_init;
+        self
+    }
+
+    /// Sends the display, debugging images and resampled audio to the given
+    /// output buffer, instead of a new one.
+    pub fn output(mut self, output_buffer: Arc<Mutex<Output>>) -> Self {
+        self.output_buffer = Some(output_buffer);
+        self
+    }
+
+    /// Connects the serial port to the given sink, instead of leaving it
+    /// disconnected.
+    pub fn serial<S: SerialSink + 'static>(mut self, sink: S) -> Self {
+        self.serial = Some(Box::new(sink));
+        self
+    }
+
+    /// Labels the ROM's addresses in traces and the debugger.
+    pub fn symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Labels the ROM's addresses with those from a `.sym` file.
+    pub fn symbols_file(self, path: &Path) -> io::Result<Self> {
+        let symbols = fs::read_to_string(path)?
+            .parse()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Ok(self.symbols(symbols))
+    }
+
+    pub fn build(self) -> Result<GameBoy, CartridgeError> {
+        let mut cartridge = match self.cartridge {
+            Some(cartridge) => cartridge,
+            None => Cartridge::from_bytes(
+                self.rom
+                    .unwrap_or_else(|| zerodmg_codes::roms::jeb_demo().assemble().to_bytes()),
+            )?,
+        };
+        let cartridge_model = Model::from_header(cartridge.rom());
+        let model = self.model.unwrap_or(cartridge_model);
+        // CGB features require both CGB hardware and a cartridge supporting
+        // them
+        let cgb_mode = model == Model::Cgb && cartridge_model == Model::Cgb;
+
+        let mut ram = RamInitializer::new(self.ram_init);
+        ram.fill(cartridge.ram_mut());
+        let skip_boot_rom = match self.boot_rom {
+            BootRom::Skipped => true,
+            _ => false,
+        };
+        let boot_rom = match self.boot_rom {
+            BootRom::Embedded => Some(zerodmg_codes::roms::dmg_boot().to_bytes()),
+            BootRom::External(boot_rom) => Some(boot_rom),
+            BootRom::Skipped => None,
+        };
+
+        let mut gameboy = GameBoy {
+            cpu: CPUData::new(&mut ram),
+            mem: MemoryData::new(cartridge, boot_rom, &mut ram),
+            aud: AudioData::new(&mut ram),
+            vid: VideoData::new(&mut ram),
+            serial: SerialData::new(self.serial),
+            timer: TimerData::new(),
+            joypad: JoypadData::new(),
+            watch: WatchData::new(),
+            calls: CallStackData::new(),
+            symbols: self.symbols,
+            model,
+            cgb_mode,
+            t: 0,
+            debug_latest_executions: vec![],
+            debug_latest_executions_next_i: 0,
+            output_buffer: self
+                .output_buffer
+                .unwrap_or_else(|| Arc::new(Mutex::new(Output::new()))),
+        };
+        if skip_boot_rom {
+            set_post_boot_state(&mut gameboy);
+        }
+        Ok(gameboy)
+    }
+}
+
+impl Default for GameBoyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sets the state the DMG boot ROM leaves behind when it jumps to 0x0100.
+fn set_post_boot_state(gameboy: &mut GameBoy) {
+    gameboy.cpu.set_post_boot_registers();
+    if gameboy.model == Model::Cgb {
+        // which is how games detect the CGB
+        gameboy.set_register(A, 0x11);
+    }
+    gameboy.set_register(SP, 0xFFFE);
+    gameboy.set_pc(0x0100);
+    gameboy.set_ie(0x00);
+
+    // sound on, at full volume on both sides
+    gameboy.set_mem(0xFF26, 0x80);
+    gameboy.set_mem(0xFF25, 0xF3);
+    gameboy.set_mem(0xFF24, 0x77);
+    // display and background on
+    gameboy.set_mem(0xFF47, 0xFC);
+    gameboy.set_mem(0xFF40, 0x91);
+
+    gameboy.unmap_boot_rom();
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+
+use zerodmg_codes::instruction::prelude::*;
+use zerodmg_codes::symbols::Symbols;
+
+use super::cpu::{GetSetRegisters, InstructionExecution, InstructionSource};
+use super::GameBoy;
+
+#[test]
+fn test_call_stack_and_imbalance() {
+    // calls Outer, which calls Inner, which discards its return address and
+    // so returns straight to Start
+    let program = [
+        (0x0100, vec![CALL(0x0150), JR(-2)]),
+        (0x0150, vec![CALL(0x0160), RET]),
+        (0x0160, vec![POP(BC), RET]),
+    ];
+    let mut gameboy = super::test_roms::builder_for(&program)
+        .symbols("00:0100 Start\n00:0150 Outer\n00:0160 Inner\n".parse().unwrap())
+        .build()
+        .unwrap();
+
+    gameboy.step();
+    gameboy.step();
+    assert_eq!(gameboy.call_stack().len(), 2);
+    let inner = gameboy.call_stack()[1];
+    assert_eq!(inner.caller, 0x0150);
+    assert_eq!(inner.target, 0x0160);
+    assert_eq!(inner.return_address, 0x0153);
+    assert_eq!(
+        gameboy.backtrace(),
+        vec![
+            "#0  00:0160  Inner".to_string(),
+            "#1  00:0150  Outer".to_string(),
+            "#2  00:0100  Start".to_string(),
+        ]
+    );
+
+    gameboy.step();
+    assert_eq!(gameboy.call_stack().len(), 1);
+    let imbalances = gameboy.take_stack_imbalances();
+    assert_eq!(imbalances.len(), 1);
+    assert_eq!(imbalances[0].imbalance, Imbalance::DiscardedReturnAddress(inner));
+    assert_eq!(
+        imbalances[0].describe(gameboy.symbols()),
+        "stack imbalance: POP BC at Inner discarded the return address of the call from Outer \
+         to Inner ; cycle 12"
+    );
+
+    // the return address left is Outer's, so this returns from it
+    gameboy.step();
+    assert_eq!(gameboy.pc(), 0x0103);
+    assert!(gameboy.call_stack().is_empty());
+    assert!(gameboy.take_stack_imbalances().is_empty());
+}
+
+#[test]
+fn test_returns_from_dropped_frames_are_balanced() {
+    // Recurse calls itself until BC counts down to zero, deeper than the
+    // shadow stack goes, then each call returns
+    let depth = MAX_FRAMES as u16 + 10;
+    let mut gameboy = super::test_roms::gameboy_for(&[
+        (
+            0x0100,
+            vec![
+                LD_16_IMMEDIATE(SP, 0xDFFE),
+                LD_16_IMMEDIATE(BC, depth),
+                CALL(0x0150),
+                JR(-2),
+            ],
+        ),
+        (
+            0x0150,
+            vec![
+                DEC_16(BC),
+                LD_8_INTERNAL(A, B),
+                OR(C),
+                JR_IF(if_Z, 3),
+                CALL(0x0150),
+                RET,
+            ],
+        ),
+    ]);
+
+    while gameboy.pc() != 0x0109 {
+        gameboy.step();
+    }
+    assert!(gameboy.call_stack().is_empty());
+    assert!(gameboy.take_stack_imbalances().is_empty());
+}
+
+// how many calls deep the shadow stack can get before the oldest are dropped
+const MAX_FRAMES: usize = 1024;
+// how many imbalances are kept until they're taken, after which more are
+// dropped
+const MAX_PENDING_IMBALANCES: usize = 256;
+
+/// How a function was entered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Restart,
+    Interrupt,
+}
+
+/// A call in the shadow call stack, which hasn't returned yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// The address of the CALL or RST, or of the instruction an interrupt
+    /// was dispatched before.
+    pub caller: u16,
+    /// The bank mapped at the caller.
+    pub caller_bank: usize,
+    /// The address of the function called.
+    pub target: u16,
+    pub target_bank: usize,
+    /// Where the call pushed the address to return to.
+    pub sp: u16,
+    pub return_address: u16,
+    /// When the call was made, as in [GameBoy::cycles].
+    pub cycle: u64,
+}
+
+impl CallFrame {
+    /// Describes the call, with addresses relative to the closest labels.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        let kind = match self.kind {
+            CallKind::Call => "call",
+            CallKind::Restart => "restart",
+            CallKind::Interrupt => "interrupt",
+        };
+        format!(
+            "the {} from {} to {}",
+            kind,
+            symbols.describe(self.caller_bank, self.caller),
+            symbols.describe(self.target_bank, self.target)
+        )
+    }
+}
+
+/// A way the stack was left out of step with the calls made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Imbalance {
+    /// A POP took a call's return address off the stack, so it won't
+    /// return.
+    DiscardedReturnAddress(CallFrame),
+    /// SP was moved past a call's return address by something other than a
+    /// POP or a return, so it won't return.
+    AbandonedCall(CallFrame),
+    /// A call returned somewhere other than the address it pushed, which
+    /// was overwritten on the stack.
+    ReturnAddressChanged(CallFrame),
+    /// A return popped an address that no call pushed, such as a value
+    /// left by an unmatched PUSH, or one pushed to jump to it.
+    UnmatchedReturn,
+}
+
+/// An imbalance detected in the stack, and the instruction that caused it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackImbalance {
+    pub imbalance: Imbalance,
+    pub pc: u16,
+    pub bank: usize,
+    pub instruction: Instruction,
+    /// Where the instruction left execution.
+    pub next_pc: u16,
+    /// When the instruction started, as in [GameBoy::cycles].
+    pub cycle: u64,
+}
+
+impl StackImbalance {
+    /// Describes the imbalance, with addresses relative to the closest
+    /// labels.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        let location = symbols.describe(self.bank, self.pc);
+        let what = match self.imbalance {
+            Imbalance::DiscardedReturnAddress(frame) => {
+                format!("discarded the return address of {}", frame.describe(symbols))
+            }
+            Imbalance::AbandonedCall(frame) => {
+                format!("moved SP past the return address of {}", frame.describe(symbols))
+            }
+            Imbalance::ReturnAddressChanged(frame) => format!(
+                "returned to 0x{:04X} instead of 0x{:04X} from {}",
+                self.next_pc,
+                frame.return_address,
+                frame.describe(symbols)
+            ),
+            Imbalance::UnmatchedReturn => {
+                format!("returned to 0x{:04X}, which no call pushed", self.next_pc)
+            }
+        };
+        format!(
+            "stack imbalance: {} at {} {} ; cycle {}",
+            self.instruction, location, what, self.cycle
+        )
+    }
+}
+
+impl fmt::Display for StackImbalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe(&Symbols::new()))
+    }
+}
+
+/// The calls made by the emulated code that haven't returned yet, tracked
+/// from the instructions executed rather than read from the stack, so that
+/// a backtrace can be shown at any point.
+pub struct CallStackData {
+    frames: Vec<CallFrame>,
+    // how many of the oldest frames were dropped to keep under MAX_FRAMES,
+    // whose returns shouldn't be flagged as unmatched
+    dropped_frames: usize,
+    imbalances: Vec<StackImbalance>,
+    // PC and SP before the instruction being executed
+    pc: u16,
+    sp: u16,
+}
+
+impl CallStackData {
+    pub fn new() -> Self {
+        Self {
+            frames: vec![],
+            dropped_frames: 0,
+            imbalances: vec![],
+            pc: 0x0000,
+            sp: 0x0000,
+        }
+    }
+
+    /// Forgets the calls made, such as when loading a state they may not
+    /// have been made in.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.dropped_frames = 0;
+    }
+}
+
+pub trait CallStackController {
+    fn begin_call_tracking(&mut self);
+    fn track_calls(&mut self, opex: &InstructionExecution);
+}
+
+impl GameBoy {
+    fn flag_imbalance(&mut self, imbalance: Imbalance, opex: &InstructionExecution) {
+        if self.calls.imbalances.len() < MAX_PENDING_IMBALANCES {
+            let next_pc = self.pc();
+            self.calls.imbalances.push(StackImbalance {
+                imbalance,
+                pc: self.calls.pc,
+                bank: opex.bank,
+                instruction: opex.instruction,
+                next_pc,
+                cycle: opex.t_0,
+            });
+        }
+    }
+}
+
+impl CallStackController for GameBoy {
+    /// Notes PC and SP before an instruction, to compare them to after.
+    fn begin_call_tracking(&mut self) {
+        self.calls.pc = self.pc();
+        self.calls.sp = self.get_register(SP);
+    }
+
+    /// Updates the shadow call stack after an instruction, flagging any
+    /// imbalance.
+    fn track_calls(&mut self, opex: &InstructionExecution) {
+        let sp_0 = self.calls.sp;
+        let sp_1 = self.get_register(SP);
+        let pc_1 = self.pc();
+
+        match opex.instruction {
+            // only calls that were taken pushed a return address
+            CALL(_) | CALL_IF(_, _) | RST(_) if sp_1 == sp_0.wrapping_sub(2) => {
+                let (kind, return_address) = match (opex.source, opex.instruction) {
+                    (InstructionSource::Interrupt(_), _) => (CallKind::Interrupt, self.calls.pc),
+                    (_, RST(_)) => (CallKind::Restart, self.calls.pc.wrapping_add(1)),
+                    (_, instruction) => (
+                        CallKind::Call,
+                        self.calls.pc.wrapping_add(instruction.byte_len()),
+                    ),
+                };
+                let target_bank = self.bank_at(pc_1);
+                self.calls.frames.push(CallFrame {
+                    kind,
+                    caller: self.calls.pc,
+                    caller_bank: opex.bank,
+                    target: pc_1,
+                    target_bank,
+                    sp: sp_1,
+                    return_address,
+                    cycle: opex.t_0,
+                });
+                if self.calls.frames.len() > MAX_FRAMES {
+                    self.calls.frames.remove(0);
+                    self.calls.dropped_frames += 1;
+                }
+            }
+            RET | RET_IF(_) | RETI if sp_1 == sp_0.wrapping_add(2) => {
+                match self.calls.frames.last().cloned() {
+                    Some(frame) if frame.sp == sp_0 => {
+                        self.calls.frames.pop();
+                        if pc_1 != frame.return_address {
+                            self.flag_imbalance(Imbalance::ReturnAddressChanged(frame), opex);
+                        }
+                    }
+                    None if self.calls.dropped_frames > 0 => self.calls.dropped_frames -= 1,
+                    _ => self.flag_imbalance(Imbalance::UnmatchedReturn, opex),
+                }
+            }
+            _ => {}
+        }
+
+        // any calls whose return addresses are no longer on the stack won't
+        // return
+        while let Some(frame) = self.calls.frames.last().cloned() {
+            if frame.sp >= sp_1 {
+                break;
+            }
+            self.calls.frames.pop();
+            let imbalance = match opex.instruction {
+                POP(_) => Imbalance::DiscardedReturnAddress(frame),
+                _ => Imbalance::AbandonedCall(frame),
+            };
+            self.flag_imbalance(imbalance, opex);
+        }
+    }
+}
+
+impl GameBoy {
+    /// The calls made that haven't returned yet, outermost first.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.calls.frames
+    }
+
+    /// Removes and returns the stack imbalances detected, oldest first.
+    pub fn take_stack_imbalances(&mut self) -> Vec<StackImbalance> {
+        self.calls.imbalances.split_off(0)
+    }
+
+    /// Formats the current location, then each call that led to it, like
+    /// `#1  00:0150  Main+3`, with addresses relative to the closest labels.
+    pub fn backtrace(&self) -> Vec<String> {
+        let pc = self.pc();
+        let mut lines = vec![format!(
+            "#0  {:02X}:{:04X}  {}",
+            self.bank_at(pc),
+            pc,
+            self.symbols.describe(self.bank_at(pc), pc)
+        )];
+        for (i, frame) in self.calls.frames.iter().rev().enumerate() {
+            let mut line = format!(
+                "#{:<2} {:02X}:{:04X}  {}",
+                i + 1,
+                frame.caller_bank,
+                frame.caller,
+                self.symbols.describe(frame.caller_bank, frame.caller)
+            );
+            if frame.kind == CallKind::Interrupt {
+                line.push_str(" (interrupted)");
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
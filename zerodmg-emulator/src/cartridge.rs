@@ -0,0 +1,412 @@
+use std::fmt;
+
+use super::save_state::{StateReader, StateWriter};
+
+#[test]
+fn test_mbc1_banking() {
+    let mut rom = vec![0x00; 0x4000 * 8];
+    rom[0x0147] = 0x03;
+    rom[0x0149] = 0x03;
+    for bank in 0..8 {
+        rom[bank * 0x4000 + 0x1234] = bank as u8;
+    }
+    let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+    assert_eq!(cartridge.mbc(), Mbc::Mbc1);
+    assert_eq!(cartridge.ram().len(), 0x8000);
+
+    assert_eq!(cartridge.read_rom(0x5234), 1);
+    cartridge.write_rom(0x2000, 0x05);
+    assert_eq!(cartridge.read_rom(0x5234), 5);
+    // bank 0 selects bank 1 instead
+    cartridge.write_rom(0x3FFF, 0x00);
+    assert_eq!(cartridge.read_rom(0x5234), 1);
+    // banks past the end of the ROM wrap around
+    cartridge.write_rom(0x2000, 0x0E);
+    assert_eq!(cartridge.read_rom(0x5234), 6);
+
+    // RAM is disabled until enabled
+    cartridge.write_ram(0x0010, 0x42);
+    assert_eq!(cartridge.read_ram(0x0010), 0xFF);
+    cartridge.write_rom(0x0000, 0x0A);
+    cartridge.write_ram(0x0010, 0x42);
+    assert_eq!(cartridge.read_ram(0x0010), 0x42);
+
+    // RAM banks are only switched in the second banking mode
+    cartridge.write_rom(0x4000, 0x02);
+    assert_eq!(cartridge.read_ram(0x0010), 0x42);
+    cartridge.write_rom(0x6000, 0x01);
+    assert_eq!(cartridge.read_ram(0x0010), 0x00);
+}
+
+#[test]
+fn test_mbc3_banking() {
+    let mut rom = vec![0x00; 0x4000 * 0x80];
+    rom[0x0147] = 0x13;
+    rom[0x0149] = 0x03;
+    for bank in 0..0x80 {
+        rom[bank * 0x4000 + 0x1234] = bank as u8;
+    }
+    let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+    assert_eq!(cartridge.mbc(), Mbc::Mbc3);
+
+    // all seven bits select the bank, and 0x0000-0x3FFF is always bank 0
+    cartridge.write_rom(0x2000, 0x7F);
+    assert_eq!(cartridge.read_rom(0x5234), 0x7F);
+    assert_eq!(cartridge.read_rom(0x1234), 0);
+    cartridge.write_rom(0x2000, 0x00);
+    assert_eq!(cartridge.read_rom(0x5234), 1);
+
+    // RAM banks are switched without a banking mode
+    cartridge.write_rom(0x0000, 0x0A);
+    cartridge.write_ram(0x0010, 0x42);
+    cartridge.write_rom(0x4000, 0x03);
+    assert_eq!(cartridge.read_ram(0x0010), 0x00);
+    cartridge.write_ram(0x0010, 0x43);
+    cartridge.write_rom(0x4000, 0x00);
+    assert_eq!(cartridge.read_ram(0x0010), 0x42);
+
+    // the clock isn't emulated, so its registers read as open bus
+    cartridge.write_rom(0x4000, 0x08);
+    cartridge.write_ram(0x0000, 0x12);
+    assert_eq!(cartridge.read_ram(0x0000), 0xFF);
+}
+
+#[test]
+fn test_mbc5_banking() {
+    let mut rom = vec![0x00; 0x4000 * 0x200];
+    rom[0x0147] = 0x1B;
+    rom[0x0149] = 0x04;
+    for bank in 0..0x200 {
+        rom[bank * 0x4000 + 0x1234] = bank as u8;
+        rom[bank * 0x4000 + 0x1235] = (bank >> 8) as u8;
+    }
+    let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+    assert_eq!(cartridge.mbc(), Mbc::Mbc5);
+    assert_eq!(cartridge.ram().len(), 0x20000);
+
+    // the ninth bit of the bank number is written separately
+    cartridge.write_rom(0x2000, 0x23);
+    cartridge.write_rom(0x3000, 0x01);
+    assert_eq!(cartridge.read_rom(0x5234), 0x23);
+    assert_eq!(cartridge.read_rom(0x5235), 0x01);
+    // bank 0 can be mapped at 0x4000-0x7FFF
+    cartridge.write_rom(0x2000, 0x00);
+    cartridge.write_rom(0x3000, 0x00);
+    assert_eq!(cartridge.read_rom(0x5234), 0);
+
+    cartridge.write_rom(0x0000, 0x0A);
+    cartridge.write_rom(0x4000, 0x0F);
+    cartridge.write_ram(0x0010, 0x42);
+    cartridge.write_rom(0x4000, 0x00);
+    assert_eq!(cartridge.read_ram(0x0010), 0x00);
+    cartridge.write_rom(0x4000, 0x0F);
+    assert_eq!(cartridge.read_ram(0x0010), 0x42);
+}
+
+#[test]
+fn test_unsupported_cartridge_type() {
+    let mut rom = vec![0x00; 0x8000];
+    rom[0x0147] = 0xFC;
+    assert_eq!(
+        Cartridge::from_bytes(rom).err(),
+        Some(CartridgeError::UnsupportedType(0xFC))
+    );
+    assert_eq!(
+        CartridgeError::UnsupportedType(0xFC).to_string(),
+        "unsupported cartridge type 0xFC (only no MBC, MBC1, MBC3 and MBC5 are supported)"
+    );
+}
+
+// the header fields describing the cartridge hardware
+const TYPE_ADDRESS: usize = 0x0147;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+const TITLE_ADDRESS: usize = 0x0134;
+const TITLE_LENGTH: usize = 16;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// The memory bank controller in a cartridge, which maps banks of its ROM
+/// and RAM into the address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mbc {
+    /// No controller: 32KiB of ROM, and maybe 8KiB of RAM.
+    None,
+    /// MBC1, with up to 2MiB of ROM and 32KiB of RAM.
+    Mbc1,
+    /// MBC3, with up to 2MiB of ROM and 32KiB of RAM. Its real-time clock
+    /// isn't emulated.
+    Mbc3,
+    /// MBC5, with up to 8MiB of ROM and 128KiB of RAM, but without rumble.
+    Mbc5,
+    /// The banking expected by GBS music files: any ROM bank can be
+    /// selected by writing to 0x2000-0x3FFF, and RAM is always enabled.
+    Gbs,
+}
+
+/// Why a cartridge can't be emulated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The cartridge type in the header (0x0147) isn't one we support.
+    UnsupportedType(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::UnsupportedType(cartridge_type) => write!(
+                f,
+                "unsupported cartridge type 0x{:02X} (only no MBC, MBC1, MBC3 and MBC5 are supported)",
+                cartridge_type
+            ),
+        }
+    }
+}
+
+/// The ROM, RAM and memory bank controller of a game cartridge.
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    ram_enabled: bool,
+    // the low bits of the ROM bank number
+    rom_bank: u8,
+    // the ninth bit of the ROM bank number (MBC5)
+    rom_bank_bit_8: bool,
+    // the register selecting the RAM bank or clock register (MBC3), or the
+    // high bits of the ROM bank number (MBC1)
+    bank_high: u8,
+    // whether the high bank bits also apply to 0x0000-0x3FFF and RAM (MBC1)
+    ram_banking_mode: bool,
+}
+
+impl Cartridge {
+    /// Creates a cartridge with the hardware described by the ROM's header.
+    /// ROMs too short to have a header are treated as having no MBC or RAM.
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let cartridge_type = rom.get(TYPE_ADDRESS).cloned().unwrap_or(0x00);
+        let (mbc, has_ram) = match cartridge_type {
+            0x00 => (Mbc::None, false),
+            0x01 => (Mbc::Mbc1, false),
+            0x02 | 0x03 => (Mbc::Mbc1, true),
+            0x08 | 0x09 => (Mbc::None, true),
+            0x0F | 0x11 => (Mbc::Mbc3, false),
+            0x10 | 0x12 | 0x13 => (Mbc::Mbc3, true),
+            0x19 => (Mbc::Mbc5, false),
+            0x1A | 0x1B => (Mbc::Mbc5, true),
+            _ => return Err(CartridgeError::UnsupportedType(cartridge_type)),
+        };
+        let ram_size = if has_ram {
+            match rom.get(RAM_SIZE_ADDRESS) {
+                Some(0x01) => 0x800,
+                Some(0x02) => 0x2000,
+                Some(0x03) => 0x8000,
+                Some(0x04) => 0x20000,
+                Some(0x05) => 0x10000,
+                _ => 0,
+            }
+        } else {
+            0
+        };
+        Ok(Self::with_mbc(rom, mbc, ram_size))
+    }
+
+    /// Creates a cartridge for the ROM image of a GBS file, with 8KiB of RAM.
+    pub fn for_gbs(rom: Vec<u8>) -> Self {
+        Self::with_mbc(rom, Mbc::Gbs, RAM_BANK_SIZE)
+    }
+
+    fn with_mbc(rom: Vec<u8>, mbc: Mbc, ram_size: usize) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            mbc,
+            // only real MBCs require RAM to be enabled before it's used
+            ram_enabled: mbc == Mbc::None || mbc == Mbc::Gbs,
+            rom_bank: 1,
+            rom_bank_bit_8: false,
+            bank_high: 0,
+            ram_banking_mode: false,
+        }
+    }
+
+    pub fn mbc(&self) -> Mbc {
+        self.mbc
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// The contents of the cartridge's RAM, which is empty if it has none.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// Saves the RAM and the state of the MBC.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.vec(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank);
+        state.u8(self.bank_high);
+        state.bool(self.ram_banking_mode);
+        state.bool(self.rom_bank_bit_8);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.vec(&mut self.ram);
+        state.bool(&mut self.ram_enabled);
+        state.u8(&mut self.rom_bank);
+        state.u8(&mut self.bank_high);
+        state.bool(&mut self.ram_banking_mode);
+        state.bool(&mut self.rom_bank_bit_8);
+    }
+
+    /// The writes to the MBC's registers that would put it in its current
+    /// state.
+    pub fn mbc_writes(&self) -> Vec<(u16, u8)> {
+        match self.mbc {
+            Mbc::None => vec![],
+            Mbc::Mbc1 => vec![
+                (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+                (0x2000, self.rom_bank),
+                (0x4000, self.bank_high),
+                (0x6000, self.ram_banking_mode as u8),
+            ],
+            Mbc::Mbc3 => vec![
+                (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+                (0x2000, self.rom_bank),
+                (0x4000, self.bank_high),
+            ],
+            Mbc::Mbc5 => vec![
+                (0x0000, if self.ram_enabled { 0x0A } else { 0x00 }),
+                (0x2000, self.rom_bank),
+                (0x3000, self.rom_bank_bit_8 as u8),
+                (0x4000, self.bank_high),
+            ],
+            Mbc::Gbs => vec![(0x2000, self.rom_bank)],
+        }
+    }
+
+    /// The game's title from the header, if it has one.
+    pub fn title(&self) -> String {
+        self.rom
+            .iter()
+            .skip(TITLE_ADDRESS)
+            .take(TITLE_LENGTH)
+            .take_while(|&&byte| byte != 0x00)
+            .map(|&byte| if byte.is_ascii_graphic() { byte as char } else { ' ' })
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        ((self.rom.len() + ROM_BANK_SIZE - 1) / ROM_BANK_SIZE).max(1)
+    }
+
+    /// The ROM bank mapped at an address in 0x0000-0x7FFF.
+    pub fn rom_bank_at(&self, addr: u16) -> usize {
+        let bank = if addr <= 0x3FFF {
+            match self.mbc {
+                Mbc::Mbc1 if self.ram_banking_mode => usize::from(self.bank_high) << 5,
+                _ => 0,
+            }
+        } else {
+            match self.mbc {
+                Mbc::None => 1,
+                Mbc::Mbc1 => usize::from(self.bank_high) << 5 | usize::from(self.rom_bank),
+                Mbc::Mbc5 => usize::from(self.rom_bank_bit_8) << 8 | usize::from(self.rom_bank),
+                Mbc::Mbc3 | Mbc::Gbs => usize::from(self.rom_bank),
+            }
+        };
+        bank % self.rom_bank_count()
+    }
+
+    /// Reads from the cartridge's ROM, mapped at 0x0000-0x7FFF.
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        let bank = self.rom_bank_at(addr);
+        let i = bank * ROM_BANK_SIZE + usize::from(addr) % ROM_BANK_SIZE;
+        self.rom.get(i).cloned().unwrap_or(0xFF)
+    }
+
+    /// Handles a write to 0x0000-0x7FFF, which controls the MBC.
+    pub fn write_rom(&mut self, addr: u16, value: u8) {
+        match self.mbc {
+            Mbc::None => {}
+            Mbc::Mbc1 => {
+                if addr <= 0x1FFF {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else if addr <= 0x3FFF {
+                    // bank 0 selects bank 1 instead
+                    self.rom_bank = (value & 0b1_1111).max(1);
+                } else if addr <= 0x5FFF {
+                    self.bank_high = value & 0b11;
+                } else {
+                    self.ram_banking_mode = value & 0b1 != 0;
+                }
+            }
+            Mbc::Mbc3 => {
+                if addr <= 0x1FFF {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else if addr <= 0x3FFF {
+                    self.rom_bank = (value & 0b111_1111).max(1);
+                } else if addr <= 0x5FFF {
+                    self.bank_high = value & 0b1111;
+                }
+                // writes to 0x6000-0x7FFF latch the clock, which we don't have
+            }
+            Mbc::Mbc5 => {
+                if addr <= 0x1FFF {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else if addr <= 0x2FFF {
+                    // unlike the other MBCs, bank 0 can be selected
+                    self.rom_bank = value;
+                } else if addr <= 0x3FFF {
+                    self.rom_bank_bit_8 = value & 0b1 != 0;
+                } else if addr <= 0x5FFF {
+                    self.bank_high = value & 0b1111;
+                }
+            }
+            Mbc::Gbs => {
+                if 0x2000 <= addr && addr <= 0x3FFF {
+                    self.rom_bank = value.max(1);
+                }
+            }
+        }
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = match self.mbc {
+            Mbc::Mbc1 if self.ram_banking_mode => usize::from(self.bank_high),
+            // 0x08-0x0C select the clock's registers instead of RAM
+            Mbc::Mbc3 if self.bank_high > 0b11 => return None,
+            Mbc::Mbc3 | Mbc::Mbc5 => usize::from(self.bank_high),
+            _ => 0,
+        };
+        Some((bank * RAM_BANK_SIZE + usize::from(addr)) % self.ram.len())
+    }
+
+    /// Reads from the cartridge's RAM, at the given offset from 0xA000.
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram_index(addr) {
+            Some(i) => self.ram[i],
+            None => 0xFF,
+        }
+    }
+
+    /// Writes to the cartridge's RAM, at the given offset from 0xA000.
+    pub fn write_ram(&mut self, addr: u16, value: u8) {
+        if let Some(i) = self.ram_index(addr) {
+            self.ram[i] = value;
+        }
+    }
+}
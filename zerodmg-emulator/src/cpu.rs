@@ -4,9 +4,13 @@ use zerodmg_codes::instruction::{
     FlagCondition, Instruction, U16Register, U8Register, U8SecondaryRegister,
 };
 
+use super::builder::RamInitializer;
+use super::joypad::JoypadController;
 use super::memory::MemoryController;
+use super::save_state::{StateReader, StateWriter};
+use super::video::{OamBugAccess, VideoController};
+use super::watch::WatchController;
 use super::GameBoy;
-use rand;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CPUData {
@@ -39,12 +43,20 @@ pub struct CPUData {
     di_pending: bool,
     /// Enable interrupt after next instruction
     ei_pending: bool,
+    /// Whether the CPU is running at double speed (CGB)
+    double_speed: bool,
+    /// Whether the next STOP will switch speeds (CGB KEY1 bit 0)
+    speed_switch_armed: bool,
+    /// Whether HALT has stopped the CPU until an interrupt is pending
+    halted: bool,
 }
 
 pub struct InstructionExecution {
     pub t_0: u64,
     pub t_1: u64,
     pub instruction: Instruction,
+    /// The bank mapped where the instruction was read from.
+    pub bank: usize,
     /// Formats some additional debug information about the execution.
     pub tracer: Option<Box<Fn() -> String>>,
     pub source: InstructionSource,
@@ -57,6 +69,8 @@ pub trait CPUController:
 {
     fn tick(&mut self) -> InstructionExecution;
     fn relative_jump(&mut self, n: i8);
+    fn pc(&self) -> u16;
+    fn set_pc(&mut self, value: u16);
     fn stack_push(&mut self, value: u16);
     fn stack_pop(&mut self) -> u16;
     fn af(&self) -> u16;
@@ -78,29 +92,111 @@ pub trait CPUController:
     fn set_ie(&mut self, value: u8);
     fn ift(&self) -> u8;
     fn set_ift(&mut self, value: u8);
+    fn key1(&self) -> u8;
+    fn set_key1(&mut self, value: u8);
+    fn double_speed(&self) -> bool;
 }
 
 impl CPUData {
-    pub fn new() -> Self {
+    pub fn new(ram: &mut RamInitializer) -> Self {
         Self {
             t: 0x0000000000000000,
-            a: rand::random(),
-            f: rand::random(),
-            b: rand::random(),
-            c: rand::random(),
-            d: rand::random(),
-            e: rand::random(),
-            h: rand::random(),
-            l: rand::random(),
+            a: ram.byte(),
+            f: ram.byte(),
+            b: ram.byte(),
+            c: ram.byte(),
+            d: ram.byte(),
+            e: ram.byte(),
+            h: ram.byte(),
+            l: ram.byte(),
             sp: 0x0000,
             pc: 0x0000,
-            ime: true,
+            ime: false,
             ie: 0xFF,
             ift: 0x00,
             di_pending: false,
             ei_pending: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            halted: false,
         }
     }
+    /// Sets the registers as the DMG boot ROM leaves them, other than SP and
+    /// PC.
+    pub fn set_post_boot_registers(&mut self) {
+        self.a = 0x01;
+        self.f = 0xB0;
+        self.b = 0x00;
+        self.c = 0x13;
+        self.d = 0x00;
+        self.e = 0xD8;
+        self.h = 0x01;
+        self.l = 0x4D;
+        self.ime = false;
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.t);
+        for &register in &[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            state.u8(register);
+        }
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.bool(self.ime);
+        state.u8(self.ie);
+        state.u8(self.ift);
+        state.bool(self.di_pending);
+        state.bool(self.ei_pending);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.halted);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u64(&mut self.t);
+        for register in vec![
+            &mut self.a,
+            &mut self.f,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            state.u8(register);
+        }
+        state.u16(&mut self.sp);
+        state.u16(&mut self.pc);
+        state.bool(&mut self.ime);
+        state.u8(&mut self.ie);
+        state.u8(&mut self.ift);
+        state.bool(&mut self.di_pending);
+        state.bool(&mut self.ei_pending);
+        state.bool(&mut self.double_speed);
+        state.bool(&mut self.speed_switch_armed);
+        state.bool(&mut self.halted);
+    }
 }
 
 /// Iterates over bytes at PC, while incrementing it, in a borrowed [GameBoy].
@@ -113,7 +209,7 @@ impl Iterator for PCMemoryIterator<'gb> {
 
     fn next(&mut self) -> Option<u8> {
         let pc_0 = self.gb.cpu.pc;
-        let byte = self.gb.mem(pc_0);
+        let byte = self.gb.unwatched(|gb| gb.mem(pc_0));
         let pc_1 = pc_0.wrapping_add(0x001);
         self.gb.cpu.pc = pc_1;
         Some(byte)
@@ -175,11 +271,43 @@ impl std::fmt::Display for InstructionSource {
     }
 }
 
+/// Adds two bytes and a carry bit, returning the sum and whether it carried
+/// out of the low four bits and out of the byte.
+fn add_8(a: u8, value: u8, carry: bool) -> (u8, bool, bool) {
+    let carry = u8::from(carry);
+    let sum = u16::from(a) + u16::from(value) + u16::from(carry);
+    let half_carry = (a & 0x0F) + (value & 0x0F) + carry > 0x0F;
+    (sum as u8, half_carry, sum > 0xFF)
+}
+
+/// Subtracts a byte and a carry (borrow) bit from another, returning the
+/// difference and whether it borrowed from the high four bits and from
+/// outside the byte.
+fn sub_8(a: u8, value: u8, carry: bool) -> (u8, bool, bool) {
+    let carry = u8::from(carry);
+    let difference = a.wrapping_sub(value).wrapping_sub(carry);
+    let half_carry = (a & 0x0F) < (value & 0x0F) + carry;
+    let borrow = u16::from(a) < u16::from(value) + u16::from(carry);
+    (difference, half_carry, borrow)
+}
+
+/// Adds a signed offset to SP, returning the result and the half-carry and
+/// carry flags, which come from adding the low byte as unsigned.
+fn add_sp_offset(sp: u16, offset: i8) -> (u16, bool, bool) {
+    let unsigned_offset = u16::from(offset as u8);
+    let half_carry = (sp & 0x0F) + (unsigned_offset & 0x0F) > 0x0F;
+    let carry = (sp & 0xFF) + unsigned_offset > 0xFF;
+    (sp.wrapping_add(offset as i16 as u16), half_carry, carry)
+}
+
 impl CPUController for GameBoy {
     fn tick(&mut self) -> InstructionExecution {
         use zerodmg_codes::instruction::prelude::*;
 
         let has_interrupt = self.pop_interrupt();
+        // EI takes effect after the instruction following it
+        let ei_pending = self.cpu.ei_pending;
+        self.cpu.ei_pending = false;
 
         let source;
         let instruction;
@@ -197,19 +325,24 @@ impl CPUController for GameBoy {
         if let Some(interrupt) = has_interrupt {
             // disable interrupts
             self.cpu.ime = false;
+            self.cpu.halted = false;
             source = InstructionSource::Interrupt(interrupt);
             instruction = Instruction::CALL(interrupt.handler_address());
+        } else if self.cpu.halted {
+            // the CPU stays on the HALT it has already stepped past
+            source = InstructionSource::ProgramCounter(self.cpu.pc.wrapping_sub(1));
+            instruction = HALT;
         } else {
             source = InstructionSource::ProgramCounter(self.cpu.pc);
             instruction = self.instruction_from_pc();
         };
 
-        // println!("   t = {:<10}  f_z = {}", self.cpu.t, self.z_flag());
-        // println!("  HL = {:04X}  A = {:02X}  B = {:02X}  C = {:02X}  D = {:02X}, E =
-        // {:02X}", self.get_register(HL), self.get_register(A), self.get_register(B),
-        // self.get_register(C), self.get_register(D), self.get_register(E));
-        // println!("{:6}:   {:<16}  ; {:<16}", source, format!("{}", instruction),
-        // format!("{:?}", instruction));
+        let pc = match source {
+            InstructionSource::ProgramCounter(pc) => pc,
+            InstructionSource::Interrupt(_) => self.cpu.pc,
+        };
+        self.begin_instruction(pc, instruction);
+        let bank = self.bank_at(pc);
 
         let t_0 = self.cpu.t;
         let cycles;
@@ -226,10 +359,42 @@ impl CPUController for GameBoy {
                 cycles = 1;
                 tracer = None;
             }
-            HALT => unimplemented!("{}", instruction),
-            STOP(_unused) => unimplemented!("{}", instruction),
-            EI => unimplemented!("{}", instruction),
-            DI => unimplemented!("{}", instruction),
+            HALT => {
+                // without interrupts enabled, a pending interrupt just ends
+                // the halt without being dispatched
+                self.cpu.halted = self.ie() & self.ift() & 0b1_1111 == 0;
+                cycles = 1;
+                tracer = None;
+            }
+            STOP(_unused) => {
+                if self.cgb_mode && self.cpu.speed_switch_armed {
+                    self.cpu.speed_switch_armed = false;
+                    self.cpu.double_speed = !self.cpu.double_speed;
+                    let double_speed = self.cpu.double_speed;
+                    // the switch stalls the CPU for 2050 M-cycles
+                    cycles = 2050;
+                    trace!("double speed₁ = {}", double_speed);
+                } else if self.p1() & 0x0F == 0x0F {
+                    // the CPU stops until a selected button is pressed, which
+                    // we emulate by running STOP again until then
+                    self.cpu.pc = pc;
+                    cycles = 1;
+                    tracer = None;
+                } else {
+                    cycles = 1;
+                    tracer = None;
+                }
+            }
+            EI => {
+                self.cpu.ei_pending = true;
+                cycles = 1;
+                tracer = None;
+            }
+            DI => {
+                self.cpu.ime = false;
+                cycles = 1;
+                tracer = None;
+            }
             HCF(_variant) => unimplemented!("{}", instruction),
             // 8-Bit Arithmatic and Logic
             INC(target) => {
@@ -238,7 +403,7 @@ impl CPUController for GameBoy {
                 let extra_write_cycles = self.set_register(target, new_value);
                 self.set_z_flag(new_value == 0);
                 self.set_n_flag(false);
-                self.set_h_flag(u8_get_bit(new_value, 4));
+                self.set_h_flag(old_value & 0x0F == 0x0F);
                 cycles = 1 + extra_read_cycles + extra_write_cycles;
                 trace!(
                     "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
@@ -254,7 +419,7 @@ impl CPUController for GameBoy {
                 let extra_write_cycles = self.set_register(target, new_value);
                 self.set_z_flag(new_value == 0);
                 self.set_n_flag(true);
-                self.set_h_flag(u8_get_bit(new_value, 4));
+                self.set_h_flag(old_value & 0x0F == 0x00);
                 cycles = 1 + extra_read_cycles + extra_write_cycles;
                 trace!(
                     "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
@@ -267,9 +432,24 @@ impl CPUController for GameBoy {
             ADD(source) => {
                 let a_0 = self.cpu.a;
                 let (value, extra_read_cycles) = self.read_register(source);
-                let a_1 = a_0.wrapping_add(value);
+                let (a_1, half_carry, carry) = add_8(a_0, value, false);
                 self.cpu.a = a_1;
-                self.set_znhc_flags(a_1 == 0, false, u8_get_bit(a_1, 4), a_1 < a_0);
+                self.set_znhc_flags(a_1 == 0, false, half_carry, carry);
+                cycles = 1 + extra_read_cycles;
+                trace!(
+                    "A₀ = 0x{:02X}, {} = 0x{:02X}, A₁ = 0x{:02X}",
+                    a_0,
+                    source,
+                    value,
+                    a_1
+                );
+            }
+            ADC(source) => {
+                let a_0 = self.cpu.a;
+                let (value, extra_read_cycles) = self.read_register(source);
+                let (a_1, half_carry, carry) = add_8(a_0, value, self.c_flag());
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, half_carry, carry);
                 cycles = 1 + extra_read_cycles;
                 trace!(
                     "A₀ = 0x{:02X}, {} = 0x{:02X}, A₁ = 0x{:02X}",
@@ -279,13 +459,27 @@ impl CPUController for GameBoy {
                     a_1
                 );
             }
-            ADC(_source) => unimplemented!("{}", instruction),
             SUB(source) => {
                 let (value, extra_read_cycles) = self.read_register(source);
                 let a_0 = self.cpu.a;
-                let a_1 = a_0.wrapping_sub(value);
+                let (a_1, half_carry, carry) = sub_8(a_0, value, false);
                 self.cpu.a = a_1;
-                self.set_znhc_flags(a_1 == 0, false, u8_get_bit(a_1, 4), a_1 > a_0);
+                self.set_znhc_flags(a_1 == 0, true, half_carry, carry);
+                cycles = 1 + extra_read_cycles;
+                trace!(
+                    "A₀ = 0x{:02X}, {} = 0x{:02X}, A₁ = 0x{:02X}",
+                    a_0,
+                    source,
+                    value,
+                    a_1
+                );
+            }
+            SBC(source) => {
+                let (value, extra_read_cycles) = self.read_register(source);
+                let a_0 = self.cpu.a;
+                let (a_1, half_carry, carry) = sub_8(a_0, value, self.c_flag());
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, true, half_carry, carry);
                 cycles = 1 + extra_read_cycles;
                 trace!(
                     "A₀ = 0x{:02X}, {} = 0x{:02X}, A₁ = 0x{:02X}",
@@ -295,13 +489,12 @@ impl CPUController for GameBoy {
                     a_1
                 );
             }
-            SBC(_source) => unimplemented!("{}", instruction),
             AND(source) => {
                 let (value, extra_read_cycles) = self.read_register(source);
                 let a_0 = self.cpu.a;
                 let a_1 = a_0 & value;
                 self.cpu.a = a_1;
-                self.set_znhc_flags(a_1 == 0, true, true, false);
+                self.set_znhc_flags(a_1 == 0, false, true, false);
                 cycles = 1 + extra_read_cycles;
                 trace!(
                     "A₀ = 0x{:02X}, {} = 0x{:02X}, A₁ = 0x{:02X}",
@@ -344,34 +537,135 @@ impl CPUController for GameBoy {
             CP(source) => {
                 let (value, extra_read_cycles) = self.read_register(source);
                 let a = self.cpu.a;
-                let delta = a.wrapping_sub(value);
-                self.set_znhc_flags(delta == 0, false, u8_get_bit(delta, 4), delta > a);
+                let (delta, half_carry, carry) = sub_8(a, value, false);
+                self.set_znhc_flags(delta == 0, true, half_carry, carry);
                 cycles = 1 + extra_read_cycles;
                 trace!("A = 0x{:02X}, {} = 0x{:02X}", a, source, value);
             }
-            ADD_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            ADC_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            SUB_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            SBC_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            AND_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            XOR_IMMEDIATE(_value) => unimplemented!("{}", instruction),
-            OR_IMMEDIATE(_value) => unimplemented!("{}", instruction),
+            ADD_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let (a_1, half_carry, carry) = add_8(a_0, value, false);
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, half_carry, carry);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            ADC_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let (a_1, half_carry, carry) = add_8(a_0, value, self.c_flag());
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, half_carry, carry);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            SUB_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let (a_1, half_carry, carry) = sub_8(a_0, value, false);
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, true, half_carry, carry);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            SBC_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let (a_1, half_carry, carry) = sub_8(a_0, value, self.c_flag());
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, true, half_carry, carry);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            AND_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let a_1 = a_0 & value;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, true, false);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            XOR_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let a_1 = a_0 ^ value;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, false, false);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            OR_IMMEDIATE(value) => {
+                let a_0 = self.cpu.a;
+                let a_1 = a_0 | value;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(a_1 == 0, false, false, false);
+                cycles = 2;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
             CP_IMMEDIATE(value) => {
                 let a = self.cpu.a;
-                let delta = a.wrapping_sub(value);
-                self.set_znhc_flags(delta == 0, true, u8_get_bit(delta, 4), a < value);
+                let (delta, half_carry, carry) = sub_8(a, value, false);
+                self.set_znhc_flags(delta == 0, true, half_carry, carry);
                 let z_flag = self.z_flag();
                 let c_flag = self.c_flag();
                 cycles = 2;
                 trace!("A = 0x{:02X}, F_Z = {}, F_C = {}", a, z_flag, c_flag);
             }
-            CPL => unimplemented!("{}", instruction),
-            CCF => unimplemented!("{}", instruction),
-            SCF => unimplemented!("{}", instruction),
-            DAA => unimplemented!("{}", instruction),
+            CPL => {
+                let a_0 = self.cpu.a;
+                let a_1 = !a_0;
+                self.cpu.a = a_1;
+                self.set_n_flag(true);
+                self.set_h_flag(true);
+                cycles = 1;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            CCF => {
+                let f_c_1 = !self.c_flag();
+                self.set_n_flag(false);
+                self.set_h_flag(false);
+                self.set_c_flag(f_c_1);
+                cycles = 1;
+                trace!("Fc₁ = {}", f_c_1);
+            }
+            SCF => {
+                self.set_n_flag(false);
+                self.set_h_flag(false);
+                self.set_c_flag(true);
+                cycles = 1;
+                tracer = None;
+            }
+            DAA => {
+                // adjusts the result of adding or subtracting two binary-coded
+                // decimal numbers, using the flags it left
+                let a_0 = self.cpu.a;
+                let mut correction = 0u8;
+                let mut f_c_1 = false;
+                if self.n_flag() {
+                    if self.h_flag() {
+                        correction |= 0x06;
+                    }
+                    if self.c_flag() {
+                        correction |= 0x60;
+                        f_c_1 = true;
+                    }
+                    self.cpu.a = a_0.wrapping_sub(correction);
+                } else {
+                    if self.h_flag() || a_0 & 0x0F > 0x09 {
+                        correction |= 0x06;
+                    }
+                    if self.c_flag() || a_0 > 0x99 {
+                        correction |= 0x60;
+                        f_c_1 = true;
+                    }
+                    self.cpu.a = a_0.wrapping_add(correction);
+                }
+                let a_1 = self.cpu.a;
+                let f_n = self.n_flag();
+                self.set_znhc_flags(a_1 == 0, f_n, false, f_c_1);
+                cycles = 1;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
             // 16-Bit Arithmatic and Logic
             INC_16(target) => {
                 let old_value = self.get_register(target);
+                self.oam_bug(old_value, OamBugAccess::Write);
                 let new_value = old_value.wrapping_add(1);
                 self.set_register(target, new_value);
                 cycles = 2;
@@ -385,6 +679,7 @@ impl CPUController for GameBoy {
             }
             DEC_16(target) => {
                 let old_value = self.get_register(target);
+                self.oam_bug(old_value, OamBugAccess::Write);
                 let new_value = old_value.wrapping_sub(1);
                 self.set_register(target, new_value);
                 cycles = 2;
@@ -396,17 +691,41 @@ impl CPUController for GameBoy {
                     new_value
                 );
             }
-            ADD_TO_HL(_) => unimplemented!("{}", instruction),
-            ADD_SP(_) => unimplemented!("{}", instruction),
+            ADD_TO_HL(source) => {
+                let hl_0 = self.get_register(HL);
+                let value = self.get_register(source);
+                let hl_1 = hl_0.wrapping_add(value);
+                self.set_register(HL, hl_1);
+                let f_z = self.z_flag();
+                let half_carry = (hl_0 & 0x0FFF) + (value & 0x0FFF) > 0x0FFF;
+                let carry = u32::from(hl_0) + u32::from(value) > 0xFFFF;
+                self.set_znhc_flags(f_z, false, half_carry, carry);
+                cycles = 2;
+                trace!(
+                    "HL₀ = 0x{:04X}, {:?} = 0x{:04X}, HL₁ = 0x{:04X}",
+                    hl_0,
+                    source,
+                    value,
+                    hl_1
+                );
+            }
+            ADD_SP(offset) => {
+                let sp_0 = self.cpu.sp;
+                let (sp_1, half_carry, carry) = add_sp_offset(sp_0, offset);
+                self.cpu.sp = sp_1;
+                self.set_znhc_flags(false, false, half_carry, carry);
+                cycles = 4;
+                trace!("SP₀ = 0x{:04X}, SP₁ = 0x{:04X}", sp_0, sp_1);
+            }
             // 8-Bit Bitwise Operations
             RL(register) => {
                 let f_c_0 = self.c_flag();
-                let value_0 = self.get_register(register);
+                let (value_0, extra_read_cycles) = self.read_register(register);
                 let value_1 = (value_0 << 1) + if f_c_0 { 1 } else { 0 };
                 let f_c_1 = value_0 & 0b1000_0000 > 0;
-                self.set_register(register, value_1);
+                let extra_write_cycles = self.set_register(register, value_1);
                 self.set_znhc_flags(value_1 == 0, false, false, f_c_1);
-                cycles = 2;
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
                 trace!(
                     "Fc₀ = {}, {}₀ = 0x{:02X}, Fc₁ = {}, {}₁ = 0x{:02X}",
                     f_c_0,
@@ -423,9 +742,9 @@ impl CPUController for GameBoy {
                 let a_1 = (a_0 << 1) + if f_c_0 { 1 } else { 0 };
                 let f_c_1 = a_0 & 0b1000_0000 > 0;
                 self.cpu.a = a_1;
-                // We're setting the wrong flags!
-                self.set_znhc_flags(a_1 == 0, false, false, f_c_1);
-                cycles = 2;
+                // unlike RL A, this always clears the zero flag
+                self.set_znhc_flags(false, false, false, f_c_1);
+                cycles = 1;
                 trace!(
                     "Fc₀ = {}, A₀ = 0x{:02X}, Fc₁ = {}, A₁ = 0x{:02X}",
                     f_c_0,
@@ -434,30 +753,157 @@ impl CPUController for GameBoy {
                     a_1
                 );
             }
-            RLC(_register) => unimplemented!("{}", instruction),
-            RLCA => unimplemented!("{}", instruction),
-            RR(_register) => unimplemented!("{}", instruction),
-            RRA => unimplemented!("{}", instruction),
-            RRC(_register) => unimplemented!("{}", instruction),
-            RRCA => unimplemented!("{}", instruction),
-            SRL(_register) => unimplemented!("{}", instruction),
-            SRA(_register) => unimplemented!("{}", instruction),
-            SLA(_register) => unimplemented!("{}", instruction),
-            SWAP(_register) => unimplemented!("{}", instruction),
+            RLC(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0.rotate_left(1);
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1000_0000 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            RLCA => {
+                let a_0 = self.cpu.a;
+                let a_1 = a_0.rotate_left(1);
+                let f_c_1 = a_0 & 0b1000_0000 != 0;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(false, false, false, f_c_1);
+                cycles = 1;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            RR(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = (value_0 >> 1) | if self.c_flag() { 0b1000_0000 } else { 0 };
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            RRA => {
+                let a_0 = self.cpu.a;
+                let a_1 = (a_0 >> 1) | if self.c_flag() { 0b1000_0000 } else { 0 };
+                let f_c_1 = a_0 & 0b1 != 0;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(false, false, false, f_c_1);
+                cycles = 1;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            RRC(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0.rotate_right(1);
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            RRCA => {
+                let a_0 = self.cpu.a;
+                let a_1 = a_0.rotate_right(1);
+                let f_c_1 = a_0 & 0b1 != 0;
+                self.cpu.a = a_1;
+                self.set_znhc_flags(false, false, false, f_c_1);
+                cycles = 1;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
+            SRL(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0 >> 1;
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            SRA(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = (value_0 >> 1) | (value_0 & 0b1000_0000);
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            SLA(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0 << 1;
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, value_0 & 0b1000_0000 != 0);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
+            SWAP(register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0.rotate_left(4);
+                let extra_write_cycles = self.set_register(register, value_1);
+                self.set_znhc_flags(value_1 == 0, false, false, false);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!(
+                    "{}₀ = 0x{:02X}, {}₁ = 0x{:02X}",
+                    register,
+                    value_0,
+                    register,
+                    value_1
+                );
+            }
             BIT(bit, register) => {
-                let value = self.get_register(register);
+                let (value, extra_read_cycles) = self.read_register(register);
                 let result = !u8_get_bit(value, bit.index());
                 self.set_z_flag(result);
                 self.set_n_flag(false);
                 self.set_h_flag(true);
-                cycles = 2;
+                cycles = 2 + extra_read_cycles;
                 trace!("Z₁ = {}", result);
             }
-            SET(_bit, _register) => unimplemented!("{}", instruction),
-            RES(_bit, _register) => unimplemented!("{}", instruction),
+            SET(bit, register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0 | (1 << bit.index());
+                let extra_write_cycles = self.set_register(register, value_1);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!("{}₀ = 0x{:02X}", register, value_0);
+            }
+            RES(bit, register) => {
+                let (value_0, extra_read_cycles) = self.read_register(register);
+                let value_1 = value_0 & !(1 << bit.index());
+                let extra_write_cycles = self.set_register(register, value_1);
+                cycles = 2 + extra_read_cycles + extra_write_cycles;
+                trace!("{}₀ = 0x{:02X}", register, value_0);
+            }
             // 8-Bit Loads
             LD_8_INTERNAL(dest, source) => {
-                let dest_value_0 = self.get_register(dest);
+                let dest_value_0 = self.unwatched(|gb| gb.get_register(dest));
                 let (source_value, extra_read_cycles) = self.read_register(source);
                 let extra_write_cycles = self.set_register(dest, source_value);
                 cycles = 1 + extra_read_cycles + extra_write_cycles;
@@ -470,13 +916,13 @@ impl CPUController for GameBoy {
                 );
             }
             LD_8_IMMEDIATE(dest, value) => {
-                let dest_value_0 = self.get_register(dest);
+                let dest_value_0 = self.unwatched(|gb| gb.get_register(dest));
                 let extra_write_cycles = self.set_register(dest, value);
                 cycles = 2 + extra_write_cycles;
                 trace!("{}₀ = 0x{:02X}", dest, dest_value_0);
             }
             LD_8_TO_SECONDARY(dest) => {
-                let dest_value_0 = self.get_register(dest);
+                let dest_value_0 = self.unwatched(|gb| gb.get_register(dest));
                 let a = self.cpu.a;
                 self.set_register(dest, a);
                 cycles = 2;
@@ -484,7 +930,7 @@ impl CPUController for GameBoy {
             }
             LD_8_FROM_SECONDARY(source) => {
                 let a_0 = self.cpu.a;
-                let a_1 = self.get_register(source);
+                let (a_1, _) = self.read_register(source);
                 self.cpu.a = a_1;
                 cycles = 2;
                 trace!("A₀ = 0x{:02X}, {} = 0x{:02X}", a_0, source, a_1)
@@ -493,7 +939,7 @@ impl CPUController for GameBoy {
                 let a = self.cpu.a;
                 let address = 0xFF00 + u16::from(offset);
                 self.set_mem(address, a);
-                cycles = 4;
+                cycles = 3;
                 trace!("A = 0x{:02X}", a);
             }
             LD_8_FROM_FF_IMMEDIATE(offset) => {
@@ -507,7 +953,7 @@ impl CPUController for GameBoy {
                 let a = self.cpu.a;
                 let c = self.cpu.c;
                 let address = 0xFF00 + u16::from(c);
-                let old_value = self.mem(address);
+                let old_value = self.unwatched(|gb| gb.mem(address));
                 self.set_mem(address, a);
                 cycles = 2;
                 trace!(
@@ -517,10 +963,17 @@ impl CPUController for GameBoy {
                     old_value
                 );
             }
-            LD_8_FROM_FF_C => unimplemented!("{}", instruction),
+            LD_8_FROM_FF_C => {
+                let a_0 = self.cpu.a;
+                let c = self.cpu.c;
+                let a_1 = self.mem(0xFF00 + u16::from(c));
+                self.cpu.a = a_1;
+                cycles = 2;
+                trace!("C = 0x{:02X}, A₀ = 0x{:02X}, A₁ = 0x{:02X}", c, a_0, a_1);
+            }
             LD_8_TO_MEMORY_IMMEDIATE(address) => {
                 let a = self.cpu.a;
-                let old_value = self.mem(address);
+                let old_value = self.unwatched(|gb| gb.mem(address));
                 self.set_mem(address, a);
                 cycles = 4;
                 trace!(
@@ -530,7 +983,13 @@ impl CPUController for GameBoy {
                     old_value
                 );
             }
-            LD_8_FROM_MEMORY_IMMEDIATE(_address) => unimplemented!("{}", instruction),
+            LD_8_FROM_MEMORY_IMMEDIATE(address) => {
+                let a_0 = self.cpu.a;
+                let a_1 = self.mem(address);
+                self.cpu.a = a_1;
+                cycles = 4;
+                trace!("A₀ = 0x{:02X}, A₁ = 0x{:02X}", a_0, a_1);
+            }
             // 16-Bit Loads
             LD_16_IMMEDIATE(dest, value) => {
                 let old_value = self.get_register(dest);
@@ -538,11 +997,38 @@ impl CPUController for GameBoy {
                 cycles = 3;
                 trace!("{:?}₀ = 0x{:04X}", dest, old_value);
             }
-            LD_HL_FROM_SP => unimplemented!("{}", instruction),
-            LD_HL_FROM_SP_PLUS(_value) => unimplemented!("{}", instruction),
-            LD_SP_TO_IMMEDIATE_ADDRESS(_address) => unimplemented!("{}", instruction),
+            LD_HL_FROM_SP => {
+                // despite the name, opcode 0xF9 loads SP from HL
+                let sp_0 = self.cpu.sp;
+                let hl = self.get_register(HL);
+                self.oam_bug(hl, OamBugAccess::Write);
+                self.cpu.sp = hl;
+                cycles = 2;
+                trace!("SP₀ = 0x{:04X}, HL = 0x{:04X}", sp_0, hl);
+            }
+            LD_HL_FROM_SP_PLUS(offset) => {
+                let hl_0 = self.get_register(HL);
+                let (hl_1, half_carry, carry) = add_sp_offset(self.cpu.sp, offset);
+                self.set_register(HL, hl_1);
+                self.set_znhc_flags(false, false, half_carry, carry);
+                cycles = 3;
+                trace!("HL₀ = 0x{:04X}, HL₁ = 0x{:04X}", hl_0, hl_1);
+            }
+            LD_SP_TO_IMMEDIATE_ADDRESS(address) => {
+                let sp = self.cpu.sp;
+                let [sp_low, sp_high] = u16_to_u8s(sp);
+                self.set_mem(address, sp_low);
+                self.set_mem(address.wrapping_add(1), sp_high);
+                cycles = 5;
+                trace!("SP = 0x{:04X}", sp);
+            }
             PUSH(register) => {
-                let value = self.get_register(register);
+                // the stack instructions use AF in place of SP
+                let value = if register == SP {
+                    self.af()
+                } else {
+                    self.get_register(register)
+                };
                 self.stack_push(value);
                 let sp_1 = self.cpu.sp;
                 cycles = 4;
@@ -551,7 +1037,12 @@ impl CPUController for GameBoy {
             POP(register) => {
                 let value = self.stack_pop();
                 let sp_1 = self.cpu.sp;
-                self.set_register(register, value);
+                if register == SP {
+                    // the low bits of F are always zero
+                    self.set_af(value & 0xFFF0);
+                } else {
+                    self.set_register(register, value);
+                }
                 cycles = 3;
                 trace!(
                     "{:?}₁ = 0x{:02X}, SP₁ = 0x{:04X}",
@@ -576,7 +1067,11 @@ impl CPUController for GameBoy {
                 cycles = 4;
                 tracer = None;
             }
-            JP_HL => unimplemented!("{}", instruction),
+            JP_HL => {
+                self.cpu.pc = self.get_register(HL);
+                cycles = 1;
+                tracer = None;
+            }
             JR_IF(condition, offset) => {
                 if self.condition(condition) {
                     self.relative_jump(offset);
@@ -625,11 +1120,33 @@ impl CPUController for GameBoy {
                 let pc_1 = self.stack_pop();
                 let sp_1 = self.cpu.sp;
                 self.cpu.pc = pc_1;
-                cycles = 2;
+                cycles = 4;
+                trace!("SP₁ = {:04X}", sp_1);
+            }
+            RET_IF(condition) => {
+                if self.condition(condition) {
+                    let pc_1 = self.stack_pop();
+                    let sp_1 = self.cpu.sp;
+                    self.cpu.pc = pc_1;
+                    cycles = 5;
+                    trace!("SP₁ = {:04X}", sp_1);
+                } else {
+                    cycles = 2;
+                    trace!("skipped - condition false");
+                }
+            }
+            RETI => {
+                let pc_1 = self.stack_pop();
+                let sp_1 = self.cpu.sp;
+                self.cpu.pc = pc_1;
+                self.cpu.ime = true;
+                cycles = 4;
                 trace!("SP₁ = {:04X}", sp_1);
             }
-            RET_IF(_condition) => unimplemented!("{}", instruction),
-            RETI => unimplemented!("{}", instruction),
+        }
+
+        if ei_pending && instruction != DI {
+            self.cpu.ime = true;
         }
 
         let t_1 = t_0 + cycles;
@@ -637,6 +1154,7 @@ impl CPUController for GameBoy {
 
         InstructionExecution {
             instruction,
+            bank,
             t_0,
             t_1,
             source,
@@ -648,7 +1166,9 @@ impl CPUController for GameBoy {
     /// and unsets it there.
     fn pop_interrupt(&mut self) -> Option<InterruptType> {
         let enabled_and_triggered = self.cpu.ie & self.cpu.ift;
-        if enabled_and_triggered & 0b00001 != 0 {
+        if !self.cpu.ime {
+            None
+        } else if enabled_and_triggered & 0b00001 != 0 {
             self.cpu.ift &= !0b00001;
             Some(InterruptType::VBlank)
         } else if enabled_and_triggered & 0b00010 != 0 {
@@ -684,6 +1204,26 @@ impl CPUController for GameBoy {
         self.cpu.ift = ift;
     }
 
+    fn key1(&self) -> u8 {
+        if self.cgb_mode {
+            0b0111_1110
+                | if self.cpu.double_speed { 0b1000_0000 } else { 0 }
+                | if self.cpu.speed_switch_armed { 0b1 } else { 0 }
+        } else {
+            0xFF
+        }
+    }
+
+    fn set_key1(&mut self, value: u8) {
+        if self.cgb_mode {
+            self.cpu.speed_switch_armed = value & 0b1 != 0;
+        }
+    }
+
+    fn double_speed(&self) -> bool {
+        self.cpu.double_speed
+    }
+
     // Returns the instruction in memory at PC, and advances PC past it.
     fn instruction_from_pc(&mut self) -> Instruction {
         Instruction::from_byte_iter(&mut self.iter_bytes_at_pc()).unwrap()
@@ -698,20 +1238,35 @@ impl CPUController for GameBoy {
         self.cpu.pc = (i32::from(self.cpu.pc) + i32::from(n)) as u16;
     }
 
+    fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+
+    fn set_pc(&mut self, value: u16) {
+        self.cpu.pc = value;
+    }
+
     fn stack_push(&mut self, value: u16) {
         let sp0 = self.cpu.sp;
         let sp1 = sp0 - 2;
-        let (value_low, value_high) = u16_to_u8s(value);
-        self.set_mem(sp1 + 1, value_low);
-        self.set_mem(sp1 + 0, value_high);
+        // the first decrement, and each decrement-and-write
+        self.oam_bug(sp0, OamBugAccess::Write);
+        self.oam_bug(sp0 - 1, OamBugAccess::Write);
+        self.oam_bug(sp0 - 2, OamBugAccess::Write);
+        let [value_low, value_high] = u16_to_u8s(value);
+        self.set_mem(sp1 + 1, value_high);
+        self.set_mem(sp1 + 0, value_low);
         self.cpu.sp = sp1;
     }
 
     fn stack_pop(&mut self) -> u16 {
         let sp0 = self.cpu.sp;
         let sp1 = sp0 + 2;
-        let value_low = self.mem(sp0 + 1);
-        let value_high = self.mem(sp0 + 0);
+        // each read-and-increment
+        self.oam_bug(sp0, OamBugAccess::ReadDuringIncrease);
+        self.oam_bug(sp0 + 1, OamBugAccess::ReadDuringIncrease);
+        let value_low = self.mem(sp0 + 0);
+        let value_high = self.mem(sp0 + 1);
         let value = u8s_to_u16(value_low, value_high);
         self.cpu.sp = sp1;
         value
@@ -722,7 +1277,7 @@ impl CPUController for GameBoy {
     }
 
     fn set_af(&mut self, value: u16) {
-        let (f, a) = u16_to_u8s(value);
+        let [f, a] = u16_to_u8s(value);
         self.cpu.a = a;
         self.cpu.f = f;
     }
@@ -812,6 +1367,10 @@ pub trait GetSetRegisters<Register, RegisterValue> {
 impl GetSetRegisters<U8Register, u8> for GameBoy {
     fn read_register(&mut self, register: U8Register) -> (u8, u64) {
         use zerodmg_codes::instruction::prelude::*;
+        if register == AT_HL {
+            let hl = self.get_register(HL);
+            self.oam_bug(hl, OamBugAccess::Read);
+        }
         (
             self.get_register(register),
             match register {
@@ -851,6 +1410,7 @@ impl GetSetRegisters<U8Register, u8> for GameBoy {
             AT_HL => {
                 extra_cycles = 1;
                 let hl = self.get_register(HL);
+                self.oam_bug(hl, OamBugAccess::Write);
                 self.set_mem(hl, value);
             }
             A => {
@@ -874,7 +1434,7 @@ impl GetSetRegisters<U16Register, u16> for GameBoy {
 
     fn set_register(&mut self, register: U16Register, value: u16) -> u64 {
         use zerodmg_codes::instruction::prelude::*;
-        let (low, high) = u16_to_u8s(value);
+        let [low, high] = u16_to_u8s(value);
         match register {
             BC => {
                 self.cpu.b = high;
@@ -904,12 +1464,14 @@ impl GetSetRegisters<U8SecondaryRegister, u8> for GameBoy {
                 AT_HL_Plus => {
                     let hl_0 = self.get_register(HL);
                     let hl_1 = hl_0.wrapping_add(0x0001);
+                    self.oam_bug(hl_0, OamBugAccess::ReadDuringIncrease);
                     self.set_register(HL, hl_1);
                     self.mem(hl_0)
                 }
                 AT_HL_Minus => {
                     let hl_0 = self.get_register(HL);
                     let hl_1 = hl_0.wrapping_sub(0x0001);
+                    self.oam_bug(hl_0, OamBugAccess::ReadDuringIncrease);
                     self.set_register(HL, hl_1);
                     self.mem(hl_0)
                 }
@@ -943,12 +1505,14 @@ impl GetSetRegisters<U8SecondaryRegister, u8> for GameBoy {
             AT_HL_Plus => {
                 let hl_0 = self.get_register(HL);
                 let hl_1 = hl_0.wrapping_add(0x0001);
+                self.oam_bug(hl_0, OamBugAccess::Write);
                 self.set_mem(hl_0, value);
                 self.set_register(HL, hl_1);
             }
             AT_HL_Minus => {
                 let hl_0 = self.get_register(HL);
                 let hl_1 = hl_0.wrapping_sub(0x0001);
+                self.oam_bug(hl_0, OamBugAccess::Write);
                 self.set_mem(hl_0, value);
                 self.set_register(HL, hl_1);
             }
@@ -0,0 +1,520 @@
+use std::fmt;
+use std::str::FromStr;
+
+use zerodmg_codes::instruction::prelude::*;
+use zerodmg_codes::symbols::Symbols;
+
+use super::cpu::{CPUController, GetSetRegisters};
+use super::expression::Expression;
+use super::memory::{io_register_mapped, MemoryController};
+use super::watch::WatchController;
+use super::{GameBoy, StopReason};
+
+#[cfg(test)]
+fn test_gameboy() -> GameBoy {
+    // calls a function at 0x0150 setting B, then loops forever
+    super::test_roms::gameboy_for(&[
+        (0x0100, vec![CALL(0x0150), LD(A, 0x42), JR(-2)]),
+        (0x0150, vec![LD(B, 0x01), RET]),
+    ])
+}
+
+#[test]
+fn test_next_steps_over_calls() {
+    let mut gameboy = test_gameboy();
+    let debugger = Debugger::new();
+    assert_eq!(gameboy.disassemble(0x0100, 2)[0], (0x0100, CALL(0x0150)));
+
+    assert_eq!(debugger.next(&mut gameboy), StopReason::Stepped);
+    assert_eq!(gameboy.pc(), 0x0103);
+    assert_eq!(gameboy.registers().bc >> 8, 0x01);
+    assert_eq!(debugger.next(&mut gameboy), StopReason::Stepped);
+    assert_eq!(gameboy.pc(), 0x0105);
+
+    // without any breakpoints, running only stops when its time is up
+    let start = gameboy.cycles();
+    let reason = debugger.resume(&mut gameboy, RunMode::Continue, 100);
+    assert_eq!(reason, StopReason::CyclesElapsed);
+    assert!(gameboy.cycles() >= start + 100);
+}
+
+#[test]
+fn test_breakpoints_and_finish() {
+    let mut gameboy = test_gameboy();
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint("0150".parse().unwrap());
+    // in another bank, so never reached
+    debugger.add_breakpoint("02:0105".parse().unwrap());
+
+    assert_eq!(debugger.run(&mut gameboy), StopReason::Breakpoint(id));
+    assert_eq!(gameboy.pc(), 0x0150);
+    assert_eq!(debugger.finish(&mut gameboy), StopReason::Returned);
+    assert_eq!(gameboy.pc(), 0x0103);
+
+    assert!(debugger.remove_breakpoint(id));
+    assert!(!debugger.remove_breakpoint(id));
+    assert_eq!(
+        "1:4a2F".parse(),
+        Ok(Breakpoint {
+            bank: Some(1),
+            address: Some(0x4A2F),
+            condition: None,
+        })
+    );
+    assert!("1:4000:0".parse::<Breakpoint>().is_err());
+    assert!("if".parse::<Breakpoint>().is_err());
+}
+
+#[test]
+fn test_conditional_breakpoints() {
+    let mut gameboy = test_gameboy();
+    let mut debugger = Debugger::new();
+    let breakpoint: Breakpoint = "0103 if B == 1 && [0x0100] == 0xCD".parse().unwrap();
+    assert_eq!(breakpoint.to_string(), "0x0103 if B == 1 && [0x0100] == 0xCD");
+    let id = debugger.add_breakpoint(breakpoint);
+    assert_eq!(debugger.run(&mut gameboy), StopReason::Breakpoint(id));
+    assert_eq!(gameboy.pc(), 0x0103);
+
+    // a condition alone is checked before every instruction
+    let id = debugger.add_breakpoint("if A == 0x42".parse().unwrap());
+    assert_eq!(debugger.run(&mut gameboy), StopReason::Breakpoint(id));
+    assert_eq!(gameboy.pc(), 0x0105);
+}
+
+#[test]
+fn test_labels() {
+    let mut gameboy = test_gameboy();
+    gameboy.set_symbols("00:0100 Start\n00:0150 SetB\n".parse().unwrap());
+    let mut debugger = Debugger::new();
+    let breakpoint = Breakpoint::parse("setb if B == 0", gameboy.symbols()).unwrap();
+    assert_eq!(breakpoint.to_string(), "00:0150 if B == 0");
+    let id = debugger.add_breakpoint(breakpoint);
+    assert_eq!(debugger.run(&mut gameboy), StopReason::Breakpoint(id));
+    let trace = &gameboy.latest_executions(1)[0];
+    assert!(trace.starts_with("CALL 0x0150 <SetB> "));
+    assert!(trace.contains(" ; Start  ; "));
+    assert_eq!(gameboy.resolve_address("SetB"), Ok(0x0150));
+    assert_eq!(gameboy.resolve_address("$C000"), Ok(0xC000));
+    assert!(gameboy.resolve_address("Missing").is_err());
+}
+
+/// Parses an address in hex, with an optional `0x` or `$` prefix.
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let digits = if s.starts_with("0x") || s.starts_with("0X") {
+        &s[2..]
+    } else if s.starts_with('$') {
+        &s[1..]
+    } else {
+        s
+    };
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address: {}", s))
+}
+
+/// A location to stop executing at, optionally only when a particular bank
+/// is mapped there, or when a condition is true, or both.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    /// Where to stop, or None to check the condition before every
+    /// instruction.
+    pub address: Option<u16>,
+    pub condition: Option<Expression>,
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    /// Parses an `address` or `bank:address`, both in hex, optionally
+    /// followed by `if` and an [Expression], or just `if` and an expression.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, &Symbols::new())
+    }
+}
+
+impl Breakpoint {
+    /// Parses a breakpoint like [Breakpoint::from_str], but also accepts a
+    /// label from the symbols in place of the bank and address.
+    pub fn parse(s: &str, symbols: &Symbols) -> Result<Self, String> {
+        let s = s.trim();
+        let (location, condition) = if s == "if" || s.starts_with("if ") {
+            ("", Some(&s[2..]))
+        } else {
+            match s.find(" if ") {
+                Some(i) => (&s[..i], Some(&s[i + 4..])),
+                None => (s, None),
+            }
+        };
+        let condition = match condition {
+            Some(condition) => Some(condition.parse::<Expression>()?),
+            None => None,
+        };
+
+        let parts: Vec<&str> = location.split(':').collect();
+        let (bank, address) = match parts.len() {
+            1 if location.is_empty() && condition.is_some() => (None, None),
+            1 => match symbols.get(location) {
+                Some(symbol) => (Some(symbol.bank), Some(symbol.address)),
+                None => (None, Some(parse_address(parts[0])?)),
+            },
+            2 => (
+                Some(
+                    usize::from_str_radix(parts[0], 16)
+                        .map_err(|_| format!("invalid bank: {}", parts[0]))?,
+                ),
+                Some(parse_address(parts[1])?),
+            ),
+            _ => return Err(format!("invalid breakpoint: {}", s)),
+        };
+        Ok(Self {
+            bank,
+            address,
+            condition,
+        })
+    }
+
+    // whether to stop before executing the next instruction
+    fn hit(&self, gameboy: &GameBoy) -> bool {
+        let pc = gameboy.pc();
+        let at_address = match self.address {
+            Some(address) => {
+                address == pc && self.bank.map_or(true, |bank| bank == gameboy.bank_at(pc))
+            }
+            None => true,
+        };
+        at_address && self
+            .condition
+            .as_ref()
+            .map_or(true, |condition| condition.is_true(gameboy))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.bank, self.address) {
+            (Some(bank), Some(address)) => write!(f, "{:02X}:{:04X}", bank, address)?,
+            (None, Some(address)) => write!(f, "0x{:04X}", address)?,
+            _ => {}
+        }
+        if let Some(ref condition) = self.condition {
+            if self.address.is_some() {
+                write!(f, " ")?;
+            }
+            write!(f, "if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the CPU registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+}
+
+impl Registers {
+    pub fn z_flag(&self) -> bool {
+        self.af & 0b1000_0000 != 0
+    }
+
+    pub fn n_flag(&self) -> bool {
+        self.af & 0b0100_0000 != 0
+    }
+
+    pub fn h_flag(&self) -> bool {
+        self.af & 0b0010_0000 != 0
+    }
+
+    pub fn c_flag(&self) -> bool {
+        self.af & 0b0001_0000 != 0
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(
+            f,
+            "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} flags={}{}{}{} IME={}",
+            self.af,
+            self.bc,
+            self.de,
+            self.hl,
+            self.sp,
+            self.pc,
+            flag(self.z_flag(), 'Z'),
+            flag(self.n_flag(), 'N'),
+            flag(self.h_flag(), 'H'),
+            flag(self.c_flag(), 'C'),
+            self.ime as u8
+        )
+    }
+}
+
+impl GameBoy {
+    pub fn registers(&self) -> Registers {
+        Registers {
+            af: self.af(),
+            bc: self.get_register(BC),
+            de: self.get_register(DE),
+            hl: self.get_register(HL),
+            sp: self.get_register(SP),
+            pc: self.pc(),
+            ime: self.cpu.ime(),
+        }
+    }
+
+    /// Reads memory as the CPU would, except that unused IO registers read
+    /// as 0xFF instead of panicking, and watchpoints aren't triggered.
+    pub fn peek(&self, addr: u16) -> u8 {
+        if 0xFF00 <= addr && addr <= 0xFF7F && !io_register_mapped(addr) {
+            0xFF
+        } else {
+            self.unwatched(|gameboy| gameboy.mem(addr))
+        }
+    }
+
+    /// The bank mapped at an address: the cartridge's ROM bank at
+    /// 0x0000-0x7FFF, the WRAM bank at 0xD000-0xDFFF, or 0 anywhere else.
+    pub fn bank_at(&self, addr: u16) -> usize {
+        if addr <= 0x7FFF {
+            self.mem.cartridge().rom_bank_at(addr)
+        } else if 0xD000 <= addr && addr <= 0xDFFF {
+            self.wram_bank()
+        } else {
+            0
+        }
+    }
+
+    /// Decodes the given number of instructions starting at an address.
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<(u16, Instruction)> {
+        let mut instructions = vec![];
+        let mut addr = addr;
+        for _ in 0..count {
+            let mut bytes = (0..).map(|i| self.peek(addr.wrapping_add(i)));
+            let instruction = Instruction::from_byte_iter(&mut bytes)
+                .expect("memory reads never run out");
+            instructions.push((addr, instruction));
+            addr = addr.wrapping_add(instruction.byte_len());
+        }
+        instructions
+    }
+
+    /// The labels shown in traces and usable in place of addresses.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Parses an address like [parse_address], or looks up a label.
+    pub fn resolve_address(&self, s: &str) -> Result<u16, String> {
+        match self.symbols.get(s) {
+            Some(symbol) => Ok(symbol.address),
+            None => parse_address(s),
+        }
+    }
+}
+
+/// Breakpoints, and ways of running until they're reached.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    // numbered from 1 by their index, with removed breakpoints left empty
+    breakpoints: Vec<Option<Breakpoint>>,
+    // logs each instruction executed while this is true before it
+    trace_filter: Option<Expression>,
+    // whether to stop when the stack gets out of step with the calls made
+    stop_on_imbalance: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a breakpoint, returning its number.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(Some(breakpoint));
+        self.breakpoints.len()
+    }
+
+    /// Removes a breakpoint by number, returning whether it existed.
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        match self.breakpoints.get_mut(id.wrapping_sub(1)) {
+            Some(breakpoint) => breakpoint.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// The breakpoints, with their numbers.
+    pub fn breakpoints(&self) -> Vec<(usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, breakpoint)| breakpoint.as_ref().map(|breakpoint| (i + 1, breakpoint)))
+            .collect()
+    }
+
+    /// Logs each instruction executed while running for which the filter
+    /// is true beforehand, to be taken by [GameBoy::take_log], or stops
+    /// tracing with None.
+    pub fn set_trace_filter(&mut self, filter: Option<Expression>) {
+        self.trace_filter = filter;
+    }
+
+    pub fn trace_filter(&self) -> Option<&Expression> {
+        self.trace_filter.as_ref()
+    }
+
+    /// Sets whether running stops after an instruction that leaves the
+    /// stack out of step with the calls made, such as a POP discarding a
+    /// return address. Otherwise they're left for
+    /// [GameBoy::take_stack_imbalances].
+    pub fn set_stop_on_imbalance(&mut self, stop: bool) {
+        self.stop_on_imbalance = stop;
+    }
+
+    pub fn stop_on_imbalance(&self) -> bool {
+        self.stop_on_imbalance
+    }
+
+    // the breakpoint at the next instruction to execute, if any
+    fn breakpoint_hit(&self, gameboy: &GameBoy) -> Option<usize> {
+        self.breakpoints()
+            .into_iter()
+            .find(|(_, breakpoint)| breakpoint.hit(gameboy))
+            .map(|(id, _)| id)
+    }
+
+    // executes an instruction, tracing it if it matches the filter, and
+    // returns the first access a watchpoint recorded during it, or the first
+    // stack imbalance if those stop it, if any
+    fn step_traced(&self, gameboy: &mut GameBoy) -> Option<StopReason> {
+        let traced = self
+            .trace_filter
+            .as_ref()
+            .map_or(false, |filter| filter.is_true(gameboy));
+        gameboy.step();
+        if traced {
+            let line = gameboy.last_execution().map(|opex| gameboy.format_execution(opex));
+            if let Some(line) = line {
+                gameboy.log(line);
+            }
+        }
+        if let Some(&hit) = gameboy.take_watch_hits().first() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+        if self.stop_on_imbalance {
+            if let Some(&imbalance) = gameboy.take_stack_imbalances().first() {
+                return Some(StopReason::StackImbalance(imbalance));
+            }
+        }
+        None
+    }
+
+    /// Executes one instruction.
+    pub fn step(&self, gameboy: &mut GameBoy) -> StopReason {
+        self.step_traced(gameboy).unwrap_or(StopReason::Stepped)
+    }
+
+    /// Executes one instruction, but runs the whole function if it's a call,
+    /// unless a breakpoint or watchpoint is reached first.
+    pub fn next(&self, gameboy: &mut GameBoy) -> StopReason {
+        match RunMode::step_over(gameboy) {
+            Some(mode) => self.resume(gameboy, mode, u64::max_value()),
+            None => self.step(gameboy),
+        }
+    }
+
+    /// Runs until a breakpoint or watchpoint is reached, which may be never.
+    /// Interactive callers should [Debugger::resume] a slice at a time
+    /// instead, so that they can be interrupted.
+    pub fn run(&self, gameboy: &mut GameBoy) -> StopReason {
+        self.resume(gameboy, RunMode::Continue, u64::max_value())
+    }
+
+    /// Runs until the current function returns, or a breakpoint or
+    /// watchpoint is reached.
+    pub fn finish(&self, gameboy: &mut GameBoy) -> StopReason {
+        self.resume(gameboy, RunMode::step_out(gameboy), u64::max_value())
+    }
+
+    /// Runs in the given mode until it's done, or a breakpoint or watchpoint
+    /// is reached, or the given number of cycles elapse. Running can be
+    /// continued in the same mode after CyclesElapsed, so a caller can check
+    /// for other work in between.
+    pub fn resume(&self, gameboy: &mut GameBoy, mode: RunMode, cycles: u64) -> StopReason {
+        let end = gameboy.cycles().saturating_add(cycles);
+        loop {
+            if let Some(reason) = self.step_traced(gameboy) {
+                return reason;
+            }
+            match mode {
+                RunMode::Continue => {}
+                RunMode::StepOver { return_address, sp } => {
+                    let registers = gameboy.registers();
+                    if registers.pc == return_address && registers.sp >= sp {
+                        return StopReason::Stepped;
+                    }
+                }
+                RunMode::StepOut { sp } => {
+                    let returned = match gameboy.last_execution().map(|opex| opex.instruction) {
+                        Some(RET) | Some(RET_IF(_)) | Some(RETI) => gameboy.registers().sp > sp,
+                        _ => false,
+                    };
+                    if returned {
+                        return StopReason::Returned;
+                    }
+                }
+            }
+            if let Some(id) = self.breakpoint_hit(gameboy) {
+                return StopReason::Breakpoint(id);
+            }
+            if gameboy.cycles() >= end {
+                return StopReason::CyclesElapsed;
+            }
+        }
+    }
+}
+
+/// What the debugger is running until, besides a breakpoint or watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    /// Nothing else.
+    Continue,
+    /// Execution returning from a call to the given address, with the stack
+    /// no deeper than SP was before it.
+    StepOver { return_address: u16, sp: u16 },
+    /// A return leaving the stack shallower than SP was when the mode was
+    /// chosen.
+    StepOut { sp: u16 },
+}
+
+impl RunMode {
+    /// The mode for running over the next instruction, if it's a call.
+    pub fn step_over(gameboy: &GameBoy) -> Option<Self> {
+        let pc = gameboy.pc();
+        let (_, instruction) = gameboy.disassemble(pc, 1)[0];
+        match instruction {
+            CALL(_) | CALL_IF(_, _) | RST(_) => Some(RunMode::StepOver {
+                return_address: pc.wrapping_add(instruction.byte_len()),
+                sp: gameboy.registers().sp,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The mode for running until the current function returns.
+    pub fn step_out(gameboy: &GameBoy) -> Self {
+        RunMode::StepOut {
+            sp: gameboy.registers().sp,
+        }
+    }
+}
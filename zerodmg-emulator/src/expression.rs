@@ -0,0 +1,451 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::memory::io_register_address;
+use super::GameBoy;
+
+#[test]
+fn test_parse_expressions() {
+    let value = |s: &str| {
+        let gameboy = GameBoy::builder().build().unwrap();
+        s.parse::<Expression>().unwrap().evaluate(&gameboy)
+    };
+    assert_eq!(value("1 + 2 * 3"), 7);
+    assert_eq!(value("(1 + 2) * 3"), 9);
+    assert_eq!(value("0x10 - $8 - 0b11"), 5);
+    assert_eq!(value("-1 < 0 && !0 == 1"), 1);
+    assert_eq!(value("(1 << 4 | 1) == 17"), 1);
+    assert_eq!(value("1 << 4 | 1 == 17"), 16);
+    assert_eq!(value("7 / 0"), 0);
+    assert_eq!(value("rLY"), 0xFF44);
+
+    assert!("1 +".parse::<Expression>().is_err());
+    assert!("(1".parse::<Expression>().is_err());
+    assert!("[HL".parse::<Expression>().is_err());
+    assert!("1 2".parse::<Expression>().is_err());
+    assert!("rNOPE".parse::<Expression>().is_err());
+    assert_eq!(
+        "PC  ==  0x150".parse::<Expression>().unwrap().to_string(),
+        "PC  ==  0x150"
+    );
+}
+
+#[test]
+fn test_evaluate_against_state() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    let evaluate = |gameboy: &GameBoy, s: &str| s.parse::<Expression>().unwrap().evaluate(gameboy);
+
+    // the registers the boot ROM leaves behind
+    assert_eq!(evaluate(&gameboy, "AF"), 0x01B0);
+    assert_eq!(evaluate(&gameboy, "a == 1 && zf && !nf && cf"), 1);
+    assert_eq!(evaluate(&gameboy, "HL"), 0x014D);
+    assert_eq!(evaluate(&gameboy, "PC == 0x0100 && SP == 0xFFFE"), 1);
+    assert_eq!(evaluate(&gameboy, "bank"), 0);
+
+    gameboy.run_frame();
+    assert_eq!(evaluate(&gameboy, "[rLY]"), 144);
+    assert_eq!(evaluate(&gameboy, "[0xFF40] & 0x80 != 0"), 1);
+    assert_eq!(evaluate(&gameboy, "[HL]"), i64::from(gameboy.peek(gameboy.registers().hl)));
+    assert_eq!(evaluate(&gameboy, "cycles"), gameboy.cycles() as i64);
+}
+
+/// An expression over the state of a Game Boy, such as `PC == 0x0150 &&
+/// [rLY] > 0x90`, used as a condition by the debugger.
+///
+/// Values are integers, with comparisons and `!` giving 1 or 0, and any
+/// non-zero value being true. Operands can be:
+///
+/// - numbers, in decimal, or in hex with a `0x` or `$` prefix, or in binary
+///   with a `0b` prefix
+/// - registers: `A`, `F`, `B`, `C`, `D`, `E`, `H`, `L`, `AF`, `BC`, `DE`,
+///   `HL`, `SP` and `PC`
+/// - flags: `ZF`, `NF`, `HF` and `CF`, and `IME`
+/// - `BANK`, the bank mapped at PC, and `CYCLES`, as in [GameBoy::cycles]
+/// - IO register addresses, by their hardware.inc names such as `rLY`
+/// - the byte at an address in memory, as `[address]`
+///
+/// Names are case-insensitive. The operators are those of C, with the same
+/// precedence: `* / % + - << >> < <= > >= == != & ^ | && ||`, and unary `-`,
+/// `!` and `~`. Division by zero gives zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    node: Node,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Variable(Variable),
+    Memory(Box<Node>),
+    Unary(Token, Box<Node>),
+    Binary(Token, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Variable {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    ZeroFlag,
+    SubtractFlag,
+    HalfCarryFlag,
+    CarryFlag,
+    Ime,
+    Bank,
+    Cycles,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_lowercase().as_ref() {
+            "a" => Variable::A,
+            "f" => Variable::F,
+            "b" => Variable::B,
+            "c" => Variable::C,
+            "d" => Variable::D,
+            "e" => Variable::E,
+            "h" => Variable::H,
+            "l" => Variable::L,
+            "af" => Variable::AF,
+            "bc" => Variable::BC,
+            "de" => Variable::DE,
+            "hl" => Variable::HL,
+            "sp" => Variable::SP,
+            "pc" => Variable::PC,
+            "zf" => Variable::ZeroFlag,
+            "nf" => Variable::SubtractFlag,
+            "hf" => Variable::HalfCarryFlag,
+            "cf" => Variable::CarryFlag,
+            "ime" => Variable::Ime,
+            "bank" => Variable::Bank,
+            "cycles" => Variable::Cycles,
+            _ => return None,
+        })
+    }
+
+    fn value(self, gameboy: &GameBoy) -> i64 {
+        let registers = gameboy.registers();
+        let high = |pair: u16| i64::from(pair >> 8);
+        let low = |pair: u16| i64::from(pair & 0xFF);
+        match self {
+            Variable::A => high(registers.af),
+            Variable::F => low(registers.af),
+            Variable::B => high(registers.bc),
+            Variable::C => low(registers.bc),
+            Variable::D => high(registers.de),
+            Variable::E => low(registers.de),
+            Variable::H => high(registers.hl),
+            Variable::L => low(registers.hl),
+            Variable::AF => i64::from(registers.af),
+            Variable::BC => i64::from(registers.bc),
+            Variable::DE => i64::from(registers.de),
+            Variable::HL => i64::from(registers.hl),
+            Variable::SP => i64::from(registers.sp),
+            Variable::PC => i64::from(registers.pc),
+            Variable::ZeroFlag => i64::from(registers.z_flag()),
+            Variable::SubtractFlag => i64::from(registers.n_flag()),
+            Variable::HalfCarryFlag => i64::from(registers.h_flag()),
+            Variable::CarryFlag => i64::from(registers.c_flag()),
+            Variable::Ime => i64::from(registers.ime),
+            Variable::Bank => gameboy.bank_at(registers.pc) as i64,
+            Variable::Cycles => gameboy.cycles() as i64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+    Not,
+    Complement,
+    Multiply,
+    Divide,
+    Remainder,
+    Add,
+    Subtract,
+    ShiftLeft,
+    ShiftRight,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+impl Token {
+    // how tightly a binary operator binds, or None if it isn't one
+    fn precedence(&self) -> Option<u8> {
+        Some(match self {
+            Token::Multiply | Token::Divide | Token::Remainder => 10,
+            Token::Add | Token::Subtract => 9,
+            Token::ShiftLeft | Token::ShiftRight => 8,
+            Token::Less | Token::LessOrEqual | Token::Greater | Token::GreaterOrEqual => 7,
+            Token::Equal | Token::NotEqual => 6,
+            Token::BitAnd => 5,
+            Token::BitXor => 4,
+            Token::BitOr => 3,
+            Token::And => 2,
+            Token::Or => 1,
+            _ => return None,
+        })
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' || c == '$' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(if c.is_ascii_digit() || c == '$' {
+                Token::Number(parse_number(&word)?)
+            } else {
+                Token::Name(word)
+            });
+            continue;
+        }
+
+        let (token, length) = match (c, next) {
+            ('<', Some('<')) => (Token::ShiftLeft, 2),
+            ('>', Some('>')) => (Token::ShiftRight, 2),
+            ('<', Some('=')) => (Token::LessOrEqual, 2),
+            ('>', Some('=')) => (Token::GreaterOrEqual, 2),
+            ('=', Some('=')) => (Token::Equal, 2),
+            ('!', Some('=')) => (Token::NotEqual, 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('(', _) => (Token::OpenParen, 1),
+            (')', _) => (Token::CloseParen, 1),
+            ('[', _) => (Token::OpenBracket, 1),
+            (']', _) => (Token::CloseBracket, 1),
+            ('!', _) => (Token::Not, 1),
+            ('~', _) => (Token::Complement, 1),
+            ('*', _) => (Token::Multiply, 1),
+            ('/', _) => (Token::Divide, 1),
+            ('%', _) => (Token::Remainder, 1),
+            ('+', _) => (Token::Add, 1),
+            ('-', _) => (Token::Subtract, 1),
+            ('<', _) => (Token::Less, 1),
+            ('>', _) => (Token::Greater, 1),
+            ('&', _) => (Token::BitAnd, 1),
+            ('^', _) => (Token::BitXor, 1),
+            ('|', _) => (Token::BitOr, 1),
+            _ => return Err(format!("unexpected character: {}", c)),
+        };
+        tokens.push(token);
+        i += length;
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<i64, String> {
+    let lower = word.to_lowercase();
+    let result = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.starts_with('$') {
+        i64::from_str_radix(&lower[1..], 16)
+    } else if lower.starts_with("0b") {
+        i64::from_str_radix(&lower[2..], 2)
+    } else {
+        lower.parse()
+    };
+    result.map_err(|_| format!("invalid number: {}", word))
+}
+
+// a recursive descent parser over the tokens, with precedence climbing for
+// binary operators
+struct Parser {
+    tokens: Vec<Token>,
+    i: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.i)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.i).cloned();
+        self.i += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), String> {
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err(format!("expected {}", description))
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        loop {
+            let (operator, precedence) = match self.peek() {
+                Some(token) => match token.precedence() {
+                    Some(precedence) if precedence >= min_precedence => {
+                        (token.clone(), precedence)
+                    }
+                    _ => return Ok(left),
+                },
+                None => return Ok(left),
+            };
+            self.next();
+            let right = self.binary(precedence + 1)?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Name(name)) => {
+                if let Some(variable) = Variable::from_name(&name) {
+                    Ok(Node::Variable(variable))
+                } else if name.starts_with('r') || name.starts_with('R') {
+                    io_register_address(&name)
+                        .map(|addr| Node::Number(i64::from(addr)))
+                        .ok_or_else(|| format!("unknown name: {}", name))
+                } else {
+                    Err(format!("unknown name: {}", name))
+                }
+            }
+            Some(Token::OpenParen) => {
+                let node = self.binary(0)?;
+                self.expect(Token::CloseParen, ")")?;
+                Ok(node)
+            }
+            Some(Token::OpenBracket) => {
+                let node = self.binary(0)?;
+                self.expect(Token::CloseBracket, "]")?;
+                Ok(Node::Memory(Box::new(node)))
+            }
+            Some(operator @ Token::Subtract)
+            | Some(operator @ Token::Not)
+            | Some(operator @ Token::Complement) => {
+                Ok(Node::Unary(operator, Box::new(self.unary()?)))
+            }
+            _ => Err("expected a value".to_string()),
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            i: 0,
+        };
+        let node = parser.binary(0)?;
+        if parser.peek().is_some() {
+            return Err("expected an operator".to_string());
+        }
+        Ok(Self {
+            source: s.trim().to_string(),
+            node,
+        })
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Node {
+    fn evaluate(&self, gameboy: &GameBoy) -> i64 {
+        match self {
+            Node::Number(n) => *n,
+            Node::Variable(variable) => variable.value(gameboy),
+            Node::Memory(address) => i64::from(gameboy.peek(address.evaluate(gameboy) as u16)),
+            Node::Unary(operator, operand) => {
+                let value = operand.evaluate(gameboy);
+                match operator {
+                    Token::Subtract => value.wrapping_neg(),
+                    Token::Not => i64::from(value == 0),
+                    _ => !value,
+                }
+            }
+            Node::Binary(operator, left, right) => {
+                let left = left.evaluate(gameboy);
+                // the logical operators short-circuit, like in C
+                match operator {
+                    Token::And if left == 0 => return 0,
+                    Token::Or if left != 0 => return 1,
+                    _ => {}
+                }
+                let right = right.evaluate(gameboy);
+                match operator {
+                    Token::Multiply => left.wrapping_mul(right),
+                    Token::Divide => left.checked_div(right).unwrap_or(0),
+                    Token::Remainder => left.checked_rem(right).unwrap_or(0),
+                    Token::Add => left.wrapping_add(right),
+                    Token::Subtract => left.wrapping_sub(right),
+                    Token::ShiftLeft => left.wrapping_shl(right as u32),
+                    Token::ShiftRight => left.wrapping_shr(right as u32),
+                    Token::Less => i64::from(left < right),
+                    Token::LessOrEqual => i64::from(left <= right),
+                    Token::Greater => i64::from(left > right),
+                    Token::GreaterOrEqual => i64::from(left >= right),
+                    Token::Equal => i64::from(left == right),
+                    Token::NotEqual => i64::from(left != right),
+                    Token::BitAnd => left & right,
+                    Token::BitXor => left ^ right,
+                    Token::BitOr => left | right,
+                    Token::And | Token::Or => i64::from(right != 0),
+                    _ => unreachable!("not a binary operator"),
+                }
+            }
+        }
+    }
+}
+
+impl Expression {
+    /// The value of the expression for the Game Boy's current state.
+    pub fn evaluate(&self, gameboy: &GameBoy) -> i64 {
+        self.node.evaluate(gameboy)
+    }
+
+    /// Whether the expression's value is non-zero.
+    pub fn is_true(&self, gameboy: &GameBoy) -> bool {
+        self.evaluate(gameboy) != 0
+    }
+}
@@ -0,0 +1,254 @@
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+
+#[test]
+fn test_integer_scale() {
+    let mut chain = FilterChain::new(vec![Filter::Scale(3)]);
+    let frame = DynamicImage::ImageRgba8(ImageBuffer::new(160, 144));
+    let scaled = chain.apply(&frame).to_rgba();
+    assert_eq!(scaled.dimensions(), (480, 432));
+}
+
+#[test]
+fn test_scale2x_smooths_diagonal() {
+    let black = Rgba([0x00, 0x00, 0x00, 0xFF]);
+    let white = Rgba([0xFF, 0xFF, 0xFF, 0xFF]);
+    let mut frame = ImageBuffer::from_pixel(3, 3, white);
+    frame.put_pixel(0, 0, black);
+    frame.put_pixel(1, 1, black);
+    frame.put_pixel(2, 2, black);
+    let scaled = scale2x(&frame);
+    assert_eq!(scaled.dimensions(), (6, 6));
+    // The diagonal pixels themselves stay black...
+    assert_eq!(*scaled.get_pixel(2, 2), black);
+    assert_eq!(*scaled.get_pixel(3, 3), black);
+    // ...while the inner corners of their white neighbours fill in the steps...
+    assert_eq!(*scaled.get_pixel(2, 1), black);
+    assert_eq!(*scaled.get_pixel(1, 2), black);
+    // ...and their outer corners stay white.
+    assert_eq!(*scaled.get_pixel(3, 0), white);
+    assert_eq!(*scaled.get_pixel(0, 3), white);
+}
+
+#[test]
+fn test_ghosting_blends_with_previous_frame() {
+    let mut chain = FilterChain::new(vec![Filter::Ghosting(0x80)]);
+    let black = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 0xFF])));
+    let white = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+        1,
+        1,
+        Rgba([0xFF, 0xFF, 0xFF, 0xFF]),
+    ));
+    chain.apply(&black);
+    let blended = chain.apply(&white).to_rgba();
+    let pixel = blended.get_pixel(0, 0);
+    assert!(0x70 < pixel.data[0] && pixel.data[0] < 0x90);
+    assert_eq!(pixel.data[3], 0xFF);
+}
+
+/// A post-processing effect applied to rendered display frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Nearest-neighbour scaling by an integer factor.
+    Scale(u32),
+    /// The Scale2x/AdvMAME2x edge-smoothing 2x upscaler.
+    Scale2x,
+    /// The Scale3x/AdvMAME3x edge-smoothing 3x upscaler.
+    Scale3x,
+    /// Blends each frame with the previous output, imitating the slow
+    /// response of the original LCD. The value is the weight (out of 0xFF)
+    /// given to the previous output.
+    Ghosting(u8),
+    /// Nearest-neighbour scaling by an integer factor, with the last row and
+    /// column of each scaled pixel darkened by the given opacity (out of
+    /// 0xFF) to imitate the gaps between LCD pixels.
+    PixelGrid {
+        /// The integer scaling factor.
+        scale: u32,
+        /// How much to darken the grid lines, out of 0xFF.
+        opacity: u8,
+    },
+}
+
+/// An ordered list of [Filter]s, plus any state they carry between frames.
+#[derive(Clone, Debug, Default)]
+pub struct FilterChain {
+    filters: Vec<Filter>,
+    /// The previous output of each filter, for those that need it.
+    previous: Vec<Option<RgbaImage>>,
+}
+
+impl FilterChain {
+    /// Creates a chain applying the given filters in order.
+    pub fn new(filters: Vec<Filter>) -> Self {
+        let previous = vec![None; filters.len()];
+        Self { filters, previous }
+    }
+
+    /// The filters in this chain, in the order they're applied.
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    /// Replaces the filters in this chain, discarding any state.
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        *self = Self::new(filters);
+    }
+
+    /// Runs a frame through each filter in the chain.
+    pub fn apply(&mut self, frame: &DynamicImage) -> DynamicImage {
+        let mut image = frame.to_rgba();
+        for (filter, previous) in self.filters.iter().zip(self.previous.iter_mut()) {
+            image = match *filter {
+                Filter::Scale(factor) => scale(&image, factor),
+                Filter::Scale2x => scale2x(&image),
+                Filter::Scale3x => scale3x(&image),
+                Filter::Ghosting(weight) => {
+                    let blended = match previous {
+                        Some(ref previous) if previous.dimensions() == image.dimensions() => {
+                            blend(previous, &image, weight)
+                        }
+                        _ => image,
+                    };
+                    *previous = Some(blended.clone());
+                    blended
+                }
+                Filter::PixelGrid { scale: factor, opacity } => {
+                    pixel_grid(&image, factor, opacity)
+                }
+            };
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+fn scale(image: &RgbaImage, factor: u32) -> RgbaImage {
+    let factor = factor.max(1);
+    let (width, height) = image.dimensions();
+    ImageBuffer::from_fn(width * factor, height * factor, |x, y| {
+        *image.get_pixel(x / factor, y / factor)
+    })
+}
+
+/// Returns the pixel at the given offset from (x, y), clamped to the edges.
+fn neighbour(image: &RgbaImage, x: u32, y: u32, dx: i32, dy: i32) -> Rgba<u8> {
+    let (width, height) = image.dimensions();
+    let x = (x as i32 + dx).max(0).min(width as i32 - 1) as u32;
+    let y = (y as i32 + dy).max(0).min(height as i32 - 1) as u32;
+    *image.get_pixel(x, y)
+}
+
+fn scale2x(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut scaled = ImageBuffer::new(width * 2, height * 2);
+    for y in 0..height {
+        for x in 0..width {
+            let b = neighbour(image, x, y, 0, -1);
+            let d = neighbour(image, x, y, -1, 0);
+            let e = *image.get_pixel(x, y);
+            let f = neighbour(image, x, y, 1, 0);
+            let h = neighbour(image, x, y, 0, 1);
+
+            let (e0, e1, e2, e3) = if b != h && d != f {
+                (
+                    if d == b { d } else { e },
+                    if b == f { f } else { e },
+                    if d == h { d } else { e },
+                    if h == f { f } else { e },
+                )
+            } else {
+                (e, e, e, e)
+            };
+
+            scaled.put_pixel(x * 2, y * 2, e0);
+            scaled.put_pixel(x * 2 + 1, y * 2, e1);
+            scaled.put_pixel(x * 2, y * 2 + 1, e2);
+            scaled.put_pixel(x * 2 + 1, y * 2 + 1, e3);
+        }
+    }
+    scaled
+}
+
+fn scale3x(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut scaled = ImageBuffer::new(width * 3, height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let a = neighbour(image, x, y, -1, -1);
+            let b = neighbour(image, x, y, 0, -1);
+            let c = neighbour(image, x, y, 1, -1);
+            let d = neighbour(image, x, y, -1, 0);
+            let e = *image.get_pixel(x, y);
+            let f = neighbour(image, x, y, 1, 0);
+            let g = neighbour(image, x, y, -1, 1);
+            let h = neighbour(image, x, y, 0, 1);
+            let i = neighbour(image, x, y, 1, 1);
+
+            let pixels = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) {
+                        b
+                    } else {
+                        e
+                    },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) {
+                        d
+                    } else {
+                        e
+                    },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) {
+                        f
+                    } else {
+                        e
+                    },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) {
+                        h
+                    } else {
+                        e
+                    },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+
+            for (n, pixel) in pixels.iter().enumerate() {
+                let n = n as u32;
+                scaled.put_pixel(x * 3 + n % 3, y * 3 + n / 3, *pixel);
+            }
+        }
+    }
+    scaled
+}
+
+fn blend(previous: &RgbaImage, current: &RgbaImage, weight: u8) -> RgbaImage {
+    let weight = u32::from(weight);
+    ImageBuffer::from_fn(current.width(), current.height(), |x, y| {
+        let old = previous.get_pixel(x, y).data;
+        let new = current.get_pixel(x, y).data;
+        let mix = |i: usize| {
+            ((u32::from(old[i]) * weight + u32::from(new[i]) * (0xFF - weight)) / 0xFF) as u8
+        };
+        Rgba([mix(0), mix(1), mix(2), new[3]])
+    })
+}
+
+fn pixel_grid(image: &RgbaImage, factor: u32, opacity: u8) -> RgbaImage {
+    let factor = factor.max(1);
+    let mut scaled = scale(image, factor);
+    if factor < 2 {
+        return scaled;
+    }
+    let keep = u32::from(0xFF - opacity);
+    for (x, y, pixel) in scaled.enumerate_pixels_mut() {
+        if x % factor == factor - 1 || y % factor == factor - 1 {
+            for channel in pixel.data.iter_mut().take(3) {
+                *channel = ((u32::from(*channel) * keep) / 0xFF) as u8;
+            }
+        }
+    }
+    scaled
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use zerodmg_codes::assembled::AssembledRom;
+use zerodmg_codes::disassembled::DisassembledRom;
+use zerodmg_codes::instruction::prelude::*;
+use zerodmg_utils::little_endian::{u16_to_u8s, u8s_to_u16};
+
+use super::audio::AudioController;
+use super::builder::RamInit;
+use super::cartridge::Cartridge;
+use super::cpu::{CPUController, GetSetRegisters};
+use super::memory::MemoryController;
+use super::model::Model;
+use super::pacing::FRAME_CYCLES;
+use super::{GameBoy, Output};
+
+#[test]
+fn test_gbs_header_round_trip() {
+    let gbs = GbsFile::from_rom(&AssembledRom::from_bytes(&vec![0xC9]), 0x0400, 0x0400, 0x0400);
+    let parsed = GbsFile::from_bytes(&gbs.to_bytes()).unwrap();
+    assert_eq!(parsed, gbs);
+    assert_eq!(parsed.play_period(), 17_556);
+
+    let mut bytes = gbs.to_bytes();
+    bytes[0x0E] = 0xC0;
+    bytes[0x0F] = 0x06;
+    // 65536Hz / 64
+    assert_eq!(GbsFile::from_bytes(&bytes).unwrap().play_period(), 16 * 64);
+
+    bytes[0x03] = 0x02;
+    assert_eq!(GbsFile::from_bytes(&bytes), Err(GbsError::UnsupportedVersion(2)));
+    assert_eq!(GbsFile::from_bytes(b"GBX"), Err(GbsError::TooShort));
+}
+
+#[test]
+fn test_gbs_player_calls_init_and_play() {
+    let init = vec![
+        LD(A, 0x80),
+        LD_8_TO_FF_IMMEDIATE(0x26),
+        LD(A, 0x00),
+        LD_8_TO_FF_IMMEDIATE(0x80),
+        RET,
+    ];
+    let play = vec![LD(HL, 0xFF80), INC(AT_HL), RET];
+    let init_len: u16 = init.iter().map(|instruction| instruction.byte_len()).sum();
+    let mut code = init;
+    code.extend(play);
+    let rom = DisassembledRom::from(code).assemble();
+    let gbs = GbsFile::from_rom(&rom, 0x0400, 0x0400, 0x0400 + init_len);
+
+    let mut player = GbsPlayer::new(gbs, Arc::new(Mutex::new(Output::new())));
+    player.start_song(1);
+    player.run_cycles(u64::from(super::AUDIO_SAMPLE_RATE));
+
+    let gameboy = player.gameboy();
+    assert_eq!(gameboy.mem(0xFF26) & 0x80, 0x80);
+    // PLAY is called at the VBlank rate, of about 59.7Hz
+    assert_eq!(gameboy.mem(0xFF80), 59);
+}
+
+#[test]
+fn test_gbs_player_is_deterministic() {
+    let rom = DisassembledRom::from(vec![LD(A, 0x80), LD_8_TO_FF_IMMEDIATE(0x26), RET]).assemble();
+    let gbs = GbsFile::from_rom(&rom, 0x0400, 0x0400, 0x0400);
+    let play = || {
+        let mut player = GbsPlayer::new(gbs.clone(), Arc::new(Mutex::new(Output::new())));
+        player.start_song(1);
+        player.run_cycles(10_000);
+        player.gameboy().save_state()
+    };
+    assert_eq!(play(), play());
+}
+
+// the size of a GBS file header, before the data
+const HEADER_SIZE: usize = 0x70;
+
+// where INIT and PLAY return to, an address in echo RAM that GBS code should
+// never jump to itself
+const RETURN_ADDRESS: u16 = 0xF00D;
+
+/// A Game Boy Sound System file, containing the music code and data ripped
+/// from a game, and the addresses of the routines that play it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GbsFile {
+    pub song_count: u8,
+    /// The song to play by default, counting from 1.
+    pub first_song: u8,
+    /// Where the data is loaded, from 0x0400 to 0x7FFF.
+    pub load_address: u16,
+    /// Called once with the song number (counting from 0) in A to start it.
+    pub init_address: u16,
+    /// Called at the play rate to continue the song.
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    /// Timer modulo (TMA), used for the play rate if the timer is enabled.
+    pub timer_modulo: u8,
+    /// Timer control (TAC), with bit 2 set if the timer is used for the play
+    /// rate instead of VBlank, and bit 7 set if it runs at double speed.
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub data: Vec<u8>,
+}
+
+/// The reasons we can't parse a GBS file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GbsError {
+    TooShort,
+    InvalidSignature,
+    UnsupportedVersion(u8),
+    InvalidLoadAddress(u16),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::TooShort => write!(f, "file is too short for a GBS header"),
+            GbsError::InvalidSignature => write!(f, "file does not start with \"GBS\""),
+            GbsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported GBS version {}", version)
+            }
+            GbsError::InvalidLoadAddress(address) => {
+                write!(f, "load address 0x{:04X} is outside of ROM", address)
+            }
+        }
+    }
+}
+
+impl GbsFile {
+    /// Parses a GBS file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, GbsError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(GbsError::TooShort);
+        }
+        if &bytes[0x00..0x03] != b"GBS" {
+            return Err(GbsError::InvalidSignature);
+        }
+        if bytes[0x03] != 1 {
+            return Err(GbsError::UnsupportedVersion(bytes[0x03]));
+        }
+
+        let u16_at = |offset: usize| u8s_to_u16(bytes[offset], bytes[offset + 1]);
+        let string_at = |offset: usize| {
+            let field = &bytes[offset..offset + 0x20];
+            let end = field.iter().position(|&byte| byte == 0).unwrap_or(0x20);
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        let load_address = u16_at(0x06);
+        if load_address > 0x7FFF {
+            return Err(GbsError::InvalidLoadAddress(load_address));
+        }
+
+        Ok(Self {
+            song_count: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address,
+            init_address: u16_at(0x08),
+            play_address: u16_at(0x0A),
+            stack_pointer: u16_at(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: string_at(0x10),
+            author: string_at(0x30),
+            copyright: string_at(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Creates a GBS file with a single song, played at the VBlank rate,
+    /// from a ROM whose first byte is at the load address.
+    pub fn from_rom(
+        rom: &AssembledRom,
+        load_address: u16,
+        init_address: u16,
+        play_address: u16,
+    ) -> Self {
+        Self {
+            song_count: 1,
+            first_song: 1,
+            load_address,
+            init_address,
+            play_address,
+            stack_pointer: 0xFFFE,
+            timer_modulo: 0x00,
+            timer_control: 0x00,
+            title: String::new(),
+            author: String::new(),
+            copyright: String::new(),
+            data: rom.to_bytes(),
+        }
+    }
+
+    /// Encodes this as a GBS file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        bytes[0x00..0x03].copy_from_slice(b"GBS");
+        bytes[0x03] = 1;
+        bytes[0x04] = self.song_count;
+        bytes[0x05] = self.first_song;
+        for &(offset, value) in [
+            (0x06, self.load_address),
+            (0x08, self.init_address),
+            (0x0A, self.play_address),
+            (0x0C, self.stack_pointer),
+        ]
+            .iter()
+        {
+            let [low, high] = u16_to_u8s(value);
+            bytes[offset] = low;
+            bytes[offset + 1] = high;
+        }
+        bytes[0x0E] = self.timer_modulo;
+        bytes[0x0F] = self.timer_control;
+        for &(offset, ref string) in [
+            (0x10, &self.title),
+            (0x30, &self.author),
+            (0x50, &self.copyright),
+        ]
+            .iter()
+        {
+            let length = string.len().min(0x20);
+            bytes[offset..offset + length].copy_from_slice(&string.as_bytes()[..length]);
+        }
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    /// The number of M-cycles between calls to PLAY.
+    pub fn play_period(&self) -> u64 {
+        if self.timer_control & 0b0100 == 0 {
+            return FRAME_CYCLES;
+        }
+        // the timer input clock period, from 4096Hz to 262144Hz
+        let input_period = match self.timer_control & 0b11 {
+            0b00 => 256,
+            0b01 => 4,
+            0b10 => 16,
+            _ => 64,
+        };
+        let period = input_period * (256 - u64::from(self.timer_modulo));
+        if self.timer_control & 0x80 != 0 {
+            period / 2
+        } else {
+            period
+        }
+    }
+
+    /// Returns the ROM that's mapped into memory: the data at the load
+    /// address, padded to a whole number of 16KiB banks, with the RST
+    /// vectors jumping to their relocated addresses after the load address.
+    pub fn rom_image(&self) -> Vec<u8> {
+        let mut image = vec![0x00; usize::from(self.load_address)];
+        if self.load_address >= 0x0040 {
+            for vector in (0x00..0x40).step_by(8) {
+                let jump = JP(self.load_address + vector as u16).to_bytes();
+                image[vector..vector + jump.len()].copy_from_slice(&jump);
+            }
+        }
+        image.extend(&self.data);
+        let banks = ((image.len() + 0x3FFF) / 0x4000).max(2);
+        image.resize(banks * 0x4000, 0x00);
+        image
+    }
+
+    /// Disassembles the code reachable from the INIT and PLAY routines.
+    pub fn disassemble(&self) -> DisassembledRom {
+        let mut rom = AssembledRom::from_bytes(&self.rom_image());
+        rom.get_known_instruction(self.init_address);
+        rom.get_known_instruction(self.play_address);
+        rom.disassemble()
+    }
+}
+
+/// Plays the songs in a GBS file on a [GameBoy] that only runs its CPU and
+/// APU, calling the file's routines as the game would.
+pub struct GbsPlayer {
+    gameboy: GameBoy,
+    gbs: GbsFile,
+    // the cycle at which PLAY is next due to be called
+    next_play: u64,
+}
+
+impl GbsPlayer {
+    pub fn new(gbs: GbsFile, output_buffer: Arc<Mutex<Output>>) -> Self {
+        let gameboy = GameBoy::builder()
+            .cartridge(Cartridge::for_gbs(gbs.rom_image()))
+            .model(Model::Dmg)
+            .skip_boot_rom()
+            // so that rendering the same song always gives the same audio
+            .ram_init(RamInit::Filled(0x00))
+            .output(output_buffer)
+            .build()
+            .expect("a GBS cartridge can always be emulated");
+        let mut player = Self {
+            gameboy,
+            gbs,
+            next_play: 0,
+        };
+        // nothing is running until a song is started
+        player.gameboy.set_pc(RETURN_ADDRESS);
+        player
+    }
+
+    pub fn gbs(&self) -> &GbsFile {
+        &self.gbs
+    }
+
+    /// The Game Boy the music is playing on, such as for recording it.
+    pub fn gameboy(&mut self) -> &mut GameBoy {
+        &mut self.gameboy
+    }
+
+    /// Starts playing the given song, counting from 1, by calling INIT.
+    pub fn start_song(&mut self, song: u8) {
+        let gameboy = &mut self.gameboy;
+        // the sound hardware is on, as it is after the boot ROM
+        gameboy.set_mem(0xFF26, 0x80);
+        gameboy.set_mem(0xFF25, 0xFF);
+        gameboy.set_mem(0xFF24, 0x77);
+
+        gameboy.set_register(A, song.wrapping_sub(1));
+        gameboy.set_register(SP, self.gbs.stack_pointer);
+        gameboy.stack_push(RETURN_ADDRESS);
+        gameboy.set_pc(self.gbs.init_address);
+        self.next_play = gameboy.t + self.gbs.play_period();
+    }
+
+    /// Runs for the given number of M-cycles, calling PLAY whenever it's due
+    /// and the previous call has returned.
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.gameboy.t + cycles;
+        while self.gameboy.t < end {
+            if self.gameboy.pc() != RETURN_ADDRESS {
+                // still in INIT or PLAY
+                let opex = self.gameboy.tick();
+                for _ in opex.t_0..opex.t_1 {
+                    self.gameboy.audio_cycle();
+                    self.gameboy.t += 1;
+                }
+            } else if self.gameboy.t >= self.next_play {
+                self.next_play += self.gbs.play_period();
+                let gameboy = &mut self.gameboy;
+                gameboy.stack_push(RETURN_ADDRESS);
+                gameboy.set_pc(self.gbs.play_address);
+            } else {
+                // the CPU is idle until the next call, but the APU isn't
+                while self.gameboy.t < end.min(self.next_play) {
+                    self.gameboy.audio_cycle();
+                    self.gameboy.t += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::str::FromStr;
+
+use super::cpu::CPUController;
+use super::save_state::{StateReader, StateWriter};
+use super::GameBoy;
+
+#[test]
+fn test_selected_buttons_are_read() {
+    let mut gameboy = GameBoy::builder().build().unwrap();
+    gameboy.press(Button::Start);
+    gameboy.press(Button::Left);
+
+    gameboy.set_p1(0b0010_0000);
+    assert_eq!(gameboy.p1(), 0b1110_1101);
+    gameboy.set_p1(0b0001_0000);
+    assert_eq!(gameboy.p1(), 0b1101_0111);
+    gameboy.set_p1(0b0011_0000);
+    assert_eq!(gameboy.p1(), 0b1111_1111);
+
+    gameboy.set_ift(0x00);
+    gameboy.set_p1(0b0001_0000);
+    gameboy.press(Button::A);
+    assert_eq!(gameboy.ift() & 0b1_0000, 0b1_0000);
+    assert_eq!(gameboy.buttons(), 0b1001_0010);
+    assert_eq!("arrowup".parse(), Ok(Button::Up));
+}
+
+#[test]
+fn test_stop_waits_for_a_selected_button() {
+    use zerodmg_codes::instruction::prelude::*;
+
+    use super::cpu::GetSetRegisters;
+    use super::test_roms::gameboy_for;
+
+    let mut gameboy = gameboy_for(&[(
+        0x0100,
+        vec![
+            LD(A, 0b0010_0000),
+            LD_8_TO_FF_IMMEDIATE(0x00),
+            STOP(0x00),
+            LD(B, 0x42),
+            JR(-2),
+        ],
+    )]);
+    gameboy.set_register(B, 0x00);
+    gameboy.run_cycles(100);
+    assert_eq!(gameboy.pc(), 0x0104);
+
+    // only the directions are selected
+    gameboy.press(Button::A);
+    gameboy.run_cycles(100);
+    assert_eq!(gameboy.pc(), 0x0104);
+    gameboy.press(Button::Down);
+    gameboy.run_cycles(100);
+    assert_eq!(gameboy.get_register(B), 0x42);
+}
+
+/// A button on the Game Boy, and its bit in [GameBoy::buttons].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub fn bit(self) -> u8 {
+        match self {
+            Button::Right => 0b0000_0001,
+            Button::Left => 0b0000_0010,
+            Button::Up => 0b0000_0100,
+            Button::Down => 0b0000_1000,
+            Button::A => 0b0001_0000,
+            Button::B => 0b0010_0000,
+            Button::Select => 0b0100_0000,
+            Button::Start => 0b1000_0000,
+        }
+    }
+}
+
+impl FromStr for Button {
+    type Err = String;
+
+    /// Parses a button's name, or the name of the key the web UI maps to it.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "right" | "arrowright" => Ok(Button::Right),
+            "left" | "arrowleft" => Ok(Button::Left),
+            "up" | "arrowup" => Ok(Button::Up),
+            "down" | "arrowdown" => Ok(Button::Down),
+            "a" => Ok(Button::A),
+            "b" => Ok(Button::B),
+            "select" | "space" | " " => Ok(Button::Select),
+            "start" | "enter" => Ok(Button::Start),
+            _ => Err(format!("unknown button: {}", s)),
+        }
+    }
+}
+
+/// Game Boy joypad state
+pub struct JoypadData {
+    // the bits of the P1 register selecting which buttons are read, which
+    // are active low
+    select: u8,
+    // the buttons being held, as in GameBoy::buttons
+    pressed: u8,
+}
+
+impl JoypadData {
+    pub fn new() -> Self {
+        Self {
+            select: 0b0011_0000,
+            pressed: 0x00,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+        state.u8(self.pressed);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u8(&mut self.select);
+        state.u8(&mut self.pressed);
+    }
+}
+
+pub trait JoypadController {
+    fn p1(&self) -> u8;
+    fn set_p1(&mut self, value: u8);
+    fn set_pressed(&mut self, pressed: u8);
+}
+
+impl JoypadController for GameBoy {
+    fn p1(&self) -> u8 {
+        let mut low = 0x00;
+        if self.joypad.select & 0b0001_0000 == 0 {
+            low |= self.joypad.pressed & 0x0F;
+        }
+        if self.joypad.select & 0b0010_0000 == 0 {
+            low |= self.joypad.pressed >> 4;
+        }
+        0b1100_0000 | self.joypad.select | (!low & 0x0F)
+    }
+
+    fn set_p1(&mut self, value: u8) {
+        self.joypad.select = value & 0b0011_0000;
+    }
+
+    /// Changes the buttons being held, requesting the joypad interrupt if a
+    /// selected line goes low.
+    fn set_pressed(&mut self, pressed: u8) {
+        let before = self.p1();
+        self.joypad.pressed = pressed;
+        if before & !self.p1() & 0x0F != 0 {
+            let ift = self.ift();
+            self.set_ift(ift | 0b0001_0000);
+        }
+    }
+}
+
+impl GameBoy {
+    /// The buttons being held, with a bit set for each as in [Button::bit].
+    pub fn buttons(&self) -> u8 {
+        self.joypad.pressed
+    }
+
+    /// Holds exactly the given buttons.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.set_pressed(buttons);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let buttons = self.buttons() | button.bit();
+        self.set_pressed(buttons);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let buttons = self.buttons() & !button.bit();
+        self.set_pressed(buttons);
+    }
+}
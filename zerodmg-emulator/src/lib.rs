@@ -4,29 +4,147 @@
 // #![warn(missing_docs, missing_debug_implementations)]
 
 mod audio;
+mod audio_debug;
+mod audio_output;
+mod bess;
+mod builder;
+mod callstack;
+mod cartridge;
 mod cpu;
+mod debugger;
+mod expression;
+mod filters;
+mod gbs;
+mod joypad;
 mod memory;
+mod model;
+mod movie;
+mod pacing;
+mod palette;
+mod rewind;
+mod save_state;
+mod serial;
+#[cfg(test)]
+mod test_roms;
+mod timer;
+mod vgm;
 mod video;
+mod watch;
+mod wav;
+
+pub use self::audio::{StereoSample, SAMPLE_RATE as AUDIO_SAMPLE_RATE};
+pub use self::audio_debug::{ChannelMix, ChannelStatus};
+pub use self::audio_output::{
+    AudioOutput, AudioRingBuffer, HighPassFilter, Resampler, SampleSink, AUDIO_FRAME_SIZE,
+};
+pub use self::builder::{GameBoyBuilder, RamInit};
+pub use self::callstack::{CallFrame, CallKind, Imbalance, StackImbalance};
+pub use self::cartridge::{Cartridge, CartridgeError, Mbc};
+pub use self::debugger::{parse_address, Breakpoint, Debugger, Registers, RunMode};
+pub use self::expression::Expression;
+pub use self::filters::{Filter, FilterChain};
+pub use self::gbs::{GbsError, GbsFile, GbsPlayer};
+pub use self::joypad::Button;
+pub use self::memory::{io_register_address, io_register_name};
+pub use self::model::Model;
+pub use self::movie::{Movie, MovieError, MovieRecorder};
+pub use self::pacing::{Pacer, PacingStats, Speed, CLOCK_RATE, FRAME_CYCLES, FRAME_RATE};
+pub use self::palette::{Palette, TilePalette};
+pub use self::rewind::RewindBuffer;
+pub use self::save_state::{LoadedState, StateError, STATE_VERSION};
+pub use self::serial::{SerialLog, SerialSink, SerialStdout};
+pub use self::vgm::VgmWriter;
+pub use self::video::Sprite;
+pub use self::watch::{Access, WatchHit, Watchpoint};
+pub use self::wav::{AudioRecorder, WavWriter};
 
 use self::audio::{AudioController, AudioData};
-use self::cpu::{CPUController, CPUData, InstructionExecution};
+use self::callstack::{CallStackController, CallStackData};
+use self::cpu::{CPUController, CPUData, InstructionExecution, InstructionSource};
+use self::joypad::JoypadData;
 use self::memory::MemoryData;
+use self::serial::SerialData;
+use self::timer::{TimerController, TimerData};
 use self::video::{VideoController, VideoData};
+use self::watch::{WatchController, WatchData};
 use std::clone::Clone;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::{Duration, SystemTime};
 
-use zerodmg_codes;
 
 const EXECUTIONS_BUFFER_SIZE: usize = 1024;
+// how long GameBoy::run_frame waits for vertical blanking before giving up,
+// such as while the LCD is off
+const MAX_FRAME_CYCLES: u64 = 4 * FRAME_CYCLES;
 use image::{DynamicImage, GenericImage, ImageBuffer};
+use zerodmg_codes::symbols::Symbols;
+
+#[test]
+fn test_run_frame_stops_at_vblank() {
+    let mut gameboy = GameBoy::new(Arc::new(Mutex::new(Output::new())));
+    assert_eq!(gameboy.run_frame(), StopReason::VBlank);
+    let first = gameboy.cycles();
+    assert_eq!(gameboy.run_frame(), StopReason::VBlank);
+    // instructions can overshoot the start of vertical blanking by a few
+    // cycles
+    let frame = gameboy.cycles() - first;
+    assert!(FRAME_CYCLES - 6 <= frame && frame <= FRAME_CYCLES + 6);
+}
+
+#[test]
+fn test_step_and_run_until() {
+    let mut gameboy = GameBoy::new(Arc::new(Mutex::new(Output::new())));
+    assert_eq!(gameboy.step(), StopReason::Stepped);
+    // the boot ROM starts by setting SP, a three-byte instruction
+    assert_eq!(gameboy.pc(), 0x0003);
+    assert_eq!(gameboy.cycles(), 3);
+
+    assert_eq!(gameboy.run_cycles(100), StopReason::CyclesElapsed);
+    assert!(gameboy.cycles() >= 103);
+
+    let reason = gameboy.run_until(10 * FRAME_CYCLES, |gameboy| gameboy.pc() == 0x000C);
+    assert_eq!(reason, StopReason::Predicate);
+    assert_eq!(gameboy.pc(), 0x000C);
+
+    let start = gameboy.cycles();
+    assert_eq!(gameboy.run_until(100, |_| false), StopReason::CyclesElapsed);
+    assert!(gameboy.cycles() >= start + 100);
+}
+
+#[test]
+fn test_take_recent_executions_keeps_the_latest() {
+    use zerodmg_codes::instruction::prelude::*;
+
+    let mut gameboy = test_roms::gameboy_for(&[(
+        0x0100,
+        vec![LD(A, 0x01), LD(B, 0x02), LD(C, 0x03), JR(-2)],
+    )]);
+    for _ in 0..3 {
+        gameboy.step();
+    }
+    let lines = gameboy.take_recent_executions(2);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&LD(B, 0x02).to_string()));
+    assert!(lines[1].starts_with(&LD(C, 0x03).to_string()));
+    assert!(gameboy.take_recent_executions(2).is_empty());
+}
 
 pub struct GameBoy {
     cpu: CPUData,
     mem: MemoryData,
     aud: AudioData,
     vid: VideoData,
+    serial: SerialData,
+    timer: TimerData,
+    joypad: JoypadData,
+    watch: WatchData,
+    calls: CallStackData,
+    // labels for addresses, shown in traces and the debugger
+    symbols: Symbols,
+
+    model: Model,
+    // whether CGB features are enabled, which requires both CGB hardware and
+    // a cartridge supporting it
+    cgb_mode: bool,
 
     debug_latest_executions: Vec<InstructionExecution>,
     debug_latest_executions_next_i: usize,
@@ -36,6 +154,27 @@ pub struct GameBoy {
     pub output_buffer: Arc<Mutex<Output>>,
 }
 
+/// Why [GameBoy::step] or one of the `run_` methods returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// One instruction was executed.
+    Stepped,
+    /// The requested number of cycles elapsed, or a frame took too long.
+    CyclesElapsed,
+    /// Vertical blanking started, after a frame was drawn.
+    VBlank,
+    /// The predicate returned true.
+    Predicate,
+    /// The debugger breakpoint with the given number was reached.
+    Breakpoint(usize),
+    /// The function the debugger was asked to finish returned.
+    Returned,
+    /// A watchpoint recorded an access.
+    Watchpoint(WatchHit),
+    /// The stack was left out of step with the calls made.
+    StackImbalance(StackImbalance),
+}
+
 pub struct Output {
     // Fully-Rendered Game Boy Display
     pub display: DynamicImage,
@@ -53,6 +192,26 @@ pub struct Output {
     pub bg_1: DynamicImage,
     // Sprites (Tile + Palette + Transform)
     pub sprites: DynamicImage,
+    // Decoded OAM entries for each sprite
+    pub oam: Vec<Sprite>,
+    // Display after post-processing by filters
+    pub screen: DynamicImage,
+    // Colours used to display the four DMG shades
+    pub palette: Palette,
+    // Post-processing applied to the display to produce the screen
+    pub filters: FilterChain,
+    // Palette used to colour the tile data viewer
+    pub tile_palette: TilePalette,
+    // Resampled audio, to be pulled in frames
+    pub audio: AudioOutput,
+    // Recent waveform and state of each sound channel
+    pub channel_scopes: Vec<DynamicImage>,
+    // Decoded state of each sound channel
+    pub channel_status: Vec<ChannelStatus>,
+    // Samples in wave RAM
+    pub wave_ram: DynamicImage,
+    // Which sound channels are heard, which may be changed while running
+    pub channel_mix: ChannelMix,
 }
 
 impl Default for Output {
@@ -90,13 +249,44 @@ impl Output {
 
         Self {
             display: filled(160, 144),
-            tiles: filled(128 + 15, 128 + 15),
+            tiles: filled(16 * 9 - 1, 24 * 9 - 1),
             bgp: filled(4, 1),
             op_0: filled(3, 1),
             op_1: filled(3, 1),
             bg_0: filled(256, 256),
             bg_1: filled(256, 256),
-            sprites: filled(80 + 4, 64 + 7),
+            sprites: filled(10 * 9 - 1, 4 * 17 - 1),
+            oam: vec![],
+            screen: filled(160, 144),
+            palette: Palette::default(),
+            filters: FilterChain::default(),
+            tile_palette: TilePalette::default(),
+            audio: AudioOutput::default(),
+            channel_scopes: vec![filled(256, 82); 4],
+            channel_status: vec![],
+            wave_ram: filled(256, 64),
+            channel_mix: ChannelMix::default(),
+        }
+    }
+
+    // Returns the output image with the given name, as used in URLs.
+    pub fn image(&self, name: &str) -> Option<&DynamicImage> {
+        match name {
+            "display" => Some(&self.display),
+            "screen" => Some(&self.screen),
+            "tiles" => Some(&self.tiles),
+            "bgp" => Some(&self.bgp),
+            "op_0" => Some(&self.op_0),
+            "op_1" => Some(&self.op_1),
+            "bg_0" => Some(&self.bg_0),
+            "bg_1" => Some(&self.bg_1),
+            "sprites" => Some(&self.sprites),
+            "ch1" => self.channel_scopes.get(0),
+            "ch2" => self.channel_scopes.get(1),
+            "ch3" => self.channel_scopes.get(2),
+            "ch4" => self.channel_scopes.get(3),
+            "wave_ram" => Some(&self.wave_ram),
+            _ => None,
         }
     }
 
@@ -134,44 +324,69 @@ impl Output {
 }
 
 impl GameBoy {
+    /// Creates a Game Boy running the built-in demo ROM. Use
+    /// [GameBoy::builder] to run anything else.
     pub fn new(output_buffer: Arc<Mutex<Output>>) -> Self {
-        use zerodmg_codes::roms::*;
-
-        let game_rom = jeb_demo().assemble().to_bytes();
-
-        Self {
-            cpu: CPUData::new(),
-            mem: MemoryData::new(game_rom),
-            aud: AudioData::new(),
-            vid: VideoData::new(),
-            t: 0,
-            debug_latest_executions: vec![],
-            debug_latest_executions_next_i: 0,
-            output_buffer,
-        }
+        Self::builder()
+            .output(output_buffer)
+            .build()
+            .expect("the demo ROM should be supported")
     }
 
-    pub fn print_recent_executions(&mut self, limit: usize) {
-        println!("; assembly:                        addr:         t|μs:   codes:");
-        println!("; ---------                        ------        -----   --------");
+    pub fn builder() -> GameBoyBuilder {
+        GameBoyBuilder::new()
+    }
 
-        let len = self.debug_latest_executions.len();
-        for i in 0..len.min(limit) {
-            let offset_i = (self.debug_latest_executions_next_i + i) % len;
-            let opex = &self.debug_latest_executions[offset_i];
-            self.print_execution(opex);
-        }
+    /// The cartridge that's inserted, including its RAM.
+    pub fn cartridge(&self) -> &Cartridge {
+        self.mem.cartridge()
+    }
 
+    /// Formats the latest instructions executed since this was last called,
+    /// up to the given number, oldest first, and clears them all from the
+    /// buffer.
+    pub fn take_recent_executions(&mut self, limit: usize) -> Vec<String> {
+        let lines = self.latest_executions(limit);
         self.debug_latest_executions.clear();
         self.debug_latest_executions_next_i = 0;
 
-        println!();
+        lines
     }
 
-    fn print_execution(&self, opex: &InstructionExecution) {
-        print!("{:32}", format!("{}", opex.instruction));
-        print!(" ; {:6}", opex.source);
-        print!(" ; {:10}", opex.t_0);
+    /// Formats the latest instructions executed, up to the given number,
+    /// oldest first, in the same format as [GameBoy::take_recent_executions]
+    /// but leaving them in the buffer.
+    pub fn latest_executions(&self, limit: usize) -> Vec<String> {
+        let len = self.debug_latest_executions.len();
+        (len - len.min(limit)..len)
+            .map(|i| {
+                let offset_i = (self.debug_latest_executions_next_i + i) % len;
+                self.format_execution(&self.debug_latest_executions[offset_i])
+            }).collect()
+    }
+
+    // the instruction executed most recently
+    fn last_execution(&self) -> Option<&InstructionExecution> {
+        let len = self.debug_latest_executions.len();
+        if len == 0 {
+            return None;
+        }
+        let i = (self.debug_latest_executions_next_i + len - 1) % len;
+        Some(&self.debug_latest_executions[i])
+    }
+
+    fn format_execution(&self, opex: &InstructionExecution) -> String {
+        // addresses are shown relative to the closest label, if any
+        let (address, source) = match opex.source {
+            InstructionSource::ProgramCounter(pc) => (pc, self.symbols.describe(opex.bank, pc)),
+            InstructionSource::Interrupt(_) => (0, opex.source.to_string()),
+        };
+        let instruction =
+            self.symbols
+                .format_instruction(opex.instruction, address, &|target| self.bank_at(target));
+        let mut line = format!("{:32}", instruction);
+        line.push_str(&format!(" ; {:6}", source));
+        line.push_str(&format!(" ; {:10}", opex.t_0));
         let code = opex
             .instruction
             .to_bytes()
@@ -179,115 +394,111 @@ impl GameBoy {
             .map(|c| format!("{:02X}", c))
             .collect::<Vec<String>>()
             .join("");
-        print!(" ; 0x{:8}", code);
+        line.push_str(&format!(" ; 0x{:8}", code));
         if let Some(ref tracer) = opex.tracer {
             let trace = tracer();
-            print!(" ; {}", trace);
+            line.push_str(&format!(" ; {}", trace));
         }
-        println!();
+        line
     }
 
-    pub fn run(&mut self) -> ! {
-        let log_size = EXECUTIONS_BUFFER_SIZE.min(32);
-        let log_interval = (1024 * 1024) / 2;
+    /// The number of M-cycles the hardware has run for, which in double
+    /// speed mode is half the number of CPU cycles.
+    pub fn cycles(&self) -> u64 {
+        self.t
+    }
 
-        let mut last_color: &'static str = "";
-        let red = "\x1b[91m";
-        let _green = "\x1b[92m";
-        let yellow = "\x1b[93m";
-        let _blue = "\x1b[94m";
-        let clear = "\x1b[0m";
+    /// The address of the next instruction to execute.
+    pub fn pc(&self) -> u16 {
+        CPUController::pc(self)
+    }
 
-        // using Instant seems to produced very unsteady results
-        let start_time = SystemTime::now();
-        let sync_time_every_ticks = 1024 * 4;
-        let mut sync_time_at_tick = sync_time_every_ticks;
+    /// Executes one instruction, or dispatches one interrupt, and runs the
+    /// rest of the hardware for as long as that took.
+    pub fn step(&mut self) -> StopReason {
+        self.step_instruction();
+        StopReason::Stepped
+    }
 
-        loop {
-            let opex = self.tick();
+    /// Runs whole instructions until at least the given number of M-cycles
+    /// have elapsed.
+    pub fn run_cycles(&mut self, cycles: u64) -> StopReason {
+        let end = self.t + cycles;
+        while self.t < end {
+            self.step_instruction();
+        }
+        StopReason::CyclesElapsed
+    }
 
-            let t_0 = opex.t_0;
-            let t_1 = opex.t_1;
+    /// Runs until the instruction during which vertical blanking starts,
+    /// when a complete frame has been drawn, or until a few frames' worth of
+    /// cycles have elapsed without it.
+    pub fn run_frame(&mut self) -> StopReason {
+        let end = self.t + MAX_FRAME_CYCLES;
+        while self.t < end {
+            if self.step_instruction() {
+                return StopReason::VBlank;
+            }
+        }
+        StopReason::CyclesElapsed
+    }
 
-            if self.debug_latest_executions.len() < EXECUTIONS_BUFFER_SIZE {
-                self.debug_latest_executions.push(opex);
-            } else {
-                self.debug_latest_executions[self.debug_latest_executions_next_i] = opex;
+    /// Runs until the predicate, which is checked after each instruction,
+    /// returns true, or until at least the given number of M-cycles have
+    /// elapsed.
+    pub fn run_until<F>(&mut self, cycles: u64, mut predicate: F) -> StopReason
+    where
+        F: FnMut(&GameBoy) -> bool,
+    {
+        let end = self.t + cycles;
+        while self.t < end {
+            self.step_instruction();
+            if predicate(self) {
+                return StopReason::Predicate;
             }
+        }
+        StopReason::CyclesElapsed
+    }
 
-            self.debug_latest_executions_next_i =
-                (self.debug_latest_executions_next_i + 1) % EXECUTIONS_BUFFER_SIZE;
+    /// Executes one instruction and runs the rest of the hardware alongside
+    /// it, returning whether vertical blanking started.
+    fn step_instruction(&mut self) -> bool {
+        self.begin_call_tracking();
+        let opex = self.tick();
+        self.track_calls(&opex);
 
-            let mut should_log = false;
+        let t_0 = opex.t_0;
+        let t_1 = opex.t_1;
 
-            for _t in t_0..t_1 {
-                self.video_cycle();
-                self.audio_cycle();
+        if self.debug_latest_executions.len() < EXECUTIONS_BUFFER_SIZE {
+            self.debug_latest_executions.push(opex);
+        } else {
+            self.debug_latest_executions[self.debug_latest_executions_next_i] = opex;
+        }
 
-                if (self.t + log_interval - log_interval.min(log_size as u64)) % log_interval == 0 {
-                    should_log = true;
-                }
+        self.debug_latest_executions_next_i =
+            (self.debug_latest_executions_next_i + 1) % EXECUTIONS_BUFFER_SIZE;
 
-                self.t += 1;
-            }
+        let mut vblank_started = false;
+        for t in t_0..t_1 {
+            self.timer_cycle();
 
-            if self.t >= sync_time_at_tick {
-                sync_time_at_tick += sync_time_every_ticks;
-
-                // duration by which we allow internal time to slip ahead of real time,
-                // for the sake of doing several operations in a batch, rather than
-                // sleeping between each of them
-                const BATCH_MARGIN: Duration = Duration::from_millis(8);
-                const MAX_LAG: Duration = Duration::from_millis(8);
-                const ZERO: Duration = Duration::from_secs(0);
-
-                // TODO: this is exactly 1MHz, which is wrong.
-                let internal_elapsed =
-                    Duration::new(self.t / 1000000, ((self.t * 1000) % 1000000000) as u32);
-                let wall_elapsed = start_time.elapsed().expect("failed to get elapsed time?!");
-
-                let skew_ahead = if internal_elapsed > wall_elapsed {
-                    internal_elapsed - wall_elapsed
-                } else {
-                    ZERO
-                };
-                let skew_behind = if wall_elapsed > internal_elapsed {
-                    wall_elapsed - internal_elapsed
-                } else {
-                    ZERO
-                };
-
-                if skew_ahead > BATCH_MARGIN {
-                    // going too fast -- sleep a bit
-                    thread::sleep(skew_ahead);
-                    if clear != last_color {
-                        last_color = clear;
-                        print!("{}", clear);
-                    }
-                } else if skew_ahead > ZERO {
-                    // going good
-                    if clear != last_color {
-                        last_color = clear;
-                        print!("{}", clear);
-                    }
-                } else if skew_behind < MAX_LAG {
-                    // going a bit slow
-                    if yellow != last_color {
-                        last_color = yellow;
-                        print!("{}", yellow);
-                    }
-                } else {
-                    // going waaay too slow! crap!
-                    if red != last_color {
-                        last_color = red;
-                        print!("{}", red);
-                    }
-                }
+            // in double speed mode, the rest of the hardware only runs
+            // every other CPU cycle
+            if self.double_speed() && t % 2 == 1 {
+                continue;
             }
 
-            if should_log {
-                self.print_recent_executions(log_size);
+            self.video_cycle();
+            self.audio_cycle();
+
+            if self.vblank_started() {
+                vblank_started = true;
             }
+
+            self.t += 1;
         }
+        self.watch_execute();
+        vblank_started
     }
 }
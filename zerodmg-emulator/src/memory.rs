@@ -1,123 +1,448 @@
 use super::GameBoy;
 
+use zerodmg_codes::instruction::U8Register;
+use zerodmg_utils::little_endian::{u16_to_u8s, u8s_to_u16};
+
 use super::audio::AudioController;
-use super::cpu::CPUController;
+use super::builder::RamInitializer;
+use super::cartridge::Cartridge;
+use super::cpu::{CPUController, GetSetRegisters};
+use super::joypad::JoypadController;
+use super::model::Model;
+use super::save_state::{StateReader, StateWriter};
+use super::serial::SerialController;
+use super::timer::TimerController;
 use super::video::VideoController;
+use super::watch::WatchController;
 
 /// Game Boy general memory state
 pub struct MemoryData {
-    wram: [u8; 0x2000],
+    // work RAM, with banks 2-7 only used in CGB mode
+    wram: [u8; 0x8000],
+    // WRAM bank select register (CGB)
+    svbk: u8,
+    // HDMA source and destination addresses (CGB)
+    hdma_source: u16,
+    hdma_destination: u16,
+    // remaining 16-byte blocks in the current or cancelled HDMA transfer
+    hdma_blocks: u8,
+    // whether an HBlank HDMA transfer is in progress
+    hdma_active: bool,
     stack_ram: [u8; 0x80],
+    // the DMG boot ROM, or a CGB boot ROM which is also mapped at
+    // 0x0200-0x08FF
     boot_rom: Vec<u8>,
-    game_rom: Vec<u8>,
+    cartridge: Cartridge,
     boot_rom_mapped: bool,
 }
 
 impl MemoryData {
-    pub fn new(game_rom: Vec<u8>) -> Self {
+    /// Creates memory with the given cartridge inserted, starting with the
+    /// boot ROM mapped if there is one.
+    pub fn new(cartridge: Cartridge, boot_rom: Option<Vec<u8>>, ram: &mut RamInitializer) -> Self {
         Self {
             wram: {
-                let mut a = [0u8; 0x2000];
-                for x in a.iter_mut() {
-                    *x = rand::random();
-                }
+                let mut a = [0u8; 0x8000];
+                ram.fill(&mut a);
                 a
             },
+            svbk: 0x00,
+            hdma_source: 0x0000,
+            hdma_destination: 0x0000,
+            hdma_blocks: 0,
+            hdma_active: false,
             stack_ram: {
                 let mut a = [0u8; 0x80];
-                for x in a.iter_mut() {
-                    *x = rand::random();
-                }
+                ram.fill(&mut a);
                 a
             },
-            game_rom,
-            boot_rom: zerodmg_codes::roms::dmg_boot().to_bytes(),
-            boot_rom_mapped: true,
+            cartridge,
+            boot_rom_mapped: boot_rom.is_some(),
+            boot_rom: boot_rom.unwrap_or_default(),
         }
     }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    pub fn wram_bytes(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn wram_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.wram
+    }
+
+    /// High RAM, at 0xFF80-0xFFFE.
+    pub fn hram_bytes(&self) -> &[u8] {
+        &self.stack_ram[..0x7F]
+    }
+
+    pub fn hram_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.stack_ram[..0x7F]
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    pub fn set_boot_rom_mapped(&mut self, mapped: bool) {
+        self.boot_rom_mapped = mapped;
+    }
+
+    /// Saves the state of memory, other than the cartridge.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.wram);
+        state.u8(self.svbk);
+        state.u16(self.hdma_source);
+        state.u16(self.hdma_destination);
+        state.u8(self.hdma_blocks);
+        state.bool(self.hdma_active);
+        state.bytes(&self.stack_ram);
+        state.bool(self.boot_rom_mapped);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.bytes(&mut self.wram);
+        state.u8(&mut self.svbk);
+        state.u16(&mut self.hdma_source);
+        state.u16(&mut self.hdma_destination);
+        state.u8(&mut self.hdma_blocks);
+        state.bool(&mut self.hdma_active);
+        state.bytes(&mut self.stack_ram);
+        state.bool(&mut self.boot_rom_mapped);
+    }
+
+    // whether the address is in the mapped part of the boot ROM
+    fn in_boot_rom(&self, addr: u16) -> bool {
+        self.boot_rom_mapped
+            && (addr <= 0x00FF
+                || (0x0200 <= addr && addr <= 0x08FF && self.boot_rom.len() > 0x0100))
+    }
+}
+
+/// Whether an IO register, at 0xFF00-0xFF7F, is mapped to anything, rather
+/// than unused.
+pub fn io_register_mapped(addr: u16) -> bool {
+    match addr {
+        0xFF00 | 0xFF01 | 0xFF02 | 0xFF0F | 0xFF4D | 0xFF4F | 0xFF50 | 0xFF70 => true,
+        0xFF04..=0xFF07 => true,
+        0xFF10..=0xFF3F | 0xFF40..=0xFF4B | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B => true,
+        _ => false,
+    }
+}
+
+/// The names of the IO registers, and IE, as used in hardware.inc without
+/// their `r` prefix.
+pub const IO_REGISTER_NAMES: &[(u16, &str)] = &[
+    (0xFF00, "P1"),
+    (0xFF01, "SB"),
+    (0xFF02, "SC"),
+    (0xFF04, "DIV"),
+    (0xFF05, "TIMA"),
+    (0xFF06, "TMA"),
+    (0xFF07, "TAC"),
+    (0xFF0F, "IF"),
+    (0xFF10, "NR10"),
+    (0xFF11, "NR11"),
+    (0xFF12, "NR12"),
+    (0xFF13, "NR13"),
+    (0xFF14, "NR14"),
+    (0xFF16, "NR21"),
+    (0xFF17, "NR22"),
+    (0xFF18, "NR23"),
+    (0xFF19, "NR24"),
+    (0xFF1A, "NR30"),
+    (0xFF1B, "NR31"),
+    (0xFF1C, "NR32"),
+    (0xFF1D, "NR33"),
+    (0xFF1E, "NR34"),
+    (0xFF20, "NR41"),
+    (0xFF21, "NR42"),
+    (0xFF22, "NR43"),
+    (0xFF23, "NR44"),
+    (0xFF24, "NR50"),
+    (0xFF25, "NR51"),
+    (0xFF26, "NR52"),
+    (0xFF40, "LCDC"),
+    (0xFF41, "STAT"),
+    (0xFF42, "SCY"),
+    (0xFF43, "SCX"),
+    (0xFF44, "LY"),
+    (0xFF45, "LYC"),
+    (0xFF46, "DMA"),
+    (0xFF47, "BGP"),
+    (0xFF48, "OBP0"),
+    (0xFF49, "OBP1"),
+    (0xFF4A, "WY"),
+    (0xFF4B, "WX"),
+    (0xFF4D, "KEY1"),
+    (0xFF4F, "VBK"),
+    (0xFF50, "BOOT"),
+    (0xFF51, "HDMA1"),
+    (0xFF52, "HDMA2"),
+    (0xFF53, "HDMA3"),
+    (0xFF54, "HDMA4"),
+    (0xFF55, "HDMA5"),
+    (0xFF68, "BCPS"),
+    (0xFF69, "BCPD"),
+    (0xFF6A, "OCPS"),
+    (0xFF6B, "OCPD"),
+    (0xFF70, "SVBK"),
+    (0xFFFF, "IE"),
+];
+
+/// The name of the IO register at an address, if it has one.
+pub fn io_register_name(addr: u16) -> Option<&'static str> {
+    IO_REGISTER_NAMES
+        .iter()
+        .find(|&&(a, _)| a == addr)
+        .map(|&(_, name)| name)
+}
+
+/// The address of an IO register, by name, with or without the `r` prefix
+/// and ignoring case.
+pub fn io_register_address(name: &str) -> Option<u16> {
+    let unprefixed = if name.starts_with('r') || name.starts_with('R') {
+        &name[1..]
+    } else {
+        name
+    };
+    IO_REGISTER_NAMES
+        .iter()
+        .find(|&&(_, n)| n.eq_ignore_ascii_case(name) || n.eq_ignore_ascii_case(unprefixed))
+        .map(|&(addr, _)| addr)
 }
 
 pub trait MemoryController {
     fn mem(&self, addr: u16) -> u8;
     fn set_mem(&mut self, addr: u16, value: u8);
+    fn wram_bank(&self) -> usize;
+    fn svbk(&self) -> u8;
+    fn set_svbk(&mut self, value: u8);
+    fn hdma5(&self) -> u8;
+    fn set_hdma5(&mut self, value: u8);
+    fn hdma_hblank(&mut self);
+    fn hdma_copy_block(&mut self);
+    fn unmap_boot_rom(&mut self);
 }
 
 impl MemoryController for GameBoy {
     fn mem(&self, addr: u16) -> u8 {
-        if self.mem.boot_rom_mapped && addr <= 0x00FF {
+        let value = if self.mem.in_boot_rom(addr) {
             // boot ROM, until unmapped to expose initial bytes of game ROM
-            self.mem.boot_rom[addr as usize]
+            self.mem.boot_rom.get(addr as usize).cloned().unwrap_or(0xFF)
         } else if addr <= 0x7FFF {
-            // println!("    ; game_rom[0x{:02X}] == 0x{:02X}", addr, self.mem.game_rom[addr as usize]);
-            // first page of game ROM
-            self.mem.game_rom[addr as usize]
+            // game ROM, with banks switched by the cartridge's MBC
+            self.mem.cartridge.read_rom(addr)
         } else if 0x8000 <= addr && addr <= 0x9FFF {
             let i: usize = (addr - 0x8000) as usize;
             self.vram(i)
-        } else if 0xC000 <= addr && addr <= 0xDFFF {
+        } else if 0xA000 <= addr && addr <= 0xBFFF {
+            self.mem.cartridge.read_ram(addr - 0xA000)
+        } else if 0xC000 <= addr && addr <= 0xCFFF {
             let i: usize = (addr - 0xC000) as usize;
             self.mem.wram[i]
+        } else if 0xD000 <= addr && addr <= 0xDFFF {
+            let i: usize = (addr - 0xD000) as usize;
+            self.mem.wram[self.wram_bank() * 0x1000 + i]
+        } else if 0xFE00 <= addr && addr <= 0xFE9F {
+            let i: usize = (addr - 0xFE00) as usize;
+            self.oam(i)
+        } else if 0xFEA0 <= addr && addr <= 0xFEFF {
+            // unusable
+            0x00
         } else if 0xFF80 <= addr && addr <= 0xFFFE {
             let i: usize = (addr - 0xFF80) as usize;
             self.mem.stack_ram[i]
-        } else if 0xFF10 <= addr && addr <= 0xFF26 {
+        } else if 0xFF10 <= addr && addr <= 0xFF2F {
             let i = (addr - 0xFF10) as usize;
             self.audio_register(i)
+        } else if 0xFF30 <= addr && addr <= 0xFF3F {
+            let i = (addr - 0xFF30) as usize;
+            self.wave_ram(i)
         } else if addr == 0xFF40 {
             self.lcdc()
+        } else if addr == 0xFF41 {
+            self.stat()
         } else if addr == 0xFF42 {
             self.scy()
         } else if addr == 0xFF43 {
             self.scx()
         } else if addr == 0xFF44 {
             self.ly()
+        } else if addr == 0xFF45 {
+            self.lyc()
+        } else if addr == 0xFF46 {
+            self.dma()
         } else if addr == 0xFF47 {
             self.bgp()
+        } else if addr == 0xFF48 {
+            self.obp0()
+        } else if addr == 0xFF49 {
+            self.obp1()
+        } else if addr == 0xFF4A {
+            self.wy()
+        } else if addr == 0xFF4B {
+            self.wx()
         } else if addr == 0xFF50 {
             if self.mem.boot_rom_mapped {
                 0x01
             } else {
                 0x00
             }
+        } else if addr == 0xFF4D {
+            self.key1()
+        } else if addr == 0xFF4F {
+            self.vbk()
+        } else if 0xFF51 <= addr && addr <= 0xFF54 {
+            // HDMA source and destination are write-only
+            0xFF
+        } else if addr == 0xFF55 {
+            self.hdma5()
+        } else if addr == 0xFF68 {
+            self.bcps()
+        } else if addr == 0xFF69 {
+            self.bcpd()
+        } else if addr == 0xFF6A {
+            self.ocps()
+        } else if addr == 0xFF6B {
+            self.ocpd()
+        } else if addr == 0xFF70 {
+            self.svbk()
+        } else if addr == 0xFF00 {
+            self.p1()
+        } else if addr == 0xFF01 {
+            self.sb()
+        } else if addr == 0xFF02 {
+            self.sc()
+        } else if addr == 0xFF04 {
+            self.div()
+        } else if addr == 0xFF05 {
+            self.tima()
+        } else if addr == 0xFF06 {
+            self.tma()
+        } else if addr == 0xFF07 {
+            self.tac()
         } else if addr == 0xFF0F {
             self.ift()
         } else if addr == 0xFFFF {
             self.ie()
         } else {
             panic!("I don't know how to get memory address 0x{:04X}.", addr);
-        }
+        };
+        self.watch_read(addr, value);
+        value
     }
 
     fn set_mem(&mut self, addr: u16, value: u8) {
-        if 0x8000 <= addr && addr <= 0x9FFF {
+        self.watch_write(addr, value);
+        if addr <= 0x7FFF {
+            // cartridge MBC control
+            self.mem.cartridge.write_rom(addr, value);
+        } else if 0x8000 <= addr && addr <= 0x9FFF {
             let i: usize = (addr - 0x8000) as usize;
             self.set_vram(i, value);
-        } else if 0xC000 <= addr && addr <= 0xDFFF {
+        } else if 0xA000 <= addr && addr <= 0xBFFF {
+            self.mem.cartridge.write_ram(addr - 0xA000, value);
+        } else if 0xC000 <= addr && addr <= 0xCFFF {
             let i: usize = (addr - 0xC000) as usize;
             self.mem.wram[i] = value;
+        } else if 0xD000 <= addr && addr <= 0xDFFF {
+            let i: usize = (addr - 0xD000) as usize;
+            let bank = self.wram_bank();
+            self.mem.wram[bank * 0x1000 + i] = value;
+        } else if 0xFE00 <= addr && addr <= 0xFE9F {
+            let i: usize = (addr - 0xFE00) as usize;
+            self.set_oam(i, value);
+        } else if 0xFEA0 <= addr && addr <= 0xFEFF {
+            // unusable
         } else if 0xFF80 <= addr && addr <= 0xFFFE {
             let i: usize = (addr - 0xFF80) as usize;
             self.mem.stack_ram[i] = value;
-        } else if 0xFF10 <= addr && addr <= 0xFF26 {
+        } else if 0xFF10 <= addr && addr <= 0xFF2F {
             let i = (addr - 0xFF10) as usize;
             self.set_audio_register(i, value);
+        } else if 0xFF30 <= addr && addr <= 0xFF3F {
+            let i = (addr - 0xFF30) as usize;
+            self.set_wave_ram(i, value);
         } else if addr == 0xFF40 {
             self.set_lcdc(value);
+        } else if addr == 0xFF41 {
+            self.set_stat(value);
         } else if addr == 0xFF42 {
             self.set_scy(value);
         } else if addr == 0xFF43 {
             self.set_scx(value);
         } else if addr == 0xFF44 {
             self.set_ly(value);
+        } else if addr == 0xFF45 {
+            self.set_lyc(value);
+        } else if addr == 0xFF46 {
+            self.set_dma(value);
         } else if addr == 0xFF47 {
             self.set_bgp(value);
+        } else if addr == 0xFF48 {
+            self.set_obp0(value);
+        } else if addr == 0xFF49 {
+            self.set_obp1(value);
+        } else if addr == 0xFF4A {
+            self.set_wy(value);
+        } else if addr == 0xFF4B {
+            self.set_wx(value);
         } else if addr == 0xFF50 {
             if value != 0x01 {
                 panic!(
                     "got unexpected value (not 0x01) written to 0xFF50 boot rom disable register"
                 );
             }
-            self.mem.boot_rom_mapped = false;
+            self.unmap_boot_rom();
+        } else if addr == 0xFF4D {
+            self.set_key1(value);
+        } else if addr == 0xFF4F {
+            self.set_vbk(value);
+        } else if 0xFF51 <= addr && addr <= 0xFF54 {
+            let [source_low, source_high] = u16_to_u8s(self.mem.hdma_source);
+            let [destination_low, destination_high] = u16_to_u8s(self.mem.hdma_destination);
+            match addr {
+                0xFF51 => self.mem.hdma_source = u8s_to_u16(source_low, value),
+                0xFF52 => self.mem.hdma_source = u8s_to_u16(value & 0xF0, source_high),
+                0xFF53 => {
+                    self.mem.hdma_destination = u8s_to_u16(destination_low, value & 0x1F)
+                }
+                _ => self.mem.hdma_destination = u8s_to_u16(value & 0xF0, destination_high),
+            }
+        } else if addr == 0xFF55 {
+            self.set_hdma5(value);
+        } else if addr == 0xFF68 {
+            self.set_bcps(value);
+        } else if addr == 0xFF69 {
+            self.set_bcpd(value);
+        } else if addr == 0xFF6A {
+            self.set_ocps(value);
+        } else if addr == 0xFF6B {
+            self.set_ocpd(value);
+        } else if addr == 0xFF70 {
+            self.set_svbk(value);
+        } else if addr == 0xFF00 {
+            self.set_p1(value);
+        } else if addr == 0xFF01 {
+            self.set_sb(value);
+        } else if addr == 0xFF02 {
+            self.set_sc(value);
+        } else if addr == 0xFF04 {
+            self.set_div(value);
+        } else if addr == 0xFF05 {
+            self.set_tima(value);
+        } else if addr == 0xFF06 {
+            self.set_tma(value);
+        } else if addr == 0xFF07 {
+            self.set_tac(value);
         } else if addr == 0xFF0F {
             self.set_ift(value);
         } else if addr == 0xFFFF {
@@ -129,4 +454,100 @@ impl MemoryController for GameBoy {
             );
         }
     }
+
+    /// Returns the WRAM bank mapped at 0xD000, which is always 1 in DMG mode.
+    fn wram_bank(&self) -> usize {
+        if self.cgb_mode {
+            usize::from(self.mem.svbk & 0b111).max(1)
+        } else {
+            1
+        }
+    }
+
+    fn svbk(&self) -> u8 {
+        if self.cgb_mode {
+            0b1111_1000 | self.mem.svbk
+        } else {
+            0xFF
+        }
+    }
+
+    fn set_svbk(&mut self, value: u8) {
+        if self.cgb_mode {
+            self.mem.svbk = value & 0b111;
+        }
+    }
+
+    fn hdma5(&self) -> u8 {
+        if !self.cgb_mode || self.mem.hdma_blocks == 0 {
+            0xFF
+        } else if self.mem.hdma_active {
+            self.mem.hdma_blocks - 1
+        } else {
+            0b1000_0000 | (self.mem.hdma_blocks - 1)
+        }
+    }
+
+    /// Starts a general-purpose or HBlank HDMA transfer, or cancels an active
+    /// HBlank transfer.
+    fn set_hdma5(&mut self, value: u8) {
+        if !self.cgb_mode {
+            return;
+        }
+
+        if self.mem.hdma_active && value & 0b1000_0000 == 0 {
+            self.mem.hdma_active = false;
+            return;
+        }
+
+        self.mem.hdma_blocks = (value & 0b0111_1111) + 1;
+        if value & 0b1000_0000 == 0 {
+            // general-purpose transfers happen all at once
+            while self.mem.hdma_blocks > 0 {
+                self.hdma_copy_block();
+                self.mem.hdma_blocks -= 1;
+            }
+        } else {
+            self.mem.hdma_active = true;
+        }
+    }
+
+    /// Copies the next block of an active HBlank HDMA transfer.
+    fn hdma_hblank(&mut self) {
+        if !self.mem.hdma_active {
+            return;
+        }
+
+        self.hdma_copy_block();
+        self.mem.hdma_blocks -= 1;
+        if self.mem.hdma_blocks == 0 {
+            self.mem.hdma_active = false;
+        }
+    }
+
+    fn hdma_copy_block(&mut self) {
+        for i in 0..0x10 {
+            let value = self.mem(self.mem.hdma_source.wrapping_add(i));
+            let index = usize::from(self.mem.hdma_destination.wrapping_add(i) & 0x1FFF);
+            self.set_vram(index, value);
+        }
+        self.mem.hdma_source = self.mem.hdma_source.wrapping_add(0x10);
+        self.mem.hdma_destination = self.mem.hdma_destination.wrapping_add(0x10) & 0x1FFF;
+    }
+
+    /// Unmaps the boot ROM, exposing the start of the game ROM.
+    ///
+    /// Unless we ran a CGB boot ROM, on a CGB we then fake the parts of the
+    /// state that it would have left differently from the DMG boot ROM.
+    fn unmap_boot_rom(&mut self) {
+        self.mem.boot_rom_mapped = false;
+
+        if self.model == Model::Cgb && self.mem.boot_rom.len() <= 0x0100 {
+            // games check for this value of A to detect a CGB
+            self.set_register(U8Register::A, 0x11);
+            if !self.cgb_mode {
+                self.set_compatibility_palettes();
+            }
+        }
+    }
 }
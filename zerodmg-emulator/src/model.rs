@@ -0,0 +1,48 @@
+use std::str::FromStr;
+
+/// Game Boy hardware models we can emulate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// The original Game Boy.
+    Dmg,
+    /// The Game Boy Color.
+    Cgb,
+}
+
+impl Model {
+    /// Returns the model a cartridge asks for with the CGB flag in its header
+    /// (0x0143), which is set for both CGB-enhanced and CGB-only games.
+    pub fn from_header(game_rom: &[u8]) -> Self {
+        match game_rom.get(0x0143) {
+            Some(flag) if flag & 0x80 != 0 => Model::Cgb,
+            _ => Model::Dmg,
+        }
+    }
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::Dmg
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    /// Parses `dmg` or `cgb`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dmg" => Ok(Model::Dmg),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("unknown model: {}", s)),
+        }
+    }
+}
+
+/// The RGB555 background palette the CGB boot ROM installs for DMG games it
+/// doesn't recognize.
+pub const DMG_COMPATIBILITY_BG_PALETTE: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+
+/// The RGB555 object palettes the CGB boot ROM installs for DMG games it
+/// doesn't recognize.
+pub const DMG_COMPATIBILITY_OBJ_PALETTE: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
@@ -0,0 +1,254 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use zerodmg_utils::little_endian::{u16_to_u8s, u32_to_u8s, u8s_to_u16, u8s_to_u32};
+
+use super::builder::{GameBoyBuilder, RamInit};
+use super::cartridge::CartridgeError;
+use super::save_state::StateError;
+use super::GameBoy;
+
+#[test]
+fn test_movie_plays_back_and_detects_desyncs() {
+    use super::joypad::Button;
+
+    let mut gameboy = GameBoy::builder()
+        .ram_init(RamInit::Seeded(5))
+        .build()
+        .unwrap();
+    let mut recorder = MovieRecorder::new(vec![], &gameboy, 5, 4).unwrap();
+    for frame in 0..18 {
+        gameboy.set_buttons(if frame % 3 == 0 { Button::A.bit() } else { 0x00 });
+        gameboy.run_frame();
+        recorder.record_frame(&gameboy).unwrap();
+    }
+    let bytes = recorder.into_inner();
+
+    let movie = Movie::from_bytes(&bytes).unwrap();
+    assert_eq!(movie.len(), 18);
+    let played = movie.play(GameBoy::builder()).unwrap();
+    assert_eq!(played.save_state(), gameboy.save_state());
+
+    // a movie cut off while recording can still be played, up to the last
+    // complete frame
+    let movie = Movie::from_bytes(&bytes[..bytes.len() - 3]).unwrap();
+    assert_eq!(movie.len(), 15);
+    movie.play(GameBoy::builder()).unwrap();
+
+    // different input is detected at the next hash
+    let mut changed = bytes.clone();
+    let first_frame = bytes.len() - 18 - 4 * 8;
+    changed[first_frame + 3] = Button::B.bit();
+    let movie = Movie::from_bytes(&changed).unwrap();
+    assert_eq!(
+        movie.play(GameBoy::builder()).err(),
+        Some(MovieError::Desync { frame: 4 })
+    );
+}
+
+const SIGNATURE: &[u8; 8] = b"0DMGMOVI";
+const MOVIE_VERSION: u16 = 1;
+
+/// Why a movie couldn't be loaded or played back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    InvalidSignature,
+    UnsupportedVersion(u16),
+    /// The movie ends before its starting state does.
+    Truncated,
+    /// The starting state couldn't be loaded.
+    State(StateError),
+    Cartridge(CartridgeError),
+    /// The hardware state after the given frame, counting from 1, doesn't
+    /// match the hash recorded for it.
+    Desync { frame: usize },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::InvalidSignature => write!(f, "not a zerodmg movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::State(error) => write!(f, "can't load movie's starting state: {}", error),
+            MovieError::Cartridge(error) => write!(f, "can't run ROM: {}", error),
+            MovieError::Desync { frame } => write!(f, "movie desynced at frame {}", frame),
+        }
+    }
+}
+
+// identifies the state of the hardware after a frame
+fn state_hash(gameboy: &GameBoy) -> u64 {
+    // FNV-1a
+    gameboy
+        .save_state()
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+        })
+}
+
+/// A recording of the buttons held during each frame, from a starting state,
+/// which can be played back to reproduce the same run exactly.
+///
+/// Movies start with a header: a signature, a version, the seed used for the
+/// power-on RAM, how many frames apart hashes are, and the length and bytes
+/// of the starting save state. Then each frame is a byte of
+/// [GameBoy::buttons], followed by a u64 hash of the save state after every
+/// `hash_interval`th frame, so that a movie can be written as it's recorded.
+#[derive(Clone, Debug)]
+pub struct Movie {
+    pub seed: u64,
+    pub start_state: Vec<u8>,
+    pub hash_interval: u32,
+    frames: Vec<u8>,
+    hashes: Vec<u64>,
+}
+
+impl Movie {
+    /// Parses a movie, ignoring an incomplete frame at the end.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        if bytes.len() < 8 || &bytes[..8] != SIGNATURE {
+            return Err(MovieError::InvalidSignature);
+        }
+        if bytes.len() < 26 {
+            return Err(MovieError::Truncated);
+        }
+        let version = u8s_to_u16(bytes[8], bytes[9]);
+        if version > MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let u32_at = |i: usize| u8s_to_u32([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let seed = u64::from(u32_at(10)) | u64::from(u32_at(14)) << 32;
+        let hash_interval = u32_at(18).max(1);
+        let state_length = u32_at(22) as usize;
+        let frames_start = 26 + state_length;
+        if bytes.len() < frames_start {
+            return Err(MovieError::Truncated);
+        }
+
+        let mut frames = vec![];
+        let mut hashes = vec![];
+        let mut i = frames_start;
+        while i < bytes.len() {
+            let hashed = (frames.len() + 1) % hash_interval as usize == 0;
+            if hashed && i + 9 > bytes.len() {
+                break;
+            }
+            frames.push(bytes[i]);
+            i += 1;
+            if hashed {
+                hashes.push(u64::from(u32_at(i)) | u64::from(u32_at(i + 4)) << 32);
+                i += 8;
+            }
+        }
+
+        Ok(Self {
+            seed,
+            start_state: bytes[26..frames_start].to_vec(),
+            hash_interval,
+            frames,
+            hashes,
+        })
+    }
+
+    /// The number of frames recorded.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Creates a Game Boy at the start of the movie, with RAM initialized
+    /// from its seed and its starting state loaded. The builder should be
+    /// for the same ROM the movie was recorded with.
+    pub fn start(&self, builder: GameBoyBuilder) -> Result<GameBoy, MovieError> {
+        let mut gameboy = builder
+            .ram_init(RamInit::Seeded(self.seed))
+            .build()
+            .map_err(MovieError::Cartridge)?;
+        gameboy
+            .load_state(&self.start_state)
+            .map_err(MovieError::State)?;
+        Ok(gameboy)
+    }
+
+    /// Runs the given frame of the movie, counting from 0, with the buttons
+    /// that were held during it, and checks the hash recorded after it.
+    pub fn play_frame(&self, gameboy: &mut GameBoy, frame: usize) -> Result<(), MovieError> {
+        gameboy.set_buttons(self.frames[frame]);
+        gameboy.run_frame();
+        let count = frame + 1;
+        if count % self.hash_interval as usize == 0 {
+            let expected = self.hashes[count / self.hash_interval as usize - 1];
+            if state_hash(gameboy) != expected {
+                return Err(MovieError::Desync { frame: count });
+            }
+        }
+        Ok(())
+    }
+
+    /// Plays back the whole movie, returning the Game Boy at its end.
+    pub fn play(&self, builder: GameBoyBuilder) -> Result<GameBoy, MovieError> {
+        let mut gameboy = self.start(builder)?;
+        for frame in 0..self.len() {
+            self.play_frame(&mut gameboy, frame)?;
+        }
+        Ok(gameboy)
+    }
+}
+
+/// Writes a [Movie] as it's recorded.
+pub struct MovieRecorder<W: Write> {
+    writer: W,
+    hash_interval: u32,
+    frames: u64,
+}
+
+impl<W: Write> MovieRecorder<W> {
+    /// Starts recording from the Game Boy's current state. The seed is the
+    /// one its RAM was initialized from, with [RamInit::Seeded].
+    pub fn new(
+        mut writer: W,
+        gameboy: &GameBoy,
+        seed: u64,
+        hash_interval: u32,
+    ) -> io::Result<Self> {
+        let hash_interval = hash_interval.max(1);
+        let state = gameboy.save_state();
+        writer.write_all(SIGNATURE)?;
+        writer.write_all(&u16_to_u8s(MOVIE_VERSION))?;
+        writer.write_all(&u32_to_u8s(seed as u32))?;
+        writer.write_all(&u32_to_u8s((seed >> 32) as u32))?;
+        writer.write_all(&u32_to_u8s(hash_interval))?;
+        writer.write_all(&u32_to_u8s(state.len() as u32))?;
+        writer.write_all(&state)?;
+        writer.flush()?;
+        Ok(Self {
+            writer,
+            hash_interval,
+            frames: 0,
+        })
+    }
+
+    /// Records the frame the Game Boy just ran, which should be called after
+    /// each frame, with the buttons that were held throughout it.
+    pub fn record_frame(&mut self, gameboy: &GameBoy) -> io::Result<()> {
+        self.frames += 1;
+        self.writer.write_all(&[gameboy.buttons()])?;
+        if self.frames % u64::from(self.hash_interval) == 0 {
+            let hash = state_hash(gameboy);
+            self.writer.write_all(&u32_to_u8s(hash as u32))?;
+            self.writer.write_all(&u32_to_u8s((hash >> 32) as u32))?;
+        }
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
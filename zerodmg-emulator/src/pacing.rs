@@ -0,0 +1,307 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_pacer_waits_for_emulated_time() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new();
+    assert!(pacer.next_frame());
+    assert_eq!(pacer.frame_done(0, start), Duration::from_secs(0));
+
+    // a frame at normal speed takes about 16.74ms
+    assert!(pacer.next_frame());
+    let wait = pacer.frame_done(FRAME_CYCLES, start);
+    assert_eq!(wait.as_secs(), 0);
+    assert_eq!(wait.subsec_micros(), 16_742);
+
+    pacer.set_speed(Speed::Scaled(2.0));
+    pacer.next_frame();
+    pacer.frame_done(FRAME_CYCLES, start);
+    pacer.next_frame();
+    let wait = pacer.frame_done(2 * FRAME_CYCLES, start);
+    assert_eq!(wait.subsec_micros(), 8_371);
+
+    pacer.set_speed(Speed::Uncapped);
+    pacer.next_frame();
+    assert_eq!(pacer.frame_done(3 * FRAME_CYCLES, start), Duration::from_secs(0));
+}
+
+#[test]
+fn test_pause_and_frame_advance() {
+    let mut pacer = Pacer::new();
+    pacer.pause();
+    assert!(!pacer.next_frame());
+    pacer.advance_frame();
+    pacer.advance_frame();
+    assert!(pacer.next_frame());
+    assert!(pacer.next_frame());
+    assert!(!pacer.next_frame());
+    pacer.resume();
+    assert!(pacer.next_frame());
+}
+
+#[test]
+fn test_parse_speed() {
+    assert_eq!("0.5".parse(), Ok(Speed::Scaled(0.5)));
+    assert_eq!("uncapped".parse(), Ok(Speed::Uncapped));
+    for invalid in ["0", "-2", "NaN", "inf", "fast"].iter() {
+        assert!(invalid.parse::<Speed>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_late_frames_reset_schedule() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new();
+    pacer.next_frame();
+    pacer.frame_done(0, start);
+    pacer.next_frame();
+    let wait = pacer.frame_done(FRAME_CYCLES, start + Duration::from_millis(500));
+    assert_eq!(wait, Duration::from_secs(0));
+    assert_eq!(pacer.stats().late_frames, 1);
+    // the next frame is scheduled from when the late one finished
+    pacer.next_frame();
+    let wait = pacer.frame_done(2 * FRAME_CYCLES, start + Duration::from_millis(500));
+    assert_eq!(wait.subsec_micros(), 16_742);
+}
+
+#[test]
+fn test_resync_after_going_back_in_time() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new();
+    pacer.next_frame();
+    pacer.frame_done(10 * FRAME_CYCLES, start);
+    // as when loading an earlier state
+    pacer.resync(FRAME_CYCLES, start);
+    pacer.next_frame();
+    let wait = pacer.frame_done(2 * FRAME_CYCLES, start);
+    assert_eq!(wait.subsec_micros(), 16_742);
+    pacer.next_frame();
+    pacer.frame_done(3 * FRAME_CYCLES, start + STATS_INTERVAL);
+    assert!(pacer.stats().speed > 0.0);
+}
+
+/// The rate of the Game Boy's master clock, in T-cycles per second.
+pub const CLOCK_RATE: u32 = 4_194_304;
+
+/// The number of M-cycles in each frame: 154 lines of 114 M-cycles.
+pub const FRAME_CYCLES: u64 = 154 * 114;
+
+/// The number of frames per second, about 59.73.
+pub const FRAME_RATE: f64 = CLOCK_RATE as f64 / (FRAME_CYCLES * 4) as f64;
+
+// the rate at which M-cycles are counted by GameBoy::cycles()
+const M_CYCLE_RATE: u64 = CLOCK_RATE as u64 / 4;
+
+// how far behind schedule we can fall before giving up on catching up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// how often the measured speed in the stats is updated
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How fast emulated time passes relative to real time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// A multiple of real time: 1.0 for normal speed, above it for
+    /// fast-forward, or below it for slow motion.
+    Scaled(f64),
+    /// As fast as the host can run.
+    Uncapped,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::Scaled(1.0)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    /// Parses a positive multiple of real time, like `2` or `0.5`, or
+    /// `uncapped`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "uncapped" {
+            return Ok(Speed::Uncapped);
+        }
+        match s.parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(Speed::Scaled(factor)),
+            _ => Err(format!("invalid speed: {}", s)),
+        }
+    }
+}
+
+/// Measurements of how well emulation is keeping up with real time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PacingStats {
+    /// The number of frames run.
+    pub frames: u64,
+    /// The number of frames that finished too late to be caught up on.
+    pub late_frames: u64,
+    /// Frames run per second of real time, over the last second.
+    pub frames_per_second: f64,
+    /// Emulated time per real time, over the last second.
+    pub speed: f64,
+    /// How far behind schedule the last frame finished.
+    pub lag: Duration,
+    pub paused: bool,
+}
+
+impl fmt::Display for PacingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} fps, {:.1}% speed, {} frames, {} late, {}ms lag",
+            self.frames_per_second,
+            self.speed * 100.0,
+            self.frames,
+            self.late_frames,
+            self.lag.subsec_millis() + self.lag.as_secs() as u32 * 1000
+        )?;
+        if self.paused {
+            write!(f, " (paused)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Schedules frames against the real-time clock, at a chosen speed, and
+/// handles pausing and frame advance.
+///
+/// A frontend calls [Pacer::next_frame] to find out whether to run a frame,
+/// then [Pacer::frame_done] to find out how long to wait before the next.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    speed: Speed,
+    paused: bool,
+    // frames still to be run while paused
+    frames_to_advance: u32,
+    // the time and cycle count the schedule is measured from, reset whenever
+    // it's interrupted
+    epoch: Option<(Instant, u64)>,
+    // the time, cycle count and frame count the stats are measured from
+    stats_epoch: Option<(Instant, u64, u64)>,
+    stats: PacingStats,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Self {
+            speed: Speed::default(),
+            paused: false,
+            frames_to_advance: 0,
+            epoch: None,
+            stats_epoch: None,
+            stats: PacingStats::default(),
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.epoch = None;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.stats.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.frames_to_advance = 0;
+        self.stats.paused = false;
+    }
+
+    /// Runs one more frame while paused.
+    pub fn advance_frame(&mut self) {
+        self.frames_to_advance += 1;
+    }
+
+    pub fn stats(&self) -> PacingStats {
+        self.stats
+    }
+
+    /// Returns whether the frontend should run the next frame, or wait for
+    /// one frame's time because we're paused.
+    pub fn next_frame(&mut self) -> bool {
+        if !self.paused {
+            return true;
+        }
+        // don't try to catch up on the time spent paused
+        self.epoch = None;
+        self.stats_epoch = None;
+        if self.frames_to_advance > 0 {
+            self.frames_to_advance -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Starts the schedule and stats over from a cycle count, such as after
+    /// loading a state or rewinding moves it back.
+    pub fn resync(&mut self, cycles: u64, now: Instant) {
+        self.epoch = Some((now, cycles));
+        self.stats_epoch = Some((now, cycles, self.stats.frames));
+    }
+
+    /// Records that a frame has been run, given the Game Boy's cycle count
+    /// and the current time, and returns how long to wait before the next.
+    pub fn frame_done(&mut self, cycles: u64, now: Instant) -> Duration {
+        self.stats.frames += 1;
+        self.update_stats(cycles, now);
+
+        let factor = match self.speed {
+            Speed::Scaled(factor) if factor > 0.0 => factor,
+            _ => {
+                self.stats.lag = Duration::from_secs(0);
+                return Duration::from_secs(0);
+            }
+        };
+
+        let (epoch_time, epoch_cycles) = *self.epoch.get_or_insert((now, cycles));
+        let emulated_nanos = (cycles - epoch_cycles) as f64 * 1e9 / (M_CYCLE_RATE as f64 * factor);
+        let due = epoch_time + Duration::from_nanos(emulated_nanos as u64);
+        if due > now {
+            self.stats.lag = Duration::from_secs(0);
+            due - now
+        } else {
+            self.stats.lag = now - due;
+            if self.stats.lag > MAX_LAG {
+                // we can't catch up, so start a new schedule from here
+                self.stats.late_frames += 1;
+                self.epoch = Some((now, cycles));
+            }
+            Duration::from_secs(0)
+        }
+    }
+
+    fn update_stats(&mut self, cycles: u64, now: Instant) {
+        let frames = self.stats.frames;
+        let (epoch_time, epoch_cycles, epoch_frames) =
+            *self.stats_epoch.get_or_insert((now, cycles, frames));
+        let elapsed = now - epoch_time;
+        if elapsed < STATS_INTERVAL {
+            return;
+        }
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.stats.frames_per_second = (frames - epoch_frames) as f64 / seconds;
+        self.stats.speed = (cycles - epoch_cycles) as f64 / M_CYCLE_RATE as f64 / seconds;
+        self.stats_epoch = Some((now, cycles, frames));
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,99 @@
+/// The RGB colours used to display the four DMG shades.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    /// RGB colours for shades 0 (lightest) through 3 (darkest).
+    pub shades: [[u8; 3]; 4],
+}
+
+impl Palette {
+    /// Evenly-spaced grays, from white to black.
+    pub const GRAYSCALE: Palette = Palette {
+        shades: [
+            [0xFF, 0xFF, 0xFF],
+            [0xAA, 0xAA, 0xAA],
+            [0x55, 0x55, 0x55],
+            [0x00, 0x00, 0x00],
+        ],
+    };
+
+    /// The pea-soup green of the original DMG screen.
+    pub const DMG_GREEN: Palette = Palette {
+        shades: [
+            [0x9B, 0xBC, 0x0F],
+            [0x8B, 0xAC, 0x0F],
+            [0x30, 0x62, 0x30],
+            [0x0F, 0x38, 0x0F],
+        ],
+    };
+
+    /// The warmer grays of the Game Boy Pocket screen.
+    pub const POCKET: Palette = Palette {
+        shades: [
+            [0xE0, 0xDB, 0xCD],
+            [0xA8, 0x9F, 0x94],
+            [0x70, 0x6B, 0x66],
+            [0x2B, 0x2B, 0x26],
+        ],
+    };
+
+    /// The blue-green backlight of the Game Boy Light screen.
+    pub const LIGHT: Palette = Palette {
+        shades: [
+            [0x00, 0xD2, 0x9A],
+            [0x00, 0x9E, 0x74],
+            [0x00, 0x5C, 0x44],
+            [0x00, 0x2C, 0x20],
+        ],
+    };
+
+    /// Creates a user-defined palette from RGB colours for shades 0 (lightest)
+    /// through 3 (darkest).
+    pub fn custom(shades: [[u8; 3]; 4]) -> Self {
+        Self { shades }
+    }
+
+    /// Returns the preset palette with the given name, if there is one.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "grayscale" | "gray" => Some(Palette::GRAYSCALE),
+            "dmg" | "green" => Some(Palette::DMG_GREEN),
+            "pocket" => Some(Palette::POCKET),
+            "light" => Some(Palette::LIGHT),
+            _ => None,
+        }
+    }
+
+    /// Returns the opaque colour for a two-bit shade value.
+    pub fn color(&self, shade: u8) -> image::Rgba<u8> {
+        let [r, g, b] = self.shades[usize::from(shade & 0b11)];
+        image::Rgba([r, g, b, 0xFF])
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::GRAYSCALE
+    }
+}
+
+/// The palette used to colour tiles in the VRAM tile viewer, which can't know
+/// which palette each tile is meant to be displayed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TilePalette {
+    /// The raw shades of the display [Palette], ignoring palette registers.
+    Shades,
+    /// The DMG background palette (BGP).
+    Background,
+    /// DMG object palette 0 (OBP0) or 1 (OBP1).
+    Object(u8),
+    /// One of the eight CGB background colour palettes.
+    CgbBackground(u8),
+    /// One of the eight CGB object colour palettes.
+    CgbObject(u8),
+}
+
+impl Default for TilePalette {
+    fn default() -> Self {
+        TilePalette::Shades
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+
+use zerodmg_utils::little_endian::{u16_to_u8s, u8s_to_u16};
+
+use super::GameBoy;
+
+#[test]
+fn test_step_back_restores_earlier_frames() {
+    use super::builder::RamInit;
+
+    let display = |gameboy: &GameBoy| gameboy.output_buffer.lock().unwrap().display.raw_pixels();
+
+    let mut gameboy = GameBoy::builder()
+        .ram_init(RamInit::Seeded(1))
+        .build()
+        .unwrap();
+    let mut rewind = RewindBuffer::new(4, 16);
+    let mut frames = vec![];
+    for _ in 0..30 {
+        gameboy.run_frame();
+        rewind.record(&gameboy);
+        frames.push((gameboy.cycles(), display(&gameboy)));
+    }
+
+    for i in (20..29).rev() {
+        assert!(rewind.step_back(&mut gameboy));
+        assert_eq!(gameboy.cycles(), frames[i].0);
+        assert_eq!(display(&gameboy), frames[i].1);
+    }
+
+    // running forward again records a new history
+    gameboy.run_frame();
+    rewind.record(&gameboy);
+    assert_eq!(gameboy.cycles(), frames[21].0);
+    assert!(rewind.step_back(&mut gameboy));
+    assert_eq!(gameboy.cycles(), frames[20].0);
+}
+
+#[test]
+fn test_step_back_replays_the_buttons_held() {
+    use zerodmg_codes::instruction::prelude::*;
+
+    use super::joypad::Button;
+    use super::test_roms::gameboy_for;
+
+    // adds the selected buttons to B, over and over
+    let mut gameboy = gameboy_for(&[(
+        0x0100,
+        vec![
+            LD(A, 0b0010_0000),
+            LD_8_TO_FF_IMMEDIATE(0x00),
+            LD_8_FROM_FF_IMMEDIATE(0x00),
+            ADD(B),
+            LD(B, A),
+            JR(-6),
+        ],
+    )]);
+    let mut rewind = RewindBuffer::new(4, 16);
+    let mut states = vec![];
+    for i in 0..20 {
+        let buttons = if i % 3 == 0 { Button::Down.bit() } else { 0 };
+        gameboy.set_buttons(buttons);
+        gameboy.run_frame();
+        rewind.record(&gameboy);
+        states.push(gameboy.save_state());
+    }
+
+    for i in (10..19).rev() {
+        assert!(rewind.step_back(&mut gameboy));
+        assert!(gameboy.save_state() == states[i], "frame {} differs", i);
+    }
+}
+
+#[test]
+fn test_history_is_limited() {
+    let mut gameboy = GameBoy::builder().build().unwrap();
+    let mut rewind = RewindBuffer::new(2, 5);
+    assert!(!rewind.step_back(&mut gameboy));
+    for _ in 0..40 {
+        gameboy.run_frame();
+        rewind.record(&gameboy);
+    }
+    let available = rewind.available_frames();
+    assert!(8 <= available && available < 40);
+    let mut steps = 0;
+    while rewind.step_back(&mut gameboy) {
+        steps += 1;
+    }
+    assert_eq!(steps, available);
+}
+
+#[test]
+fn test_delta_round_trip() {
+    let base: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+    let mut state = base.clone();
+    state[3] = 0xFF;
+    state[500..520].copy_from_slice(&[0xAA; 20]);
+    let delta = encode_delta(&base, &state);
+    assert!(delta.len() < 64);
+    assert_eq!(decode_delta(&base, &delta), state);
+    assert_eq!(decode_delta(&base, &encode_delta(&base, &base)), base);
+}
+
+// the most snapshots stored as deltas from each complete one
+const MAX_KEYFRAME_INTERVAL: usize = 32;
+
+// a snapshot, stored either as a complete save state or as the changes from
+// the last keyframe before it
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    bytes: Vec<u8>,
+}
+
+/// A history of recent frames, which can be stepped back through one at a
+/// time.
+///
+/// A save state is kept every `interval` frames, mostly as the bytes that
+/// changed since the last complete one, which is usually just some RAM and
+/// VRAM. Frames in between are recreated by running forward from the
+/// snapshot before them, with the buttons that were held during them.
+pub struct RewindBuffer {
+    interval: u64,
+    capacity: usize,
+    // how often a snapshot is stored completely, which is also how many are
+    // dropped at once when the history is full
+    keyframe_interval: usize,
+    snapshots: VecDeque<Snapshot>,
+    // the last keyframe, uncompressed
+    keyframe_state: Vec<u8>,
+    // the buttons held during each frame since the oldest snapshot's
+    buttons: VecDeque<u8>,
+    // the number of frames recorded, including the current one
+    frames: u64,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer taking a snapshot every `interval` frames, and
+    /// keeping up to the latest `capacity` of them.
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            interval: u64::from(interval.max(1)),
+            capacity,
+            keyframe_interval: (capacity / 4).max(1).min(MAX_KEYFRAME_INTERVAL),
+            snapshots: VecDeque::new(),
+            keyframe_state: vec![],
+            buttons: VecDeque::new(),
+            frames: 0,
+        }
+    }
+
+    /// Records the frame the Game Boy just finished, which should be called
+    /// after each frame is run.
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        let frame = self.frames;
+        self.frames += 1;
+        self.buttons.push_back(gameboy.buttons());
+        if frame % self.interval != 0 {
+            return;
+        }
+
+        let state = gameboy.save_state();
+        let since_keyframe = self
+            .snapshots
+            .iter()
+            .rev()
+            .position(|snapshot| snapshot.keyframe);
+        let keyframe = match since_keyframe {
+            Some(count) => {
+                count + 1 >= self.keyframe_interval || state.len() != self.keyframe_state.len()
+            }
+            None => true,
+        };
+        let snapshot = if keyframe {
+            self.keyframe_state = state.clone();
+            Snapshot {
+                frame,
+                keyframe,
+                bytes: state,
+            }
+        } else {
+            Snapshot {
+                frame,
+                keyframe,
+                bytes: encode_delta(&self.keyframe_state, &state),
+            }
+        };
+        self.snapshots.push_back(snapshot);
+
+        // the deltas after a keyframe are dropped along with it
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+            while self.snapshots.front().map_or(false, |snapshot| !snapshot.keyframe) {
+                self.snapshots.pop_front();
+            }
+        }
+        let oldest = self.oldest_frame();
+        while self.buttons.len() as u64 > self.frames - oldest {
+            self.buttons.pop_front();
+        }
+    }
+
+    // the frame of the oldest snapshot, which the buttons start from
+    fn oldest_frame(&self) -> u64 {
+        self.snapshots.front().map_or(0, |snapshot| snapshot.frame)
+    }
+
+    /// How many frames back we can step.
+    pub fn available_frames(&self) -> u64 {
+        match self.snapshots.front() {
+            Some(oldest) => self.frames - 1 - oldest.frame,
+            None => 0,
+        }
+    }
+
+    /// Returns the Game Boy to the frame before the current one, returning
+    /// false if it's no longer in the history.
+    pub fn step_back(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.available_frames() == 0 {
+            return false;
+        }
+        let target = self.frames - 2;
+
+        while self.snapshots.back().map_or(false, |snapshot| snapshot.frame > target) {
+            self.snapshots.pop_back();
+        }
+        // run at least one frame if we can, so the display shows it
+        let latest_i = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.frame < target)
+            .unwrap_or(self.snapshots.len() - 1);
+        let latest = &self.snapshots[latest_i];
+        let keyframe = self
+            .snapshots
+            .iter()
+            .take(latest_i + 1)
+            .rev()
+            .find(|snapshot| snapshot.keyframe)
+            .expect("the oldest snapshot is always a keyframe");
+        let state = if latest.keyframe {
+            latest.bytes.clone()
+        } else {
+            decode_delta(&keyframe.bytes, &latest.bytes)
+        };
+        let last_keyframe = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.keyframe)
+            .expect("the oldest snapshot is always a keyframe");
+        self.keyframe_state = last_keyframe.bytes.clone();
+        gameboy
+            .load_state(&state)
+            .expect("rewinding to a state we saved");
+        // these frames were already recorded when they were first played
+        let oldest = self.oldest_frame();
+        gameboy.set_audio_recording_paused(true);
+        for frame in latest.frame + 1..=target {
+            gameboy.set_buttons(self.buttons[(frame - oldest) as usize]);
+            gameboy.run_frame();
+        }
+        gameboy.set_audio_recording_paused(false);
+
+        self.frames = target + 1;
+        self.buttons.truncate((self.frames - oldest) as usize);
+        true
+    }
+
+    /// Forgets the history, such as after loading a save state.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe_state.clear();
+        self.buttons.clear();
+        self.frames = 0;
+    }
+}
+
+// Encodes the bytes that differ from the base, which must be the same length,
+// as alternating runs of unchanged and changed bytes: the length of each, as
+// u16s, followed by the changed bytes XORed with the base.
+fn encode_delta(base: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    let mut i = 0;
+    while i < state.len() {
+        let unchanged_start = i;
+        while i < state.len() && state[i] == base[i] && i - unchanged_start < 0xFFFF {
+            i += 1;
+        }
+        let changed_start = i;
+        while i < state.len() && state[i] != base[i] && i - changed_start < 0xFFFF {
+            i += 1;
+        }
+        for &length in [changed_start - unchanged_start, i - changed_start].iter() {
+            delta.extend(&u16_to_u8s(length as u16));
+        }
+        for j in changed_start..i {
+            delta.push(state[j] ^ base[j]);
+        }
+    }
+    delta
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = base.to_vec();
+    let mut i = 0;
+    let mut d = 0;
+    while d + 4 <= delta.len() {
+        i += usize::from(u8s_to_u16(delta[d], delta[d + 1]));
+        let changed = usize::from(u8s_to_u16(delta[d + 2], delta[d + 3]));
+        d += 4;
+        for _ in 0..changed {
+            state[i] ^= delta[d];
+            i += 1;
+            d += 1;
+        }
+    }
+    state
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+
+use zerodmg_utils::little_endian::{u16_to_u8s, u32_to_u8s, u8s_to_u16, u8s_to_u32};
+
+use super::model::Model;
+use super::GameBoy;
+
+#[test]
+fn test_loaded_state_runs_the_same_frames() {
+    use super::builder::RamInit;
+
+    let display = |gameboy: &GameBoy| gameboy.output_buffer.lock().unwrap().display.raw_pixels();
+
+    let mut original = GameBoy::builder()
+        .ram_init(RamInit::Seeded(1))
+        .build()
+        .unwrap();
+    for _ in 0..30 {
+        original.run_frame();
+    }
+    let state = original.save_state();
+
+    let mut restored = GameBoy::builder()
+        .ram_init(RamInit::Seeded(2))
+        .build()
+        .unwrap();
+    restored.load_state(&state).unwrap();
+    assert_eq!(restored.cycles(), original.cycles());
+
+    for _ in 0..30 {
+        original.run_frame();
+        restored.run_frame();
+        assert_eq!(display(&restored), display(&original));
+        assert_eq!(restored.cycles(), original.cycles());
+    }
+    assert_eq!(restored.save_state(), original.save_state());
+}
+
+#[test]
+fn test_unknown_blocks_and_fields_are_ignored() {
+    let mut gameboy = GameBoy::builder().build().unwrap();
+    gameboy.run_cycles(1000);
+    let state = gameboy.save_state();
+
+    // a future version with a new block, and a new field in the CPU block
+    let mut future = state[..HEADER_SIZE].to_vec();
+    future[8] = 0x09;
+    let mut reader = StateReader::new(&state[HEADER_SIZE..]);
+    while let Some((tag, payload)) = reader.block() {
+        let mut payload = payload.to_vec();
+        if &tag == b"CPU " {
+            payload.extend(&[0xAB, 0xCD]);
+        }
+        write_block(&mut future, tag, &payload);
+        if &tag == b"INFO" {
+            write_block(&mut future, *b"NEW ", &[1, 2, 3]);
+        }
+    }
+
+    let mut loaded = GameBoy::builder().build().unwrap();
+    loaded.load_state(&future).unwrap();
+    assert_eq!(loaded.save_state(), state);
+
+    future[10] = 0x09;
+    assert_eq!(
+        loaded.load_state(&future),
+        Err(StateError::UnsupportedVersion(9))
+    );
+    assert_eq!(
+        loaded.load_state(b"not a state"),
+        Err(StateError::InvalidSignature)
+    );
+}
+
+const SIGNATURE: &[u8; 8] = b"0DMGSAVE";
+// the signature, the version that wrote the state and the oldest version
+// that can read it
+const HEADER_SIZE: usize = 12;
+
+/// The version of the save state format we write. Later versions may add
+/// blocks, and fields at the end of blocks, which older versions ignore.
+pub const STATE_VERSION: u16 = 4;
+// the oldest version that can read the states we write, which only changes
+// if the format changes in a way that older versions can't just ignore
+const STATE_COMPATIBLE_VERSION: u16 = 1;
+
+/// Why a save state couldn't be loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    InvalidSignature,
+    /// The state requires a newer version of the format than we support.
+    UnsupportedVersion(u16),
+    /// The state ends in the middle of a block.
+    Truncated,
+    /// The state was saved while running a different ROM.
+    DifferentRom,
+    /// The state ends with BESS blocks, but we can't use them.
+    InvalidBess(&'static str),
+}
+
+/// What loading a save state found that didn't stop it from being loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct LoadedState {
+    /// The state's BESS blocks name a different ROM. The format only has
+    /// this warned about, since the ROM may just be a patched version.
+    pub different_rom: bool,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidSignature => write!(f, "not a zerodmg save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::DifferentRom => write!(f, "save state is for a different ROM"),
+            StateError::InvalidBess(reason) => write!(f, "invalid BESS save state: {}", reason),
+        }
+    }
+}
+
+/// Builds the payload of a save state block, from fields written in order.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend(&u16_to_u8s(value));
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend(&u32_to_u8s(value));
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    /// Writes bytes of a fixed length, known to the reader.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+    }
+
+    /// Writes bytes preceded by their length.
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+}
+
+/// Reads fields from the payload of a save state block, in the order they
+/// were written. Fields missing from the end of a block, because it was
+/// written by an older version, are left unchanged.
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < length {
+            self.bytes = &[];
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+        if let Some(bytes) = self.take(1) {
+            *value = bytes[0];
+        }
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+        if let Some(bytes) = self.take(1) {
+            *value = bytes[0] != 0;
+        }
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+        if let Some(bytes) = self.take(2) {
+            *value = u8s_to_u16(bytes[0], bytes[1]);
+        }
+    }
+
+    pub fn u32(&mut self, value: &mut u32) {
+        if let Some(bytes) = self.take(4) {
+            *value = u8s_to_u32([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+
+    pub fn i32(&mut self, value: &mut i32) {
+        let mut unsigned = *value as u32;
+        self.u32(&mut unsigned);
+        *value = unsigned as i32;
+    }
+
+    pub fn u64(&mut self, value: &mut u64) {
+        let mut low = *value as u32;
+        let mut high = (*value >> 32) as u32;
+        self.u32(&mut low);
+        self.u32(&mut high);
+        *value = u64::from(high) << 32 | u64::from(low);
+    }
+
+    pub fn bytes(&mut self, value: &mut [u8]) {
+        if let Some(bytes) = self.take(value.len()) {
+            value.copy_from_slice(bytes);
+        }
+    }
+
+    /// Reads length-prefixed bytes into a buffer of a known size, ignoring
+    /// them if their length doesn't match.
+    pub fn vec(&mut self, value: &mut [u8]) {
+        let mut length = 0;
+        self.u32(&mut length);
+        if let Some(bytes) = self.take(length as usize) {
+            if bytes.len() == value.len() {
+                value.copy_from_slice(bytes);
+            }
+        }
+    }
+
+    /// Reads the tag and payload of the next block, if there is a complete
+    /// one.
+    fn block(&mut self) -> Option<([u8; 4], &'a [u8])> {
+        let header = self.take(8)?;
+        let tag = [header[0], header[1], header[2], header[3]];
+        let length = u8s_to_u32([header[4], header[5], header[6], header[7]]);
+        self.take(length as usize).map(|payload| (tag, payload))
+    }
+}
+
+fn write_block(state: &mut Vec<u8>, tag: [u8; 4], payload: &[u8]) {
+    state.extend(&tag);
+    state.extend(&u32_to_u8s(payload.len() as u32));
+    state.extend(payload);
+}
+
+// identifies the ROM a state was saved with, since it isn't included
+fn rom_checksum(rom: &[u8]) -> u32 {
+    rom.iter()
+        .fold(0u32, |sum, &byte| sum.rotate_left(1) ^ u32::from(byte))
+}
+
+impl GameBoy {
+    /// Saves the complete state of the hardware, other than the cartridge ROM
+    /// and the emulator's output and settings.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = SIGNATURE.to_vec();
+        state.extend(&u16_to_u8s(STATE_VERSION));
+        state.extend(&u16_to_u8s(STATE_COMPATIBLE_VERSION));
+
+        let mut info = StateWriter::new();
+        info.u32(rom_checksum(self.mem.cartridge().rom()));
+        info.u8(match self.model {
+            Model::Dmg => 0,
+            Model::Cgb => 1,
+        });
+        info.bool(self.cgb_mode);
+        info.u64(self.t);
+        write_block(&mut state, *b"INFO", &info.into_bytes());
+
+        let mut blocks = vec![];
+        let mut writer = StateWriter::new();
+        self.cpu.save_state(&mut writer);
+        blocks.push((*b"CPU ", writer));
+        let mut writer = StateWriter::new();
+        self.mem.save_state(&mut writer);
+        blocks.push((*b"MEM ", writer));
+        let mut writer = StateWriter::new();
+        self.mem.cartridge().save_state(&mut writer);
+        blocks.push((*b"CART", writer));
+        let mut writer = StateWriter::new();
+        self.vid.save_state(&mut writer);
+        blocks.push((*b"VID ", writer));
+        let mut writer = StateWriter::new();
+        self.aud.save_state(&mut writer);
+        blocks.push((*b"AUD ", writer));
+        let mut writer = StateWriter::new();
+        self.serial.save_state(&mut writer);
+        blocks.push((*b"SER ", writer));
+        let mut writer = StateWriter::new();
+        self.timer.save_state(&mut writer);
+        blocks.push((*b"TIMR", writer));
+        let mut writer = StateWriter::new();
+        self.joypad.save_state(&mut writer);
+        blocks.push((*b"JOYP", writer));
+        for (tag, writer) in blocks {
+            write_block(&mut state, tag, &writer.into_bytes());
+        }
+
+        write_block(&mut state, *b"END ", &[]);
+        state
+    }
+
+    /// Restores a state saved by [GameBoy::save_state], while running the
+    /// same ROM. States saved by other emulators are loaded from their BESS
+    /// blocks instead. Nothing is changed if the state can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<LoadedState, StateError> {
+        if state.len() < HEADER_SIZE || &state[..8] != SIGNATURE {
+            return self.load_bess_state(state);
+        }
+        let compatible_version = u8s_to_u16(state[10], state[11]);
+        if compatible_version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(compatible_version));
+        }
+
+        let mut blocks = vec![];
+        let mut reader = StateReader::new(&state[HEADER_SIZE..]);
+        loop {
+            match reader.block() {
+                Some((tag, _)) if &tag == b"END " => break,
+                Some(block) => blocks.push(block),
+                None => return Err(StateError::Truncated),
+            }
+        }
+
+        for &(tag, payload) in blocks.iter() {
+            if &tag == b"INFO" {
+                let mut checksum = 0;
+                StateReader::new(payload).u32(&mut checksum);
+                if checksum != rom_checksum(self.mem.cartridge().rom()) {
+                    return Err(StateError::DifferentRom);
+                }
+            }
+        }
+
+        for (tag, payload) in blocks {
+            let mut reader = StateReader::new(payload);
+            match &tag {
+                b"INFO" => {
+                    let mut checksum = 0;
+                    reader.u32(&mut checksum);
+                    let mut model = match self.model {
+                        Model::Dmg => 0,
+                        Model::Cgb => 1,
+                    };
+                    reader.u8(&mut model);
+                    self.model = if model == 1 { Model::Cgb } else { Model::Dmg };
+                    reader.bool(&mut self.cgb_mode);
+                    reader.u64(&mut self.t);
+                }
+                b"CPU " => self.cpu.load_state(&mut reader),
+                b"MEM " => self.mem.load_state(&mut reader),
+                b"CART" => self.mem.cartridge_mut().load_state(&mut reader),
+                b"VID " => self.vid.load_state(&mut reader),
+                b"AUD " => self.aud.load_state(&mut reader),
+                b"SER " => self.serial.load_state(&mut reader),
+                b"TIMR" => self.timer.load_state(&mut reader),
+                b"JOYP" => self.joypad.load_state(&mut reader),
+                // from a later version
+                _ => {}
+            }
+        }
+
+        self.debug_latest_executions.clear();
+        self.debug_latest_executions_next_i = 0;
+        self.calls.clear();
+        Ok(LoadedState::default())
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use super::cpu::CPUController;
+use super::save_state::{StateReader, StateWriter};
+use super::GameBoy;
+
+/// Something connected to the serial port, exchanging a byte for each byte
+/// the Game Boy sends.
+pub trait SerialSink: Send {
+    /// Receives a byte sent by the Game Boy, and returns the byte sent back.
+    fn transfer(&mut self, byte: u8) -> u8;
+}
+
+/// Collects the bytes sent over the serial port, such as the results that
+/// test ROMs print. Clones share the same bytes, so one can be kept to read
+/// them while another is connected.
+#[derive(Clone, Debug, Default)]
+pub struct SerialLog {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SerialLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().clone()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+}
+
+impl SerialSink for SerialLog {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.bytes.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+/// Writes the bytes sent over the serial port to standard output.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerialStdout;
+
+impl SerialSink for SerialStdout {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).unwrap();
+        stdout.flush().unwrap();
+        0xFF
+    }
+}
+
+/// Game Boy serial port state
+pub struct SerialData {
+    // serial transfer data register
+    sb: u8,
+    // serial transfer control register
+    sc: u8,
+    // what's on the other end of the link cable, if anything
+    sink: Option<Box<SerialSink>>,
+}
+
+impl SerialData {
+    pub fn new(sink: Option<Box<SerialSink>>) -> Self {
+        Self {
+            sb: 0x00,
+            sc: 0x00,
+            sink,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u8(&mut self.sb);
+        state.u8(&mut self.sc);
+    }
+}
+
+pub trait SerialController {
+    fn sb(&self) -> u8;
+    fn set_sb(&mut self, value: u8);
+    fn sc(&self) -> u8;
+    fn set_sc(&mut self, value: u8);
+}
+
+impl SerialController for GameBoy {
+    fn sb(&self) -> u8 {
+        self.serial.sb
+    }
+
+    fn set_sb(&mut self, value: u8) {
+        self.serial.sb = value;
+    }
+
+    fn sc(&self) -> u8 {
+        0b0111_1110 | self.serial.sc
+    }
+
+    /// Starts a transfer if requested using the internal clock, which we
+    /// complete immediately instead of over the 1024 M-cycles it would take.
+    /// Transfers using an external clock never complete, as nothing else
+    /// drives the clock.
+    fn set_sc(&mut self, value: u8) {
+        self.serial.sc = value & 0b1000_0001;
+        if self.serial.sc != 0b1000_0001 {
+            return;
+        }
+
+        let sent = self.serial.sb;
+        // with nothing connected, 1s are shifted in
+        self.serial.sb = match self.serial.sink {
+            Some(ref mut sink) => sink.transfer(sent),
+            None => 0xFF,
+        };
+        self.serial.sc &= 0b0111_1111;
+        let ift = self.ift();
+        self.set_ift(ift | 0b0000_1000);
+    }
+}
@@ -0,0 +1,24 @@
+//! ROMs assembled from a few instructions, for tests that need particular
+//! code to run.
+
+use zerodmg_codes::instruction::Instruction;
+
+use super::builder::GameBoyBuilder;
+use super::GameBoy;
+
+/// A builder for a Game Boy running a 32KB ROM with each list of
+/// instructions at its address, starting at 0x0100 without a boot ROM.
+pub fn builder_for(program: &[(usize, Vec<Instruction>)]) -> GameBoyBuilder {
+    let mut rom = vec![0x00; 0x8000];
+    for &(address, ref instructions) in program.iter() {
+        let bytes: Vec<u8> = instructions.iter().flat_map(|i| i.to_bytes()).collect();
+        rom[address..address + bytes.len()].copy_from_slice(&bytes);
+    }
+    GameBoy::builder().rom_bytes(rom).skip_boot_rom()
+}
+
+/// A Game Boy running a ROM with each list of instructions at its address,
+/// as in [builder_for].
+pub fn gameboy_for(program: &[(usize, Vec<Instruction>)]) -> GameBoy {
+    builder_for(program).build().unwrap()
+}
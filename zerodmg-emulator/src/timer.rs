@@ -0,0 +1,184 @@
+use super::cpu::CPUController;
+use super::save_state::{StateReader, StateWriter};
+use super::GameBoy;
+
+#[test]
+fn test_tima_overflow_reloads_and_interrupts() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    gameboy.set_ift(0x00);
+    gameboy.set_div(0x00);
+    gameboy.set_tima(0xFE);
+    gameboy.set_tma(0x42);
+    // enabled, incrementing every 4 M-cycles
+    gameboy.set_tac(0b101);
+    assert_eq!(gameboy.tac(), 0b1111_1101);
+
+    for _ in 0..4 {
+        gameboy.timer_cycle();
+    }
+    assert_eq!(gameboy.tima(), 0xFF);
+    assert_eq!(gameboy.ift() & 0b100, 0);
+    for _ in 0..4 {
+        gameboy.timer_cycle();
+    }
+    assert_eq!(gameboy.tima(), 0x42);
+    assert_eq!(gameboy.ift() & 0b100, 0b100);
+
+    // DIV counts every 64 M-cycles, and is reset by any write
+    for _ in 0..56 {
+        gameboy.timer_cycle();
+    }
+    assert_eq!(gameboy.div(), 0x01);
+    gameboy.set_div(0xAB);
+    assert_eq!(gameboy.div(), 0x00);
+}
+
+#[test]
+fn test_div_reset_can_increment_tima() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    gameboy.set_div(0x00);
+    gameboy.set_tima(0x00);
+    // incrementing every 256 M-cycles, when bit 7 of the counter falls
+    gameboy.set_tac(0b100);
+    for _ in 0..128 {
+        gameboy.timer_cycle();
+    }
+    assert_eq!(gameboy.tima(), 0x00);
+    gameboy.set_div(0x00);
+    assert_eq!(gameboy.tima(), 0x01);
+}
+
+/// Game Boy timer state
+pub struct TimerData {
+    // M-cycles counted since DIV was last reset, which DIV is the upper bits of
+    counter: u16,
+    // timer counter register
+    tima: u8,
+    // timer modulo register, reloaded into TIMA when it overflows
+    tma: u8,
+    // timer control register
+    tac: u8,
+}
+
+impl TimerData {
+    pub fn new() -> Self {
+        Self {
+            counter: 0x0000,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0x00,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u16(&mut self.counter);
+        state.u8(&mut self.tima);
+        state.u8(&mut self.tma);
+        state.u8(&mut self.tac);
+    }
+
+    /// Whether TIMA's clock input is high: the counter bit selected in TAC,
+    /// while the timer is enabled. TIMA increments when it falls.
+    fn input(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 7,
+            0b01 => 1,
+            0b10 => 3,
+            _ => 5,
+        };
+        self.tac & 0b100 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    /// Applies a change that may have made the clock input fall, returning
+    /// whether TIMA overflowed.
+    fn update<F>(&mut self, change: F) -> bool
+    where
+        F: FnOnce(&mut Self),
+    {
+        let input_0 = self.input();
+        change(self);
+        if !input_0 || self.input() {
+            return false;
+        }
+
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = if overflowed { self.tma } else { tima };
+        overflowed
+    }
+}
+
+pub trait TimerController {
+    fn timer_cycle(&mut self);
+    fn div(&self) -> u8;
+    fn set_div(&mut self, value: u8);
+    fn tima(&self) -> u8;
+    fn set_tima(&mut self, value: u8);
+    fn tma(&self) -> u8;
+    fn set_tma(&mut self, value: u8);
+    fn tac(&self) -> u8;
+    fn set_tac(&mut self, value: u8);
+    fn update_timer<F>(&mut self, change: F)
+    where
+        F: FnOnce(&mut TimerData);
+}
+
+impl TimerController for GameBoy {
+    /// Runs the timer for one M-cycle of the CPU, which in double speed mode
+    /// is twice as often as the rest of the hardware.
+    fn timer_cycle(&mut self) {
+        self.update_timer(|timer| timer.counter = timer.counter.wrapping_add(1));
+    }
+
+    fn div(&self) -> u8 {
+        (self.timer.counter >> 6) as u8
+    }
+
+    /// Resets DIV, whatever the value written.
+    fn set_div(&mut self, _value: u8) {
+        self.update_timer(|timer| timer.counter = 0x0000);
+    }
+
+    fn tima(&self) -> u8 {
+        self.timer.tima
+    }
+
+    fn set_tima(&mut self, value: u8) {
+        self.timer.tima = value;
+    }
+
+    fn tma(&self) -> u8 {
+        self.timer.tma
+    }
+
+    fn set_tma(&mut self, value: u8) {
+        self.timer.tma = value;
+    }
+
+    fn tac(&self) -> u8 {
+        0b1111_1000 | self.timer.tac
+    }
+
+    fn set_tac(&mut self, value: u8) {
+        self.update_timer(|timer| timer.tac = value & 0b111);
+    }
+
+    /// Changes the timer's state, incrementing TIMA if that makes its clock
+    /// input fall, and requesting the timer interrupt if it overflows. The
+    /// reload is done immediately, rather than a cycle later.
+    fn update_timer<F>(&mut self, change: F)
+    where
+        F: FnOnce(&mut TimerData),
+    {
+        if self.timer.update(change) {
+            let ift = self.ift();
+            self.set_ift(ift | 0b0000_0100);
+        }
+    }
+}
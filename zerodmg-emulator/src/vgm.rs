@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use zerodmg_utils::little_endian::{u16_to_u8s, u32_to_u8s};
+
+use super::audio::SAMPLE_RATE as APU_SAMPLE_RATE;
+
+#[test]
+fn test_vgm_header_and_commands() {
+    let path = std::env::temp_dir().join(format!("zerodmg-test-{}.vgm", std::process::id()));
+    {
+        let mut writer = VgmWriter::create(&path, 1000).unwrap();
+        writer.write_register(1000, 0x16, 0x80);
+        // 0.5 seconds later
+        writer.write_register(1000 + u64::from(APU_SAMPLE_RATE) / 2, 0x24, 0x77);
+        writer.finish(1000 + u64::from(APU_SAMPLE_RATE)).unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&bytes[0x00..0x04], b"Vgm ");
+    assert_eq!(&bytes[0x04..0x08], &u32_to_u8s(bytes.len() as u32 - 4));
+    assert_eq!(&bytes[0x08..0x0C], &u32_to_u8s(0x161));
+    assert_eq!(&bytes[0x18..0x1C], &u32_to_u8s(44_100));
+    assert_eq!(&bytes[0x34..0x38], &u32_to_u8s(0xCC));
+    assert_eq!(&bytes[0x80..0x84], &u32_to_u8s(4_194_304));
+    assert_eq!(
+        &bytes[0x100..],
+        &[0xB3, 0x16, 0x80, 0x61, 0x22, 0x56, 0xB3, 0x24, 0x77, 0x61, 0x22, 0x56, 0x66]
+    );
+}
+
+// the size of the VGM 1.61 header, which includes the Game Boy clock field
+const HEADER_SIZE: u32 = 0x100;
+
+// VGM files count time in samples at this rate
+const VGM_SAMPLE_RATE: u64 = 44_100;
+
+// the Game Boy's T-cycle clock rate
+const DMG_CLOCK: u32 = 4_194_304;
+
+/// Writes VGM files logging every write to the APU's registers (from 0xFF10
+/// to 0xFF3F, including wave RAM), so the music can be played back by other
+/// tools independently of the emulator.
+pub struct VgmWriter {
+    file: BufWriter<File>,
+    // the APU cycle at which logging started
+    start_t: u64,
+    samples_written: u64,
+    data_size: u32,
+    // the first error encountered while writing commands
+    error: Option<io::Error>,
+}
+
+impl VgmWriter {
+    /// Creates a VGM file, with time starting from the given APU cycle.
+    pub fn create(path: &Path, start_t: u64) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            start_t,
+            samples_written: 0,
+            data_size: 0,
+            error: None,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        let mut put_u32 = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&u32_to_u8s(value));
+        };
+        put_u32(0x04, HEADER_SIZE + self.data_size + 1 - 4);
+        put_u32(0x08, 0x161);
+        put_u32(0x18, self.samples_written as u32);
+        // relative to the field itself
+        put_u32(0x34, HEADER_SIZE - 0x34);
+        put_u32(0x80, DMG_CLOCK);
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        self.file.write_all(&header)
+    }
+
+    /// Logs a register write, made at the given APU cycle, to the register
+    /// at the given offset from 0xFF10.
+    pub fn write_register(&mut self, t: u64, register: u8, value: u8) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = self
+            .wait_until(t)
+            .and_then(|_| self.write_command(&[0xB3, register, value]));
+        if let Err(error) = result {
+            self.error = Some(error);
+        }
+    }
+
+    fn wait_until(&mut self, t: u64) -> io::Result<()> {
+        // rewinding can go back to before the log started
+        let target = t.saturating_sub(self.start_t) * VGM_SAMPLE_RATE / u64::from(APU_SAMPLE_RATE);
+        let previous_seconds = self.samples_written / VGM_SAMPLE_RATE;
+        while self.samples_written < target {
+            let wait = (target - self.samples_written).min(0xFFFF);
+            if wait <= 16 {
+                self.write_command(&[0x70 + (wait - 1) as u8])?;
+            } else {
+                let [low, high] = u16_to_u8s(wait as u16);
+                self.write_command(&[0x61, low, high])?;
+            }
+            self.samples_written += wait;
+        }
+
+        // keep the header up to date, so that the file is valid even if we
+        // never get to finish it
+        if self.samples_written / VGM_SAMPLE_RATE != previous_seconds {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    fn write_command(&mut self, command: &[u8]) -> io::Result<()> {
+        self.file.write_all(command)?;
+        self.data_size += command.len() as u32;
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        // the end of data command, which the next command will overwrite
+        let end = u64::from(HEADER_SIZE + self.data_size);
+        self.file.seek(SeekFrom::Start(end))?;
+        self.file.write_all(&[0x66])?;
+        self.file.flush()?;
+        self.file.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// Ends the log at the given APU cycle, and reports any error from
+    /// writing commands.
+    pub fn finish(mut self, t: u64) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.wait_until(t)?;
+        self.update_header()
+    }
+}
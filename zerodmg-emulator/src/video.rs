@@ -1,16 +1,232 @@
+use super::builder::RamInitializer;
+use super::memory::MemoryController;
+use super::watch::WatchController;
+use super::model::{Model, DMG_COMPATIBILITY_BG_PALETTE, DMG_COMPATIBILITY_OBJ_PALETTE};
 use super::GameBoy;
 
-use image::GenericImage;
+use zerodmg_utils::little_endian::{u16_to_u8s, u8s_to_u16};
 
-// seems to be the right value to meet the apparent framerate
-const CYCLES_PER_LINE: u64 = 113;
+use image::{DynamicImage, GenericImage, ImageBuffer};
+
+use super::palette::TilePalette;
+use super::save_state::{StateReader, StateWriter};
+
+#[test]
+fn test_tile_pixel_bit_planes() {
+    // low bit plane first, then high bit plane, leftmost pixel in the high bit
+    let vram = [0b1010_0000, 0b1100_0000];
+    assert_eq!(tile_pixel(&vram, 0, 0, 0), 0b11);
+    assert_eq!(tile_pixel(&vram, 0, 1, 0), 0b10);
+    assert_eq!(tile_pixel(&vram, 0, 2, 0), 0b01);
+    assert_eq!(tile_pixel(&vram, 0, 3, 0), 0b00);
+}
+
+#[test]
+fn test_cgb_color_expands_rgb555() {
+    let mut palette_ram = [0u8; 0x40];
+    // palette 1, colour 2: pure red, then pure blue
+    palette_ram[8 + 4] = 0b0001_1111;
+    palette_ram[8 + 5] = 0b0000_0000;
+    assert_eq!(cgb_color(&palette_ram, 1, 2).data, [0xFF, 0x00, 0x00, 0xFF]);
+    palette_ram[8 + 4] = 0b0000_0000;
+    palette_ram[8 + 5] = 0b0111_1100;
+    assert_eq!(cgb_color(&palette_ram, 1, 2).data, [0x00, 0x00, 0xFF, 0xFF]);
+}
+
+#[test]
+fn test_oam_bug_write_corruption() {
+    let mut oam = [0u8; 0xA0];
+    for (i, byte) in oam.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    corrupt_oam(&mut oam, 2, OamBugAccess::Write);
+    // ((0x1110 ^ 0x0D0C) & (0x0908 ^ 0x0D0C)) ^ 0x0D0C, then the rest of row 1
+    assert_eq!(oam[16..24], [0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F]);
+    corrupt_oam(&mut oam, 3, OamBugAccess::Read);
+    // 0x0908 | (0x1918 & 0x0D0C)
+    assert_eq!(oam[24..26], [0x08, 0x09]);
+    // the first row isn't affected
+    corrupt_oam(&mut oam, 0, OamBugAccess::Write);
+    assert_eq!(oam[0], 0x00);
+}
+
+#[test]
+fn test_oam_bug_read_during_increase_corruption() {
+    let mut oam = [0u8; 0xA0];
+    // a, the first word of row 3
+    oam[24..26].copy_from_slice(&[0xFF, 0xFF]);
+    // b and d, the first and third words of row 4
+    oam[32..34].copy_from_slice(&[0x34, 0x12]);
+    oam[36..38].copy_from_slice(&[0xFF, 0x00]);
+    // c, the first word of row 5
+    oam[40..42].copy_from_slice(&[0x0F, 0x0F]);
+    corrupt_oam(&mut oam, 5, OamBugAccess::ReadDuringIncrease);
+    // (0x1234 & (0xFFFF | 0x0F0F | 0x00FF)) | (0xFFFF & 0x0F0F & 0x00FF),
+    // copied to row 3, and then to row 5 by the read corruption
+    let corrupted = [0x3F, 0x12, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00];
+    assert_eq!(oam[24..32], corrupted);
+    assert_eq!(oam[32..40], corrupted);
+    assert_eq!(oam[40..48], corrupted);
+    assert_eq!(oam[16..24], [0x00; 8]);
+}
+
+#[test]
+fn test_sprite_attribute_flags() {
+    let sprite = Sprite {
+        index: 3,
+        y: 16,
+        x: 8,
+        tile: 0x42,
+        flags: 0b1011_1101,
+    };
+    assert!(sprite.behind_background());
+    assert!(!sprite.y_flip());
+    assert!(sprite.x_flip());
+    assert_eq!(sprite.dmg_palette(), 1);
+    assert_eq!(sprite.cgb_bank(), 1);
+    assert_eq!(sprite.cgb_palette(), 5);
+}
+
+#[test]
+fn test_cgb_background_priority_over_sprites() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    gameboy.cgb_mode = true;
+    // tile 1 is all colour 1, used by the top left of the map and sprite 0
+    for y in 0..8 {
+        gameboy.vid.vram[0x10 + y * 2] = 0xFF;
+        gameboy.vid.vram[0x10 + y * 2 + 1] = 0x00;
+    }
+    gameboy.vid.vram[0x1800] = 0x01;
+    gameboy.vid.oam[0..4].copy_from_slice(&[16, 8, 0x01, 0x00]);
+    // red background and blue sprite
+    gameboy.vid.bg_palette_ram[2..4].copy_from_slice(&[0b0001_1111, 0b0000_0000]);
+    gameboy.vid.obj_palette_ram[2..4].copy_from_slice(&[0b0000_0000, 0b0111_1100]);
+    let red = [0xFF, 0x00, 0x00, 0xFF];
+    let blue = [0x00, 0x00, 0xFF, 0xFF];
+
+    let top_left = |gameboy: &mut GameBoy, attributes: u8, lcdc: u8| {
+        gameboy.vid.vram[0x3800] = attributes;
+        gameboy.set_lcdc(lcdc);
+        gameboy.draw_output();
+        let output_buffer = gameboy.output_buffer.lock().unwrap();
+        output_buffer.display.get_pixel(0, 0).data
+    };
+    assert_eq!(top_left(&mut gameboy, 0x00, 0x93), blue);
+    // the tile's priority attribute puts its non-zero colours in front
+    assert_eq!(top_left(&mut gameboy, 0x80, 0x93), red);
+    // unless LCDC bit 0 gives all sprites priority
+    assert_eq!(top_left(&mut gameboy, 0x80, 0x92), blue);
+    // and hidden sprites aren't drawn at all
+    assert_eq!(top_left(&mut gameboy, 0x00, 0x91), red);
+}
+
+#[cfg(test)]
+fn run_video_to(gameboy: &mut GameBoy, ly: u8, line_cycle: u64) {
+    gameboy.vid.restart_line(ly);
+    for _ in 0..line_cycle {
+        gameboy.video_cycle();
+    }
+}
+
+#[test]
+fn test_ppu_modes_and_stat() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    run_video_to(&mut gameboy, 0, 0);
+    assert_eq!(gameboy.mode(), 2);
+    run_video_to(&mut gameboy, 0, DRAWING_START_CYCLE);
+    assert_eq!(gameboy.mode(), 3);
+    run_video_to(&mut gameboy, 0, HBLANK_START_CYCLE);
+    assert_eq!(gameboy.mode(), 0);
+    run_video_to(&mut gameboy, GB_HEIGHT, 0);
+    assert_eq!(gameboy.ly(), GB_HEIGHT);
+    assert_eq!(gameboy.mode(), 1);
+
+    // only the interrupt enable bits are writable, and the LY = LYC flag
+    // follows LY
+    gameboy.set_stat(0xFF);
+    gameboy.set_lyc(GB_HEIGHT);
+    assert_eq!(gameboy.stat(), 0b1111_1101);
+    gameboy.set_lyc(0);
+    assert_eq!(gameboy.stat(), 0b1111_1001);
+
+    // nothing is in use while the LCD is off
+    gameboy.set_lcdc(0x11);
+    assert_eq!(gameboy.mode(), 0);
+}
+
+#[test]
+fn test_vram_and_oam_blocked_while_in_use() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    gameboy.set_video_access_logging(true);
+    gameboy.vid.oam_bytes_mut()[0] = 0x00;
+
+    run_video_to(&mut gameboy, 0, 0);
+    gameboy.set_mem(0x8000, 0x12);
+    assert_eq!(gameboy.mem(0x8000), 0x12);
+    gameboy.set_mem(0xFE00, 0x34);
+    assert_eq!(gameboy.mem(0xFE00), 0xFF);
+    run_video_to(&mut gameboy, 0, DRAWING_START_CYCLE);
+    gameboy.set_mem(0x8000, 0x56);
+    assert_eq!(gameboy.mem(0x8000), 0xFF);
+    run_video_to(&mut gameboy, 0, HBLANK_START_CYCLE);
+    assert_eq!(gameboy.mem(0x8000), 0x12);
+    assert_eq!(gameboy.mem(0xFE00), 0x00);
+    assert_eq!(
+        gameboy.take_log(),
+        vec![
+            "warning: dropping write of 0x34 to OAM at 0xFE00 during mode 2 (LY = 0)".to_string(),
+            "warning: dropping write of 0x56 to VRAM at 0x8000 during mode 3 (LY = 0)"
+                .to_string(),
+        ]
+    );
+
+    gameboy.set_relaxed_video_access(true);
+    gameboy.set_video_access_logging(false);
+    run_video_to(&mut gameboy, 1, DRAWING_START_CYCLE);
+    gameboy.set_mem(0x8000, 0x56);
+    gameboy.set_mem(0xFE00, 0x34);
+    assert_eq!(gameboy.mem(0x8000), 0x56);
+    assert_eq!(gameboy.mem(0xFE00), 0x34);
+    assert!(gameboy.take_log().is_empty());
+}
+
+#[test]
+fn test_oam_dma_ignores_access_restrictions() {
+    let mut gameboy = GameBoy::builder().skip_boot_rom().build().unwrap();
+    for i in 0..0xA0 {
+        gameboy.set_mem(0xC000 + i, i as u8);
+    }
+    run_video_to(&mut gameboy, 0, DRAWING_START_CYCLE);
+    gameboy.set_mem(0xFF46, 0xC0);
+    assert_eq!(gameboy.mem(0xFF46), 0xC0);
+    for (i, &byte) in gameboy.vid.oam_bytes().iter().enumerate() {
+        assert_eq!(byte, i as u8);
+    }
+}
+
+// 456 T-cycles, for 154 lines at 59.73 frames per second
+const CYCLES_PER_LINE: u64 = 114;
+// the cycle within each visible line at which the PPU stops searching OAM
+// and starts drawing
+const DRAWING_START_CYCLE: u64 = 20;
+// the cycle within each visible line at which horizontal blanking begins
+const HBLANK_START_CYCLE: u64 = 63;
 
 /// Game Boy video memory state
 pub struct VideoData {
     t: u64,
-    vram: [u8; 0x2000],
+    // video RAM, with the second bank only used in CGB mode
+    vram: [u8; 0x4000],
+    // VRAM bank select register (CGB)
+    vbk: u8,
     // background palette register
     bgp: u8,
+    // object palette registers
+    obp0: u8,
+    obp1: u8,
+    // window position registers
+    wy: u8,
+    wx: u8,
     // background scroll/offset x and y
     scx: u8,
     scy: u8,
@@ -18,33 +234,169 @@ pub struct VideoData {
     lcdc: u8,
     // LCD Y draw line
     ly: u8,
+    // LCD Y compare register
+    lyc: u8,
+    // interrupt enable bits of the LCD status register
+    stat: u8,
+    // object attribute memory
+    oam: [u8; 0xA0],
+    // last value written to the OAM DMA register
+    dma: u8,
+    // whether to allow CPU access to VRAM and OAM while the PPU is using them
+    relaxed_access: bool,
+    // whether to log CPU writes to VRAM and OAM while the PPU is using them
+    log_blocked_access: bool,
+    // whether to emulate the DMG's corruption of OAM by 16-bit register
+    // increments and decrements during mode 2
+    oam_bug: bool,
+    // background colour palette specification/index register (CGB)
+    bcps: u8,
+    // background colour palette memory, as little-endian RGB555 (CGB)
+    bg_palette_ram: [u8; 0x40],
+    // object colour palette specification/index register (CGB)
+    ocps: u8,
+    // object colour palette memory, as little-endian RGB555 (CGB)
+    obj_palette_ram: [u8; 0x40],
 }
 
 const GB_WIDTH: u8 = 160;
 const GB_HEIGHT: u8 = 144;
 
 impl VideoData {
-    pub fn new() -> Self {
+    pub fn new(ram: &mut RamInitializer) -> Self {
         Self {
             t: 0,
             vram: {
-                let mut a = [0u8; 0x2000];
-                for x in a.iter_mut() {
-                    *x = rand::random();
-                }
+                let mut a = [0u8; 0x4000];
+                ram.fill(&mut a);
                 a
             },
-            bgp: rand::random(),
+            vbk: 0x00,
+            bgp: ram.byte(),
+            obp0: ram.byte(),
+            obp1: ram.byte(),
+            wy: 0x00,
+            wx: 0x00,
             scx: 0x00,
             scy: 0x00,
             lcdc: 0x00,
             ly: 0x00,
+            lyc: 0x00,
+            stat: 0x00,
+            oam: {
+                let mut a = [0u8; 0xA0];
+                ram.fill(&mut a);
+                a
+            },
+            dma: 0x00,
+            relaxed_access: false,
+            log_blocked_access: false,
+            oam_bug: true,
+            bcps: 0x00,
+            bg_palette_ram: {
+                let mut a = [0u8; 0x40];
+                ram.fill(&mut a);
+                a
+            },
+            ocps: 0x00,
+            obj_palette_ram: {
+                let mut a = [0u8; 0x40];
+                ram.fill(&mut a);
+                a
+            },
+        }
+    }
+
+    /// Saves the state of the PPU, other than the access emulation settings.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.t);
+        state.bytes(&self.vram);
+        for &register in &[
+            self.vbk, self.bgp, self.obp0, self.obp1, self.wy, self.wx, self.scx, self.scy,
+            self.lcdc, self.ly, self.lyc, self.stat,
+        ] {
+            state.u8(register);
+        }
+        state.bytes(&self.oam);
+        state.u8(self.dma);
+        state.u8(self.bcps);
+        state.bytes(&self.bg_palette_ram);
+        state.u8(self.ocps);
+        state.bytes(&self.obj_palette_ram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.u64(&mut self.t);
+        state.bytes(&mut self.vram);
+        for register in vec![
+            &mut self.vbk,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.scx,
+            &mut self.scy,
+            &mut self.lcdc,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.stat,
+        ] {
+            state.u8(register);
         }
+        state.bytes(&mut self.oam);
+        state.u8(&mut self.dma);
+        state.u8(&mut self.bcps);
+        state.bytes(&mut self.bg_palette_ram);
+        state.u8(&mut self.ocps);
+        state.bytes(&mut self.obj_palette_ram);
+    }
+
+    pub fn vram_bytes(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn vram_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+
+    pub fn oam_bytes(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn oam_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.oam
+    }
+
+    pub fn bg_palette_ram(&self) -> &[u8] {
+        &self.bg_palette_ram
+    }
+
+    pub fn bg_palette_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.bg_palette_ram
+    }
+
+    pub fn obj_palette_ram(&self) -> &[u8] {
+        &self.obj_palette_ram
+    }
+
+    pub fn obj_palette_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.obj_palette_ram
+    }
+
+    /// Moves to the start of the given line of the current frame, for
+    /// restoring states that don't include the position within the line.
+    pub fn restart_line(&mut self, ly: u8) {
+        let frame_cycles = CYCLES_PER_LINE * u64::from(GB_HEIGHT + 10);
+        let line = u64::from(ly) % u64::from(GB_HEIGHT + 10);
+        self.t = self.t - self.t % frame_cycles + line * CYCLES_PER_LINE;
+        self.ly = line as u8;
     }
 }
 
 pub trait VideoController {
     fn video_cycle(&mut self);
+    fn vblank_started(&self) -> bool;
     fn vram(&self, index: usize) -> u8;
     fn set_vram(&mut self, index: usize, value: u8);
     fn bgp(&self) -> u8;
@@ -57,7 +409,255 @@ pub trait VideoController {
     fn set_lcdc(&mut self, value: u8);
     fn ly(&self) -> u8;
     fn set_ly(&mut self, value: u8);
+    fn lyc(&self) -> u8;
+    fn set_lyc(&mut self, value: u8);
+    fn mode(&self) -> u8;
+    fn stat(&self) -> u8;
+    fn set_stat(&mut self, value: u8);
+    fn oam(&self, index: usize) -> u8;
+    fn set_oam(&mut self, index: usize, value: u8);
+    fn oam_bug(&mut self, address: u16, access: OamBugAccess);
+    fn dma(&self) -> u8;
+    fn set_dma(&mut self, value: u8);
+    fn vbk(&self) -> u8;
+    fn set_vbk(&mut self, value: u8);
+    fn bcps(&self) -> u8;
+    fn set_bcps(&mut self, value: u8);
+    fn bcpd(&self) -> u8;
+    fn set_bcpd(&mut self, value: u8);
+    fn ocps(&self) -> u8;
+    fn set_ocps(&mut self, value: u8);
+    fn ocpd(&self) -> u8;
+    fn set_ocpd(&mut self, value: u8);
+    fn set_compatibility_palettes(&mut self);
+    fn bg_tile_data_index(&self, tile_index: u8) -> usize;
+    fn obp0(&self) -> u8;
+    fn set_obp0(&mut self, value: u8);
+    fn obp1(&self) -> u8;
+    fn set_obp1(&mut self, value: u8);
+    fn wy(&self) -> u8;
+    fn set_wy(&mut self, value: u8);
+    fn wx(&self) -> u8;
+    fn set_wx(&mut self, value: u8);
     fn draw_output(&mut self);
+    fn draw_tile_map(&self, map_offset: usize, bg_palette: &[image::Rgba<u8>; 4]) -> DynamicImage;
+    fn tile_map_pixel(&self, map_offset: usize, x: u8, y: u8) -> (u8, u8);
+    fn draw_tiles(&self, colors: &[image::Rgba<u8>; 4]) -> DynamicImage;
+    fn draw_sprites(&self, obj_palettes: &[[image::Rgba<u8>; 4]; 2]) -> DynamicImage;
+    fn draw_display_sprites(
+        &self,
+        display: &mut DynamicImage,
+        bg_pixels: &[(u8, u8)],
+        obj_palettes: &[[image::Rgba<u8>; 4]; 2],
+    );
+    fn sprite_pixel(&self, sprite: &Sprite, x: u8, y: u8) -> u8;
+    fn sprite_color(
+        &self,
+        sprite: &Sprite,
+        color_number: u8,
+        obj_palettes: &[[image::Rgba<u8>; 4]; 2],
+    ) -> image::Rgba<u8>;
+    fn sprites(&self) -> Vec<Sprite>;
+    fn sprite_height(&self) -> u8;
+}
+
+/// The kinds of CPU bus activity that can corrupt OAM on the DMG if they
+/// happen in the 0xFE00-0xFEFF range while the PPU is scanning OAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OamBugAccess {
+    /// A write, or a 16-bit increment or decrement.
+    Write,
+    /// A read.
+    Read,
+    /// A read in the same cycle as a 16-bit increment or decrement, such as
+    /// by LD A, (HL+) or POP.
+    ReadDuringIncrease,
+}
+
+/// Applies the corruption pattern for an access that collided with the
+/// PPU's read of the given OAM row, operating on 16-bit words.
+fn corrupt_oam(oam: &mut [u8; 0xA0], row: usize, access: OamBugAccess) {
+    let word = |oam: &[u8; 0xA0], row: usize, n: usize| {
+        u8s_to_u16(oam[row * 8 + n * 2], oam[row * 8 + n * 2 + 1])
+    };
+    let set_word = |oam: &mut [u8; 0xA0], row: usize, n: usize, value: u16| {
+        let [low, high] = u16_to_u8s(value);
+        oam[row * 8 + n * 2] = low;
+        oam[row * 8 + n * 2 + 1] = high;
+    };
+    let copy_row = |oam: &mut [u8; 0xA0], from: usize, to: usize| {
+        for i in 0..8 {
+            oam[to * 8 + i] = oam[from * 8 + i];
+        }
+    };
+
+    // the first row is never corrupted
+    if row == 0 || row >= 20 {
+        return;
+    }
+
+    if access == OamBugAccess::ReadDuringIncrease && 4 <= row && row < 19 {
+        let a = word(oam, row - 2, 0);
+        let b = word(oam, row - 1, 0);
+        let c = word(oam, row, 0);
+        let d = word(oam, row - 1, 2);
+        set_word(oam, row - 1, 0, (b & (a | c | d)) | (a & c & d));
+        copy_row(oam, row - 1, row - 2);
+    }
+
+    let a = word(oam, row, 0);
+    let b = word(oam, row - 1, 0);
+    let c = word(oam, row - 1, 2);
+    let first_word = match access {
+        OamBugAccess::Write => ((a ^ c) & (b ^ c)) ^ c,
+        OamBugAccess::Read | OamBugAccess::ReadDuringIncrease => b | (a & c),
+    };
+    copy_row(oam, row - 1, row);
+    set_word(oam, row, 0, first_word);
+}
+
+/// A decoded entry from object attribute memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sprite {
+    /// The index of this sprite in OAM (0-39).
+    pub index: u8,
+    /// The vertical position, plus 16.
+    pub y: u8,
+    /// The horizontal position, plus 8.
+    pub x: u8,
+    /// The tile index, from 0x8000.
+    pub tile: u8,
+    /// The attribute flags.
+    pub flags: u8,
+}
+
+impl Sprite {
+    /// Whether the sprite is drawn behind non-zero background colours.
+    pub fn behind_background(&self) -> bool {
+        self.flags & 0b1000_0000 != 0
+    }
+
+    /// Whether the sprite is flipped vertically.
+    pub fn y_flip(&self) -> bool {
+        self.flags & 0b0100_0000 != 0
+    }
+
+    /// Whether the sprite is flipped horizontally.
+    pub fn x_flip(&self) -> bool {
+        self.flags & 0b0010_0000 != 0
+    }
+
+    /// Which DMG object palette (OBP0 or OBP1) the sprite uses.
+    pub fn dmg_palette(&self) -> u8 {
+        (self.flags & 0b0001_0000) >> 4
+    }
+
+    /// Which VRAM bank the sprite's tile is in, in CGB mode.
+    pub fn cgb_bank(&self) -> u8 {
+        (self.flags & 0b0000_1000) >> 3
+    }
+
+    /// Which CGB object colour palette the sprite uses, in CGB mode.
+    pub fn cgb_palette(&self) -> u8 {
+        self.flags & 0b0000_0111
+    }
+}
+
+impl std::fmt::Display for Sprite {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "#{:02} x = {:3} y = {:3} tile = 0x{:02X} flags = 0b{:08b} ; OBP{} bank {} palette {}{}{}{}",
+            self.index,
+            i16::from(self.x) - 8,
+            i16::from(self.y) - 16,
+            self.tile,
+            self.flags,
+            self.dmg_palette(),
+            self.cgb_bank(),
+            self.cgb_palette(),
+            if self.x_flip() { ", x-flipped" } else { "" },
+            if self.y_flip() { ", y-flipped" } else { "" },
+            if self.behind_background() {
+                ", behind background"
+            } else {
+                ""
+            },
+        )
+    }
+}
+
+// colour used for the gaps between tiles in debug images
+const GAP_COLOR: image::Rgba<u8> = image::Rgba {
+    data: [0x90, 0x60, 0x90, 0xFF],
+};
+
+/// Fades out the tile map outside of the display-sized viewport with its top
+/// left corner at the given position, wrapping around the edges.
+fn mark_viewport(map: &mut DynamicImage, left: u8, top: u8) {
+    let border_width: i16 = 12;
+    for dy in -border_width..border_width {
+        let dya: u8 = (if dy > 0 { dy + 1 } else { -dy }) as u8;
+        let dyp = if dy < 0 {
+            dy
+        } else {
+            dy + i16::from(GB_HEIGHT)
+        };
+        let y = u32::from((i16::from(top) + dyp) as u8);
+
+        for x in 0..=0xFF {
+            let mut color = map.get_pixel(x, y);
+            color.data[3] = ((color.data[3] as u32 * dya as u32) / (border_width as u32)) as u8;
+            map.put_pixel(x, y, color);
+        }
+    }
+    for dx in -border_width..border_width {
+        let dxa: u8 = (if dx > 0 { dx + 1 } else { -dx }) as u8;
+        let dxp = if dx < 0 { dx } else { dx + i16::from(GB_WIDTH) };
+        let x = u32::from((i16::from(left) + dxp) as u8);
+
+        for y in 0..=0xFF {
+            let mut color = map.get_pixel(x, y);
+            color.data[3] = ((color.data[3] as u32 * dxa as u32) / (border_width as u32)) as u8;
+            map.put_pixel(x, y, color);
+        }
+    }
+}
+
+/// Outlines the region of the tile map visible through the window, which
+/// always starts from the top left of the map.
+fn mark_window(map: &mut DynamicImage, width: u32, height: u32) {
+    let color = image::Rgba([0xCC, 0x33, 0xCC, 0xFF]);
+    let right = width.min(256) - 1;
+    let bottom = height.min(256) - 1;
+    for x in 0..=right {
+        map.put_pixel(x, 0, color);
+        map.put_pixel(x, bottom, color);
+    }
+    for y in 0..=bottom {
+        map.put_pixel(0, y, color);
+        map.put_pixel(right, y, color);
+    }
+}
+
+/// Returns the two-bit colour number of a pixel in the tile whose data
+/// starts at the given index in VRAM.
+fn tile_pixel(vram: &[u8], tile_data_index: usize, x: u8, y: u8) -> u8 {
+    let low_byte = vram[tile_data_index + usize::from(y) * 2];
+    let high_byte = vram[tile_data_index + usize::from(y) * 2 + 1];
+    let bit = 7 - x;
+    (((high_byte >> bit) & 1) << 1) | ((low_byte >> bit) & 1)
+}
+
+/// Returns the colour for a colour number in a CGB palette.
+fn cgb_color(palette_ram: &[u8; 0x40], palette: u8, color_number: u8) -> image::Rgba<u8> {
+    let i = usize::from(palette & 0b111) * 8 + usize::from(color_number & 0b11) * 2;
+    let rgb555 = u8s_to_u16(palette_ram[i], palette_ram[i + 1]);
+    let channel = |shift: u16| {
+        let value = ((rgb555 >> shift) & 0b1_1111) as u8;
+        (value << 3) | (value >> 2)
+    };
+    image::Rgba([channel(0), channel(5), channel(10), 0xFF])
 }
 
 impl VideoController for GameBoy {
@@ -69,237 +669,596 @@ impl VideoController for GameBoy {
         if 0 == self.vid.ly && 0 == self.vid.t % CYCLES_PER_LINE {
             self.draw_output();
         }
+
+        if self.vid.ly < GB_HEIGHT && HBLANK_START_CYCLE == self.vid.t % CYCLES_PER_LINE {
+            self.hdma_hblank();
+        }
+    }
+
+    /// Whether the last video cycle was the first of vertical blanking.
+    fn vblank_started(&self) -> bool {
+        self.vid.ly == GB_HEIGHT && 0 == self.vid.t % CYCLES_PER_LINE
     }
 
     fn vram(&self, index: usize) -> u8 {
-        self.vid.vram[index]
+        if self.mode() == 3 && !self.vid.relaxed_access {
+            return 0xFF;
+        }
+
+        self.vid.vram[usize::from(self.vbk() & 0b1) * 0x2000 + index]
     }
 
     fn set_vram(&mut self, index: usize, value: u8) {
-        // println!("    ; vram[0x{:02X}] = 0x{:02X}", index, value);
-        self.vid.vram[index] = value;
+        let mode = self.mode();
+        if mode == 3 {
+            if self.vid.log_blocked_access {
+                self.log(format!(
+                    "warning: {} write of 0x{:02X} to VRAM at 0x{:04X} during mode {} (LY = {})",
+                    if self.vid.relaxed_access {
+                        "allowing"
+                    } else {
+                        "dropping"
+                    },
+                    value,
+                    0x8000 + index,
+                    mode,
+                    self.ly()
+                ));
+            }
+            if !self.vid.relaxed_access {
+                return;
+            }
+        }
+
+        self.vid.vram[usize::from(self.vbk() & 0b1) * 0x2000 + index] = value;
+    }
+
+    fn oam(&self, index: usize) -> u8 {
+        let mode = self.mode();
+        if (mode == 2 || mode == 3) && !self.vid.relaxed_access {
+            return 0xFF;
+        }
+
+        self.vid.oam[index]
+    }
+
+    fn set_oam(&mut self, index: usize, value: u8) {
+        let mode = self.mode();
+        if mode == 2 || mode == 3 {
+            if self.vid.log_blocked_access {
+                self.log(format!(
+                    "warning: {} write of 0x{:02X} to OAM at 0x{:04X} during mode {} (LY = {})",
+                    if self.vid.relaxed_access {
+                        "allowing"
+                    } else {
+                        "dropping"
+                    },
+                    value,
+                    0xFE00 + index,
+                    mode,
+                    self.ly()
+                ));
+            }
+            if !self.vid.relaxed_access {
+                return;
+            }
+        }
+
+        self.vid.oam[index] = value;
+    }
+
+    fn oam_bug(&mut self, address: u16, access: OamBugAccess) {
+        if !self.vid.oam_bug || self.model != Model::Dmg {
+            return;
+        }
+        if address < 0xFE00 || 0xFEFF < address || self.mode() != 2 {
+            return;
+        }
+
+        // during mode 2 the PPU reads one 8-byte row of OAM per cycle
+        let row = (self.vid.t % CYCLES_PER_LINE) as usize;
+        corrupt_oam(&mut self.vid.oam, row, access);
+    }
+
+    fn dma(&self) -> u8 {
+        self.vid.dma
+    }
+
+    /// Copies 0xA0 bytes into OAM from the page given by the value.
+    ///
+    /// This happens instantly, rather than over 160 cycles, and isn't subject
+    /// to the PPU mode access restrictions.
+    fn set_dma(&mut self, value: u8) {
+        self.vid.dma = value;
+        let source = u16::from(value) << 8;
+        for i in 0..0xA0 {
+            self.vid.oam[usize::from(i)] = self.mem(source + i);
+        }
+    }
+
+    /// Returns the current PPU mode: 0 in horizontal blanking, 1 in vertical
+    /// blanking, 2 while searching OAM, or 3 while drawing.
+    fn mode(&self) -> u8 {
+        if self.lcdc() & 0b1000_0000 == 0 {
+            // the LCD is off, so it's not using anything
+            return 0;
+        }
+
+        if self.vid.ly >= GB_HEIGHT {
+            return 1;
+        }
+
+        let line_cycle = self.vid.t % CYCLES_PER_LINE;
+        if line_cycle < DRAWING_START_CYCLE {
+            2
+        } else if line_cycle < HBLANK_START_CYCLE {
+            3
+        } else {
+            0
+        }
+    }
+
+    fn stat(&self) -> u8 {
+        0b1000_0000
+            | self.vid.stat
+            | if self.ly() == self.lyc() { 0b0100 } else { 0 }
+            | self.mode()
+    }
+
+    fn set_stat(&mut self, value: u8) {
+        // only the interrupt enable bits are writable
+        self.vid.stat = value & 0b0111_1000;
+    }
+
+    fn lyc(&self) -> u8 {
+        self.vid.lyc
+    }
+
+    fn set_lyc(&mut self, value: u8) {
+        self.vid.lyc = value;
+    }
+
+    fn vbk(&self) -> u8 {
+        if self.cgb_mode {
+            0b1111_1110 | self.vid.vbk
+        } else {
+            0b1111_1110
+        }
+    }
+
+    fn set_vbk(&mut self, value: u8) {
+        if self.cgb_mode {
+            self.vid.vbk = value & 0b1;
+        }
+    }
+
+    fn bcps(&self) -> u8 {
+        0b0100_0000 | self.vid.bcps
+    }
+
+    fn set_bcps(&mut self, value: u8) {
+        self.vid.bcps = value & 0b1011_1111;
+    }
+
+    fn bcpd(&self) -> u8 {
+        self.vid.bg_palette_ram[usize::from(self.vid.bcps & 0b0011_1111)]
+    }
+
+    fn set_bcpd(&mut self, value: u8) {
+        let bcps = self.vid.bcps;
+        self.vid.bg_palette_ram[usize::from(bcps & 0b0011_1111)] = value;
+        if bcps & 0b1000_0000 != 0 {
+            self.vid.bcps = 0b1000_0000 | (bcps.wrapping_add(1) & 0b0011_1111);
+        }
+    }
+
+    fn ocps(&self) -> u8 {
+        0b0100_0000 | self.vid.ocps
+    }
+
+    fn set_ocps(&mut self, value: u8) {
+        self.vid.ocps = value & 0b1011_1111;
+    }
+
+    fn ocpd(&self) -> u8 {
+        self.vid.obj_palette_ram[usize::from(self.vid.ocps & 0b0011_1111)]
+    }
+
+    fn set_ocpd(&mut self, value: u8) {
+        let ocps = self.vid.ocps;
+        self.vid.obj_palette_ram[usize::from(ocps & 0b0011_1111)] = value;
+        if ocps & 0b1000_0000 != 0 {
+            self.vid.ocps = 0b1000_0000 | (ocps.wrapping_add(1) & 0b0011_1111);
+        }
+    }
+
+    /// Installs the colour palettes the CGB boot ROM uses for DMG games,
+    /// which BGP/OBP0/OBP1 shades are then looked up in.
+    fn set_compatibility_palettes(&mut self) {
+        for (i, color) in DMG_COMPATIBILITY_BG_PALETTE.iter().enumerate() {
+            let [low, high] = u16_to_u8s(*color);
+            self.vid.bg_palette_ram[i * 2] = low;
+            self.vid.bg_palette_ram[i * 2 + 1] = high;
+        }
+        for palette in 0..2 {
+            for (i, color) in DMG_COMPATIBILITY_OBJ_PALETTE.iter().enumerate() {
+                let [low, high] = u16_to_u8s(*color);
+                self.vid.obj_palette_ram[palette * 8 + i * 2] = low;
+                self.vid.obj_palette_ram[palette * 8 + i * 2 + 1] = high;
+            }
+        }
+    }
+
+    /// Returns the index in a VRAM bank of the data for a background tile,
+    /// depending on the addressing mode selected in LCDC.
+    fn bg_tile_data_index(&self, tile_index: u8) -> usize {
+        if self.lcdc() & 0b0001_0000 != 0 {
+            usize::from(tile_index) * 16
+        } else {
+            (0x1000 + i32::from(tile_index as i8) * 16) as usize
+        }
     }
 
     fn draw_output(&mut self) {
         // redraw display because vram was touched!
-        let (mut display, mut bg_0, mut tiles, mut bgp) = {
+        let (mut display, mut bgp, mut op_0, mut op_1, palette, tile_palette) = {
             let output_buffer = self.output_buffer.lock().unwrap();
             (
                 output_buffer.display.clone(),
-                output_buffer.bg_0.clone(),
-                output_buffer.tiles.clone(),
                 output_buffer.bgp.clone(),
+                output_buffer.op_0.clone(),
+                output_buffer.op_1.clone(),
+                output_buffer.palette,
+                output_buffer.tile_palette,
             )
         };
 
+        let cgb_mode = self.cgb_mode;
+        // a CGB running a DMG game looks shades up in its colour palettes
+        let compatibility_mode = self.model == Model::Cgb && !cgb_mode;
+        let shade_color = |shade: u8| {
+            if compatibility_mode {
+                cgb_color(&self.vid.bg_palette_ram, 0, shade)
+            } else {
+                palette.color(shade)
+            }
+        };
+        let object_shade_color = |obp_index: u8, shade: u8| {
+            if compatibility_mode {
+                cgb_color(&self.vid.obj_palette_ram, obp_index, shade)
+            } else {
+                palette.color(shade)
+            }
+        };
+
         // draw background palettes
-        // the ! is because the GB colours values are darkness, but RGB is brightness
-        let bgp_a = (!self.bgp() & 0b1100_0000) >> 6;
-        let bgp_a_color = image::Rgba([
-            bgp_a * 0b0101_0101,
-            bgp_a * 0b0101_0101,
-            bgp_a * 0b0101_0101,
-            0xFF,
-        ]);
-        let bgp_b = (!self.bgp() & 0b0011_0000) >> 4;
-        let bgp_b_color = image::Rgba([
-            bgp_b * 0b0101_0101,
-            bgp_b * 0b0101_0101,
-            bgp_b * 0b0101_0101,
-            0xFF,
-        ]);
-        let bgp_c = (!self.bgp() & 0b0000_1100) >> 2;
-        let bgp_c_color = image::Rgba([
-            bgp_c * 0b0101_0101,
-            bgp_c * 0b0101_0101,
-            bgp_c * 0b0101_0101,
-            0xFF,
-        ]);
-        let bgp_d = (!self.bgp() & 0b0000_0011) >> 0;
-        let bgp_d_color = image::Rgba([
-            bgp_d * 0b0101_0101,
-            bgp_d * 0b0101_0101,
-            bgp_d * 0b0101_0101,
-            0xFF,
-        ]);
+        let bgp_a = (self.bgp() & 0b1100_0000) >> 6;
+        let bgp_a_color = shade_color(bgp_a);
+        let bgp_b = (self.bgp() & 0b0011_0000) >> 4;
+        let bgp_b_color = shade_color(bgp_b);
+        let bgp_c = (self.bgp() & 0b0000_1100) >> 2;
+        let bgp_c_color = shade_color(bgp_c);
+        let bgp_d = (self.bgp() & 0b0000_0011) >> 0;
+        let bgp_d_color = shade_color(bgp_d);
         bgp.put_pixel(0, 0, bgp_a_color);
         bgp.put_pixel(1, 0, bgp_b_color);
         bgp.put_pixel(2, 0, bgp_c_color);
         bgp.put_pixel(3, 0, bgp_d_color);
-        let bg_palette = [bgp_a_color, bgp_b_color, bgp_c_color, bgp_d_color];
-
-        // draw tiles into debug buffer
-        for i in 0..256 {
-            let tile_data = &self.vid.vram[i * 16..(i + 1) * 16];
-            let mut new_tile_data = vec![0u8; 16];
-            new_tile_data.clone_from_slice(tile_data);
-            for y_offset in 0..8 {
-                let low_byte = new_tile_data[y_offset * 2 + 1];
-                let high_byte = new_tile_data[y_offset * 2];
-                let first_byte = ((high_byte & 0b1000_0000) >> 0)
-                    | ((low_byte & 0b1000_0000) >> 1)
-                    | ((high_byte & 0b0100_0000) >> 1)
-                    | ((low_byte & 0b0100_0000) >> 2)
-                    | ((high_byte & 0b0010_0000) >> 2)
-                    | ((low_byte & 0b0010_0000) >> 3)
-                    | ((high_byte & 0b0001_0000) >> 3)
-                    | ((low_byte & 0b0001_0000) >> 4);
-                let second_byte = ((high_byte & 0b0000_1000) << 4)
-                    | ((low_byte & 0b0000_1000) << 3)
-                    | ((high_byte & 0b0000_0100) << 3)
-                    | ((low_byte & 0b0000_0100) << 2)
-                    | ((high_byte & 0b0000_0010) << 2)
-                    | ((low_byte & 0b0000_0010) << 1)
-                    | ((high_byte & 0b0000_0001) << 1)
-                    | ((low_byte & 0b0000_0001) << 0);
-                new_tile_data[y_offset * 2] = first_byte;
-                new_tile_data[y_offset * 2 + 1] = second_byte;
-            }
+        // indexed by colour number
+        let bg_palette = [bgp_d_color, bgp_c_color, bgp_b_color, bgp_a_color];
 
-            for j in 0..16 {
-                let tile_col = (i % 16) as u32;
-                let x_tile_offset = 8 * i64::from(tile_col);
-                let x = ((x_tile_offset + 4 * (j % 2) as i64) % 256) as u32;
-
-                let tile_row = (i / 16) as u32;
-                let y_tile_offset = 8 * i64::from(tile_row);
-                let y = ((y_tile_offset + 1 * (j / 2) as i64) % 256) as u32;
-
-                let byte = !new_tile_data[j];
-                let a = (byte & 0b1100_0000) >> 6;
-                let a_color =
-                    image::Rgba([a * 0b0101_0101, a * 0b0101_0101, a * 0b0101_0101, 0xFF]);
-                let b = (byte & 0b0011_0000) >> 4;
-                let b_color =
-                    image::Rgba([b * 0b0101_0101, b * 0b0101_0101, b * 0b0101_0101, 0xFF]);
-                let c = (byte & 0b0000_1100) >> 2;
-                let c_color =
-                    image::Rgba([c * 0b0101_0101, c * 0b0101_0101, c * 0b0101_0101, 0xFF]);
-                let d = (byte & 0b0000_0011) >> 0;
-                let d_color =
-                    image::Rgba([d * 0b0101_0101, d * 0b0101_0101, d * 0b0101_0101, 0xFF]);
-
-                tiles.put_pixel(x + 0 + tile_col, y + tile_row, a_color);
-                tiles.put_pixel(x + 1 + tile_col, y + tile_row, b_color);
-                tiles.put_pixel(x + 2 + tile_col, y + tile_row, c_color);
-                tiles.put_pixel(x + 3 + tile_col, y + tile_row, d_color);
+        // draw object palettes, which don't display colour 0 (transparent)
+        let mut obj_palettes = [[bgp_d_color; 4]; 2];
+        for (obp_index, obp) in [self.obp0(), self.obp1()].iter().enumerate() {
+            for color_number in 0..4u8 {
+                let shade = (obp >> (color_number * 2)) & 0b11;
+                obj_palettes[obp_index][usize::from(color_number)] =
+                    object_shade_color(obp_index as u8, shade);
             }
         }
+        for color_number in 1..4u8 {
+            let x = 3 - u32::from(color_number);
+            op_0.put_pixel(x, 0, obj_palettes[0][usize::from(color_number)]);
+            op_1.put_pixel(x, 0, obj_palettes[1][usize::from(color_number)]);
+        }
 
-        // draw background
-        for i in 0..1024 {
-            let tile_index = self.vid.vram[0x1800 + i];
-            let tile_data_index = tile_index as usize * 16;
-            let tile_data = &self.vid.vram[tile_data_index..tile_data_index + 16];
-
-            let mut new_tile_data = vec![0u8; 16];
-            new_tile_data.clone_from_slice(tile_data);
-
-            for y_offset in 0..8 {
-                // XXX: this is really dumb.
-                // why are you converting back into bytes?
-                // convert into pixels.
-                let low_byte = new_tile_data[y_offset * 2 + 1];
-                let high_byte = new_tile_data[y_offset * 2];
-                let first_byte = ((high_byte & 0b1000_0000) >> 0)
-                    | ((low_byte & 0b1000_0000) >> 1)
-                    | ((high_byte & 0b0100_0000) >> 1)
-                    | ((low_byte & 0b0100_0000) >> 2)
-                    | ((high_byte & 0b0010_0000) >> 2)
-                    | ((low_byte & 0b0010_0000) >> 3)
-                    | ((high_byte & 0b0001_0000) >> 3)
-                    | ((low_byte & 0b0001_0000) >> 4);
-                let second_byte = ((high_byte & 0b0000_1000) << 4)
-                    | ((low_byte & 0b0000_1000) << 3)
-                    | ((high_byte & 0b0000_0100) << 3)
-                    | ((low_byte & 0b0000_0100) << 2)
-                    | ((high_byte & 0b0000_0010) << 2)
-                    | ((low_byte & 0b0000_0010) << 1)
-                    | ((high_byte & 0b0000_0001) << 1)
-                    | ((low_byte & 0b0000_0001) << 0);
-                new_tile_data[y_offset * 2] = first_byte;
-                new_tile_data[y_offset * 2 + 1] = second_byte;
-            }
-
-            for j in 0..16 {
-                let tile_col = (i % 32) as u32;
-                let x_tile_offset = 8 * i64::from(tile_col);
-                let x = ((x_tile_offset + 4 * (j % 2) as i64) % 256) as u32;
-                let scrolled_x = (x + 256 - u32::from(self.scx())) % 256;
-
-                let tile_row = (i / 32) as u32;
-                let y_tile_offset = 8 * i64::from(tile_row);
-                let y = ((y_tile_offset + 1 * (j / 2) as i64) % 256) as u32;
-                let scrolled_y = (y + 256 - u32::from(self.scy())) % 256;
-
-                let byte = !new_tile_data[j];
-                let a = (byte & 0b1100_0000) >> 6;
-                let a_color = bg_palette[a as usize];
-                let b = (byte & 0b0011_0000) >> 4;
-                let b_color = bg_palette[b as usize];
-                let c = (byte & 0b0000_1100) >> 2;
-                let c_color = bg_palette[c as usize];
-                let d = (byte & 0b0000_0011) >> 0;
-                let d_color = bg_palette[d as usize];
-
-                bg_0.put_pixel((x + 0) % 256, y % 256, a_color);
-                bg_0.put_pixel((x + 1) % 256, y % 256, b_color);
-                bg_0.put_pixel((x + 2) % 256, y % 256, c_color);
-                bg_0.put_pixel((x + 3) % 256, y % 256, d_color);
-
-                if scrolled_y < GB_HEIGHT.into() {
-                    if ((scrolled_x + 0) as u8) < GB_WIDTH {
-                        display.put_pixel((scrolled_x + 0) % 256, scrolled_y, a_color);
-                    }
-                    if ((scrolled_x + 1) as u8) < GB_WIDTH {
-                        display.put_pixel((scrolled_x + 1) % 256, scrolled_y, b_color);
-                    }
-                    if ((scrolled_x + 2) as u8) < GB_WIDTH {
-                        display.put_pixel((scrolled_x + 2) % 256, scrolled_y, c_color);
-                    }
-                    if ((scrolled_x + 3) as u8) < GB_WIDTH {
-                        display.put_pixel((scrolled_x + 3) % 256, scrolled_y, d_color);
-                    }
+        // draw both tile maps, and the display from the one selected for the
+        // background
+        let mut bg_0 = self.draw_tile_map(0x1800, &bg_palette);
+        let mut bg_1 = self.draw_tile_map(0x1C00, &bg_palette);
+        let bg_uses_second_map = self.lcdc() & 0b0000_1000 != 0;
+        let bg_map_offset = if bg_uses_second_map { 0x1C00 } else { 0x1800 };
+        // the colour number and CGB attributes under each display pixel, for
+        // deciding whether sprites are drawn over it
+        let mut bg_pixels = Vec::with_capacity(usize::from(GB_WIDTH) * usize::from(GB_HEIGHT));
+        {
+            let bg_map = if bg_uses_second_map { &bg_1 } else { &bg_0 };
+            for y in 0..u32::from(GB_HEIGHT) {
+                for x in 0..u32::from(GB_WIDTH) {
+                    let map_x = (x + u32::from(self.scx())) % 256;
+                    let map_y = (y + u32::from(self.scy())) % 256;
+                    display.put_pixel(x, y, bg_map.get_pixel(map_x, map_y));
+                    bg_pixels.push(self.tile_map_pixel(bg_map_offset, map_x as u8, map_y as u8));
                 }
             }
         }
+        if self.lcdc() & 0b0000_0010 != 0 {
+            self.draw_display_sprites(&mut display, &bg_pixels, &obj_palettes);
+        }
 
-        // Draw border around active background in debug buffer.
-        let border_width: i16 = 12;
-        for dy in -border_width..border_width {
-            let dya: u8 = (if dy > 0 { dy + 1 } else { -dy }) as u8;
-            let dyp = if dy < 0 {
-                dy
+        // mark the parts of the tile maps that are visible on the display
+        let (scx, scy) = (self.scx(), self.scy());
+        mark_viewport(if bg_uses_second_map { &mut bg_1 } else { &mut bg_0 }, scx, scy);
+        let window_enabled = self.lcdc() & 0b0010_0001 == 0b0010_0001;
+        let window_x = i32::from(self.wx()) - 7;
+        let window_y = i32::from(self.wy());
+        if window_enabled && window_x < i32::from(GB_WIDTH) && window_y < i32::from(GB_HEIGHT) {
+            let window_map = if self.lcdc() & 0b0100_0000 != 0 {
+                &mut bg_1
             } else {
-                dy + i16::from(GB_HEIGHT)
+                &mut bg_0
             };
-            let y = u32::from((i16::from(self.scy()) + dyp) as u8);
-
-            for x in 0..=0xFF {
-                let mut color = bg_0.get_pixel(x, y);
-                color.data[3] = ((color.data[3] as u32 * dya as u32) / (border_width as u32)) as u8;
-                bg_0.put_pixel(x, y, color);
-            }
+            mark_window(
+                window_map,
+                (i32::from(GB_WIDTH) - window_x.max(0)) as u32,
+                (i32::from(GB_HEIGHT) - window_y) as u32,
+            );
         }
-        for dx in -border_width..border_width {
-            let dxa: u8 = (if dx > 0 { dx + 1 } else { -dx }) as u8;
-            let dxp = if dx < 0 { dx } else { dx + i16::from(GB_WIDTH) };
-            let x = u32::from((i16::from(self.scx()) + dxp) as u8);
 
-            for y in 0..=0xFF {
-                let mut color = bg_0.get_pixel(x, y);
-                color.data[3] = ((color.data[3] as u32 * dxa as u32) / (border_width as u32)) as u8;
-                bg_0.put_pixel(x, y, color);
-            }
-        }
+        let tile_colors = match tile_palette {
+            TilePalette::Shades => [
+                palette.color(0),
+                palette.color(1),
+                palette.color(2),
+                palette.color(3),
+            ],
+            TilePalette::Background => bg_palette,
+            TilePalette::Object(obp_index) => obj_palettes[usize::from(obp_index & 0b1)],
+            TilePalette::CgbBackground(index) => [
+                cgb_color(&self.vid.bg_palette_ram, index, 0),
+                cgb_color(&self.vid.bg_palette_ram, index, 1),
+                cgb_color(&self.vid.bg_palette_ram, index, 2),
+                cgb_color(&self.vid.bg_palette_ram, index, 3),
+            ],
+            TilePalette::CgbObject(index) => [
+                cgb_color(&self.vid.obj_palette_ram, index, 0),
+                cgb_color(&self.vid.obj_palette_ram, index, 1),
+                cgb_color(&self.vid.obj_palette_ram, index, 2),
+                cgb_color(&self.vid.obj_palette_ram, index, 3),
+            ],
+        };
+        let tiles = self.draw_tiles(&tile_colors);
+        let sprites = self.draw_sprites(&obj_palettes);
+        let oam = self.sprites();
 
         {
             let mut self_output_buffer = self.output_buffer.lock().unwrap();
+            self_output_buffer.screen = self_output_buffer.filters.apply(&display);
             self_output_buffer.display = display;
             self_output_buffer.bg_0 = bg_0;
+            self_output_buffer.bg_1 = bg_1;
             self_output_buffer.tiles = tiles;
             self_output_buffer.bgp = bgp;
+            self_output_buffer.op_0 = op_0;
+            self_output_buffer.op_1 = op_1;
+            self_output_buffer.sprites = sprites;
+            self_output_buffer.oam = oam;
+        };
+    }
+
+    /// Draws the full 32x32 tile map at the given VRAM index, using the tile
+    /// data addressing mode currently selected in LCDC.
+    fn draw_tile_map(&self, map_offset: usize, bg_palette: &[image::Rgba<u8>; 4]) -> DynamicImage {
+        let mut map = DynamicImage::new_rgba8(256, 256);
+        for y in 0..256u32 {
+            for x in 0..256u32 {
+                let (color_number, attributes) = self.tile_map_pixel(map_offset, x as u8, y as u8);
+                let color = if self.cgb_mode {
+                    cgb_color(&self.vid.bg_palette_ram, attributes, color_number)
+                } else {
+                    bg_palette[usize::from(color_number)]
+                };
+
+                map.put_pixel(x, y, color);
+            }
+        }
+        map
+    }
+
+    /// Returns the colour number of a pixel in the tile map at the given VRAM
+    /// index, along with the CGB attributes of its tile (zero on the DMG).
+    fn tile_map_pixel(&self, map_offset: usize, x: u8, y: u8) -> (u8, u8) {
+        let map_index = map_offset + usize::from(y / 8) * 32 + usize::from(x / 8);
+        let tile_index = self.vid.vram[map_index];
+        // CGB tile attributes are in the same position in the second bank
+        let attributes = if self.cgb_mode {
+            self.vid.vram[0x2000 + map_index]
+        } else {
+            0x00
+        };
+
+        let mut tile_x = x % 8;
+        let mut tile_y = y % 8;
+        if attributes & 0b0010_0000 != 0 {
+            tile_x = 7 - tile_x;
+        }
+        if attributes & 0b0100_0000 != 0 {
+            tile_y = 7 - tile_y;
+        }
+        let bank_offset = usize::from((attributes & 0b0000_1000) >> 3) * 0x2000;
+        let tile_data_index = bank_offset + self.bg_tile_data_index(tile_index);
+        (
+            tile_pixel(&self.vid.vram, tile_data_index, tile_x, tile_y),
+            attributes,
+        )
+    }
+
+    /// Draws all 384 tiles in VRAM (from both banks in CGB mode) in rows of
+    /// 16, with one-pixel gaps between them.
+    fn draw_tiles(&self, colors: &[image::Rgba<u8>; 4]) -> DynamicImage {
+        let banks = if self.cgb_mode { 2 } else { 1 };
+        let mut tiles = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+            banks * 16 * 9 - 1,
+            24 * 9 - 1,
+            GAP_COLOR,
+        ));
+        for bank in 0..banks {
+            for i in 0..384u32 {
+                let tile_data_index = bank as usize * 0x2000 + i as usize * 16;
+                let tile_x = (bank * 16 + i % 16) * 9;
+                let tile_y = (i / 16) * 9;
+                for y in 0..8u8 {
+                    for x in 0..8u8 {
+                        let color_number = tile_pixel(&self.vid.vram, tile_data_index, x, y);
+                        tiles.put_pixel(
+                            tile_x + u32::from(x),
+                            tile_y + u32::from(y),
+                            colors[usize::from(color_number)],
+                        );
+                    }
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Draws all 40 sprites in OAM in rows of 10, with their palettes and
+    /// flips applied, and one-pixel gaps between them.
+    fn draw_sprites(&self, obj_palettes: &[[image::Rgba<u8>; 4]; 2]) -> DynamicImage {
+        let height = self.sprite_height();
+        let mut sprites =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(10 * 9 - 1, 4 * 17 - 1, GAP_COLOR));
+        for sprite in self.sprites() {
+            let sprite_x = u32::from(sprite.index % 10) * 9;
+            let sprite_y = u32::from(sprite.index / 10) * 17;
+            for y in 0..height {
+                for x in 0..8u8 {
+                    let color_number = self.sprite_pixel(&sprite, x, y);
+                    if color_number == 0 {
+                        // transparent
+                        continue;
+                    }
+
+                    let color = self.sprite_color(&sprite, color_number, obj_palettes);
+                    sprites.put_pixel(sprite_x + u32::from(x), sprite_y + u32::from(y), color);
+                }
+            }
+        }
+        sprites
+    }
+
+    /// Draws the sprites over the background on the display, up to ten per
+    /// line, leaving background colours 1-3 in front of them where either
+    /// the sprite or (in CGB mode) the background tile asks for it.
+    fn draw_display_sprites(
+        &self,
+        display: &mut DynamicImage,
+        bg_pixels: &[(u8, u8)],
+        obj_palettes: &[[image::Rgba<u8>; 4]; 2],
+    ) {
+        let height = self.sprite_height();
+        // in CGB mode, clearing LCDC bit 0 puts all sprites in front
+        let bg_priority_enabled = !self.cgb_mode || self.lcdc() & 0b0000_0001 != 0;
+        let sprites = self.sprites();
+        for y in 0..GB_HEIGHT {
+            let mut line_sprites: Vec<&Sprite> = sprites
+                .iter()
+                .filter(|sprite| {
+                    let top = i16::from(sprite.y) - 16;
+                    top <= i16::from(y) && i16::from(y) < top + i16::from(height)
+                }).take(10)
+                .collect();
+            // the DMG prefers the leftmost sprite, the CGB the first in OAM
+            if !self.cgb_mode {
+                line_sprites.sort_by_key(|sprite| (sprite.x, sprite.index));
+            }
+
+            // draw the preferred sprites last, so they end up on top
+            for sprite in line_sprites.iter().rev() {
+                let sprite_y = (i16::from(y) - (i16::from(sprite.y) - 16)) as u8;
+                for x in 0..8u8 {
+                    let display_x = i16::from(sprite.x) - 8 + i16::from(x);
+                    if display_x < 0 || display_x >= i16::from(GB_WIDTH) {
+                        continue;
+                    }
+                    let color_number = self.sprite_pixel(sprite, x, sprite_y);
+                    if color_number == 0 {
+                        // transparent
+                        continue;
+                    }
+
+                    let (bg_color_number, attributes) =
+                        bg_pixels[usize::from(y) * usize::from(GB_WIDTH) + display_x as usize];
+                    let bg_in_front = bg_priority_enabled
+                        && bg_color_number != 0
+                        && (sprite.behind_background() || attributes & 0b1000_0000 != 0);
+                    if bg_in_front {
+                        continue;
+                    }
+
+                    let color = self.sprite_color(sprite, color_number, obj_palettes);
+                    display.put_pixel(display_x as u32, u32::from(y), color);
+                }
+            }
+        }
+    }
+
+    /// Returns the colour number of a pixel in a sprite, with its flips
+    /// applied.
+    fn sprite_pixel(&self, sprite: &Sprite, x: u8, y: u8) -> u8 {
+        let height = self.sprite_height();
+        let source_x = if sprite.x_flip() { 7 - x } else { x };
+        let source_y = if sprite.y_flip() { height - 1 - y } else { y };
+        let tile = if height == 16 {
+            (sprite.tile & 0b1111_1110) + source_y / 8
+        } else {
+            sprite.tile
         };
+        let bank_offset = if self.cgb_mode {
+            usize::from(sprite.cgb_bank()) * 0x2000
+        } else {
+            0
+        };
+        let tile_data_index = bank_offset + usize::from(tile) * 16;
+        tile_pixel(&self.vid.vram, tile_data_index, source_x, source_y % 8)
+    }
+
+    /// Returns the colour for a colour number in the palette a sprite uses.
+    fn sprite_color(
+        &self,
+        sprite: &Sprite,
+        color_number: u8,
+        obj_palettes: &[[image::Rgba<u8>; 4]; 2],
+    ) -> image::Rgba<u8> {
+        if self.cgb_mode {
+            cgb_color(&self.vid.obj_palette_ram, sprite.cgb_palette(), color_number)
+        } else {
+            obj_palettes[usize::from(sprite.dmg_palette())][usize::from(color_number)]
+        }
+    }
+
+    /// Decodes all 40 entries in OAM.
+    fn sprites(&self) -> Vec<Sprite> {
+        self.vid
+            .oam
+            .chunks(4)
+            .enumerate()
+            .map(|(index, entry)| Sprite {
+                index: index as u8,
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                flags: entry[3],
+            }).collect()
+    }
+
+    /// Returns the height of sprites in pixels, as selected in LCDC.
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc() & 0b0000_0100 != 0 {
+            16
+        } else {
+            8
+        }
     }
 
     fn bgp(&self) -> u8 {
@@ -307,16 +1266,46 @@ impl VideoController for GameBoy {
     }
 
     fn set_bgp(&mut self, value: u8) {
-        // println!("    ; vid bgp = 0x{:02X}", value);
         self.vid.bgp = value;
     }
 
+    fn obp0(&self) -> u8 {
+        self.vid.obp0
+    }
+
+    fn set_obp0(&mut self, value: u8) {
+        self.vid.obp0 = value;
+    }
+
+    fn obp1(&self) -> u8 {
+        self.vid.obp1
+    }
+
+    fn set_obp1(&mut self, value: u8) {
+        self.vid.obp1 = value;
+    }
+
+    fn wy(&self) -> u8 {
+        self.vid.wy
+    }
+
+    fn set_wy(&mut self, value: u8) {
+        self.vid.wy = value;
+    }
+
+    fn wx(&self) -> u8 {
+        self.vid.wx
+    }
+
+    fn set_wx(&mut self, value: u8) {
+        self.vid.wx = value;
+    }
+
     fn scy(&self) -> u8 {
         self.vid.scy
     }
 
     fn set_scy(&mut self, value: u8) {
-        // println!("    ; vid scy = 0x{:02X}", value);
         self.vid.scy = value;
     }
 
@@ -325,7 +1314,6 @@ impl VideoController for GameBoy {
     }
 
     fn set_scx(&mut self, value: u8) {
-        // println!("    ; vid scx = 0x{:02X}", value);
         self.vid.scx = value;
     }
 
@@ -334,7 +1322,6 @@ impl VideoController for GameBoy {
     }
 
     fn set_lcdc(&mut self, value: u8) {
-        // println!("    ; vid lcdc = 0x{:02X}", value);
         self.vid.lcdc = value;
     }
 
@@ -346,3 +1333,27 @@ impl VideoController for GameBoy {
         panic!("writing to LY is not supported");
     }
 }
+
+impl GameBoy {
+    /// Sets whether the CPU may access VRAM and OAM while the PPU is using
+    /// them (in modes 3, and 2 and 3, respectively). Real hardware doesn't
+    /// allow this, but it can help when debugging.
+    pub fn set_relaxed_video_access(&mut self, relaxed: bool) {
+        self.vid.relaxed_access = relaxed;
+    }
+
+    /// Sets whether writes to VRAM and OAM while the PPU is using them are
+    /// logged, whether or not they're allowed, to be taken by
+    /// [GameBoy::take_log].
+    pub fn set_video_access_logging(&mut self, logged: bool) {
+        self.vid.log_blocked_access = logged;
+    }
+
+    /// Sets whether to emulate the DMG hardware bug that corrupts OAM when
+    /// 16-bit registers pointing into 0xFE00-0xFEFF are incremented or
+    /// decremented while the PPU is scanning OAM. It's enabled by default;
+    /// disabling it keeps OAM clean for games that trip over it.
+    pub fn set_oam_bug(&mut self, enabled: bool) {
+        self.vid.oam_bug = enabled;
+    }
+}
@@ -0,0 +1,447 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::str::FromStr;
+
+use zerodmg_codes::instruction::Instruction;
+
+use super::debugger::parse_address;
+use super::expression::Expression;
+use super::memory::io_register_name;
+use super::GameBoy;
+
+#[cfg(test)]
+fn test_gameboy() -> GameBoy {
+    use zerodmg_codes::instruction::prelude::*;
+
+    // writes 0x42 to 0xC000, then increments it forever
+    super::test_roms::gameboy_for(&[(
+        0x0100,
+        vec![
+            LD_8_IMMEDIATE(A, 0x42),
+            LD_8_TO_MEMORY_IMMEDIATE(0xC000),
+            LD_16_IMMEDIATE(HL, 0xC000),
+            INC(AT_HL),
+            JR(-3),
+        ],
+    )])
+}
+
+#[test]
+fn test_watchpoints_record_accesses() {
+    use zerodmg_codes::instruction::prelude::*;
+
+    let mut gameboy = test_gameboy();
+    let write = gameboy.add_watchpoint("w C000-C00F".parse().unwrap());
+    let read = gameboy.add_watchpoint("r C000 if [0xC000] > 0x42".parse().unwrap());
+    let execute = gameboy.add_watchpoint("x 0108".parse().unwrap());
+    let mut step = || -> Vec<usize> {
+        gameboy.step();
+        let hits = gameboy.take_watch_hits();
+        hits.iter().map(|hit| hit.watchpoint).collect()
+    };
+
+    assert_eq!(step(), vec![]);
+    assert_eq!(step(), vec![write]);
+    // stops before the increment, which can't be read as more than 0x42 yet
+    assert_eq!(step(), vec![execute]);
+    assert_eq!(step(), vec![write]);
+    assert_eq!(step(), vec![execute]);
+    assert_eq!(step(), vec![read, write]);
+    assert!(gameboy.remove_watchpoint(read));
+    assert_eq!(gameboy.watchpoints().len(), 2);
+
+    let mut gameboy = test_gameboy();
+    gameboy.add_watchpoint("w C000".parse().unwrap());
+    gameboy.step();
+    gameboy.step();
+    let hit = gameboy.take_watch_hits()[0];
+    assert_eq!(hit.access, Access::Write);
+    assert_eq!(hit.pc, 0x0102);
+    assert_eq!(hit.instruction, LD_8_TO_MEMORY_IMMEDIATE(0xC000));
+    assert_eq!(hit.new_value, 0x42);
+    assert_eq!(hit.cycle, 2);
+    assert_eq!(
+        "rwx 0xC000-0xC00F if A == 1"
+            .parse::<Watchpoint>()
+            .unwrap()
+            .to_string(),
+        "rwx 0xC000-0xC00F if A == 1"
+    );
+    assert!("w C010-C000".parse::<Watchpoint>().is_err());
+}
+
+#[test]
+fn test_io_logging() {
+    use super::memory::MemoryController;
+
+    let mut gameboy = test_gameboy();
+    assert!(gameboy.set_io_logging(0xC000, true).is_err());
+    gameboy.set_io_logging(0xFF47, true).unwrap();
+    assert_eq!(gameboy.logged_io_registers(), vec![0xFF47]);
+    gameboy.set_mem(0xFF47, 0xE4);
+    gameboy.mem(0xFF47);
+    gameboy.set_mem(0xFF48, 0xE4);
+    let log = gameboy.take_log();
+    assert_eq!(log.len(), 2);
+    assert!(log[0].starts_with("write rBGP = 0xE4, was "), "{}", log[0]);
+    assert!(log[1].starts_with("read rBGP = 0xE4 ; "), "{}", log[1]);
+    assert!(gameboy.take_log().is_empty());
+}
+
+// how many hits are kept until they're taken, after which more are dropped
+const MAX_PENDING_HITS: usize = 256;
+// how many log messages are kept until they're taken, after which more are
+// counted and dropped
+const MAX_PENDING_LOG: usize = 1024;
+
+/// A kind of memory access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// An instruction at the address being reached, before it runs.
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// A range of addresses to record accesses to, optionally only when a
+/// condition is true.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    /// The last address watched.
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub condition: Option<Expression>,
+}
+
+impl Watchpoint {
+    fn watches(&self, access: Access, addr: u16) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        kind && self.start <= addr && addr <= self.end
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// Parses the kinds of access to watch, as any of `r`, `w` and `x`
+    /// defaulting to `w`, then an `address` or `start-end` range in hex,
+    /// optionally followed by `if` and an [Expression].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, condition) = match s.find(" if ") {
+            Some(i) => (&s[..i], Some(s[i + 4..].parse::<Expression>()?)),
+            None => (s, None),
+        };
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (kinds, range) = match words.len() {
+            1 => ("w", words[0]),
+            2 if words[0].chars().all(|c| c == 'r' || c == 'w' || c == 'x') => {
+                (words[0], words[1])
+            }
+            _ => return Err(format!("invalid watchpoint: {}", s)),
+        };
+        let (start, end) = match range.find('-') {
+            Some(i) => (parse_address(&range[..i])?, parse_address(&range[i + 1..])?),
+            None => {
+                let address = parse_address(range)?;
+                (address, address)
+            }
+        };
+        if end < start {
+            return Err(format!("invalid range: {}", range));
+        }
+        Ok(Self {
+            start,
+            end,
+            read: kinds.contains('r'),
+            write: kinds.contains('w'),
+            execute: kinds.contains('x'),
+            condition,
+        })
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(kind, name) in [(self.read, "r"), (self.write, "w"), (self.execute, "x")].iter() {
+            if kind {
+                write!(f, "{}", name)?;
+            }
+        }
+        write!(f, " 0x{:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-0x{:04X}", self.end)?;
+        }
+        if let Some(ref condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// An access recorded by a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The number of the watchpoint.
+    pub watchpoint: usize,
+    pub access: Access,
+    pub address: u16,
+    /// The address of the instruction making the access.
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// The value before the access. For reads and executes, this is the
+    /// same as the new value.
+    pub old_value: u8,
+    pub new_value: u8,
+    /// When the instruction started, as in [GameBoy::cycles].
+    pub cycle: u64,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watchpoint {}: {} 0x{:04X} ", self.watchpoint, self.access, self.address)?;
+        if self.access == Access::Write {
+            write!(f, "0x{:02X} -> 0x{:02X}", self.old_value, self.new_value)?;
+        } else {
+            write!(f, "0x{:02X}", self.new_value)?;
+        }
+        write!(
+            f,
+            " ; 0x{:04X} {} ; cycle {}",
+            self.pc, self.instruction, self.cycle
+        )
+    }
+}
+
+/// Game Boy watchpoints and IO register logging, which are checked on each
+/// memory access
+pub struct WatchData {
+    // numbered from 1 by their index, with removed watchpoints left empty
+    watchpoints: Vec<Option<Watchpoint>>,
+    // the IO registers, at 0xFF00-0xFFFF, whose accesses are logged
+    logged_registers: Vec<bool>,
+    // whether there are any watchpoints or logged registers, so that
+    // accesses don't need to be checked otherwise
+    active: bool,
+    // set while memory is read for tracing or debugging, rather than by the
+    // emulated hardware
+    suspended: Cell<bool>,
+    // the instruction being executed, and its address
+    pc: u16,
+    instruction: Instruction,
+    hits: RefCell<Vec<WatchHit>>,
+    // diagnostics for the frontend to show, and how many were dropped since
+    // they were last taken
+    log: RefCell<Vec<String>>,
+    dropped_log: Cell<usize>,
+}
+
+impl WatchData {
+    pub fn new() -> Self {
+        Self {
+            watchpoints: vec![],
+            logged_registers: vec![false; 0x100],
+            active: false,
+            suspended: Cell::new(false),
+            pc: 0x0000,
+            instruction: Instruction::NOP,
+            hits: RefCell::new(vec![]),
+            log: RefCell::new(vec![]),
+            dropped_log: Cell::new(0),
+        }
+    }
+
+    fn update_active(&mut self) {
+        self.active = self.watchpoints.iter().any(Option::is_some)
+            || self.logged_registers.iter().any(|&logged| logged);
+    }
+}
+
+pub trait WatchController {
+    fn begin_instruction(&mut self, pc: u16, instruction: Instruction);
+    fn watch_execute(&mut self);
+    fn watch_read(&self, addr: u16, value: u8);
+    fn watch_write(&self, addr: u16, value: u8);
+    fn unwatched<T, F: FnOnce(&Self) -> T>(&self, read: F) -> T;
+    fn log(&self, message: String);
+}
+
+impl GameBoy {
+    // records an access for any watchpoints it matches, and logs it if it's
+    // to a logged register
+    fn watch_access(&self, access: Access, addr: u16, old_value: u8, new_value: u8) {
+        if 0xFF00 <= addr && self.watch.logged_registers[usize::from(addr - 0xFF00)] {
+            let name = io_register_name(addr).unwrap_or("?");
+            let value = if access == Access::Write {
+                format!("0x{:02X}, was 0x{:02X}", new_value, old_value)
+            } else {
+                format!("0x{:02X}", new_value)
+            };
+            self.log(format!(
+                "{} r{} = {} ; 0x{:04X} {} ; cycle {}",
+                access, name, value, self.watch.pc, self.watch.instruction, self.t
+            ));
+        }
+
+        for (i, watchpoint) in self.watch.watchpoints.iter().enumerate() {
+            let watchpoint = match watchpoint {
+                Some(watchpoint) if watchpoint.watches(access, addr) => watchpoint,
+                _ => continue,
+            };
+            let condition_met = self.unwatched(|gameboy| {
+                watchpoint
+                    .condition
+                    .as_ref()
+                    .map_or(true, |condition| condition.is_true(gameboy))
+            });
+            let mut hits = self.watch.hits.borrow_mut();
+            if condition_met && hits.len() < MAX_PENDING_HITS {
+                hits.push(WatchHit {
+                    watchpoint: i + 1,
+                    access,
+                    address: addr,
+                    pc: self.watch.pc,
+                    instruction: self.watch.instruction,
+                    old_value,
+                    new_value,
+                    cycle: self.t,
+                });
+            }
+        }
+    }
+}
+
+impl WatchController for GameBoy {
+    /// Notes the instruction about to be executed, for recording accesses it
+    /// makes.
+    fn begin_instruction(&mut self, pc: u16, instruction: Instruction) {
+        self.watch.pc = pc;
+        self.watch.instruction = instruction;
+    }
+
+    /// Checks for execute watchpoints at PC, after an instruction, so that
+    /// the debugger can stop before the next one runs.
+    fn watch_execute(&mut self) {
+        if self.watch.active {
+            let pc = self.pc();
+            let (_, instruction) = self.disassemble(pc, 1)[0];
+            self.begin_instruction(pc, instruction);
+            let opcode = self.peek(pc);
+            self.watch_access(Access::Execute, pc, opcode, opcode);
+        }
+    }
+
+    fn watch_read(&self, addr: u16, value: u8) {
+        if self.watch.active && !self.watch.suspended.get() {
+            self.watch_access(Access::Read, addr, value, value);
+        }
+    }
+
+    /// Checks a write before it's made, while the old value can be read.
+    fn watch_write(&self, addr: u16, value: u8) {
+        if self.watch.active && !self.watch.suspended.get() {
+            let old_value = self.peek(addr);
+            self.watch_access(Access::Write, addr, old_value, value);
+        }
+    }
+
+    /// Runs a function reading memory without the reads being watched.
+    fn unwatched<T, F: FnOnce(&Self) -> T>(&self, read: F) -> T {
+        let suspended = self.watch.suspended.replace(true);
+        let result = read(self);
+        self.watch.suspended.set(suspended);
+        result
+    }
+
+    /// Records a diagnostic message, to be taken by [GameBoy::take_log].
+    fn log(&self, message: String) {
+        let mut log = self.watch.log.borrow_mut();
+        if log.len() < MAX_PENDING_LOG {
+            log.push(message);
+        } else {
+            self.watch.dropped_log.set(self.watch.dropped_log.get() + 1);
+        }
+    }
+}
+
+impl GameBoy {
+    /// Adds a watchpoint, returning its number.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watch.watchpoints.push(Some(watchpoint));
+        self.watch.update_active();
+        self.watch.watchpoints.len()
+    }
+
+    /// Removes a watchpoint by number, returning whether it existed.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let removed = match self.watch.watchpoints.get_mut(id.wrapping_sub(1)) {
+            Some(watchpoint) => watchpoint.take().is_some(),
+            None => false,
+        };
+        self.watch.update_active();
+        removed
+    }
+
+    /// The watchpoints, with their numbers.
+    pub fn watchpoints(&self) -> Vec<(usize, &Watchpoint)> {
+        self.watch
+            .watchpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, watchpoint)| watchpoint.as_ref().map(|watchpoint| (i + 1, watchpoint)))
+            .collect()
+    }
+
+    /// Removes and returns the accesses recorded by watchpoints, oldest
+    /// first.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.watch.hits.replace(vec![])
+    }
+
+    /// Removes and returns the diagnostic messages logged, oldest first,
+    /// ending with how many more were dropped if they weren't taken often
+    /// enough.
+    pub fn take_log(&mut self) -> Vec<String> {
+        let mut log = self.watch.log.replace(vec![]);
+        let dropped = self.watch.dropped_log.replace(0);
+        if dropped > 0 {
+            log.push(format!("{} more messages dropped", dropped));
+        }
+        log
+    }
+
+    /// Starts or stops logging each access to an IO register, at
+    /// 0xFF00-0xFFFF, with its name and the instruction making it, to be
+    /// taken by [GameBoy::take_log].
+    pub fn set_io_logging(&mut self, addr: u16, logged: bool) -> Result<(), String> {
+        if addr < 0xFF00 {
+            return Err(format!("0x{:04X} isn't an IO register", addr));
+        }
+        self.watch.logged_registers[usize::from(addr - 0xFF00)] = logged;
+        self.watch.update_active();
+        Ok(())
+    }
+
+    /// The IO registers whose accesses are logged.
+    pub fn logged_io_registers(&self) -> Vec<u16> {
+        (0xFF00..=0xFFFF)
+            .filter(|&addr| self.watch.logged_registers[usize::from(addr - 0xFF00)])
+            .collect()
+    }
+}
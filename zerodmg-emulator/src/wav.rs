@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use zerodmg_utils::little_endian::{u16_to_u8s, u32_to_u8s};
+
+use super::audio::{StereoSample, SAMPLE_RATE as APU_SAMPLE_RATE};
+use super::audio_output::{HighPassFilter, Resampler, SampleSink};
+
+#[test]
+fn test_wav_header_and_samples() {
+    let path = std::env::temp_dir().join(format!("zerodmg-test-{}.wav", std::process::id()));
+    {
+        let mut writer = WavWriter::create(&path, 44_100, 2).unwrap();
+        writer.push_sample(StereoSample {
+            left: 1.0,
+            right: -1.0,
+        });
+        writer.push_sample(StereoSample::default());
+        writer.finish().unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[4..8], &u32_to_u8s(36 + 8));
+    assert_eq!(&bytes[8..16], b"WAVEfmt ");
+    assert_eq!(&bytes[22..24], &[2, 0]);
+    assert_eq!(&bytes[24..28], &u32_to_u8s(44_100));
+    assert_eq!(&bytes[36..40], b"data");
+    assert_eq!(&bytes[40..44], &u32_to_u8s(8));
+    assert_eq!(&bytes[44..], &[0xFF, 0x7F, 0x01, 0x80, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_wav_is_finished_when_dropped() {
+    let path = std::env::temp_dir().join(format!("zerodmg-test-{}-drop.wav", std::process::id()));
+    {
+        let mut writer = WavWriter::create(&path, 44_100, 1).unwrap();
+        for _ in 0..1000 {
+            writer.push_sample(StereoSample::default());
+        }
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&bytes[4..8], &u32_to_u8s(36 + 2000));
+    assert_eq!(&bytes[40..44], &u32_to_u8s(2000));
+    assert_eq!(bytes.len(), 44 + 2000);
+}
+
+// the size of the RIFF and format headers before the sample data
+const HEADER_SIZE: u32 = 44;
+// how often the header is rewritten with the current length, so that little
+// is lost if the process is killed, as the UI is
+const HEADER_UPDATES_PER_SECOND: u32 = 60;
+
+/// Writes 16-bit PCM WAV files, with either one (using the left side of each
+/// sample) or two channels. The file is finished when the writer is dropped,
+/// but [WavWriter::finish] also reports any errors.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    sample_rate: u32,
+    frames_written: u32,
+    // whether the header has been given its final length
+    finished: bool,
+    // the first error encountered while writing samples
+    error: Option<io::Error>,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            channels,
+            sample_rate,
+            frames_written: 0,
+            finished: false,
+            error: None,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn data_size(&self) -> u32 {
+        self.frames_written * u32::from(self.channels) * 2
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels * 2;
+        let data_size = self.data_size();
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&u32_to_u8s(HEADER_SIZE - 8 + data_size))?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&u32_to_u8s(16))?;
+        // uncompressed PCM
+        write_u16(file, 1)?;
+        write_u16(file, self.channels)?;
+        file.write_all(&u32_to_u8s(self.sample_rate))?;
+        file.write_all(&u32_to_u8s(self.sample_rate * u32::from(block_align)))?;
+        write_u16(file, block_align)?;
+        write_u16(file, 16)?;
+        file.write_all(b"data")?;
+        file.write_all(&u32_to_u8s(data_size))?;
+        Ok(())
+    }
+
+    /// Rewrites the header with the current length and flushes everything
+    /// written so far, so that the file is valid even if we never get to
+    /// finish it.
+    pub fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    /// Updates the header and reports any error from writing samples.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.update_header()
+    }
+
+    fn write_value(&mut self, value: f32) -> io::Result<()> {
+        let clamped = value.max(-1.0).min(1.0);
+        write_u16(&mut self.file, (clamped * 32767.0).round() as i16 as u16)
+    }
+}
+
+impl SampleSink for WavWriter {
+    fn push_sample(&mut self, sample: StereoSample) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut result = self.write_value(sample.left);
+        if self.channels > 1 && result.is_ok() {
+            result = self.write_value(sample.right);
+        }
+        match result {
+            Ok(()) => {
+                self.frames_written += 1;
+                let interval = (self.sample_rate / HEADER_UPDATES_PER_SECOND).max(1);
+                if self.frames_written % interval == 0 {
+                    if let Err(error) = self.update_header() {
+                        self.error = Some(error);
+                    }
+                }
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished && self.error.is_none() {
+            // there's nowhere to report an error to
+            let _ = self.update_header();
+        }
+    }
+}
+
+fn write_u16(file: &mut BufWriter<File>, value: u16) -> io::Result<()> {
+    file.write_all(&u16_to_u8s(value))
+}
+
+/// One filtered and resampled audio stream being written to a WAV file.
+struct RecordedStream {
+    high_pass: HighPassFilter,
+    resampler: Resampler,
+    writer: WavWriter,
+}
+
+impl RecordedStream {
+    fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        Ok(Self {
+            high_pass: HighPassFilter::new(APU_SAMPLE_RATE),
+            resampler: Resampler::new(APU_SAMPLE_RATE, sample_rate),
+            writer: WavWriter::create(path, sample_rate, channels)?,
+        })
+    }
+
+    fn push(&mut self, sample: StereoSample) {
+        let filtered = self.high_pass.apply(sample);
+        self.resampler.push(filtered, &mut self.writer);
+    }
+}
+
+/// Records the APU's mixed stereo output to a WAV file, and optionally each
+/// channel's mono output to its own file alongside it.
+pub struct AudioRecorder {
+    mixed: RecordedStream,
+    channels: Vec<RecordedStream>,
+}
+
+impl AudioRecorder {
+    /// Starts recording to the given path. Channels are recorded to paths
+    /// with the channel number added, like `music.ch1.wav`.
+    pub fn create(path: &Path, sample_rate: u32, per_channel: bool) -> io::Result<Self> {
+        let mut channels = vec![];
+        if per_channel {
+            for n in 1..=4 {
+                channels.push(RecordedStream::create(
+                    &channel_path(path, n),
+                    sample_rate,
+                    1,
+                )?);
+            }
+        }
+        Ok(Self {
+            mixed: RecordedStream::create(path, sample_rate, 2)?,
+            channels,
+        })
+    }
+
+    /// Adds the mixed output and each channel's output for one M-cycle.
+    pub fn push(&mut self, mixed: StereoSample, channels: [f32; 4]) {
+        self.mixed.push(mixed);
+        for (stream, value) in self.channels.iter_mut().zip(channels.iter()) {
+            stream.push(StereoSample {
+                left: *value,
+                right: *value,
+            });
+        }
+    }
+
+    /// Finishes writing all of the files.
+    pub fn finish(self) -> io::Result<()> {
+        self.mixed.writer.finish()?;
+        for stream in self.channels {
+            stream.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn channel_path(path: &Path, n: u8) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.ch{}.wav", stem, n))
+}
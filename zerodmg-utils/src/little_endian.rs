@@ -13,19 +13,46 @@ pub fn u8s_to_u16(a: u8, b: u8) -> u16 {
     u16::from(a) + (u16::from(b) << 8)
 }
 
-/// Splits a `u16` into two `u8`s.
+/// Splits a `u16` into two `u8`s, least significant first.
 ///
 /// # Examples
 ///
 /// ```
 /// use zerodmg_utils::little_endian::u16_to_u8s;
 ///
-/// assert_eq!(u16_to_u8s(0x8001), (0x01, 0x80));
-/// assert_eq!(u16_to_u8s(0x0001), (0x01, 0x00));
-/// assert_eq!(u16_to_u8s(0x0100), (0x00, 0x01));
+/// assert_eq!(u16_to_u8s(0x8001), [0x01, 0x80]);
+/// assert_eq!(u16_to_u8s(0x0001), [0x01, 0x00]);
+/// assert_eq!(u16_to_u8s(0x0100), [0x00, 0x01]);
 /// ```
-pub fn u16_to_u8s(x: u16) -> (u8, u8) {
-    (x as u8, (x >> 8) as u8)
+pub fn u16_to_u8s(x: u16) -> [u8; 2] {
+    [x as u8, (x >> 8) as u8]
+}
+
+/// Splits a `u32` into four `u8`s, least significant first.
+///
+/// # Examples
+///
+/// ```
+/// use zerodmg_utils::little_endian::u32_to_u8s;
+///
+/// assert_eq!(u32_to_u8s(0x8040_2001), [0x01, 0x20, 0x40, 0x80]);
+/// assert_eq!(u32_to_u8s(0x0000_0100), [0x00, 0x01, 0x00, 0x00]);
+/// ```
+pub fn u32_to_u8s(x: u32) -> [u8; 4] {
+    [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
+}
+
+/// Combines four `u8`s, least significant first, into a `u32`.
+///
+/// # Examples
+///
+/// ```
+/// use zerodmg_utils::little_endian::u8s_to_u32;
+///
+/// assert_eq!(u8s_to_u32([0x01, 0x20, 0x40, 0x80]), 0x8040_2001);
+/// ```
+pub fn u8s_to_u32(x: [u8; 4]) -> u32 {
+    u32::from(x[0]) + (u32::from(x[1]) << 8) + (u32::from(x[2]) << 16) + (u32::from(x[3]) << 24)
 }
 
 /// Returns the value of the `offset`th bit in a `u8` `x`.